@@ -0,0 +1,4 @@
+// sqlx::migrate!()はマイグレーションをバイナリに埋め込むため、追加・変更されたら再ビルドさせる
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
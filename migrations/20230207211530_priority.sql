@@ -0,0 +1,3 @@
+CREATE TYPE priority AS ENUM ('low', 'medium', 'high');
+
+ALTER TABLE todos ADD COLUMN priority priority NOT NULL DEFAULT 'medium';
@@ -0,0 +1 @@
+ALTER TABLE todos ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now();
@@ -0,0 +1,12 @@
+CREATE TYPE audit_action AS ENUM ('create', 'update', 'delete', 'restore', 'purge');
+
+-- 物理削除した後も履歴を参照できるよう、todosへの外部キーは張らない
+CREATE TABLE todo_audit (
+    id SERIAL PRIMARY KEY,
+    todo_id INTEGER NOT NULL,
+    action audit_action NOT NULL,
+    snapshot JSONB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX todo_audit_todo_id_idx ON todo_audit (todo_id);
@@ -0,0 +1,4 @@
+-- 親のTodoを物理削除する際に子の削除と同じトランザクションで処理できるよう、チェックをコミット時まで延期する
+ALTER TABLE todos ADD COLUMN parent_id INTEGER REFERENCES todos (id) DEFERRABLE INITIALLY DEFERRED;
+
+CREATE INDEX todos_parent_id_idx ON todos (parent_id);
@@ -0,0 +1,3 @@
+CREATE TYPE recurrence AS ENUM ('daily', 'weekly', 'monthly');
+
+ALTER TABLE todos ADD COLUMN recurrence recurrence;
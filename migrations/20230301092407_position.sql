@@ -0,0 +1,4 @@
+-- 既存の行にはid順に、以降に作成した行には末尾になるよう連番を振る
+CREATE SEQUENCE todos_position_seq OWNED BY todos.id;
+
+ALTER TABLE todos ADD COLUMN position INTEGER NOT NULL DEFAULT nextval('todos_position_seq');
@@ -0,0 +1,4 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- あいまい検索に加え、ilikeによる部分一致検索でも使われる
+CREATE INDEX todos_text_trgm_idx ON todos USING GIN (text gin_trgm_ops);
@@ -0,0 +1,11 @@
+-- 既存のTodoには所有者がいないため、user_id=1のユーザーに割り当ててからNOT NULLにする
+ALTER TABLE todos ADD COLUMN user_id INTEGER;
+
+UPDATE todos SET user_id = 1;
+
+ALTER TABLE todos ALTER COLUMN user_id SET NOT NULL;
+
+CREATE INDEX todos_user_id_idx ON todos (user_id);
+
+-- 履歴はスナップショットのuser_idで所有者を判定するため、既存の履歴にも補う
+UPDATE todo_audit SET snapshot = jsonb_set(snapshot, '{user_id}', '1');
@@ -0,0 +1,4 @@
+ALTER TABLE todos ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT now();
+
+-- 既存の行は最後に更新された日時が分からないため、作成日時で埋める
+UPDATE todos SET updated_at = created_at;
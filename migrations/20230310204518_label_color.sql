@@ -0,0 +1,2 @@
+-- 既存のラベルは色を持たないためNULLを許可する、形式はアプリケーションでも検証する
+ALTER TABLE labels ADD COLUMN color VARCHAR(7) CHECK (color ~ '^#[0-9A-Fa-f]{6}$');
@@ -0,0 +1,20 @@
+-- 大文字・小文字だけが異なるラベル名を重複として扱う
+-- 既に重複しているラベルは、idが最も小さいものに統合してから適用する
+UPDATE todo_labels SET label_id = kept.id
+FROM labels duplicated,
+    (SELECT LOWER(name) AS lower_name, MIN(id) AS id FROM labels GROUP BY LOWER(name)) kept
+WHERE todo_labels.label_id = duplicated.id
+    AND LOWER(duplicated.name) = kept.lower_name
+    AND duplicated.id <> kept.id;
+
+-- 統合によって同じTodoに同じラベルが重複した組み合わせは、最初に追加した1件を残して削除する
+DELETE FROM todo_labels duplicated USING todo_labels kept
+WHERE duplicated.todo_id = kept.todo_id
+    AND duplicated.label_id = kept.label_id
+    AND duplicated.id > kept.id;
+
+DELETE FROM labels duplicated USING labels kept
+WHERE LOWER(duplicated.name) = LOWER(kept.name)
+    AND duplicated.id > kept.id;
+
+CREATE UNIQUE INDEX labels_lower_name_idx ON labels (LOWER(name));
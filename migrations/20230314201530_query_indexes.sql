@@ -0,0 +1,11 @@
+-- 一覧取得の?completed=による絞り込みと、一括完了(/todos/complete-all)・完了済みの一括削除で使われる
+CREATE INDEX todos_completed_idx ON todos (completed);
+
+-- 一覧取得の?created_after=・?created_before=による絞り込みと、sort=created_atでの並べ替えで使われる
+CREATE INDEX todos_created_at_idx ON todos (created_at);
+
+-- Todoにラベルを結合する全ての取得(find, all, searchなど)と、Todoの削除時の紐付けの削除で使われる
+CREATE INDEX todo_labels_todo_id_idx ON todo_labels (todo_id);
+
+-- ラベルでの絞り込み(?label_id=, /labels/:id/todos)と、ラベルごとの件数の集計・ラベルの削除時の確認で使われる
+CREATE INDEX todo_labels_label_id_idx ON todo_labels (label_id);
@@ -0,0 +1,11 @@
+-- 同じTodoに同じラベルが重複して付かないようにする
+-- 既に重複している組み合わせは、最初に追加した1件を残して削除してから適用する
+DELETE FROM todo_labels duplicated USING todo_labels kept
+WHERE duplicated.todo_id = kept.todo_id
+    AND duplicated.label_id = kept.label_id
+    AND duplicated.id > kept.id;
+
+ALTER TABLE todo_labels ADD CONSTRAINT todo_labels_todo_id_label_id_key UNIQUE (todo_id, label_id);
+
+-- 一意制約のインデックスがtodo_idから始まるため、todo_idだけのインデックスは不要になる
+DROP INDEX todo_labels_todo_id_idx;
@@ -1,15 +1,169 @@
 use axum::{
     async_trait,
-    extract::{FromRequest, RequestParts},
-    BoxError, Json,
+    extract::{FromRequest, Query, RequestParts},
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use http_body::Body as _;
+use hyper::{
+    body::{Body, Buf},
+    header::CONTENT_LENGTH,
+    Request, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+use crate::{
+    normalize::{Normalize, TextNormalization},
+    pretty_json::Json,
+    repositories::{todo::MaxTextLength, RepositoryError},
 };
-use hyper::StatusCode;
-use serde::de::DeserializeOwned;
-use validator::Validate;
 
+pub mod auth;
+pub mod health;
+pub mod json_api;
 pub mod label;
+pub mod openapi;
 pub mod todo;
 
+// リポジトリ層から返ったエラーをステータスコードに変換する
+// RepositoryError以外(sqlx::Errorなど)は想定外のエラーとして500を返す
+pub fn to_status_code(error: anyhow::Error) -> StatusCode {
+    match error.downcast_ref::<RepositoryError>() {
+        Some(error) => status_code(error),
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn status_code(error: &RepositoryError) -> StatusCode {
+    match error {
+        RepositoryError::NotFound(_) | RepositoryError::LabelNotFound(_) => StatusCode::NOT_FOUND,
+        RepositoryError::Duplicate(_) | RepositoryError::Conflict(_) => StatusCode::CONFLICT,
+        RepositoryError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        RepositoryError::Unexpected(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// ハンドラがResult<_, RepositoryError>を返せるようにする
+// ボディは{"error":"not_found","message":"NotFound, id is 1"}の形式
+impl IntoResponse for RepositoryError {
+    fn into_response(self) -> Response {
+        let error = match &self {
+            RepositoryError::NotFound(_) => "not_found",
+            RepositoryError::LabelNotFound(_) => "label_not_found",
+            RepositoryError::Duplicate(_) => "duplicate",
+            RepositoryError::Conflict(_) => "conflict",
+            RepositoryError::Validation(_) => "validation",
+            RepositoryError::Unexpected(_) => "unexpected",
+        };
+        // 想定外のエラーの詳細(SQLなど)はクライアントに返さずログにだけ残す
+        let message = match &self {
+            RepositoryError::Unexpected(detail) => {
+                tracing::error!("unexpected repository error: {}", detail);
+                String::from("Internal Server Error")
+            }
+            _ => self.to_string(),
+        };
+        error_response(status_code(&self), error, message).into_response()
+    }
+}
+
+// 抽出に失敗した際のレスポンス、フロントエンドでパースできるようJSONで返す
+pub type ErrorResponse = (StatusCode, Json<Value>);
+
+fn error_response(status: StatusCode, error: &str, message: String) -> ErrorResponse {
+    (status, Json(json!({ "error": error, "message": message })))
+}
+
+// {"error":"validation","fields":{"text":["Can not be empty"]}}の形式に変換する
+fn validation_error_response(errors: &ValidationErrors) -> ErrorResponse {
+    let mut fields = BTreeMap::new();
+    collect_field_errors(errors, "", &mut fields);
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "error": "validation", "fields": fields })),
+    )
+}
+
+fn collect_field_errors(
+    errors: &ValidationErrors,
+    prefix: &str,
+    fields: &mut BTreeMap<String, Vec<String>>,
+) {
+    visit_field_errors(errors, prefix, &mut |path, error| {
+        fields.entry(path).or_default().push(error_message(error));
+    });
+}
+
+// ネストした構造体やVecの要素は"todos[1].text"のようなパスで渡す
+fn visit_field_errors(
+    errors: &ValidationErrors,
+    prefix: &str,
+    visit: &mut impl FnMut(String, &ValidationError),
+) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                for error in errors {
+                    visit(path.clone(), error);
+                }
+            }
+            ValidationErrorsKind::Struct(errors) => visit_field_errors(errors, &path, visit),
+            ValidationErrorsKind::List(list) => {
+                for (index, errors) in list {
+                    visit_field_errors(errors, &format!("{}[{}]", path, index), visit);
+                }
+            }
+        }
+    }
+}
+
+fn error_message(error: &ValidationError) -> String {
+    match &error.message {
+        Some(message) => message.to_string(),
+        None => error.code.to_string(),
+    }
+}
+
+// codeにこの値を指定した検証は、?strict=falseの場合にエラーではなく警告として扱う
+// #[validate(length(max = 100, code = "warning", message = "Over text length"))]
+pub const WARNING_CODE: &str = "warning";
+
+// 受け付けた上でレスポンスに含める、警告になった検証の内容
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct Warning {
+    pub field: String,
+    pub message: String,
+}
+
+// 全てのエラーが警告にできるものであれば警告の一覧を返す、1つでもそうでなければNone
+fn into_warnings(errors: &ValidationErrors) -> Option<Vec<Warning>> {
+    let mut warnings = vec![];
+    let mut hard_error = false;
+    visit_field_errors(errors, "", &mut |field, error| {
+        if error.code == WARNING_CODE {
+            warnings.push(Warning {
+                field,
+                message: error_message(error),
+            });
+        } else {
+            hard_error = true;
+        }
+    });
+    (!hard_error).then_some(warnings)
+}
+
+// ValidatedJsonが受け付けるリクエストボディの最大サイズ
+pub const MAX_JSON_BODY_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub struct ValidatedJson<T>(T); // (T)
 
@@ -18,22 +172,328 @@ pub struct ValidatedJson<T>(T); // (T)
 impl<T, B> FromRequest<B> for ValidatedJson<T>
 where
     // Json::<T>::from_requestとバリデーション用のメソッドを呼べるようにするためのトレイト境界
-    T: DeserializeOwned + Validate,
+    T: DeserializeOwned + Validate + Normalize,
     B: http_body::Body + Send,
     B::Data: Send,
     B::Error: Into<BoxError>,
 {
-    type Rejection = (StatusCode, String); // FromRequestがエラーとなった際のレスポンス型
+    type Rejection = ErrorResponse; // FromRequestがエラーとなった際のレスポンス型
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        let Json(value) = Json::<T>::from_request(req).await.map_err(|rejection| {
-            let message = format!("Json parse error: [{}]", rejection);
-            (StatusCode::BAD_REQUEST, message)
+        let value: T = parse_json(req).await?;
+        validate_json(req, &value).map_err(|errors| validation_error_response(&errors))?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+// ボディをパースして正規化する、検証は呼び出し側で行う
+async fn parse_json<T, B>(req: &mut RequestParts<B>) -> Result<T, ErrorResponse>
+where
+    T: DeserializeOwned + Normalize,
+    B: http_body::Body + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    // 正規化の設定はcreate_appでExtensionとして渡す、なければ前後の空白のみ取り除く
+    let normalization = req
+        .extensions()
+        .and_then(|extensions| extensions.get::<TextNormalization>())
+        .copied()
+        .unwrap_or_default();
+    // 上限を超えたボディはパースする前に弾き、読み込んだ分をJsonに渡し直す
+    let bytes = read_body_with_limit(req, MAX_JSON_BODY_SIZE).await?;
+    let mut request = Request::new(Body::from(bytes));
+    if let Some(headers) = req.headers() {
+        *request.headers_mut() = headers.clone();
+    }
+    let mut req = RequestParts::new(request);
+    let Json(mut value) = Json::<T>::from_request(&mut req)
+        .await
+        .map_err(|rejection| {
+            error_response(StatusCode::BAD_REQUEST, "parse", rejection.to_string())
         })?;
-        value.validate().map_err(|rejection| {
-            let message = format!("Validation error: [{}]", rejection).replace('\n', ", ");
-            (StatusCode::BAD_REQUEST, message)
+    value.normalize(&normalization);
+    Ok(value)
+}
+
+// Todoのtextの最大文字数はcreate_appでExtensionとして渡す、なければ既定値で検証する
+fn validate_json<T: Validate, B>(req: &RequestParts<B>, value: &T) -> Result<(), ValidationErrors> {
+    let max_text_length = req
+        .extensions()
+        .and_then(|extensions| extensions.get::<MaxTextLength>())
+        .copied()
+        .unwrap_or_default();
+    max_text_length.scope(|| value.validate())
+}
+
+// Content-Lengthを信用せず、チャンク形式で送られた場合も読み込みながらサイズを確認する
+async fn read_body_with_limit<B>(
+    req: &mut RequestParts<B>,
+    limit: usize,
+) -> Result<Vec<u8>, ErrorResponse>
+where
+    B: http_body::Body + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let too_large = || {
+        let message = format!("limit is {} bytes", limit);
+        error_response(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message)
+    };
+
+    let content_length = req
+        .headers()
+        .and_then(|headers| headers.get(CONTENT_LENGTH))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+
+    let body = req.take_body().ok_or_else(|| {
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "unexpected",
+            "Body already extracted".to_string(),
+        )
+    })?;
+    let mut body = Box::pin(body);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let mut chunk = chunk
+            .map_err(|e| error_response(StatusCode::BAD_REQUEST, "parse", e.into().to_string()))?;
+        if bytes.len() + chunk.remaining() > limit {
+            return Err(too_large());
+        }
+        while chunk.has_remaining() {
+            let slice = chunk.chunk();
+            let len = slice.len();
+            bytes.extend_from_slice(slice);
+            chunk.advance(len);
+        }
+    }
+    Ok(bytes)
+}
+
+// ValidatedJsonと同じく検証するが、?strict=falseの場合は警告だけであれば受け付けて警告を返す
+#[derive(Debug)]
+pub struct ValidatedJsonWithWarnings<T>(T, Vec<Warning>);
+
+#[derive(Debug, Deserialize)]
+struct Strictness {
+    #[serde(default = "default_strict")]
+    strict: bool,
+}
+
+fn default_strict() -> bool {
+    true
+}
+
+#[async_trait]
+impl<T, B> FromRequest<B> for ValidatedJsonWithWarnings<T>
+where
+    T: DeserializeOwned + Validate + Normalize,
+    B: http_body::Body + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Query(Strictness { strict }) =
+            Query::<Strictness>::from_request(req)
+                .await
+                .map_err(|rejection| {
+                    error_response(StatusCode::BAD_REQUEST, "parse", rejection.to_string())
+                })?;
+        let value: T = parse_json(req).await?;
+        match validate_json(req, &value) {
+            Ok(()) => Ok(ValidatedJsonWithWarnings(value, vec![])),
+            Err(errors) => match into_warnings(&errors) {
+                Some(warnings) if !strict => Ok(ValidatedJsonWithWarnings(value, warnings)),
+                _ => Err(validation_error_response(&errors)),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ValidatedQuery<T>(T);
+
+// ValidatedJsonのクエリパラメータ版
+#[async_trait]
+impl<T, B> FromRequest<B> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    B: Send,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request(req).await.map_err(|rejection| {
+            error_response(StatusCode::BAD_REQUEST, "parse", rejection.to_string())
         })?;
-        Ok(ValidatedJson(value))
+        value
+            .validate()
+            .map_err(|errors| validation_error_response(&errors))?;
+        Ok(ValidatedQuery(value))
+    }
+}
+
+// 空白のみの文字列を弾くカスタムバリデータ
+pub fn validate_not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank"));
+    }
+    Ok(())
+}
+
+// "#1a2B3c"のような#RRGGBB形式の色のみ許可する
+pub fn validate_hex_color(value: &str) -> Result<(), ValidationError> {
+    match value.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(()),
+        _ => Err(ValidationError::new("hex_color")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[tokio::test]
+    async fn validate_json_with_configured_max_text_length() {
+        use crate::repositories::todo::CreateTodo;
+
+        let extract = |text: &str, max_text_length: Option<MaxTextLength>| {
+            let body = json!({ "text": text }).to_string();
+            let mut req = Request::builder()
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            if let Some(max_text_length) = max_text_length {
+                req.extensions_mut().insert(max_text_length);
+            }
+            async move { ValidatedJson::<CreateTodo>::from_request(&mut RequestParts::new(req)).await }
+        };
+
+        // MAX_TODO_TEXT_LEN=5の場合は6文字のTodoを弾く
+        let (status, Json(body)) = extract("123456", Some(MaxTextLength(5))).await.unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(
+            json!({ "text": ["Over text length, max is 5"] }),
+            body["fields"]
+        );
+        assert!(extract("12345", Some(MaxTextLength(5))).await.is_ok());
+        assert!(extract("123456", None).await.is_ok());
+    }
+
+    #[test]
+    fn hex_color() {
+        assert!(validate_hex_color("#1a2B3c").is_ok());
+        for invalid in ["1a2b3c", "#1a2b3", "#1a2b3c4", "#gggggg", "#1a2b3c ", ""] {
+            assert!(validate_hex_color(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn repository_error_to_status_code() {
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            to_status_code(RepositoryError::NotFound(1).into())
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            to_status_code(RepositoryError::LabelNotFound(1).into())
+        );
+        assert_eq!(
+            StatusCode::CONFLICT,
+            to_status_code(RepositoryError::Duplicate(1).into())
+        );
+        assert_eq!(
+            StatusCode::CONFLICT,
+            to_status_code(RepositoryError::Conflict(1).into())
+        );
+        assert_eq!(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            to_status_code(RepositoryError::Validation(String::from("invalid")).into())
+        );
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            to_status_code(RepositoryError::Unexpected(String::from("error")).into())
+        );
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            to_status_code(anyhow::anyhow!("error"))
+        );
+
+        // contextで包まれていても判別できる
+        let error = None::<()>
+            .context(RepositoryError::NotFound(1))
+            .unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, to_status_code(error));
+    }
+
+    async fn error_body(error: RepositoryError) -> (StatusCode, Value) {
+        let res = error.into_response();
+        let status = res.status();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn repository_error_into_response() {
+        for (error, status, expected) in [
+            (
+                RepositoryError::NotFound(1),
+                StatusCode::NOT_FOUND,
+                json!({"error": "not_found", "message": "NotFound, id is 1"}),
+            ),
+            (
+                RepositoryError::LabelNotFound(1),
+                StatusCode::NOT_FOUND,
+                json!({"error": "label_not_found", "message": "Label NotFound, id is 1"}),
+            ),
+            (
+                RepositoryError::Duplicate(1),
+                StatusCode::CONFLICT,
+                json!({"error": "duplicate", "message": "Duplicate data, id is 1"}),
+            ),
+            (
+                RepositoryError::Conflict(1),
+                StatusCode::CONFLICT,
+                json!({"error": "conflict", "message": "Conflict, id is 1"}),
+            ),
+            (
+                RepositoryError::Validation(String::from("label 1 does not exist")),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({"error": "validation", "message": "Validation error: label 1 does not exist"}),
+            ),
+            // 詳細は返さない
+            (
+                RepositoryError::Unexpected(String::from("connection lost")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": "unexpected", "message": "Internal Server Error"}),
+            ),
+        ] {
+            assert_eq!((status, expected), error_body(error).await);
+        }
+    }
+
+    #[test]
+    fn repository_error_from_anyhow() {
+        let error = RepositoryError::from(anyhow::Error::from(RepositoryError::NotFound(1)));
+        assert!(matches!(error, RepositoryError::NotFound(1)));
+
+        let error = RepositoryError::from(
+            None::<()>
+                .context(RepositoryError::Conflict(1))
+                .unwrap_err(),
+        );
+        assert!(matches!(error, RepositoryError::Conflict(1)));
+
+        // RepositoryError以外は想定外のエラーになる
+        let error = RepositoryError::from(anyhow::Error::from(sqlx::Error::RowNotFound));
+        assert!(matches!(error, RepositoryError::Unexpected(_)));
     }
 }
@@ -0,0 +1,215 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, Query, RequestParts},
+    response::{IntoResponse, Response},
+};
+use hyper::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::error_response;
+
+// トークンの署名・検証に使う共有シークレット
+#[derive(Clone)]
+pub struct JwtSecret(Vec<u8>);
+
+impl JwtSecret {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        JwtSecret(secret.into())
+    }
+}
+
+// 検証済みのトークンの中身、ハンドラからはExtension<Claims>で取り出す
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub user_id: i32,
+    // 有効期限(UNIX時間の秒)
+    pub exp: i64,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("Authorization header is missing")]
+    Missing,
+    #[error("Token is invalid")]
+    Invalid,
+    #[error("Token is expired")]
+    Expired,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let mut res = error_response(StatusCode::UNAUTHORIZED, "unauthorized", self.to_string())
+            .into_response();
+        res.headers_mut()
+            .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        res
+    }
+}
+
+// トークンの発行は別サービスが担うため、テストでのみ使う
+#[cfg(test)]
+pub fn encode_token(claims: &Claims, secret: &JwtSecret) -> String {
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(Algorithm::HS256),
+        claims,
+        &jsonwebtoken::EncodingKey::from_secret(&secret.0),
+    )
+    .expect("claims can be encoded")
+}
+
+// algはHS256に固定し、alg: noneなどへのすり替えを防ぐ
+// exp・nbfは既定の猶予(60秒)を含めて検証する
+pub fn decode_token(token: &str, secret: &JwtSecret) -> Result<Claims, AuthError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_nbf = true;
+    jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(&secret.0), &validation)
+        .map(|data| data.claims)
+        .map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => AuthError::Expired,
+            _ => AuthError::Invalid,
+        })
+}
+
+// extractor_middlewareに渡してルート全体に認証をかける
+// 検証に成功したClaimsはリクエストのextensionsに格納する
+#[async_trait]
+impl<B> FromRequest<B> for Claims
+where
+    B: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(req).ok_or_else(|| AuthError::Missing.into_response())?;
+        authenticate(req, &token).await
+    }
+}
+
+// ブラウザのWebSocketやEventSourceはヘッダーを付けられないため、
+// 通知を受け取るルートに限り?access_token=でもトークンを受け付ける
+// URLはログなどに残りやすいため、他のルートでは受け付けない
+// ハンドラからはClaimsと同じくExtension<Claims>で取り出す
+#[derive(Debug, Clone)]
+pub struct StreamingClaims;
+
+#[derive(Debug, Deserialize)]
+struct AccessToken {
+    access_token: Option<String>,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for StreamingClaims
+where
+    B: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = match bearer_token(req) {
+            Some(token) => token,
+            None => Query::<AccessToken>::from_request(req)
+                .await
+                .ok()
+                .and_then(|Query(query)| query.access_token)
+                .ok_or_else(|| AuthError::Missing.into_response())?,
+        };
+        authenticate(req, &token).await.map(|_| StreamingClaims)
+    }
+}
+
+fn bearer_token<B>(req: &RequestParts<B>) -> Option<String> {
+    req.headers()
+        .and_then(|headers| headers.get(AUTHORIZATION))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+async fn authenticate<B: Send>(req: &mut RequestParts<B>, token: &str) -> Result<Claims, Response> {
+    let Extension(secret) = Extension::<Arc<JwtSecret>>::from_request(req)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let claims = decode_token(token, &secret).map_err(IntoResponse::into_response)?;
+    if let Some(extensions) = req.extensions_mut() {
+        extensions.insert(claims.clone());
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn claims(expires_in: i64) -> Claims {
+        Claims {
+            user_id: 1,
+            exp: chrono::Utc::now().timestamp() + expires_in,
+        }
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let secret = JwtSecret::new("secret");
+        let claims = claims(60);
+        let token = encode_token(&claims, &secret);
+        assert_eq!(Ok(claims), decode_token(&token, &secret));
+    }
+
+    #[test]
+    fn reject_invalid_token() {
+        let secret = JwtSecret::new("secret");
+        let token = encode_token(&claims(60), &JwtSecret::new("other"));
+        assert_eq!(Err(AuthError::Invalid), decode_token(&token, &secret));
+        assert_eq!(
+            Err(AuthError::Invalid),
+            decode_token("not a token", &secret)
+        );
+
+        // ペイロードを書き換えると署名が一致しなくなる
+        let token = encode_token(&claims(60), &secret);
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = base64::encode_config(
+            serde_json::to_vec(&Claims {
+                user_id: 2,
+                ..claims(60)
+            })
+            .unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        parts[1] = &forged;
+        assert_eq!(
+            Err(AuthError::Invalid),
+            decode_token(&parts.join("."), &secret)
+        );
+    }
+
+    #[test]
+    fn reject_expired_token() {
+        let secret = JwtSecret::new("secret");
+        let token = encode_token(&claims(-120), &secret);
+        assert_eq!(Err(AuthError::Expired), decode_token(&token, &secret));
+        // 時計のずれを考慮し、猶予の間は期限切れとしない
+        let token = encode_token(&claims(-1), &secret);
+        assert!(decode_token(&token, &secret).is_ok());
+    }
+
+    #[test]
+    fn reject_token_not_yet_valid() {
+        let secret = JwtSecret::new("secret");
+        let now = chrono::Utc::now().timestamp();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS256),
+            &serde_json::json!({ "user_id": 1, "exp": now + 600, "nbf": now + 300 }),
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert_eq!(Err(AuthError::Invalid), decode_token(&token, &secret));
+    }
+}
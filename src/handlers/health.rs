@@ -0,0 +1,33 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse};
+use serde_json::json;
+
+use crate::{pretty_json::Json, repositories::todo::TodoRepository};
+
+// DBが応答しない場合にプローブを待たせ続けないためのタイムアウト
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "Database is reachable"),
+        (status = 503, description = "Database is unreachable"),
+    )
+)]
+pub async fn health_check<T: TodoRepository>(
+    Extension(repository): Extension<Arc<T>>,
+) -> impl IntoResponse {
+    match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, repository.ping()).await {
+        Ok(Ok(())) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Ok(Err(e)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "error", "error": e.to_string() })),
+        ),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "error", "error": "timeout" })),
+        ),
+    }
+}
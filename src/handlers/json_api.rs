@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    body::{self, Full},
+    response::{Headers, IntoResponse, Response},
+};
+use hyper::{
+    header::{ACCEPT, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    pretty_json,
+    repositories::{label::Label, todo::TodoEntity},
+};
+
+pub const JSON_API_MEDIA_TYPE: &str = "application/vnd.api+json";
+
+// Acceptに"application/vnd.api+json"が含まれる場合のみJSON:API形式で返す
+// "application/vnd.api+json, application/json;q=0.9"のような複数指定にも対応する
+pub fn accepts_json_api(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| media_range.split(';').next())
+        .any(|media_type| media_type.trim().eq_ignore_ascii_case(JSON_API_MEDIA_TYPE))
+}
+
+// トップレベルのドキュメント、dataは単一のリソースかその配列
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Document<D> {
+    pub data: D,
+    // 関連するラベルの中身、リソースの識別子だけでは名前が分からないため含める
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub included: Vec<Resource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ResourceIdentifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String, // 仕様上idは文字列で表す
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Relationship {
+    pub data: Vec<ResourceIdentifier>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Resource {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+    pub attributes: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub relationships: BTreeMap<String, Relationship>,
+}
+
+impl Resource {
+    fn label(label: &Label) -> Self {
+        let mut attributes = Map::new();
+        attributes.insert("name".to_string(), Value::String(label.name.clone()));
+        attributes.insert("color".to_string(), json!(label.color));
+        Self {
+            kind: "labels".to_string(),
+            id: label.id.to_string(),
+            attributes,
+            relationships: BTreeMap::new(),
+        }
+    }
+
+    // idとlabelsを除いたフィールドをattributesにする、フィールドを追加した場合もそのまま反映される
+    fn todo(todo: &TodoEntity) -> Self {
+        let mut attributes = match serde_json::to_value(todo).expect("todo can be serialized") {
+            Value::Object(attributes) => attributes,
+            _ => unreachable!("todo is serialized as an object"),
+        };
+        attributes.remove("id");
+        attributes.remove("labels");
+        let labels = todo
+            .labels
+            .iter()
+            .map(|label| ResourceIdentifier {
+                kind: "labels".to_string(),
+                id: label.id.to_string(),
+            })
+            .collect();
+        Self {
+            kind: "todos".to_string(),
+            id: todo.id.to_string(),
+            attributes,
+            relationships: BTreeMap::from([("labels".to_string(), Relationship { data: labels })]),
+        }
+    }
+}
+
+// 同じラベルが複数のTodoに付いていても一度だけ含める
+fn included_labels<'a>(todos: impl IntoIterator<Item = &'a TodoEntity>) -> Vec<Resource> {
+    let labels: BTreeMap<i32, &Label> = todos
+        .into_iter()
+        .flat_map(|todo| todo.labels.iter())
+        .map(|label| (label.id, label))
+        .collect();
+    labels
+        .values()
+        .map(|label| Resource::label(label))
+        .collect()
+}
+
+impl Document<Resource> {
+    pub fn todo(todo: &TodoEntity) -> Self {
+        Self {
+            data: Resource::todo(todo),
+            included: included_labels([todo]),
+            meta: None,
+        }
+    }
+}
+
+impl Document<Vec<Resource>> {
+    // totalはページングに関係なく絞り込み条件に一致する件数
+    pub fn todos(todos: &[TodoEntity], total: i64) -> Self {
+        Self {
+            data: todos.iter().map(Resource::todo).collect(),
+            included: included_labels(todos),
+            meta: Some(json!({ "total": total })),
+        }
+    }
+}
+
+// Jsonと同様にシリアライズし、Content-Typeだけをapplication/vnd.api+jsonにする
+pub struct JsonApi<T>(pub T);
+
+impl<T: Serialize> IntoResponse for JsonApi<T> {
+    fn into_response(self) -> Response {
+        match pretty_json::to_vec(&self.0) {
+            Ok(bytes) => (Headers([(CONTENT_TYPE, JSON_API_MEDIA_TYPE)]), bytes).into_response(),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::boxed(Full::from(e.to_string())))
+                .unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn negotiate_json_api() {
+        let headers = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            headers
+        };
+        assert!(!accepts_json_api(&HeaderMap::new()));
+        assert!(!accepts_json_api(&headers("application/json")));
+        assert!(!accepts_json_api(&headers("*/*")));
+        assert!(accepts_json_api(&headers(JSON_API_MEDIA_TYPE)));
+        assert!(accepts_json_api(&headers(
+            "application/json;q=0.9, application/vnd.api+json"
+        )));
+    }
+
+    #[test]
+    fn todo_document() {
+        let labels = vec![
+            Label {
+                id: 2,
+                name: "b".to_string(),
+                color: Some("#ff0000".to_string()),
+            },
+            Label {
+                id: 1,
+                name: "a".to_string(),
+                color: None,
+            },
+        ];
+        let todo = TodoEntity::new(1, "text".to_string(), labels);
+        let document = serde_json::to_value(Document::todo(&todo)).unwrap();
+        assert_eq!("todos", document["data"]["type"]);
+        assert_eq!("1", document["data"]["id"]);
+        assert_eq!("text", document["data"]["attributes"]["text"]);
+        assert!(document["data"]["attributes"].get("id").is_none());
+        assert!(document["data"]["attributes"].get("labels").is_none());
+        assert_eq!(
+            json!({ "data": [{ "type": "labels", "id": "2" }, { "type": "labels", "id": "1" }] }),
+            document["data"]["relationships"]["labels"]
+        );
+        assert_eq!(
+            json!([
+                { "type": "labels", "id": "1", "attributes": { "name": "a", "color": null } },
+                { "type": "labels", "id": "2", "attributes": { "name": "b", "color": "#ff0000" } },
+            ]),
+            document["included"]
+        );
+        assert!(document.get("meta").is_none());
+    }
+}
@@ -1,50 +1,262 @@
 use axum::{
-    extract::{Extension, Path},
+    extract::{Extension, Path, Query},
     http::StatusCode,
-    response::IntoResponse,
-    Json,
+    response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use utoipa::ToSchema;
 use validator::Validate;
 
-use crate::repositories::label::LabelRepository;
+use crate::{normalize::Normalize, pretty_json::Json, repositories::label::LabelRepository};
 
-use super::ValidatedJson;
+use super::{auth::Claims, to_status_code, validate_hex_color, ValidatedJson};
 
+#[utoipa::path(
+    post,
+    path = "/labels",
+    request_body = CreateLabel,
+    responses(
+        (status = 201, description = "Label created", body = Label),
+        (status = 400, description = "Validation error"),
+        (status = 409, description = "Duplicate label name"),
+    )
+)]
 pub async fn create_label<T: LabelRepository>(
     ValidatedJson(payload): ValidatedJson<CreateLabel>,
     Extension(repository): Extension<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let label = repository
-        .create(payload.name)
+        .create(payload.name, payload.color)
         .await
-        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        .map_err(to_status_code)?;
 
     Ok((StatusCode::CREATED, Json(label)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/labels/{id}",
+    params(("id" = i32, Path, description = "Label id")),
+    responses(
+        (status = 200, description = "Label found", body = Label),
+        (status = 404, description = "Label not found"),
+    )
+)]
+pub async fn find_label<T: LabelRepository>(
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let label = repository.find(id).await.map_err(to_status_code)?;
+    Ok((StatusCode::OK, Json(label)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/labels",
+    params(
+        ("with_counts" = Option<bool>, Query, description = "Include todo_count and completed_count of the caller's todos"),
+    ),
+    responses(
+        (status = 200, description = "All labels, as LabelWithCounts when with_counts=true", body = Vec<Label>),
+    )
+)]
 pub async fn all_label<T: LabelRepository>(
+    Query(options): Query<AllLabelOptions>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, StatusCode> {
+    // 件数の集計はTodoとの結合が必要になるため、指定された場合のみ行う
+    if options.with_counts {
+        let labels = repository
+            .all_with_counts(claims.user_id)
+            .await
+            .map_err(to_status_code)?;
+        return Ok((StatusCode::OK, Json(labels)).into_response());
+    }
+    let labels = repository.all().await.map_err(to_status_code)?;
+    Ok((StatusCode::OK, Json(labels)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/labels/autocomplete",
+    params(
+        ("prefix" = Option<String>, Query, description = "Case-insensitive name prefix, all labels when empty"),
+    ),
+    responses(
+        (status = 200, description = "Up to 10 labels ordered by name", body = Vec<Label>),
+    )
+)]
+// タグ入力欄の候補に使う
+pub async fn autocomplete_label<T: LabelRepository>(
+    Query(options): Query<AutocompleteOptions>,
     Extension(repository): Extension<Arc<T>>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let labels = repository.all().await.unwrap();
+    let labels = repository
+        .autocomplete(options.prefix)
+        .await
+        .map_err(to_status_code)?;
     Ok((StatusCode::OK, Json(labels)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/labels/in-use",
+    responses(
+        (status = 200, description = "Labels attached to at least one todo", body = Vec<LabelInUse>),
+    )
+)]
+// 絞り込み用の一覧に使う、どのTodoにも付いていないラベルは含めない
+pub async fn in_use_label<T: LabelRepository>(
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let labels: Vec<LabelInUse> = repository
+        .in_use(claims.user_id)
+        .await
+        .map_err(to_status_code)?
+        .into_iter()
+        .map(|(label, todo_count)| LabelInUse {
+            id: label.id,
+            name: label.name,
+            color: label.color,
+            todo_count,
+        })
+        .collect();
+    Ok((StatusCode::OK, Json(labels)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/labels/{id}",
+    params(("id" = i32, Path, description = "Label id")),
+    request_body = UpdateLabel,
+    responses(
+        (status = 200, description = "Label renamed or recolored", body = Label),
+        (status = 400, description = "Validation error"),
+        (status = 404, description = "Label not found"),
+        (status = 409, description = "Duplicate label name"),
+    )
+)]
+pub async fn update_label<T: LabelRepository>(
+    Path(id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<UpdateLabel>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let label = repository
+        .update(id, payload.name, payload.color)
+        .await
+        .map_err(to_status_code)?;
+
+    Ok((StatusCode::OK, Json(label)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/labels/{id}",
+    params(
+        ("id" = i32, Path, description = "Label id"),
+        ("force" = Option<bool>, Query, description = "Also detach the label from todos"),
+    ),
+    responses(
+        (status = 204, description = "Label deleted"),
+        (status = 404, description = "Label not found"),
+        (status = 409, description = "Label is still attached to todos"),
+    )
+)]
+// Todoに紐付いているラベルは409、?force=trueなら紐付けごと削除する
 pub async fn delete_label<T: LabelRepository>(
     Path(id): Path<i32>,
+    Query(options): Query<DeleteLabelOptions>,
     Extension(repository): Extension<Arc<T>>,
 ) -> StatusCode {
     repository
-        .delete(id)
+        .delete(id, options.force)
         .await
         .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        .unwrap_or_else(to_status_code)
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Validate)]
+#[utoipa::path(
+    patch,
+    path = "/labels/{id}/merge",
+    params(("id" = i32, Path, description = "Label id to merge and delete")),
+    request_body = MergeLabel,
+    responses(
+        (status = 200, description = "Todos moved to the surviving label", body = Label),
+        (status = 404, description = "Label not found"),
+        (status = 422, description = "Label can not be merged into itself"),
+    )
+)]
+// パスのラベルをinto_idのラベルに統合し、パスのラベルは削除する
+pub async fn merge_label<T: LabelRepository>(
+    Path(id): Path<i32>,
+    Json(payload): Json<MergeLabel>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let label = repository
+        .merge(id, payload.into_id)
+        .await
+        .map_err(to_status_code)?;
+
+    Ok((StatusCode::OK, Json(label)))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Validate, ToSchema)]
 pub struct CreateLabel {
     #[validate(length(min = 1, message = "Can not be empty"))]
-    #[validate(length(max = 100, message = "Over text length"))]
+    #[validate(length(max = 50, message = "Over text length"))]
     name: String,
+    #[validate(custom(function = "validate_hex_color", message = "Must be a #RRGGBB color"))]
+    color: Option<String>,
+}
+
+// idはパスで指定する、省略した項目は変更しない
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Validate, ToSchema)]
+pub struct UpdateLabel {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 50, message = "Over text length"))]
+    name: Option<String>,
+    #[validate(custom(function = "validate_hex_color", message = "Must be a #RRGGBB color"))]
+    color: Option<String>,
+}
+
+// GET /labels/in-useのレスポンス
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct LabelInUse {
+    #[serde(serialize_with = "crate::id_format::serialize_id")]
+    pub id: i32,
+    pub name: String,
+    pub color: Option<String>,
+    pub todo_count: i64,
+}
+
+// 統合先のラベル
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, ToSchema)]
+pub struct MergeLabel {
+    into_id: i32,
+}
+
+// ラベル名は正規化しない
+impl Normalize for CreateLabel {}
+
+impl Normalize for UpdateLabel {}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct AllLabelOptions {
+    #[serde(default)]
+    with_counts: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct AutocompleteOptions {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct DeleteLabelOptions {
+    #[serde(default)]
+    force: bool,
 }
@@ -0,0 +1,92 @@
+use axum::response::IntoResponse;
+use utoipa::OpenApi;
+
+use super::{health, label, todo};
+use crate::pretty_json::Json;
+use crate::repositories::{
+    label::{Label, LabelWithCounts},
+    todo::{
+        AssignResult, AuditAction, CreateTodo, LabelStats, Priority, Recurrence, ReplaceTodo,
+        TodoAudit, TodoEntity, TodoGroup, TodoPage, TodoStats, UpdateTodo,
+    },
+};
+
+// ハンドラと型に付けたアノテーションから生成するため、実装と記述がずれない
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        health::health_check,
+        todo::create_todo,
+        todo::bulk_create_todo,
+        todo::bulk_delete_todo,
+        todo::import_todo,
+        todo::export_todo,
+        todo::all_todo,
+        todo::stream_todo,
+        todo::batch_find_todo,
+        todo::search_todo,
+        todo::stats_todo,
+        todo::group_todo_by_label,
+        todo::watch_todo,
+        todo::stream_todo_events,
+        todo::find_todo,
+        todo::update_todo,
+        todo::replace_todo,
+        todo::delete_todo,
+        todo::restore_todo,
+        todo::toggle_todo,
+        todo::history_todo,
+        todo::complete_all_todo,
+        todo::delete_completed_todo,
+        todo::reorder_todo,
+        todo::all_todo_label,
+        todo::add_todo_label,
+        todo::update_todo_labels,
+        todo::remove_todo_label,
+        todo::all_todo_by_label,
+        todo::assign_label,
+        label::create_label,
+        label::all_label,
+        label::in_use_label,
+        label::autocomplete_label,
+        label::find_label,
+        label::update_label,
+        label::delete_label,
+        label::merge_label,
+    ),
+    components(schemas(
+        TodoEntity,
+        TodoPage,
+        TodoStats,
+        LabelStats,
+        TodoGroup,
+        CreateTodo,
+        UpdateTodo,
+        ReplaceTodo,
+        TodoAudit,
+        AuditAction,
+        Priority,
+        Recurrence,
+        Label,
+        LabelWithCounts,
+        label::CreateLabel,
+        label::UpdateLabel,
+        label::MergeLabel,
+        label::LabelInUse,
+        todo::ImportResult,
+        todo::ImportError,
+        todo::BulkResult,
+        todo::DryRunResult,
+        todo::AssignLabel,
+        todo::UpdateTodoLabels,
+        todo::DeleteTodos,
+        todo::CreatedTodo,
+        super::Warning,
+        AssignResult,
+    ))
+)]
+pub struct ApiDoc;
+
+pub async fn openapi_json() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
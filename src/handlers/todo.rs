@@ -1,63 +1,1211 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    response::IntoResponse,
-    Json,
+    body::StreamBody,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Extension, Path, Query,
+    },
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION},
+        HeaderMap, StatusCode,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Headers, IntoResponse, Response,
+    },
 };
 
-use crate::repositories::todo::{CreateTodo, TodoRepository, UpdateTodo};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
 
-use super::ValidatedJson;
+use crate::repositories::todo::{
+    CreateTodo, CreateTodos, MaxTextLength, Pagination, ReplaceTodo, SortOrder, TodoEntity,
+    TodoEvent, TodoFilter, TodoPage, TodoRepository, TodoSort, TodoSortKey, UpdateTodo,
+    MAX_PAGE_LIMIT,
+};
+
+use crate::id_format;
+use crate::normalize::{Normalize, TextNormalization};
+use crate::pretty_json::{self, Json};
+use crate::repositories::RepositoryError;
+
+use super::{
+    auth::Claims,
+    error_response,
+    json_api::{accepts_json_api, Document, JsonApi},
+    validate_not_blank, validation_error_response, ErrorResponse, ValidatedJson,
+    ValidatedJsonWithWarnings, ValidatedQuery, Warning,
+};
+
+// WebSocketのクローズコード(RFC 6455)
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+// クライアントからのデータは使わないため、大きなメッセージは受け付けない
+const MAX_WS_MESSAGE_LEN: usize = 64 * 1024;
 
+// SSEで無通信の間に送るコメントの間隔
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+const NDJSON_MEDIA_TYPE: &str = "application/x-ndjson";
+
+#[utoipa::path(
+    post,
+    path = "/todos",
+    params(
+        ("strict" = Option<bool>, Query, description = "When false, an over-length text is accepted with warnings (default true)"),
+    ),
+    request_body = CreateTodo,
+    responses(
+        (status = 201, description = "Todo created, with warnings when strict=false or a todo with the same text already exists", body = CreatedTodo,
+            headers(("Location" = String, description = "Path of the created todo like /todos/1"))),
+        (status = 400, description = "Validation error"),
+        (status = 422, description = "Label or parent todo does not exist"),
+    )
+)]
 // リポジトリ層からResultが帰ってきた場合はResultを親に返す
 pub async fn create_todo<T: TodoRepository>(
-    ValidatedJson(payload): ValidatedJson<CreateTodo>, // バリデート+パース済みの構造体を受け取る
+    // バリデート+パース済みの構造体と、strict=falseで受け付けた警告を受け取る
+    ValidatedJsonWithWarnings(payload, mut warnings): ValidatedJsonWithWarnings<CreateTodo>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository
-        .create(payload)
-        .await
-        .or(Err(StatusCode::NOT_FOUND))?; // ResultがErrなら引数のErrを返す、そうでなければOkをそのまま返す
-    Ok((StatusCode::CREATED, Json(todo)))
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = repository.create(payload).await?; // Errならエラーのレスポンスに変換して返す、そうでなければOkの中身を取り出す
+
+    // 同じtextのTodoがあっても作成はし、重複していることを警告で伝える
+    let duplicate_ids: Vec<String> = repository
+        .find_by_text(&todo.text)
+        .await?
+        .into_iter()
+        .filter(|duplicate| duplicate.id != todo.id)
+        .map(|duplicate| duplicate.id.to_string())
+        .collect();
+    if !duplicate_ids.is_empty() {
+        warnings.push(Warning {
+            field: String::from("text"),
+            message: format!(
+                "Same text already exists, id is {}",
+                duplicate_ids.join(", ")
+            ),
+        });
+    }
+    // 作成したTodoを取得し直せるよう、GET /todos/:idのパスを返す
+    let headers = Headers([(LOCATION, format!("/todos/{}", todo.id))]);
+    Ok((
+        StatusCode::CREATED,
+        headers,
+        Json(CreatedTodo { todo, warnings }),
+    ))
 }
 
+#[utoipa::path(
+    post,
+    path = "/todos/bulk",
+    request_body = Vec<CreateTodo>,
+    responses(
+        (status = 201, description = "Todos created", body = Vec<TodoEntity>),
+        (status = 400, description = "Validation error"),
+        (status = 422, description = "Label or parent todo does not exist"),
+    )
+)]
+pub async fn bulk_create_todo<T: TodoRepository>(
+    ValidatedJson(payload): ValidatedJson<CreateTodos>, // 1件でも不正な要素があれば400
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todos = repository.create_many(payload.into_inner()).await?;
+    Ok((StatusCode::CREATED, Json(todos)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/todos",
+    request_body = DeleteTodos,
+    responses(
+        (status = 200, description = "Number of todos permanently deleted", body = BulkResult),
+        (status = 400, description = "Validation error"),
+    )
+)]
+// 存在しないTodoや、対象外の子を持つTodoは削除せずに件数から除く
+pub async fn bulk_delete_todo<T: TodoRepository>(
+    ValidatedJson(payload): ValidatedJson<DeleteTodos>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let affected = repository.delete_many(payload.ids).await?;
+    Ok((StatusCode::OK, Json(BulkResult { affected })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/{id}",
+    params(
+        ("id" = i32, Path, description = "Todo id"),
+        ("include_deleted" = Option<bool>, Query, description = "Include soft-deleted todos"),
+        ("fields" = Option<String>, Query, description = "Comma separated fields to return like id,text (ignored for JSON:API)"),
+    ),
+    responses(
+        (status = 200, description = "Todo found, or a JSON:API document when Accept is application/vnd.api+json", body = TodoEntity),
+        (status = 304, description = "Todo has not changed since the If-None-Match ETag"),
+        (status = 400, description = "Unknown field in fields"),
+        (status = 404, description = "Todo not found"),
+    )
+)]
 pub async fn find_todo<T: TodoRepository>(
     Path(id): Path<i32>,
+    Query(options): Query<FindOptions>,
+    ValidatedQuery(fields): ValidatedQuery<FieldSelection>,
+    headers: HeaderMap,
     Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
     // StatusCodeもIntoResponseを実装している
-) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.find(id).await.or(Err(StatusCode::NOT_FOUND))?;
-    Ok((StatusCode::OK, Json(todo)))
+) -> Result<Response, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = if options.include_deleted {
+        repository.find_including_deleted(id).await
+    } else {
+        repository.find(id).await
+    }?;
+
+    let unexpected = |e: serde_json::Error| RepositoryError::Unexpected(e.to_string());
+    // ?fields=で絞り込んだボディやJSON:APIの文書は別の表現になるため、ETagは返すボディから計算する
+    let (etag, body) = if accepts_json_api(&headers) {
+        let document = Document::todo(&todo);
+        (
+            etag(&document).map_err(unexpected)?,
+            JsonApi(document).into_response(),
+        )
+    } else if fields.names().is_empty() {
+        // 絞り込まない場合はJSONに変換し直さず、TodoEntityの項目の順に返す
+        (etag(&todo).map_err(unexpected)?, Json(todo).into_response())
+    } else {
+        let projected = fields.project(&todo).map_err(unexpected)?;
+        (
+            etag(&projected).map_err(unexpected)?,
+            Json(projected).into_response(),
+        )
+    };
+
+    // 手元の表現が最新であればボディを返さない
+    // 表現はAcceptで変わるため、Varyはルートに付けたレイヤーでエラーも含めて付ける
+    let headers_out = Headers([(ETAG, etag.clone())]);
+    if if_none_match(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, headers_out).into_response());
+    }
+    Ok((StatusCode::OK, headers_out, body).into_response())
 }
 
+#[utoipa::path(
+    get,
+    path = "/todos",
+    params(
+        ("completed" = Option<bool>, Query, description = "Filter by completion"),
+        ("label_id" = Option<Vec<i32>>, Query, description = "Filter by labels (AND match)"),
+        ("parent_id" = Option<i32>, Query, description = "Filter by direct parent todo"),
+        ("created_after" = Option<String>, Query, description = "Inclusive lower bound of created_at, e.g. 2023-03-01T00:00:00"),
+        ("created_before" = Option<String>, Query, description = "Inclusive upper bound of created_at, e.g. 2023-03-31T23:59:59"),
+        ("sort" = Option<String>, Query, description = "position | id | text | completed | priority | created_at"),
+        ("order" = Option<String>, Query, description = "asc | desc (defaults to asc for position, desc otherwise)"),
+        ("limit" = Option<i64>, Query, description = "Page size (1..=100)"),
+        ("offset" = Option<i64>, Query, description = "Number of todos to skip"),
+        ("fields" = Option<String>, Query, description = "Comma separated fields of each item to return like id,text (ignored for JSON:API)"),
+    ),
+    responses(
+        (status = 200, description = "Paginated todos, or a JSON:API document when Accept is application/vnd.api+json", body = TodoPage),
+        (status = 400, description = "Invalid query parameter"),
+    )
+)]
 pub async fn all_todo<T: TodoRepository>(
+    ValidatedQuery(mut filter): ValidatedQuery<TodoFilter>,
+    Query(params): Query<Vec<(String, String)>>,
+    ValidatedQuery(sort): ValidatedQuery<TodoSort>,
+    Query(pagination): Query<Pagination>,
+    headers: HeaderMap,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.all().await.unwrap();
-    Ok((StatusCode::OK, Json(todo))) // 一件もヒットしない場合は空配列がjsonで返る
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, Response> {
+    let repository = repository.for_user(claims.user_id);
+    filter.label_ids = ids_from_params(&params, "label_id").map_err(IntoResponse::into_response)?;
+    let fields = FieldSelection::from_params(&params).map_err(IntoResponse::into_response)?;
+    let total = repository
+        .count(filter.clone())
+        .await
+        .map_err(into_error_response)?;
+    let items = repository
+        .all(filter, sort, pagination.clamp())
+        .await
+        .map_err(into_error_response)?;
+    if accepts_json_api(&headers) {
+        let document = Document::todos(&items, total);
+        return Ok((StatusCode::OK, JsonApi(document)).into_response());
+    }
+    // 一件もヒットしない場合はitemsが空配列になる
+    if fields.names().is_empty() {
+        return Ok((StatusCode::OK, Json(TodoPage { items, total })).into_response());
+    }
+    let items = items
+        .iter()
+        .map(|todo| fields.project(todo))
+        .collect::<serde_json::Result<Vec<_>>>()
+        .map_err(|e| RepositoryError::Unexpected(e.to_string()).into_response())?;
+    let body = serde_json::json!({ "items": items, "total": total });
+    Ok((StatusCode::OK, Json(body)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/stream",
+    params(
+        ("completed" = Option<bool>, Query, description = "Filter by completion"),
+        ("label_id" = Option<Vec<i32>>, Query, description = "Filter by labels (AND match)"),
+        ("parent_id" = Option<i32>, Query, description = "Filter by direct parent todo"),
+        ("created_after" = Option<String>, Query, description = "Inclusive lower bound of created_at, e.g. 2023-03-01T00:00:00"),
+        ("created_before" = Option<String>, Query, description = "Inclusive upper bound of created_at, e.g. 2023-03-31T23:59:59"),
+        ("sort" = Option<String>, Query, description = "position | id | text | completed | priority | created_at"),
+        ("order" = Option<String>, Query, description = "asc | desc (defaults to asc for position, desc otherwise)"),
+    ),
+    responses(
+        (status = 200, description = "All matching todos as newline-delimited JSON, one TodoEntity per line", body = TodoEntity, content_type = "application/x-ndjson"),
+        (status = 400, description = "Invalid filter or sort"),
+    )
+)]
+// 件数が多くてもまとめてメモリに載せないよう、取得した順に1行ずつ返す
+// 途中でエラーになった場合はステータスを変えられないため、接続を切って不完全な応答であることを伝える
+pub async fn stream_todo<T: TodoRepository>(
+    ValidatedQuery(mut filter): ValidatedQuery<TodoFilter>,
+    Query(params): Query<Vec<(String, String)>>,
+    ValidatedQuery(sort): ValidatedQuery<TodoSort>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, Response> {
+    let repository = repository.for_user(claims.user_id);
+    filter.label_ids = ids_from_params(&params, "label_id").map_err(IntoResponse::into_response)?;
+    let string_ids = id_format::string_ids();
+    let lines = repository.stream_all(filter, sort).map(move |todo| {
+        let todo = todo.map_err(|e| {
+            tracing::error!("{:#}", e);
+            e
+        })?;
+        let mut line = id_format::with_string_ids(string_ids, || serde_json::to_vec(&todo))?;
+        line.push(b'\n');
+        Ok::<_, anyhow::Error>(line)
+    });
+    let headers = Headers([(CONTENT_TYPE, NDJSON_MEDIA_TYPE)]);
+    Ok((StatusCode::OK, headers, StreamBody::new(lines)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/import",
+    request_body(content = String, content_type = "text/csv"),
+    responses(
+        (status = 200, description = "Import result", body = ImportResult),
+        (status = 400, description = "Malformed CSV header"),
+    )
+)]
+// text(必須)とcompleted(任意)列を持つCSVを取り込む
+// 不正な行は取り込まずに行番号と理由を返し、残りの行は登録する
+pub async fn import_todo<T: TodoRepository>(
+    body: String,
+    Extension(normalization): Extension<TextNormalization>,
+    Extension(max_text_length): Extension<MaxTextLength>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, Response> {
+    let repository = repository.for_user(claims.user_id);
+    let mut reader = csv::Reader::from_reader(body.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, "csv", e.to_string()).into_response())?
+        .clone();
+
+    let mut payloads = Vec::new();
+    let mut completed = Vec::new();
+    let mut errors = Vec::new();
+    for record in reader.records() {
+        let row = record.and_then(|record| {
+            let line = record.position().map_or(0, |position| position.line());
+            record
+                .deserialize::<ImportRow>(Some(&headers))
+                .map(|row| (line, row))
+        });
+        let (line, row) = match row {
+            Ok(row) => row,
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                errors.push(ImportError {
+                    line,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let mut payload = CreateTodo::new(row.text, vec![]);
+        payload.normalize(&normalization);
+        if let Err(e) = max_text_length.scope(|| payload.validate()) {
+            errors.push(ImportError {
+                line,
+                message: e.to_string(),
+            });
+            continue;
+        }
+        payloads.push(payload);
+        completed.push(row.completed.unwrap_or(false));
+    }
+
+    // 完了にする更新が失敗した場合は、作成したTodoも合わせて取り消す
+    let todos = if payloads.is_empty() {
+        vec![]
+    } else {
+        repository
+            .with_transaction(move |repository| {
+                Box::pin(async move {
+                    let todos = repository.create_many(payloads).await?;
+                    for (todo, _) in todos
+                        .iter()
+                        .zip(completed)
+                        .filter(|(_, completed)| *completed)
+                    {
+                        repository
+                            .update(todo.id, UpdateTodo::new(None, Some(true), None))
+                            .await?;
+                    }
+                    Ok(todos)
+                })
+            })
+            .await
+            .map_err(into_error_response)?
+    };
+
+    let result = ImportResult {
+        created: todos.len(),
+        skipped: errors.len(),
+        errors,
+    };
+    Ok((StatusCode::OK, Json(result)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/todos/export.csv",
+    responses(
+        (status = 200, description = "Todos as CSV", body = String, content_type = "text/csv"),
+    )
+)]
+// 全てのTodoをidの昇順でCSVとしてダウンロードさせる
+pub async fn export_todo<T: TodoRepository>(
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let sort = TodoSort {
+        sort: Some(TodoSortKey::Id),
+        order: Some(SortOrder::Asc),
+    };
+    // allは1回で取得できる件数に上限があるため、ページを進めながら全件を集める
+    let mut todos = Vec::new();
+    loop {
+        let pagination = Pagination {
+            limit: MAX_PAGE_LIMIT,
+            offset: todos.len() as i64,
+        };
+        let page = repository
+            .all(TodoFilter::default(), sort, pagination)
+            .await?;
+        let fetched = page.len() as i64;
+        todos.extend(page);
+        if fetched < MAX_PAGE_LIMIT {
+            break;
+        }
+    }
+
+    let body = todos_to_csv(&todos)?;
+    let headers = Headers([
+        (CONTENT_TYPE, "text/csv; charset=utf-8"),
+        (CONTENT_DISPOSITION, "attachment; filename=\"todos.csv\""),
+    ]);
+    Ok((StatusCode::OK, headers, body))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/batch",
+    params(("id" = Vec<i32>, Query, description = "Todo ids, repeat the key for each id")),
+    responses(
+        (status = 200, description = "Todos found, missing ids are omitted", body = Vec<TodoEntity>),
+        (status = 400, description = "Invalid id"),
+    )
+)]
+// 複数のTodoを1回のクエリでまとめて取得する
+pub async fn batch_find_todo<T: TodoRepository>(
+    Query(params): Query<Vec<(String, String)>>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, Response> {
+    let repository = repository.for_user(claims.user_id);
+    let ids = ids_from_params(&params, "id").map_err(IntoResponse::into_response)?;
+    let todos = repository
+        .find_many(ids)
+        .await
+        .map_err(into_error_response)?;
+    Ok((StatusCode::OK, Json(todos)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/search",
+    params(
+        ("q" = String, Query, description = "Case-insensitive text to search for"),
+        ("fuzzy" = Option<bool>, Query, description = "Match by similarity to tolerate typos"),
+    ),
+    responses(
+        (status = 200, description = "Matched todos", body = Vec<TodoEntity>),
+        (status = 400, description = "Validation error"),
+    )
+)]
+pub async fn search_todo<T: TodoRepository>(
+    ValidatedQuery(query): ValidatedQuery<SearchQuery>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todos = if query.fuzzy {
+        repository.fuzzy_search(query.q).await
+    } else {
+        repository.search(query.q).await
+    }?;
+    Ok((StatusCode::OK, Json(todos)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/stats",
+    responses(
+        (status = 200, description = "Todo counts with a per-label breakdown", body = TodoStats),
+    )
+)]
+pub async fn stats_todo<T: TodoRepository>(
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let stats = repository.stats().await?;
+    Ok((StatusCode::OK, Json(stats)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/by-label",
+    responses(
+        (status = 200, description = "Todos grouped by label, unlabeled todos last", body = [TodoGroup]),
+    )
+)]
+pub async fn group_todo_by_label<T: TodoRepository>(
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let groups = repository.group_by_label().await?;
+    Ok((StatusCode::OK, Json(groups)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/ws",
+    params(
+        ("access_token" = Option<String>, Query, description = "JWT for clients that can not set the Authorization header, such as browsers"),
+    ),
+    responses(
+        (status = 101, description = "Upgraded to a WebSocket that pushes {\"type\":\"created\",\"id\":1} style events"),
+        (status = 400, description = "Not a WebSocket handshake"),
+    )
+)]
+pub async fn watch_todo<T: TodoRepository>(
+    ws: WebSocketUpgrade,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Response {
+    // Upgradeを待つ間の変更も取りこぼさないよう、先に購読しておく
+    let events = repository.subscribe();
+    ws.max_message_size(MAX_WS_MESSAGE_LEN)
+        .on_upgrade(move |socket| push_events(socket, events, claims.user_id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/events",
+    params(
+        ("access_token" = Option<String>, Query, description = "JWT for clients that can not set the Authorization header, such as EventSource"),
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events named created, updated or deleted with {\"type\":\"created\",\"id\":1} style data"),
+    )
+)]
+pub async fn stream_todo_events<T: TodoRepository>(
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> impl IntoResponse {
+    let user_id = claims.user_id;
+    // WebSocketと同じく、他のユーザーの変更は送らない
+    // 取りこぼした場合はストリームを終えて、EventSourceの再接続に任せる
+    let events = stream::unfold(repository.subscribe(), move |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.user_id == user_id => {
+                    let sse = Event::default().event(event.kind.name()).json_data(event);
+                    return Some((sse, events));
+                }
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+    });
+    // プロキシに無通信の接続として切断されないよう、定期的にコメントを送る
+    Sse::new(events).keep_alive(
+        KeepAlive::new()
+            .interval(SSE_KEEP_ALIVE_INTERVAL)
+            .text("keep-alive"),
+    )
+}
+
+// 他のユーザーの変更は送らない
+// Pingへの応答とクライアントからのCloseへの応答はaxum(tungstenite)が行う
+async fn push_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<TodoEvent>,
+    user_id: i32,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.user_id == user_id => {
+                    let text = serde_json::to_string(&event).expect("event can be serialized");
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                // 取りこぼしたクライアントには切断して取得し直してもらう
+                Err(RecvError::Lagged(_)) => {
+                    close_socket(socket, CLOSE_TRY_AGAIN_LATER, "lagged").await;
+                    return;
+                }
+                Err(RecvError::Closed) => {
+                    close_socket(socket, CLOSE_GOING_AWAY, "shutting down").await;
+                    return;
+                }
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn close_socket(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await;
+}
+
+#[utoipa::path(
+    get,
+    path = "/labels/{id}/todos",
+    params(("id" = i32, Path, description = "Label id")),
+    responses(
+        (status = 200, description = "Todos carrying the label", body = Vec<TodoEntity>),
+        (status = 404, description = "Label not found"),
+    )
+)]
+// ラベルが存在しなければ404、存在するが紐づくTodoがなければ空配列を返す
+pub async fn all_todo_by_label<T: TodoRepository>(
+    Path(label_id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todos = repository.find_by_label(label_id).await?;
+    Ok((StatusCode::OK, Json(todos)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/labels/{id}/assign",
+    params(("id" = i32, Path, description = "Label id")),
+    request_body = AssignLabel,
+    responses(
+        (status = 200, description = "Label attached to the todos", body = AssignResult),
+        (status = 400, description = "Validation error"),
+        (status = 404, description = "Label not found"),
+    )
+)]
+// 存在しないTodoがあっても他のTodoにはラベルを付け、そのidをnot_foundで返す
+pub async fn assign_label<T: TodoRepository>(
+    Path(label_id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<AssignLabel>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let result = repository.assign_label(label_id, payload.todo_ids).await?;
+    Ok((StatusCode::OK, Json(result)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/todos/{id}",
+    params(("id" = i32, Path, description = "Todo id")),
+    request_body = UpdateTodo,
+    responses(
+        (status = 200, description = "Todo updated", body = TodoEntity),
+        (status = 400, description = "Validation error"),
+        (status = 404, description = "Todo not found"),
+        (status = 409, description = "Todo was updated by someone else"),
+        (status = 422, description = "Label or parent todo is invalid"),
+    )
+)]
 pub async fn update_todo<T: TodoRepository>(
     Path(id): Path<i32>,
     ValidatedJson(payload): ValidatedJson<UpdateTodo>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = repository.update(id, payload).await?;
+    Ok((StatusCode::OK, Json(todo)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/todos/{id}",
+    params(("id" = i32, Path, description = "Todo id")),
+    request_body = ReplaceTodo,
+    responses(
+        (status = 200, description = "Todo replaced", body = TodoEntity),
+        (status = 400, description = "Validation error or missing field"),
+        (status = 404, description = "Todo not found"),
+        (status = 422, description = "Label or parent todo is invalid"),
+    )
+)]
+// PATCHと違い、全ての項目を指定する必要がある
+pub async fn replace_todo<T: TodoRepository>(
+    Path(id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<ReplaceTodo>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = repository.replace(id, payload).await?;
+    Ok((StatusCode::OK, Json(todo)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/{id}/labels",
+    params(("id" = i32, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "Labels attached to the todo", body = Vec<Label>),
+        (status = 404, description = "Todo not found"),
+    )
+)]
+pub async fn all_todo_label<T: TodoRepository>(
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let labels = repository.find_labels(id).await?;
+    Ok((StatusCode::OK, Json(labels)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/labels/{label_id}",
+    params(
+        ("id" = i32, Path, description = "Todo id"),
+        ("label_id" = i32, Path, description = "Label id"),
+    ),
+    responses(
+        (status = 200, description = "Label attached", body = TodoEntity),
+        (status = 404, description = "Todo not found"),
+        (status = 422, description = "Label does not exist"),
+    )
+)]
+pub async fn add_todo_label<T: TodoRepository>(
+    Path((id, label_id)): Path<(i32, i32)>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = repository.add_label(id, label_id).await?;
+    Ok((StatusCode::OK, Json(todo)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/todos/{id}/labels",
+    params(("id" = i32, Path, description = "Todo id")),
+    request_body = UpdateTodoLabels,
+    responses(
+        (status = 200, description = "Labels added and removed", body = TodoEntity),
+        (status = 400, description = "Validation error"),
+        (status = 404, description = "Todo not found"),
+        (status = 422, description = "Label to add does not exist, or too many labels"),
+    )
+)]
+// 付けるラベルと外すラベルをまとめて指定し、1つのトランザクションで反映する
+pub async fn update_todo_labels<T: TodoRepository>(
+    Path(id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<UpdateTodoLabels>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
     let todo = repository
-        .update(id, payload)
-        .await
-        .or(Err(StatusCode::NOT_FOUND))?;
-    Ok((StatusCode::CREATED, Json(todo)))
+        .update_labels(id, payload.add, payload.remove)
+        .await?;
+    Ok((StatusCode::OK, Json(todo)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/todos/{id}/labels/{label_id}",
+    params(
+        ("id" = i32, Path, description = "Todo id"),
+        ("label_id" = i32, Path, description = "Label id"),
+    ),
+    responses(
+        (status = 204, description = "Label detached"),
+        (status = 404, description = "Todo or label not found"),
+    )
+)]
+pub async fn remove_todo_label<T: TodoRepository>(
+    Path((id, label_id)): Path<(i32, i32)>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    repository.remove_label(id, label_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/restore",
+    params(("id" = i32, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "Todo restored", body = TodoEntity),
+        (status = 404, description = "Todo not found"),
+    )
+)]
+pub async fn restore_todo<T: TodoRepository>(
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = repository.restore(id).await?;
+    Ok((StatusCode::OK, Json(todo)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/toggle",
+    params(("id" = i32, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "Completed state flipped", body = TodoEntity),
+        (status = 404, description = "Todo not found"),
+    )
+)]
+// 現在の値を知らなくても完了状態を反転できる
+pub async fn toggle_todo<T: TodoRepository>(
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let todo = repository.toggle(id).await?;
+    Ok((StatusCode::OK, Json(todo)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/todos/complete-all",
+    params(
+        ("dry_run" = Option<bool>, Query, description = "Return the todos that would be completed without changing them"),
+    ),
+    responses(
+        (status = 200, description = "Number of todos completed, or a DryRunResult when dry_run=true", body = BulkResult),
+    )
+)]
+pub async fn complete_all_todo<T: TodoRepository>(
+    Query(options): Query<DryRunOptions>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let ids = repository.complete_all(options.dry_run).await?;
+    Ok(bulk_response(ids, options.dry_run))
+}
+
+#[utoipa::path(
+    put,
+    path = "/todos/order",
+    request_body = Vec<i32>,
+    responses(
+        (status = 204, description = "Todos reordered"),
+        (status = 422, description = "Ids are unknown, duplicated or some todos are missing"),
+    )
+)]
+// ドラッグ&ドロップ後の並び順をまとめて保存する
+pub async fn reorder_todo<T: TodoRepository>(
+    Json(ordered_ids): Json<Vec<i32>>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    repository.reorder(ordered_ids).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/todos/{id}/history",
+    params(("id" = i32, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "Changes to the todo, oldest first", body = Vec<TodoAudit>),
+        (status = 404, description = "Todo has no history"),
+    )
+)]
+// 物理削除済みのTodoも履歴は参照できる
+pub async fn history_todo<T: TodoRepository>(
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let audits = repository.history(id).await?;
+    Ok((StatusCode::OK, Json(audits)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/todos/completed",
+    params(
+        ("dry_run" = Option<bool>, Query, description = "Return the todos that would be deleted without deleting them"),
+    ),
+    responses(
+        (status = 200, description = "Number of todos deleted, or a DryRunResult when dry_run=true", body = BulkResult),
+    )
+)]
+// 完了済みのTodoを物理削除する
+pub async fn delete_completed_todo<T: TodoRepository>(
+    Query(options): Query<DryRunOptions>,
+    Extension(repository): Extension<Arc<T>>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    let ids = repository.delete_completed(options.dry_run).await?;
+    Ok(bulk_response(ids, options.dry_run))
+}
+
+// dry_runの場合は件数に加えて対象のidを返す、実際に変更した場合は従来どおり件数のみ
+fn bulk_response(ids: Vec<i32>, dry_run: bool) -> Response {
+    let affected = ids.len() as u64;
+    if dry_run {
+        return (StatusCode::OK, Json(DryRunResult { affected, ids })).into_response();
+    }
+    (StatusCode::OK, Json(BulkResult { affected })).into_response()
 }
 
+#[utoipa::path(
+    delete,
+    path = "/todos/{id}",
+    params(
+        ("id" = i32, Path, description = "Todo id"),
+        ("hard" = Option<bool>, Query, description = "Delete permanently instead of soft delete"),
+        ("cascade" = Option<bool>, Query, description = "Delete subtasks together"),
+    ),
+    responses(
+        (status = 204, description = "Todo deleted"),
+        (status = 404, description = "Todo not found"),
+        (status = 409, description = "Todo has subtasks and cascade is not set"),
+    )
+)]
 pub async fn delete_todo<T: TodoRepository>(
     Path(id): Path<i32>,
+    Query(options): Query<DeleteOptions>,
     Extension(repository): Extension<Arc<T>>,
-) -> StatusCode {
-    repository
-        .delete(id)
-        .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::NOT_FOUND)
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, RepositoryError> {
+    let repository = repository.for_user(claims.user_id);
+    // hard=trueの場合のみ物理削除、それ以外は論理削除
+    // サブタスクがある場合はcascade=trueでなければ削除しない
+    if options.hard {
+        repository.purge(id, options.cascade).await?;
+    } else {
+        repository.delete(id, options.cascade).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct SearchQuery {
+    #[validate(custom(function = "validate_not_blank", message = "Can not be empty"))]
+    q: String,
+    #[serde(default)]
+    fuzzy: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct FindOptions {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+// TodoEntityをシリアライズした際のキー、?fields=で指定できる
+pub const TODO_FIELDS: [&str; 14] = [
+    "id",
+    "text",
+    "completed",
+    "due_date",
+    "priority",
+    "completed_at",
+    "created_at",
+    "updated_at",
+    "version",
+    "parent_id",
+    "recurrence",
+    "position",
+    "user_id",
+    "labels",
+];
+
+// ?fields=id,textで返す項目を絞る、省略した場合や空の場合は全ての項目を返す
+// TodoEntityにない項目名は400にする
+#[derive(Debug, Deserialize, Default, Validate)]
+pub struct FieldSelection {
+    #[validate(custom = "validate_todo_fields")]
+    fields: Option<String>,
+}
+
+impl FieldSelection {
+    // 他のクエリパラメータと合わせて取り出したparamsから作る、検証はValidatedQueryと同じ
+    fn from_params(params: &[(String, String)]) -> Result<Self, ErrorResponse> {
+        let selection = Self {
+            fields: params
+                .iter()
+                .find(|(key, _)| key == "fields")
+                .map(|(_, value)| value.clone()),
+        };
+        selection
+            .validate()
+            .map_err(|errors| validation_error_response(&errors))?;
+        Ok(selection)
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.fields
+            .iter()
+            .flat_map(|fields| fields.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    // JSONに変換した上で、指定されていないキーを取り除く
+    fn project(&self, todo: &TodoEntity) -> serde_json::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(todo)?;
+        let names = self.names();
+        if names.is_empty() {
+            return Ok(value);
+        }
+        if let serde_json::Value::Object(map) = &mut value {
+            map.retain(|key, _| names.contains(&key.as_str()));
+        }
+        Ok(value)
+    }
+}
+
+fn validate_todo_fields(fields: &str) -> Result<(), ValidationError> {
+    let selection = FieldSelection {
+        fields: Some(fields.to_string()),
+    };
+    match selection
+        .names()
+        .into_iter()
+        .find(|name| !TODO_FIELDS.contains(name))
+    {
+        Some(name) => {
+            let mut error = ValidationError::new("unknown_field");
+            error.message = Some(format!("Unknown field: {}", name).into());
+            Err(error)
+        }
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct DryRunOptions {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct DeleteOptions {
+    #[serde(default)]
+    hard: bool,
+    #[serde(default)]
+    cascade: bool,
+}
+
+// 返すボディのバイト列から計算する、versionが含まれるため更新されれば必ず変わる
+// Rustのバージョンで値が変わらないよう、DefaultHasherではなくSHA-256の先頭を使う
+fn etag<B: Serialize>(body: &B) -> serde_json::Result<String> {
+    let digest = Sha256::digest(pretty_json::to_vec(body)?);
+    Ok(format!(
+        "\"{}\"",
+        base64::encode_config(&digest[..12], base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+// If-None-Matchはカンマ区切りで複数指定でき、弱いETag(W/"...")や*も受け付ける
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// リクエストの不備とリポジトリのエラーの両方を返すハンドラで、エラーの型を揃えるために使う
+fn into_error_response(error: anyhow::Error) -> Response {
+    RepositoryError::from(error).into_response()
+}
+
+// ?label_id=5&label_id=7のように繰り返されたキーを全て取り出す、数値でなければ400
+fn ids_from_params(params: &[(String, String)], name: &str) -> Result<Vec<i32>, ErrorResponse> {
+    let mut ids = params
+        .iter()
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| {
+            value.parse::<i32>().map_err(|_| {
+                let message = format!("invalid {}: {}", name, value);
+                error_response(StatusCode::BAD_REQUEST, "parse", message)
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    text: String,
+    #[serde(default)]
+    completed: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct ImportResult {
+    pub created: usize,
+    pub skipped: usize,
+    pub errors: Vec<ImportError>,
+}
+
+// ラベルを付けるTodo
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Validate, ToSchema)]
+pub struct AssignLabel {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Too many todos"))]
+    todo_ids: Vec<i32>,
+}
+
+impl Normalize for AssignLabel {}
+
+// Todoに付けるラベルと外すラベル、付いていないラベルを外しても何もしない
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Validate, ToSchema)]
+pub struct UpdateTodoLabels {
+    #[serde(default)]
+    #[validate(length(max = 100, message = "Too many labels"))]
+    add: Vec<i32>,
+    #[serde(default)]
+    #[validate(length(max = 100, message = "Too many labels"))]
+    remove: Vec<i32>,
+}
+
+impl Normalize for UpdateTodoLabels {}
+
+// 一括で物理削除するTodo
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Validate, ToSchema)]
+pub struct DeleteTodos {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Too many todos"))]
+    ids: Vec<i32>,
+}
+
+impl Normalize for DeleteTodos {}
+
+// POST /todosのレスポンス、警告がなければTodoEntityと同じ形になる
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct CreatedTodo {
+    #[serde(flatten)]
+    pub todo: TodoEntity,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+}
+
+// 一括操作で変更されたTodoの件数
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct BulkResult {
+    pub affected: u64,
+}
+
+// dry_runで変更される予定のTodo
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct DryRunResult {
+    pub affected: u64,
+    pub ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct ImportError {
+    pub line: u64, // ヘッダーを1行目とした行番号
+    pub message: String,
+}
+
+// カンマやダブルクォートを含むtextはcsvクレートがエスケープする
+fn todos_to_csv(todos: &[TodoEntity]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["id", "text", "completed", "labels"])?;
+    for todo in todos {
+        let labels = todo
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        writer.write_record([
+            todo.id.to_string(),
+            todo.text.clone(),
+            todo.completed.to_string(),
+            labels,
+        ])?;
+    }
+    Ok(writer.into_inner()?)
 }
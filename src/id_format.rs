@@ -0,0 +1,203 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::BoxBody,
+    http::{
+        header::{ACCEPT, VARY},
+        HeaderMap, HeaderValue, Request, Response,
+    },
+};
+use serde::Serializer;
+use tower::{Layer, Service};
+
+// Accept: application/json; profile="string-ids"の場合、idを文字列("1")で返す
+// JSの数値では2^53を超えるidを正確に扱えないため、必要なクライアントだけが指定する
+pub const STRING_IDS_PROFILE: &str = "string-ids";
+
+// idの形式を参照したかどうかも記録し、Acceptで表現が変わったレスポンスにだけVaryを付ける
+struct IdFormatScope {
+    string_ids: bool,
+    used: Arc<AtomicBool>,
+}
+
+impl IdFormatScope {
+    fn new(string_ids: bool) -> Self {
+        Self {
+            string_ids,
+            used: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+tokio::task_local! {
+    static ID_FORMAT: IdFormatScope;
+}
+
+// #[serde(serialize_with = "crate::id_format::serialize_id")]としてidのフィールドに付ける
+// IdFormatLayerの外(リポジトリのテストなど)では常に数値にする
+pub fn serialize_id<S: Serializer>(id: &i32, serializer: S) -> Result<S::Ok, S::Error> {
+    if string_ids() {
+        serializer.collect_str(id)
+    } else {
+        serializer.serialize_i32(*id)
+    }
+}
+
+pub fn string_ids() -> bool {
+    ID_FORMAT
+        .try_with(|scope| {
+            scope.used.store(true, Ordering::Relaxed);
+            scope.string_ids
+        })
+        .unwrap_or(false)
+}
+
+// ボディを送りながらシリアライズするストリームはIdFormatLayerのスコープの外で動くため、
+// ハンドラの中でstring_ids()を取得しておき、シリアライズの際に改めて指定する
+pub fn with_string_ids<R>(string_ids: bool, f: impl FnOnce() -> R) -> R {
+    ID_FORMAT.sync_scope(IdFormatScope::new(string_ids), f)
+}
+
+// 保存する値(履歴のスナップショットなど)はAcceptに関係なく数値のidでシリアライズする
+pub fn with_numeric_ids<R>(f: impl FnOnce() -> R) -> R {
+    with_string_ids(false, f)
+}
+
+// "application/json;q=0.9, application/json; profile=\"string-ids\""のような複数指定にも対応する
+fn accepts_string_ids(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .flat_map(|media_range| media_range.split(';').skip(1))
+        .filter_map(|param| param.split_once('='))
+        .any(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("profile")
+                && value.trim().trim_matches('"') == STRING_IDS_PROFILE
+        })
+}
+
+// ハンドラでのシリアライズをAcceptで指定されたidの形式で行わせる
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdFormatLayer;
+
+impl<S> Layer<S> for IdFormatLayer {
+    type Service = IdFormat<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdFormat { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdFormat<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for IdFormat<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let scope = IdFormatScope::new(accepts_string_ids(req.headers()));
+        let used = scope.used.clone();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let mut res = ID_FORMAT.scope(scope, future).await?;
+            // idを返したレスポンスはAcceptで形式が変わるため、キャッシュがAcceptごとに保持するようにする
+            // /healthのようにidを含まないレスポンスには付けない
+            if used.load(Ordering::Relaxed) {
+                res.headers_mut()
+                    .entry(VARY)
+                    .or_insert(HeaderValue::from_name(ACCEPT));
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Item {
+        #[serde(serialize_with = "serialize_id")]
+        id: i32,
+    }
+
+    #[test]
+    fn parse_string_ids_profile() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(accepts_string_ids(&headers(
+            r#"application/json; profile="string-ids""#
+        )));
+        assert!(accepts_string_ids(&headers(
+            "text/html, application/json;q=0.9;profile=string-ids"
+        )));
+        assert!(!accepts_string_ids(&headers("application/json")));
+        assert!(!accepts_string_ids(&headers(
+            r#"application/json; profile="other""#
+        )));
+        assert!(!accepts_string_ids(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn serialize_id_as_number_or_string() {
+        let item = Item { id: 1 };
+        assert_eq!(r#"{"id":1}"#, serde_json::to_string(&item).unwrap());
+
+        let json = with_string_ids(true, || serde_json::to_string(&item).unwrap());
+        assert_eq!(r#"{"id":"1"}"#, json);
+        let json = with_string_ids(false, || serde_json::to_string(&item).unwrap());
+        assert_eq!(r#"{"id":1}"#, json);
+    }
+
+    #[tokio::test]
+    async fn add_vary_only_when_ids_are_serialized() {
+        let vary = |serialize: bool| async move {
+            let service = tower::service_fn(move |_: Request<()>| async move {
+                let body = if serialize {
+                    serde_json::to_string(&Item { id: 1 }).unwrap()
+                } else {
+                    String::from("ok")
+                };
+                Ok::<_, Infallible>(Response::new(axum::body::boxed(axum::body::Full::from(
+                    body,
+                ))))
+            });
+            let res = IdFormatLayer
+                .layer(service)
+                .call(Request::new(()))
+                .await
+                .unwrap();
+            res.headers().get(VARY).cloned()
+        };
+        assert_eq!(Some(HeaderValue::from_name(ACCEPT)), vary(true).await);
+        assert_eq!(None, vary(false).await);
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::BoxBody,
+    http::{header::CONTENT_TYPE, HeaderMap, Request, Response},
+    response::IntoResponse,
+};
+use serde_json::json;
+use tower::{Layer, Service};
+
+use crate::pretty_json::Json;
+
+// ステータスコードだけのレスポンスや、axum標準の抽出エラー(text/plain)もJSONで返す
+// ハンドラが返す{"error": ..., "message": ...}の形式に揃えるため、エラーの種類はステータスから決める
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonErrorLayer;
+
+impl<S> Layer<S> for JsonErrorLayer {
+    type Service = JsonError<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        JsonError { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JsonError<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for JsonError<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await?;
+            if !(res.status().is_client_error() || res.status().is_server_error())
+                || is_json(res.headers())
+            {
+                return Ok(res);
+            }
+            Ok(into_json_error(res).await)
+        })
+    }
+}
+
+// application/jsonの他、application/vnd.api+jsonのような+jsonの形式もJSONとみなす
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|mime| {
+            let mime = mime.trim();
+            mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json")
+        })
+        .unwrap_or(false)
+}
+
+// 元のボディはmessageに入れ、空であればステータスの説明を使う
+// Retry-Afterなどのヘッダーはそのまま残す
+async fn into_json_error(res: Response<BoxBody>) -> Response<BoxBody> {
+    let (mut parts, body) = res.into_parts();
+    let reason = parts.status.canonical_reason().unwrap_or("Unknown Error");
+    let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let message = match String::from_utf8_lossy(&bytes).trim() {
+        "" => reason.to_string(),
+        text => text.to_string(),
+    };
+    let error = reason.to_lowercase().replace([' ', '-'], "_");
+    let json = Json(json!({ "error": error, "message": message })).into_response();
+    parts.headers.remove(CONTENT_TYPE);
+    parts.headers.remove(hyper::header::CONTENT_LENGTH);
+    let (json_parts, body) = json.into_parts();
+    parts.headers.extend(json_parts.headers);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::Path,
+        http::StatusCode,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/status", get(|| async { StatusCode::NOT_FOUND }))
+            .route(
+                "/path/:id",
+                get(|Path(id): Path<i32>| async move { id.to_string() }),
+            )
+            .route(
+                "/json",
+                post(|| async { (StatusCode::CONFLICT, Json(json!({ "error": "conflict" }))) }),
+            )
+            .layer(JsonErrorLayer)
+    }
+
+    async fn send(method: &str, path: &str) -> (StatusCode, HeaderMap, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or_default();
+        (parts.status, parts.headers, body)
+    }
+
+    #[tokio::test]
+    async fn convert_plain_error_to_json() {
+        // ボディのないレスポンス
+        let (status, headers, body) = send("GET", "/status").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!("application/json", headers.get(CONTENT_TYPE).unwrap());
+        assert_eq!(
+            json!({ "error": "not_found", "message": "Not Found" }),
+            body
+        );
+
+        // axumの抽出エラーはtext/plainで返る
+        let (status, headers, body) = send("GET", "/path/abc").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("application/json", headers.get(CONTENT_TYPE).unwrap());
+        assert_eq!("bad_request", body["error"]);
+        assert!(body["message"].as_str().unwrap().contains("parse"));
+
+        // ルートのないパスやメソッド
+        let (status, headers, _) = send("POST", "/status").await;
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, status);
+        assert_eq!("application/json", headers.get(CONTENT_TYPE).unwrap());
+    }
+
+    #[tokio::test]
+    async fn keep_json_error_and_success() {
+        let (status, _, body) = send("POST", "/json").await;
+        assert_eq!(StatusCode::CONFLICT, status);
+        assert_eq!(json!({ "error": "conflict" }), body);
+
+        let (status, headers, _) = send("GET", "/path/1").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_ne!("application/json", headers.get(CONTENT_TYPE).unwrap());
+    }
+}
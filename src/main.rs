@@ -1,27 +1,74 @@
 mod handlers;
+mod id_format;
+mod json_error;
+mod normalize;
+mod pretty_json;
+mod rate_limit;
 mod repositories;
+mod timeout;
 
 use crate::repositories::{
-    label::LabelRepositoryForDb,
-    todo::{TodoRepository, TodoRepositoryForDb},
+    label::{memory::LabelRepositoryForMemory, LabelRepositoryForDb},
+    pool::{create_pool, run_migrations, PoolConfig},
+    todo::{
+        memory::TodoRepositoryForMemory, MaxTextLength, OrderBy, TodoRepository,
+        TodoRepositoryForDb, DEFAULT_SIMILARITY_THRESHOLD,
+    },
 };
 use axum::{
-    extract::Extension,
-    routing::{delete, get, post},
+    body::{Body, BoxBody},
+    extract::{extractor_middleware, Extension, MatchedPath},
+    handler::Handler,
+    http::{Request, Response},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use handlers::{
-    label::{all_label, create_label, delete_label},
-    todo::{all_todo, create_todo, delete_todo, find_todo, update_todo},
+    auth::{Claims, JwtSecret, StreamingClaims},
+    health::health_check,
+    label::{
+        all_label, autocomplete_label, create_label, delete_label, find_label, in_use_label,
+        merge_label, update_label,
+    },
+    openapi::openapi_json,
+    todo::{
+        add_todo_label, all_todo, all_todo_by_label, all_todo_label, assign_label, batch_find_todo,
+        bulk_create_todo, bulk_delete_todo, complete_all_todo, create_todo, delete_completed_todo,
+        delete_todo, export_todo, find_todo, group_todo_by_label, history_todo, import_todo,
+        remove_todo_label, reorder_todo, replace_todo, restore_todo, search_todo, stats_todo,
+        stream_todo, stream_todo_events, toggle_todo, update_todo, update_todo_labels, watch_todo,
+    },
 };
+use id_format::IdFormatLayer;
+use json_error::JsonErrorLayer;
+use normalize::TextNormalization;
+use pretty_json::{pretty_from_env, PrettyJsonLayer};
+use rate_limit::{RateLimitConfig, RateLimitLayer};
 use repositories::label::LabelRepository;
 use std::net::SocketAddr;
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
+use timeout::{timeout_from_env, TimeoutLayer};
 
 use dotenv::dotenv;
-use hyper::header::CONTENT_TYPE;
-use sqlx::PgPool;
-use tower_http::cors::{Any, CorsLayer, Origin};
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION, VARY,
+    },
+    Method,
+};
+use tokio::sync::Notify;
+use tower_http::{
+    cors::{Any, CorsLayer, Origin},
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
+};
+use tracing::Span;
+
+// 未設定時は開発用のフロントエンドからのアクセスのみ許可する
+const DEFAULT_ALLOWED_ORIGINS: &str = "http://localhost:3001";
+
+// 終了のシグナルを受けてから処理中のリクエストを待つ時間の既定値
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
 
 #[tokio::main]
 async fn main() {
@@ -31,52 +78,323 @@ async fn main() {
     tracing_subscriber::fmt::init();
     dotenv().ok();
 
-    let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
-    tracing::debug!("start connect database...");
-    let pool = PgPool::connect(database_url)
-        .await
-        .expect(&format!("fail connect database, url is [{}]", database_url));
+    let jwt_secret = JwtSecret::new(env::var("JWT_SECRET").expect("undefined [JWT_SECRET]"));
+    let backend = RepositoryBackend::from_env().unwrap_or_else(|e| panic!("{:#}", e));
+    // どちらのリポジトリでも同じ設定で検索・並べ替えする
+    // 0.0〜1.0、大きいほどあいまい検索で一致する条件が厳しくなる
+    let similarity_threshold = env::var("SEARCH_SIMILARITY_THRESHOLD")
+        .map(|value| {
+            value
+                .parse::<f32>()
+                .unwrap_or_else(|_| panic!("invalid [SEARCH_SIMILARITY_THRESHOLD]: {}", value))
+        })
+        .unwrap_or(DEFAULT_SIMILARITY_THRESHOLD);
+    // 一覧取得でsortを省略した場合の並び順
+    let default_order = OrderBy::from_env().unwrap_or_else(|e| panic!("{:#}", e));
+    let (app, pool) = match backend {
+        RepositoryBackend::Memory => {
+            tracing::warn!("using in-memory repositories, data is lost on shutdown");
+            (
+                create_memory_app(jwt_secret, similarity_threshold, default_order),
+                None,
+            )
+        }
+        RepositoryBackend::Postgres => {
+            let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+            tracing::debug!("start connect database...");
+            let pool_config = PoolConfig::from_env().expect("invalid database pool settings");
+            let pool = create_pool(database_url, pool_config)
+                .await
+                .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+            // デプロイ時など別の仕組みでマイグレーションを適用する環境ではRUN_MIGRATIONS=falseで無効にする
+            let run_migrations_on_startup = env::var("RUN_MIGRATIONS")
+                .map(|value| {
+                    value
+                        .parse::<bool>()
+                        .unwrap_or_else(|_| panic!("invalid [RUN_MIGRATIONS]: {}", value))
+                })
+                .unwrap_or(true);
+            if run_migrations_on_startup {
+                run_migrations(&pool)
+                    .await
+                    .unwrap_or_else(|e| panic!("{:#}", e));
+            }
+
+            let app = create_app(
+                TodoRepositoryForDb::new(pool.clone())
+                    .with_similarity_threshold(similarity_threshold)
+                    .with_default_order(default_order),
+                LabelRepositoryForDb::new(pool.clone()),
+                jwt_secret,
+            );
+            (app, Some(pool))
+        }
+    };
+    let shutdown_timeout = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .map(|value| {
+            value
+                .parse::<u64>()
+                .unwrap_or_else(|_| panic!("invalid [SHUTDOWN_TIMEOUT_SECS]: {}", value))
+        })
+        .map_or(
+            Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            Duration::from_secs,
+        );
 
-    let app = create_app(
-        TodoRepositoryForDb::new(pool.clone()),
-        LabelRepositoryForDb::new(pool.clone()),
-    );
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let shutdown = Arc::new(Notify::new());
+    let server = axum::Server::bind(&addr)
+        // クライアントごとのレート制限に接続元のアドレスを使う
+        .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown_signal().await;
+                tracing::info!("shutdown started, waiting for in-flight requests");
+                shutdown.notify_one();
+            }
+        });
+    // 猶予を過ぎても終わらないリクエストは打ち切る
+    let timeout = async {
+        shutdown.notified().await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = timeout => tracing::warn!(
+            timeout_secs = shutdown_timeout.as_secs(),
+            "shutdown timed out, aborting in-flight requests"
+        ),
+    }
+
+    if let Some(pool) = pool {
+        pool.close().await;
+    }
+    tracing::info!("shutdown completed");
+}
+
+// REPOSITORY=memoryの場合はDBに接続せず、メモリ上に保存する
+// 再起動するとデータが消えるため、ローカルでの開発やデモに使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RepositoryBackend {
+    Postgres,
+    Memory,
+}
+
+impl RepositoryBackend {
+    fn from_env() -> anyhow::Result<Self> {
+        match env::var("REPOSITORY") {
+            Err(_) => Ok(Self::Postgres),
+            Ok(value) => match value.as_str() {
+                "postgres" => Ok(Self::Postgres),
+                "memory" => Ok(Self::Memory),
+                _ => Err(anyhow::anyhow!(
+                    "invalid [REPOSITORY]: {} (expected postgres or memory)",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+// Todoに付けるラベルはラベルのリポジトリと共有する
+fn create_memory_app(
+    jwt_secret: JwtSecret,
+    similarity_threshold: f32,
+    default_order: OrderBy,
+) -> Router {
+    let label_repository = LabelRepositoryForMemory::new();
+    let todo_repository = TodoRepositoryForMemory::with_label_repository(&label_repository)
+        .with_similarity_threshold(similarity_threshold)
+        .with_default_order(default_order);
+    create_app(todo_repository, label_repository, jwt_secret)
+}
+
+// Ctrl+C(SIGINT)かSIGTERM(コンテナの停止時に送られる)のどちらかを受け取るまで待つ
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 fn create_app<Todo: TodoRepository, Label: LabelRepository>(
     todo_repository: Todo,
     label_repository: Label,
+    jwt_secret: JwtSecret,
 ) -> Router {
-    Router::new()
-        .route("/", get(root))
-        .route("/todos", post(create_todo::<Todo>).get(all_todo::<Todo>))
+    // /todos, /labels配下はBearerトークンによる認証を必須にする
+    let protected = Router::new()
+        .route(
+            "/todos",
+            post(create_todo::<Todo>)
+                .get(all_todo::<Todo>.layer(vary_accept()))
+                .delete(bulk_delete_todo::<Todo>),
+        )
+        .route("/todos/bulk", post(bulk_create_todo::<Todo>))
+        .route("/todos/stream", get(stream_todo::<Todo>))
+        .route("/todos/batch", get(batch_find_todo::<Todo>))
+        .route("/todos/search", get(search_todo::<Todo>))
+        .route("/todos/stats", get(stats_todo::<Todo>))
+        .route("/todos/by-label", get(group_todo_by_label::<Todo>))
+        .route("/todos/export.csv", get(export_todo::<Todo>))
+        .route("/todos/import", post(import_todo::<Todo>))
+        .route("/todos/complete-all", post(complete_all_todo::<Todo>))
+        .route("/todos/completed", delete(delete_completed_todo::<Todo>))
+        .route("/todos/order", put(reorder_todo::<Todo>))
         .route(
             "/todos/:id",
-            get(find_todo::<Todo>)
+            get(find_todo::<Todo>.layer(vary_accept()))
                 .delete(delete_todo::<Todo>)
-                .patch(update_todo::<Todo>),
+                .patch(update_todo::<Todo>)
+                .put(replace_todo::<Todo>),
+        )
+        .route("/todos/:id/restore", post(restore_todo::<Todo>))
+        .route("/todos/:id/toggle", post(toggle_todo::<Todo>))
+        .route("/todos/:id/history", get(history_todo::<Todo>))
+        .route(
+            "/todos/:id/labels",
+            get(all_todo_label::<Todo>).patch(update_todo_labels::<Todo>),
+        )
+        .route(
+            "/todos/:id/labels/:label_id",
+            post(add_todo_label::<Todo>).delete(remove_todo_label::<Todo>),
         )
         .route(
             "/labels",
             post(create_label::<Label>).get(all_label::<Label>),
         )
-        .route("/labels/:id", delete(delete_label::<Label>))
+        .route("/labels/in-use", get(in_use_label::<Label>))
+        .route("/labels/autocomplete", get(autocomplete_label::<Label>))
+        .route(
+            "/labels/:id",
+            get(find_label::<Label>)
+                .delete(delete_label::<Label>)
+                .patch(update_label::<Label>),
+        )
+        .route("/labels/:id/todos", get(all_todo_by_label::<Todo>))
+        .route("/labels/:id/merge", patch(merge_label::<Label>))
+        .route("/labels/:id/assign", post(assign_label::<Todo>))
+        .layer(extractor_middleware::<Claims>());
+    // 接続を保ったまま通知を送り続けるため、リクエストのタイムアウトの対象外にする
+    // ブラウザのWebSocketやEventSourceはヘッダーを付けられないため、?access_token=でも認証する
+    let streaming = Router::new()
+        .route("/todos/ws", get(watch_todo::<Todo>))
+        .route("/todos/events", get(stream_todo_events::<Todo>))
+        .layer(extractor_middleware::<StreamingClaims>());
+
+    let timeout = TimeoutLayer::new(timeout_from_env().expect("invalid [REQUEST_TIMEOUT_SECS]"));
+    // ヘルスチェックは監視から頻繁に叩かれるためレート制限の対象外にする
+    let rate_limit = RateLimitConfig::from_env().expect("invalid rate limit settings");
+    let limited = Router::new()
+        .route("/", get(root))
+        .route("/openapi.json", get(openapi_json))
+        .merge(protected)
+        .layer(timeout)
+        .merge(streaming)
+        .layer(RateLimitLayer::new(rate_limit));
+
+    Router::new()
+        .route("/health", get(health_check::<Todo>))
+        .layer(timeout)
+        .merge(limited)
         .layer(Extension(Arc::new(todo_repository)))
         .layer(Extension(Arc::new(label_repository)))
+        .layer(Extension(Arc::new(jwt_secret)))
+        .layer(Extension(
+            TextNormalization::from_env().expect("invalid [TODO_COLLAPSE_WHITESPACE]"),
+        ))
+        .layer(Extension(
+            MaxTextLength::from_env().expect("invalid [MAX_TODO_TEXT_LEN]"),
+        ))
+        .layer(IdFormatLayer)
+        .layer(JsonErrorLayer)
+        // JsonErrorLayerが作るエラーのボディにも?prettyが効くよう、その外側に付ける
+        .layer(PrettyJsonLayer::new(
+            pretty_from_env().expect("invalid [JSON_PRETTY]"),
+        ))
+        .layer(cors_layer(
+            &env::var("CORS_ALLOWED_ORIGINS").unwrap_or(DEFAULT_ALLOWED_ORIGINS.to_string()),
+        ))
         .layer(
-            CorsLayer::new()
-                .allow_origin(Origin::exact("http://localhost:3001".parse().unwrap()))
-                .allow_methods(Any)
-                .allow_headers(vec![CONTENT_TYPE]),
+            TraceLayer::new_for_http()
+                // idごとにログが分かれないよう、/todos/:idのようなルートのパスを記録する
+                .make_span_with(|req: &Request<Body>| {
+                    let path = req
+                        .extensions()
+                        .get::<MatchedPath>()
+                        .map_or(req.uri().path(), |path| path.as_str());
+                    tracing::info_span!("request", method = %req.method(), path)
+                })
+                .on_response(log_response)
+                .on_failure(()), // 5xxはon_responseで記録する
         )
 }
 
+// Acceptによって表現(JSON:APIかどうか)が変わるルートに付ける
+// 抽出に失敗した場合のエラーもキャッシュがAcceptごとに保持するよう、ハンドラの外側で付ける
+fn vary_accept() -> SetResponseHeaderLayer<HeaderValue> {
+    SetResponseHeaderLayer::if_not_present(VARY, HeaderValue::from_name(ACCEPT))
+}
+
+fn log_response(res: &Response<BoxBody>, latency: Duration, _span: &Span) {
+    let status = res.status();
+    let latency_ms = latency.as_millis() as u64;
+    if status.is_server_error() {
+        tracing::error!(status = status.as_u16(), latency_ms, "response");
+    } else if status.is_client_error() {
+        tracing::warn!(status = status.as_u16(), latency_ms, "response");
+    } else {
+        tracing::info!(status = status.as_u16(), latency_ms, "response");
+    }
+}
+
+// allowed_originsはカンマ区切りのオリジン、"*"なら全てのオリジンを許可する
+fn cors_layer(allowed_origins: &str) -> CorsLayer {
+    let cors = CorsLayer::new()
+        .allow_methods(vec![
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::PUT,
+            Method::DELETE,
+        ])
+        .allow_headers(vec![CONTENT_TYPE, AUTHORIZATION, IF_NONE_MATCH])
+        // 別オリジンのクライアントがETagや作成したTodoのLocationを読めるようにする
+        .expose_headers(vec![ETAG, LOCATION]);
+    if allowed_origins.trim() == "*" {
+        return cors.allow_origin(Any);
+    }
+
+    let origins = allowed_origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            origin
+                .parse::<HeaderValue>()
+                .unwrap_or_else(|_| panic!("invalid origin in [CORS_ALLOWED_ORIGINS]: {}", origin))
+        });
+    cors.allow_origin(Origin::list(origins))
+}
+
 async fn root() -> &'static str {
     "Hello, World!"
 }
@@ -84,10 +402,15 @@ async fn root() -> &'static str {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::repositories::label::test_utils::LabelRepositoryForMemory;
+    use crate::handlers::auth::encode_token;
+    use crate::handlers::json_api::JSON_API_MEDIA_TYPE;
+    use crate::handlers::todo::{BulkResult, DryRunResult, ImportResult, TODO_FIELDS};
     use crate::repositories::label::Label;
-    use crate::repositories::todo::test_utils::TodoRepositoryForMemory;
-    use crate::repositories::todo::{CreateTodo, TodoEntity};
+    use crate::repositories::todo::test_utils::TodoRepositoryForFailure;
+    use crate::repositories::todo::{
+        AuditAction, CreateTodo, Pagination, TodoAudit, TodoEntity, TodoFilter, TodoGroup,
+        TodoPage, TodoSort, TodoStats, UpdateTodo, DEFAULT_USER_ID, MAX_LABELS_PER_TODO,
+    };
     use axum::http::StatusCode;
     use axum::response::Response;
     use axum::{
@@ -96,9 +419,37 @@ mod test {
     };
     use tower::ServiceExt;
 
+    fn test_jwt_secret() -> JwtSecret {
+        JwtSecret::new("test secret")
+    }
+
+    fn bearer_token_with(claims: Claims) -> String {
+        format!("Bearer {}", encode_token(&claims, &test_jwt_secret()))
+    }
+
+    fn bearer_token_for(user_id: i32) -> String {
+        bearer_token_with(Claims {
+            user_id,
+            exp: chrono::Utc::now().timestamp() + 60,
+        })
+    }
+
+    // リポジトリに直接作成したTodoと同じユーザーのトークン
+    fn bearer_token() -> String {
+        bearer_token_for(DEFAULT_USER_ID)
+    }
+
+    // 別のユーザーとしてリクエストする
+    fn as_user(mut req: Request<Body>, user_id: i32) -> Request<Body> {
+        let token = bearer_token_for(user_id).parse().unwrap();
+        req.headers_mut().insert(header::AUTHORIZATION, token);
+        req
+    }
+
     fn build_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
         Request::builder()
             .uri(path)
+            .header(header::AUTHORIZATION, bearer_token())
             .method(method)
             .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
             .body(Body::from(json_body))
@@ -108,6 +459,7 @@ mod test {
     fn build_todo_req_with_empty(method: Method, path: &str) -> Request<Body> {
         Request::builder()
             .uri(path)
+            .header(header::AUTHORIZATION, bearer_token())
             .method(method)
             .body(Body::empty())
             .unwrap()
@@ -117,7 +469,7 @@ mod test {
         let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
         let body: String = String::from_utf8(bytes.to_vec()).unwrap();
         let todo: TodoEntity = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Todo instance. body: {}", body));
+            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body: {}", body));
         todo
     }
 
@@ -125,7 +477,7 @@ mod test {
         let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
         let body: String = String::from_utf8(bytes.to_vec()).unwrap();
         let label: Label = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Label instance. body: {}", body));
+            .unwrap_or_else(|_| panic!("cannot convert Label instance. body: {}", body));
         label
     }
 
@@ -135,11 +487,162 @@ mod test {
             vec![Label {
                 id,
                 name: String::from("test label"),
+                color: None,
             }],
             vec![id],
         )
     }
 
+    #[tokio::test]
+    async fn should_return_ok_on_health_check() {
+        let req = build_todo_req_with_empty(Method::GET, "/health");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        // idを含まないため、Acceptによって表現は変わらない
+        assert!(res.headers().get(header::VARY).is_none());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            r#"{"status":"ok"}"#,
+            String::from_utf8(bytes.to_vec()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_request_without_valid_token() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let req_with_token = |token: Option<String>| {
+            let builder = Request::builder().uri("/todos").method(Method::GET);
+            match token {
+                Some(token) => builder.header(header::AUTHORIZATION, token),
+                None => builder,
+            }
+            .body(Body::empty())
+            .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(req_with_token(Some(bearer_token())))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let expired = bearer_token_with(Claims {
+            user_id: DEFAULT_USER_ID,
+            exp: chrono::Utc::now().timestamp() - 120,
+        });
+        let res = app
+            .clone()
+            .oneshot(req_with_token(Some(expired)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        assert_eq!("Bearer", res.headers()[header::WWW_AUTHENTICATE]);
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!({"error": "unauthorized", "message": "Token is expired"}),
+            body
+        );
+
+        let res = app.clone().oneshot(req_with_token(None)).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        let res = app
+            .clone()
+            .oneshot(req_with_token(Some("Bearer invalid".to_string())))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        // ヘルスチェックはトークンなしで叩ける
+        let req = Request::builder()
+            .uri("/health")
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_rate_limit_requests_except_health_check() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        for _ in 0..RateLimitConfig::default().max_requests {
+            let req = build_todo_req_with_empty(Method::GET, "/todos");
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+        }
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, res.status());
+        let retry_after: u64 = res.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+
+        let req = build_todo_req_with_empty(Method::GET, "/health");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_return_service_unavailable_on_health_check_failure() {
+        let req = build_todo_req_with_empty(Method::GET, "/health");
+        let res = create_app(
+            TodoRepositoryForFailure,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_return_openapi_document() {
+        let req = build_todo_req_with_empty(Method::GET, "/openapi.json");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        let todo = &doc["components"]["schemas"]["TodoEntity"]["properties"];
+        assert_eq!("boolean", todo["completed"]["type"]);
+        assert_eq!("array", todo["labels"]["type"]);
+        assert_eq!(
+            "#/components/schemas/Label",
+            todo["labels"]["items"]["$ref"]
+        );
+        assert!(doc["paths"]["/todos/{id}"]["get"]["responses"]["404"].is_object());
+    }
+
     #[tokio::test]
     async fn should_created_todo() {
         let (labels, _label_ids) = label_fixture();
@@ -153,157 +656,3065 @@ mod test {
         let res = create_app(
             TodoRepositoryForMemory::new(labels),
             LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
         )
         .oneshot(req)
         .await
         .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
         let todo = res_to_todo(res).await;
+        let expected = TodoEntity {
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+            ..expected
+        };
         assert_eq!(expected, todo);
     }
 
     #[tokio::test]
-    async fn should_find_todo() {
-        let (labels, label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_find_todo".to_string(), labels.clone());
+    async fn should_return_location_of_created_todo() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        for id in 1..=2 {
+            let req = build_req_with_json(
+                "/todos",
+                Method::POST,
+                r#"{ "text": "should_return_location" }"#.to_string(),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+            let location = res.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert_eq!(format!("/todos/{}", id), location);
+            let todo = res_to_todo(res).await;
+            assert_eq!(id, todo.id);
 
-        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
-        todo_repository
-            .create(CreateTodo::new("should_find_todo".to_string(), label_ids))
-            .await
-            .expect("failed create todo");
-        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        let todo = res_to_todo(res).await;
-        assert_eq!(expected, todo);
+            // Locationをたどると作成したTodoを取得できる
+            let req = build_todo_req_with_empty(Method::GET, &location);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            assert_eq!(todo, res_to_todo(res).await);
+        }
     }
 
     #[tokio::test]
-    async fn should_get_all_todos() {
-        let (labels, label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_get_all_todos".to_string(), labels.clone());
+    async fn should_create_todo_with_labels() {
+        let labels = vec![
+            Label::new(1, "first".to_string()),
+            Label::new(2, "second".to_string()),
+        ];
+        let app = create_app(
+            TodoRepositoryForMemory::new(labels.clone()),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
 
-        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
-        todo_repository
-            .create(CreateTodo::new(
-                "should_get_all_todos".to_string(),
-                label_ids,
-            ))
-            .await
-            .expect("failed create todo");
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "with labels", "labels": [1, 2] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_eq!(labels, res_to_todo(res).await.labels);
+
+        // labelsは省略できる
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "without labels" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert!(res_to_todo(res).await.labels.is_empty());
+
+        // 存在しないラベルを含む場合は作成しない
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "missing label", "labels": [1, 999] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
         let req = build_todo_req_with_empty(Method::GET, "/todos");
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
         let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
-            .expect(&format!("cannot convert Todo lis instance. body: {}", body));
-        assert_eq!(vec![expected], todos);
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(2, page.items.len());
     }
 
     #[tokio::test]
-    async fn should_update_todo() {
-        let (labels, label_ids) = label_fixture();
-        let expected = TodoEntity::new(1, "should_update_todo".to_string(), labels.clone());
+    async fn should_normalize_todo_text() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "  buy milk  ", "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_eq!("buy milk", res_to_todo(res).await.text);
 
-        let todo_repository = TodoRepositoryForMemory::new(labels);
-        todo_repository
-            .create(CreateTodo::new("before_update_todo".to_string(), label_ids))
-            .await
-            .expect("failed create todo");
         let req = build_req_with_json(
             "/todos/1",
             Method::PATCH,
-            r#"{
-    "text": "should_update_todo",
-    "completed": false
-}"#
-            .to_string(),
+            r#"{ "text": " buy bread\n" }"#.to_string(),
         );
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        let todo = res_to_todo(res).await;
-        assert_eq!(expected, todo);
-    }
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("buy bread", res_to_todo(res).await.text);
 
-    #[tokio::test]
-    async fn should_delete_todo() {
-        let (labels, label_ids) = label_fixture();
-        let todo_repository = TodoRepositoryForMemory::new(labels);
-        todo_repository
-            .create(CreateTodo::new("should_delete_todo".to_string(), label_ids))
-            .await
-            .expect("failed create todo");
-        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
-        let res = create_app(todo_repository, LabelRepositoryForMemory::new())
-            .oneshot(req)
-            .await
-            .unwrap();
-        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        // 空白のみのtextは正規化すると空になるため弾く
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "   ", "labels": [] }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
     }
 
     #[tokio::test]
-    async fn should_created_label() {
-        let (labels, _label_ids) = label_fixture();
-        let expected = Label::new(1, "should_created_label".to_string());
-
+    async fn should_reject_too_large_body() {
+        let text = "a".repeat(handlers::MAX_JSON_BODY_SIZE);
         let req = build_req_with_json(
-            "/labels",
+            "/todos",
             Method::POST,
-            r#"{ "name": "should_created_label" }"#.to_string(),
+            format!(r#"{{ "text": "{}", "labels": [] }}"#, text),
         );
         let res = create_app(
-            TodoRepositoryForMemory::new(labels),
+            TodoRepositoryForMemory::new(vec![]),
             LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
         )
         .oneshot(req)
         .await
         .unwrap();
-        let label = res_to_label(res).await;
-        assert_eq!(expected, label);
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
     }
 
     #[tokio::test]
-    async fn should_all_label_readed() {
-        let expected = Label::new(1, "should_all_label_readed".to_string());
-        let label_repository = LabelRepositoryForMemory::new();
-        let label = label_repository
-            .create("should_all_label_readed".to_string())
-            .await
-            .expect("failed create label");
-
-        let req = build_todo_req_with_empty(Method::GET, "/labels");
-        let res = create_app(TodoRepositoryForMemory::new(vec![label]), label_repository)
-            .oneshot(req)
-            .await
+    async fn should_reject_too_large_chunked_body() {
+        // Content-Lengthを付けずに少しずつ送る
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let chunk = hyper::body::Bytes::from(vec![b' '; 1024]);
+            for _ in 0..=handlers::MAX_JSON_BODY_SIZE / 1024 {
+                if sender.send_data(chunk.clone()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let req = Request::builder()
+            .uri("/todos")
+            .header(header::AUTHORIZATION, bearer_token())
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(body)
             .unwrap();
-        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
-        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let labels: Vec<Label> = serde_json::from_str(&body).expect(&format!(
-            "cannot convert Label list instance. body: {}",
-            body
-        ));
-        assert_eq!(vec![expected], labels);
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
     }
 
     #[tokio::test]
-    async fn should_delete_label() {
-        let label_repository = LabelRepositoryForMemory::new();
-        let label = label_repository
-            .create("should_delete_label".to_string())
+    async fn should_return_validation_errors_as_json() {
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "", "labels": [] }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "error": "validation",
+                "fields": { "text": ["Can not be empty"] }
+            }),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn should_created_todo_with_due_date() {
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_created_todo_with_due_date", "labels": [], "due_date": "2999-12-31" }"#
+                .to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(
+            Some(chrono::NaiveDate::from_ymd_opt(2999, 12, 31).unwrap()),
+            todo.due_date
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_past_due_date() {
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_reject_past_due_date", "labels": [], "due_date": "2000-01-01" }"#
+                .to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_priority() {
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_reject_invalid_priority", "labels": [], "priority": "urgent" }"#
+                .to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_bulk_created_todos() {
+        let (labels, _label_ids) = label_fixture();
+        let expected = vec![
+            TodoEntity::new(1, "bulk 1".to_string(), labels.clone()),
+            TodoEntity::new(2, "bulk 2".to_string(), vec![]),
+        ];
+
+        let req = build_req_with_json(
+            "/todos/bulk",
+            Method::POST,
+            r#"[
+    { "text": "bulk 1", "labels": [999] },
+    { "text": "bulk 2", "labels": [] }
+]"#
+            .to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(labels),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo list instance. body: {}", body));
+        let expected: Vec<TodoEntity> = expected
+            .into_iter()
+            .zip(&todos)
+            .map(|(expected, todo)| TodoEntity {
+                created_at: todo.created_at,
+                updated_at: todo.updated_at,
+                ..expected
+            })
+            .collect();
+        assert_eq!(expected, todos);
+    }
+
+    #[tokio::test]
+    async fn should_reject_bulk_todos_with_invalid_entry() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        let req = build_req_with_json(
+            "/todos/bulk",
+            Method::POST,
+            r#"[
+    { "text": "bulk 1", "labels": [] },
+    { "text": "", "labels": [] }
+]"#
+            .to_string(),
+        );
+        let res = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!(["Can not be empty"]),
+            body["fields"]["todos[1].text"]
+        );
+        let todos = todo_repository
+            .all(
+                TodoFilter::default(),
+                TodoSort::default(),
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert!(todos.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_find_todo() {
+        let (labels, label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_find_todo".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
+        todo_repository
+            .create(CreateTodo::new("should_find_todo".to_string(), label_ids))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let todo = res_to_todo(res).await;
+        let expected = TodoEntity {
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+            ..expected
+        };
+        assert_eq!(expected, todo);
+    }
+
+    #[tokio::test]
+    async fn should_return_only_requested_fields() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new(
+                "should_select_fields".to_string(),
+                label_ids,
+            ))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let get_json = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = build_todo_req_with_empty(Method::GET, uri);
+                let res = app.oneshot(req).await.unwrap();
+                let status = res.status();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                (status, json)
+            }
+        };
+
+        // 指定した項目だけを返し、labelsやtextは含まない
+        let (status, todo) = get_json("/todos/1?fields=id,completed").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(serde_json::json!({ "id": 1, "completed": false }), todo);
+
+        let (status, page) = get_json("/todos?fields=id,%20completed").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            serde_json::json!({ "items": [{ "id": 1, "completed": false }], "total": 1 }),
+            page
+        );
+
+        // 省略した場合は全ての項目を返す
+        let (_, todo) = get_json("/todos/1?fields=").await;
+        let mut keys: Vec<&str> = todo
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|key| key.as_str())
+            .collect();
+        let mut expected = TODO_FIELDS.to_vec();
+        keys.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(expected, keys);
+
+        // TodoEntityにない項目は400
+        let (status, error) = get_json("/todos?fields=id,title").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(
+            serde_json::json!({ "fields": ["Unknown field: title"] }),
+            error["fields"]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_return_method_not_allowed_with_allow_header() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        for (method, path, allow) in [
+            (Method::PUT, "/todos/1/history", "GET,HEAD"),
+            (Method::POST, "/todos/1", "GET,HEAD,DELETE,PATCH,PUT"),
+            (Method::DELETE, "/todos/1/labels", "GET,HEAD,PATCH"),
+            (Method::PUT, "/health", "GET,HEAD"),
+        ] {
+            let req = build_todo_req_with_empty(method.clone(), path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(
+                StatusCode::METHOD_NOT_ALLOWED,
+                res.status(),
+                "{} {}",
+                method,
+                path
+            );
+            assert_eq!(allow, res.headers()[header::ALLOW], "{} {}", method, path);
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(
+                serde_json::json!({
+                    "error": "method_not_allowed",
+                    "message": "Method Not Allowed"
+                }),
+                body,
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_pretty_print_json_when_requested() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("should_pretty_print".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let get_body = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = build_todo_req_with_empty(Method::GET, uri);
+                let res = app.oneshot(req).await.unwrap();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                String::from_utf8(bytes.to_vec()).unwrap()
+            }
+        };
+
+        // 既定では1行で返す
+        let body = get_body("/todos/1").await;
+        assert!(!body.contains('\n'));
+
+        let body = get_body("/todos/1?pretty=true").await;
+        assert!(body.starts_with("{\n  \"id\": 1,\n"), "{}", body);
+        let todo: TodoEntity = serde_json::from_str(&body).unwrap();
+        assert_eq!("should_pretty_print", todo.text);
+
+        // 他のクエリパラメータやエラーのレスポンスにも効く
+        let body = get_body("/todos?limit=1&pretty").await;
+        assert!(body.contains("\n  \"items\": [\n    {\n"), "{}", body);
+        let body = get_body("/todos/99?pretty=true").await;
+        assert!(body.contains("\n  \"error\": \"not_found\""), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn should_find_todo_as_json_api() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new("should_find_todo".to_string(), label_ids))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        req.headers_mut()
+            .insert(header::ACCEPT, JSON_API_MEDIA_TYPE.parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(JSON_API_MEDIA_TYPE, res.headers()[header::CONTENT_TYPE]);
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("todos", document["data"]["type"]);
+        assert_eq!("1", document["data"]["id"]);
+        assert_eq!("should_find_todo", document["data"]["attributes"]["text"]);
+        assert_eq!(
+            serde_json::json!({ "data": [{ "type": "labels", "id": "999" }] }),
+            document["data"]["relationships"]["labels"]
+        );
+        assert_eq!("test label", document["included"][0]["attributes"]["name"]);
+
+        // Acceptがなければ従来どおりのJSON
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(
+            mime::APPLICATION_JSON.as_ref(),
+            res.headers()[header::CONTENT_TYPE]
+        );
+        assert_eq!(1, res_to_todo(res).await.id);
+    }
+
+    #[tokio::test]
+    async fn should_return_not_modified_for_matching_etag() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("should_find_todo".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let req_with_etag = |etag: &HeaderValue| {
+            Request::builder()
+                .uri("/todos/1")
+                .header(header::AUTHORIZATION, bearer_token())
+                .method(Method::GET)
+                .header(header::IF_NONE_MATCH, etag)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let etag = res.headers()[header::ETAG].clone();
+
+        let res = app.clone().oneshot(req_with_etag(&etag)).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+        assert_eq!(etag, res.headers()[header::ETAG]);
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(bytes.is_empty());
+
+        // 更新後は古いETagでは304にならない
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "completed": true }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+        let res = app.oneshot(req_with_etag(&etag)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_ne!(etag, res.headers()[header::ETAG]);
+    }
+
+    #[tokio::test]
+    async fn should_return_etag_per_representation() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("should_find_todo".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let etag = res.headers()[header::ETAG].clone();
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1?fields=id,text");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let fields_etag = res.headers()[header::ETAG].clone();
+        assert_ne!(etag, fields_etag);
+
+        // 絞り込んだボディのETagでは、全ての項目を返す表現は304にならない
+        let req = Request::builder()
+            .uri("/todos/1")
+            .header(header::AUTHORIZATION, bearer_token())
+            .header(header::IF_NONE_MATCH, &fields_etag)
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(etag, res.headers()[header::ETAG]);
+
+        // JSON:APIの文書も別の表現なので、JSONのETagでは304にならない
+        let req = Request::builder()
+            .uri("/todos/1")
+            .header(header::AUTHORIZATION, bearer_token())
+            .header(header::ACCEPT, JSON_API_MEDIA_TYPE)
+            .header(header::IF_NONE_MATCH, &etag)
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(JSON_API_MEDIA_TYPE, res.headers()[header::CONTENT_TYPE]);
+        assert_ne!(etag, res.headers()[header::ETAG]);
+        assert_ne!(fields_etag, res.headers()[header::ETAG]);
+    }
+
+    #[tokio::test]
+    async fn should_get_all_todos() {
+        let (labels, label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_get_all_todos".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
+        todo_repository
+            .create(CreateTodo::new(
+                "should_get_all_todos".to_string(),
+                label_ids,
+            ))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let page: TodoPage = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body: {}", body));
+        let expected = TodoEntity {
+            created_at: page.items[0].created_at,
+            updated_at: page.items[0].updated_at,
+            ..expected
+        };
+        assert_eq!(vec![expected], page.items);
+        assert_eq!(1, page.total);
+    }
+
+    #[tokio::test]
+    async fn should_get_all_todos_as_json_api() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for text in ["todo 1", "todo 2"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos?limit=1");
+        req.headers_mut().insert(
+            header::ACCEPT,
+            "application/json;q=0.9, application/vnd.api+json"
+                .parse()
+                .unwrap(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(JSON_API_MEDIA_TYPE, res.headers()[header::CONTENT_TYPE]);
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(1, document["data"].as_array().unwrap().len());
+        assert_eq!("todos", document["data"][0]["type"]);
+        assert_eq!("1", document["data"][0]["id"]);
+        assert_eq!(serde_json::json!({ "total": 2 }), document["meta"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?limit=1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(
+            mime::APPLICATION_JSON.as_ref(),
+            res.headers()[header::CONTENT_TYPE]
+        );
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(2, page["total"]);
+        assert_eq!(1, page["items"][0]["id"]);
+    }
+
+    #[tokio::test]
+    async fn should_return_internal_server_error_on_all_todos_failure() {
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = create_app(
+            TodoRepositoryForFailure,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_hide_todos_of_other_users() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_hide_todos_of_other_users", "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(as_user(req, 1)).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let todo = res_to_todo(res).await;
+        assert_eq!(1, todo.user_id);
+        let path = format!("/todos/{}", todo.id);
+
+        // 他のユーザーには存在しないTodoとして404を返す
+        let reqs = vec![
+            build_todo_req_with_empty(Method::GET, &path),
+            build_req_with_json(&path, Method::PATCH, r#"{ "completed": true }"#.to_string()),
+            build_todo_req_with_empty(Method::DELETE, &path),
+        ];
+        for req in reqs {
+            let res = app.clone().oneshot(as_user(req, 2)).await.unwrap();
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.clone().oneshot(as_user(req, 2)).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert!(page.items.is_empty());
+        assert_eq!(0, page.total);
+
+        // 所有者からは変更されていない状態で見える
+        let req = build_todo_req_with_empty(Method::GET, &path);
+        let res = app.clone().oneshot(as_user(req, 1)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(todo, res_to_todo(res).await);
+    }
+
+    #[tokio::test]
+    async fn should_distinguish_not_found_from_failure() {
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "text": "should_distinguish_not_found_from_failure" }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!({"error": "not_found", "message": "NotFound, id is 1"}),
+            body
+        );
+
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "should_distinguish_not_found_from_failure", "labels": [] }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForFailure,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        // 想定外のエラーの詳細は返さない
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!({"error": "unexpected", "message": "Internal Server Error"}),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn should_get_paginated_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for i in 1..=3 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos?limit=2&offset=1");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let page: TodoPage = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body: {}", body));
+        let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![2, 3], ids);
+        assert_eq!(3, page.total);
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_created_in_range() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        let mut created_at = vec![];
+        for text in ["before", "inside", "after"] {
+            let todo = todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            created_at.push(todo.created_at.format("%Y-%m-%dT%H:%M:%S%.f").to_string());
+            // 作成日時が重ならないよう少し待つ
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        // 両端を含むため、1件目と3件目の日時を指定すると3件とも返る
+        for (created_after, created_before, expected) in [
+            (&created_at[1], &created_at[1], vec!["inside"]),
+            (
+                &created_at[0],
+                &created_at[2],
+                vec!["after", "inside", "before"],
+            ),
+        ] {
+            let req = build_todo_req_with_empty(
+                Method::GET,
+                &format!(
+                    "/todos?sort=id&created_after={}&created_before={}",
+                    created_after, created_before
+                ),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+            let texts: Vec<&str> = page.items.iter().map(|todo| todo.text.as_str()).collect();
+            assert_eq!(expected, texts);
+            assert_eq!(expected.len() as i64, page.total);
+        }
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?created_after=yesterday");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_filtered_by_labels() {
+        let labels = vec![
+            Label::new(5, "label 5".to_string()),
+            Label::new(7, "label 7".to_string()),
+        ];
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        for label_ids in [vec![5, 7], vec![5]] {
+            todo_repository
+                .create(CreateTodo::new("todo".to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        for (path, expected) in [
+            ("/todos?label_id=5", vec![1, 2]),
+            ("/todos?label_id=5&label_id=7", vec![1]),
+        ] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+            let page: TodoPage = serde_json::from_str(&body)
+                .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body: {}", body));
+            let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+            assert_eq!(expected, ids);
+        }
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?label_id=abc");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_sorted_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for text in ["b", "c", "a"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos?sort=text&order=asc");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let page: TodoPage = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body: {}", body));
+        let texts: Vec<&str> = page.items.iter().map(|todo| todo.text.as_str()).collect();
+        assert_eq!(vec!["a", "b", "c"], texts);
+    }
+
+    #[tokio::test]
+    async fn should_reject_unknown_sort_field() {
+        let req = build_todo_req_with_empty(Method::GET, "/todos?sort=id;drop%20table%20todos");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_completed_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for i in 1..=2 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        todo_repository
+            .update(1, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("failed update todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos?completed=true");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let page: TodoPage = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body: {}", body));
+        let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![1], ids);
+        assert_eq!(1, page.total);
+    }
+
+    #[tokio::test]
+    async fn should_export_todos_as_csv() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new(
+                r#"say "hi", then leave"#.to_string(),
+                label_ids,
+            ))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos/export.csv");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "text/csv; charset=utf-8",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        assert!(res
+            .headers()
+            .get(header::CONTENT_DISPOSITION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("attachment"));
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        assert_eq!(
+            "id,text,completed,labels\n1,\"say \"\"hi\"\", then leave\",false,test label\n",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn should_import_todos_from_csv() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        let req = Request::builder()
+            .uri("/todos/import")
+            .header(header::AUTHORIZATION, bearer_token())
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "text/csv")
+            .body(Body::from("text,completed\nbuy milk,true\n,false\n"))
+            .unwrap();
+        let res = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let result: ImportResult = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert ImportResult instance. body: {}", body));
+        assert_eq!(1, result.created);
+        assert_eq!(1, result.skipped);
+        assert_eq!(3, result.errors[0].line);
+
+        let todo = todo_repository.find(1).await.expect("failed find todo");
+        assert_eq!("buy milk", todo.text);
+        assert!(todo.completed);
+    }
+
+    #[tokio::test]
+    async fn should_get_todo_stats() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new("labeled".to_string(), label_ids))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .create(CreateTodo::new("done".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        todo_repository.toggle(2).await.expect("failed toggle todo");
+        let req = build_todo_req_with_empty(Method::GET, "/todos/stats");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let stats: TodoStats = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoStats instance. body: {}", body));
+        assert_eq!((2, 1, 1), (stats.total, stats.completed, stats.incomplete));
+        assert_eq!(1, stats.labels.len());
+        let label = &stats.labels[0];
+        assert_eq!((999, 1, 0), (label.id, label.total, label.completed));
+    }
+
+    #[tokio::test]
+    async fn should_serialize_ids_as_strings_when_requested() {
+        let (labels, _label_ids) = label_fixture();
+        let app = create_app(
+            TodoRepositoryForMemory::new(labels),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let string_ids = r#"application/json; profile="string-ids""#;
+        let mut req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "string ids", "labels": [999] }"#.to_string(),
+        );
+        req.headers_mut()
+            .insert(header::ACCEPT, HeaderValue::from_static(string_ids));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(serde_json::json!("1"), json["id"]);
+
+        let get_todo = |accept: Option<&str>| {
+            let mut req = build_todo_req_with_empty(Method::GET, "/todos/1");
+            if let Some(accept) = accept {
+                req.headers_mut()
+                    .insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+            }
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                assert_eq!(StatusCode::OK, res.status());
+                assert_eq!("accept", res.headers().get(header::VARY).unwrap());
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+            }
+        };
+
+        // 既定では数値
+        let json = get_todo(None).await;
+        assert_eq!(serde_json::json!(1), json["id"]);
+        assert_eq!(serde_json::json!(999), json["labels"][0]["id"]);
+
+        let json = get_todo(Some(string_ids)).await;
+        assert_eq!(serde_json::json!("1"), json["id"]);
+        assert_eq!(serde_json::json!("999"), json["labels"][0]["id"]);
+
+        // 文字列のidを指定したリクエストで作成しても、履歴のスナップショットは数値で残る
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1/history");
+        let res = app.oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let audits: Vec<TodoAudit> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(serde_json::json!(1), audits[0].snapshot["id"]);
+
+        // 件数付きのラベル一覧と使用中のラベルのidも文字列にする
+        let label_repository = LabelRepositoryForMemory::new();
+        let todo_repository = TodoRepositoryForMemory::with_label_repository(&label_repository);
+        let label = label_repository
+            .create("string ids".to_string(), None)
+            .await
+            .expect("failed create label");
+        todo_repository
+            .create(CreateTodo::new("string ids".to_string(), vec![label.id]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(todo_repository, label_repository, test_jwt_secret());
+        for uri in ["/labels?with_counts=true", "/labels/in-use"] {
+            let mut req = build_todo_req_with_empty(Method::GET, uri);
+            req.headers_mut()
+                .insert(header::ACCEPT, HeaderValue::from_static(string_ids));
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            assert_eq!("accept", res.headers().get(header::VARY).unwrap());
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(serde_json::json!("1"), json[0]["id"], "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_delete_todos_in_bulk() {
+        let (labels, _label_ids) = label_fixture();
+        let app = create_app(
+            TodoRepositoryForMemory::new(labels),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        for body in [
+            r#"{ "text": "first", "labels": [999] }"#,
+            r#"{ "text": "second" }"#,
+            r#"{ "text": "parent" }"#,
+            r#"{ "text": "child", "parent_id": 3 }"#,
+        ] {
+            let req = build_req_with_json("/todos", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+        let delete = |ids: &str| {
+            let req =
+                build_req_with_json("/todos", Method::DELETE, format!(r#"{{ "ids": {} }}"#, ids));
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                let status = res.status();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                (status, serde_json::from_slice::<BulkResult>(&bytes).ok())
+            }
+        };
+
+        // 存在しないidと、対象外の子を持つTodoは数えない
+        assert_eq!(
+            (StatusCode::OK, Some(BulkResult { affected: 2 })),
+            delete("[1, 2, 3, 999]").await
+        );
+        // 子と一緒であれば親も削除できる
+        assert_eq!(
+            (StatusCode::OK, Some(BulkResult { affected: 2 })),
+            delete("[3, 4, 1]").await
+        );
+        assert_eq!(StatusCode::BAD_REQUEST, delete("[]").await.0);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(0, page.total);
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_grouped_by_label() {
+        let labels = vec![
+            Label::new(1, "first".to_string()),
+            Label::new(2, "second".to_string()),
+        ];
+        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
+        for (text, label_ids) in [("both", vec![2, 1]), ("first", vec![1]), ("none", vec![])] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+        let deleted = todo_repository
+            .create(CreateTodo::new("deleted".to_string(), vec![1]))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .delete(deleted.id, false)
+            .await
+            .expect("failed delete todo");
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/by-label");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let groups: Vec<TodoGroup> = serde_json::from_slice(&bytes).unwrap();
+        let summary: Vec<(Option<Label>, Vec<String>)> = groups
+            .into_iter()
+            .map(|group| {
+                let texts = group.todos.into_iter().map(|todo| todo.text).collect();
+                (group.label, texts)
+            })
+            .collect();
+        // 複数のラベルが付いたTodoはそれぞれに含まれ、ラベルのないTodoは最後にまとまる
+        assert_eq!(
+            vec![
+                (
+                    Some(labels[0].clone()),
+                    vec!["both".to_string(), "first".to_string()]
+                ),
+                (Some(labels[1].clone()), vec!["both".to_string()]),
+                (None, vec!["none".to_string()]),
+            ],
+            summary
+        );
+    }
+
+    #[tokio::test]
+    async fn should_stream_todos_as_ndjson() {
+        let labels = vec![
+            Label::new(1, "first".to_string()),
+            Label::new(2, "second".to_string()),
+        ];
+        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
+        for (text, label_ids) in [("both", vec![1, 2]), ("none", vec![]), ("first", vec![1])] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+
+        let req =
+            build_todo_req_with_empty(Method::GET, "/todos/stream?label_id=1&sort=id&order=asc");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "application/x-ndjson",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        // 1行に1件のTodoが、ラベルをまとめた状態で並ぶ
+        let todos: Vec<TodoEntity> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let summary: Vec<(String, Vec<Label>)> = todos
+            .into_iter()
+            .map(|todo| (todo.text, todo.labels))
+            .collect();
+        assert_eq!(
+            vec![
+                ("both".to_string(), labels.clone()),
+                ("first".to_string(), vec![labels[0].clone()]),
+            ],
+            summary
+        );
+        assert!(body.ends_with('\n'));
+
+        // 送信を始めた後のエラーはステータスで返せないため、ボディを途中で打ち切る
+        let req = build_todo_req_with_empty(Method::GET, "/todos/stream");
+        let res = create_app(
+            TodoRepositoryForFailure,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_push_todo_events_over_websocket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        let app = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        // Upgradeには実際の接続が必要なため、oneshotではなくサーバーを起動する
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let handshake = format!(
+            "GET /todos/ws HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            addr,
+            bearer_token()
+        );
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{}", head);
+
+        // 他のユーザーの変更は届かず、自分の作成したTodoの通知だけが届く
+        todo_repository
+            .for_user(2)
+            .create(CreateTodo::new("other".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .create(CreateTodo::new("mine".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let mut frame_head = [0u8; 2];
+        stream.read_exact(&mut frame_head).await.unwrap();
+        assert_eq!(0x81, frame_head[0]); // FINが立ったテキストフレーム
+        let mut payload = vec![0u8; frame_head[1] as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(serde_json::json!({ "type": "created", "id": 2 }), event);
+    }
+
+    #[tokio::test]
+    async fn should_upgrade_websocket_with_access_token_query() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.clone().into_make_service()));
+
+        // ブラウザのWebSocketと同じく、Authorizationヘッダーを付けずに接続する
+        let token = bearer_token().trim_start_matches("Bearer ").to_string();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let handshake = format!(
+            "GET /todos/ws?access_token={} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            token, addr
+        );
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+        // 不正なトークンは拒否する
+        let req = Request::builder()
+            .uri("/todos/ws?access_token=invalid")
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        // 通知を受け取るルート以外ではクエリのトークンを受け付けない
+        let req = Request::builder()
+            .uri(format!("/todos?access_token={}", token))
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_stream_todo_events() {
+        use http_body::Body as _;
+
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        let req = build_todo_req_with_empty(Method::GET, "/todos/events");
+        let res = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "text/event-stream",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+
+        // 他のユーザーの変更は届かず、自分の作成したTodoの通知だけが届く
+        todo_repository
+            .for_user(2)
+            .create(CreateTodo::new("other".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .create(CreateTodo::new("mine".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let mut body = res.into_body();
+        let chunk = body.data().await.unwrap().unwrap();
+        assert_eq!(
+            "event: created\ndata:{\"type\":\"created\",\"id\":2}\n\n",
+            String::from_utf8(chunk.to_vec()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_stream_todo_events_with_access_token_query() {
+        use http_body::Body as _;
+
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        // EventSourceと同じく、Authorizationヘッダーを付けずに接続する
+        let token = bearer_token().trim_start_matches("Bearer ").to_string();
+        let req = Request::builder()
+            .uri(format!("/todos/events?access_token={}", token))
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = create_app(
+            todo_repository.clone(),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        todo_repository
+            .create(CreateTodo::new("mine".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let mut body = res.into_body();
+        let chunk = body.data().await.unwrap().unwrap();
+        assert_eq!(
+            "event: created\ndata:{\"type\":\"created\",\"id\":1}\n\n",
+            String::from_utf8(chunk.to_vec()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_websocket_without_handshake() {
+        let req = build_todo_req_with_empty(Method::GET, "/todos/ws");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_search_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for text in ["Buy milk", "Write report", "buy bread"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos/search?q=BUY");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo list instance. body: {}", body));
+        let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![3, 1], ids);
+    }
+
+    #[tokio::test]
+    async fn should_fuzzy_search_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for text in ["Buy milk", "Write report"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        // 部分一致では誤字のある検索語に一致しない
+        for (path, expected) in [
+            ("/todos/search?q=by%20milk", vec![]),
+            ("/todos/search?q=by%20milk&fuzzy=true", vec![1]),
+        ] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let todos: Vec<TodoEntity> = serde_json::from_slice(&bytes).unwrap();
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(expected, ids);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_allow_default_origin() {
+        let req = Request::builder()
+            .uri("/todos")
+            .header(header::AUTHORIZATION, bearer_token())
+            .method(Method::GET)
+            .header(header::ORIGIN, DEFAULT_ALLOWED_ORIGINS)
+            .body(Body::empty())
+            .unwrap();
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(
+            DEFAULT_ALLOWED_ORIGINS,
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        let exposed = res.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(exposed.contains("etag"));
+        assert!(exposed.contains("location"));
+    }
+
+    #[tokio::test]
+    async fn should_answer_cors_preflight() {
+        let app = Router::new()
+            .route("/todos/:id", get(root))
+            .layer(cors_layer("https://a.example.com, https://b.example.com"));
+        let preflight = |origin: &str| {
+            Request::builder()
+                .uri("/todos/1")
+                .method(Method::OPTIONS)
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PATCH")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "if-none-match")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app
+            .clone()
+            .oneshot(preflight("https://b.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "https://b.example.com",
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        let methods = res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("PATCH"));
+        assert!(methods.contains("PUT"));
+        let headers = res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(headers.contains("if-none-match"));
+
+        // 許可していないオリジンにはヘッダーを返さない
+        let res = app
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn should_answer_cors_preflight_for_reorder() {
+        let req = Request::builder()
+            .uri("/todos/order")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, DEFAULT_ALLOWED_ORIGINS)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(Body::empty())
+            .unwrap();
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            DEFAULT_ALLOWED_ORIGINS,
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        let methods = res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("PUT"));
+    }
+
+    #[tokio::test]
+    async fn should_find_todos_in_batch() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for i in 1..=3 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos/batch?id=3&id=99&id=1");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo list instance. body: {}", body));
+        let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![1, 3], ids);
+    }
+
+    #[tokio::test]
+    async fn should_reject_blank_search_query() {
+        let req = build_todo_req_with_empty(Method::GET, "/todos/search?q=%20%20");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_update_todo() {
+        let (labels, label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_update_todo".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new("before_update_todo".to_string(), label_ids))
+            .await
+            .expect("failed create todo");
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{
+    "text": "should_update_todo",
+    "completed": false
+}"#
+            .to_string(),
+        );
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let todo = res_to_todo(res).await;
+        let expected = TodoEntity {
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+            version: 2,
+            ..expected
+        };
+        assert_eq!(expected, todo);
+    }
+
+    #[tokio::test]
+    async fn should_replace_todo() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new(
+                "before_replace_todo".to_string(),
+                label_ids,
+            ))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        // PATCHは指定しなかったlabelsをそのまま残す
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "text": "patched" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(1, todo.labels.len());
+
+        // PUTは全ての項目を置き換える
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PUT,
+            r#"{ "text": "replaced", "completed": true, "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let todo = res_to_todo(res).await;
+        assert_eq!("replaced", todo.text);
+        assert!(todo.completed);
+        assert!(todo.labels.is_empty());
+
+        // PUTで項目を省略すると400
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PUT,
+            r#"{ "text": "missing labels", "completed": true }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_stale_update() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("todo".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        // 2つのクライアントがversion 1を読んだ後に続けて更新する
+        let update = |text: &str| {
+            build_req_with_json(
+                "/todos/1",
+                Method::PATCH,
+                format!(r#"{{"text": "{}", "expected_version": 1}}"#, text),
+            )
+        };
+        let res = app.clone().oneshot(update("first")).await.unwrap();
+        let todo = res_to_todo(res).await;
+        assert_eq!(2, todo.version);
+
+        let res = app.oneshot(update("second")).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_delete_todo() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new("should_delete_todo".to_string(), label_ids))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_manage_subtasks() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("parent".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{"text": "child", "labels": [], "parent_id": 1}"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let child = res_to_todo(res).await;
+        assert_eq!(Some(1), child.parent_id);
+
+        // 存在しない親や、自身の子孫を親にすることはできない
+        let req = build_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{"text": "orphan", "labels": [], "parent_id": 999}"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let req = build_req_with_json("/todos/1", Method::PATCH, r#"{"parent_id": 2}"#.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?parent_id=1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![2], ids);
+
+        // サブタスクが残っている間はcascadeを指定しないと削除できない
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?cascade=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_add_todo_label() {
+        let (labels, _label_ids) = label_fixture();
+        let expected = TodoEntity::new(1, "should_add_todo_label".to_string(), labels.clone());
+
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new("should_add_todo_label".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/labels/999");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let todo = res_to_todo(res).await;
+        let expected = TodoEntity {
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+            ..expected
+        };
+        assert_eq!(expected, todo);
+
+        // 存在しないラベルは付けられない
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/labels/1000");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_add_and_remove_todo_labels() {
+        let labels = vec![
+            Label::new(1, "first".to_string()),
+            Label::new(2, "second".to_string()),
+            Label::new(3, "third".to_string()),
+        ];
+        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
+        todo_repository
+            .create(CreateTodo::new(
+                "should_update_todo_labels".to_string(),
+                vec![1, 3],
+            ))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let patch =
+            |body: &str| build_req_with_json("/todos/1/labels", Method::PATCH, body.to_string());
+
+        // 付いている1の追加と、付いていない4の削除は何もしない
+        let req = patch(r#"{ "add": [1, 2], "remove": [3, 4] }"#);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let todo = res_to_todo(res).await;
+        assert_eq!(labels[..2].to_vec(), todo.labels);
+
+        // 存在しないラベルを付けようとした場合は422で、外すラベルも外さない
+        let req = patch(r#"{ "add": [999], "remove": [1] }"#);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(labels[..2].to_vec(), res_to_todo(res).await.labels);
+
+        let req = build_req_with_json(
+            "/todos/99/labels",
+            Method::PATCH,
+            r#"{ "remove": [1] }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_todo_labels() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels.clone());
+        for label_ids in [label_ids, vec![]] {
+            todo_repository
+                .create(CreateTodo::new(
+                    "should_get_todo_labels".to_string(),
+                    label_ids,
+                ))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        for (path, expected) in [("/todos/1/labels", labels), ("/todos/2/labels", vec![])] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(expected, body, "{}", path);
+        }
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/99/labels");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_reject_labels_over_limit() {
+        let labels: Vec<Label> = (1..=MAX_LABELS_PER_TODO as i32 + 1)
+            .map(|id| Label::new(id, format!("label {}", id)))
+            .collect();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        let label_ids: Vec<i32> = (1..=MAX_LABELS_PER_TODO as i32).collect();
+        todo_repository
+            .create(CreateTodo::new("full".to_string(), label_ids))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let path = format!("/todos/1/labels/{}", MAX_LABELS_PER_TODO + 1);
+        let req = build_todo_req_with_empty(Method::POST, &path);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(body["message"].as_str().unwrap().contains("at most"));
+
+        let labels: Vec<i32> = (1..=MAX_LABELS_PER_TODO as i32 + 1).collect();
+        let req = build_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            serde_json::json!({ "labels": labels }).to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_remove_todo_label() {
+        let (labels, label_ids) = label_fixture();
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        todo_repository
+            .create(CreateTodo::new(
+                "should_remove_todo_label".to_string(),
+                label_ids,
+            ))
+            .await
+            .expect("failed create todo");
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1/labels/999");
+        let res = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_soft_delete_and_restore_todo() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new(
+                "should_soft_delete_and_restore_todo".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1?include_deleted=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/restore");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?hard=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1?include_deleted=true");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_toggle_todo() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("should_toggle_todo".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/toggle");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let todo = res_to_todo(res).await;
+        assert!(todo.completed);
+        assert!(todo.completed_at.is_some());
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/toggle");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let todo = res_to_todo(res).await;
+        assert!(!todo.completed);
+        assert_eq!(None, todo.completed_at);
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/2/toggle");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_complete_all_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for i in 1..=2 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/complete-all?dry_run=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let dry_run: DryRunResult = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(2, dry_run.affected);
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/complete-all");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let result: BulkResult = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(BulkResult { affected: 2 }, result);
+        assert_eq!(dry_run.affected, result.affected);
+
+        // 2回目は完了にするTodoがない
+        let req = build_todo_req_with_empty(Method::POST, "/todos/complete-all");
+        let res = app.oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let result: BulkResult = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(BulkResult { affected: 0 }, result);
+    }
+
+    #[tokio::test]
+    async fn should_reorder_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for i in 1..=3 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_req_with_json("/todos/order", Method::PUT, "[2, 3, 1]".to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![2, 3, 1], ids);
+
+        // 重複・指定漏れ・存在しないidは422
+        for body in ["[2, 2, 3, 1]", "[2, 3]", "[2, 3, 1, 999]"] {
+            let req = build_req_with_json("/todos/order", Method::PUT, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_delete_completed_todos() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        for i in 1..=3 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("failed create todo");
+        }
+        todo_repository
+            .update(2, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("failed update todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        // dry_runでは削除せず、対象のidと件数を返す
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/completed?dry_run=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let dry_run: DryRunResult = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            DryRunResult {
+                affected: 1,
+                ids: vec![2]
+            },
+            dry_run
+        );
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/completed");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let result: BulkResult = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(BulkResult { affected: 1 }, result);
+        assert_eq!(dry_run.affected, result.affected);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_todo_history() {
+        let todo_repository = TodoRepositoryForMemory::new(vec![]);
+        todo_repository
+            .create(CreateTodo::new("before".to_string(), vec![]))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .update(1, UpdateTodo::new(Some("after".to_string()), None, None))
+            .await
+            .expect("failed update todo");
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1/history");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let audits: Vec<TodoAudit> = serde_json::from_slice(&bytes).unwrap();
+        let actions: Vec<AuditAction> = audits.iter().map(|audit| audit.action).collect();
+        assert_eq!(vec![AuditAction::Create, AuditAction::Update], actions);
+        assert_eq!("before", audits[0].snapshot["text"]);
+        assert_eq!("after", audits[1].snapshot["text"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2/history");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_by_label() {
+        let labels = vec![
+            Label::new(5, "label 5".to_string()),
+            Label::new(7, "label 7".to_string()),
+        ];
+        let todo_repository = TodoRepositoryForMemory::new(labels);
+        for label_ids in [vec![5, 7], vec![7]] {
+            todo_repository
+                .create(CreateTodo::new("todo".to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels/5/todos");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Todo list instance. body: {}", body));
+        assert_eq!(1, todos.len());
+        assert_eq!(2, todos[0].labels.len());
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels/999/todos");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_empty_todos_for_unused_label() {
+        let (labels, _label_ids) = label_fixture();
+        let req = build_todo_req_with_empty(Method::GET, "/labels/999/todos");
+        let res = create_app(
+            TodoRepositoryForMemory::new(labels),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!("[]", String::from_utf8(bytes.to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_created_label() {
+        let (labels, _label_ids) = label_fixture();
+        let expected = Label::new(1, "should_created_label".to_string());
+
+        let req = build_req_with_json(
+            "/labels",
+            Method::POST,
+            r#"{ "name": "should_created_label" }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(labels),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let label = res_to_label(res).await;
+        assert_eq!(expected, label);
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_label_name_on_create() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        for name in [String::new(), "a".repeat(51)] {
+            let req = build_req_with_json(
+                "/labels",
+                Method::POST,
+                format!(r#"{{ "name": "{}" }}"#, name),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_create_and_update_label_color() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let req = build_req_with_json(
+            "/labels",
+            Method::POST,
+            r##"{ "name": "colored", "color": "#1a2B3c" }"##.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let label = res_to_label(res).await;
+        assert_eq!(Some("#1a2B3c".to_string()), label.color);
+
+        // nameを省略しても色だけ変更できる
+        let req = build_req_with_json(
+            "/labels/1",
+            Method::PATCH,
+            r##"{ "color": "#ffffff" }"##.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let label = res_to_label(res).await;
+        assert_eq!(
+            ("colored", Some("#ffffff")),
+            (label.name.as_str(), label.color.as_deref())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_label_color() {
+        let label_repository = LabelRepositoryForMemory::new();
+        label_repository
+            .create("label".to_string(), None)
             .await
             .expect("failed create label");
-        let req = build_todo_req_with_empty(Method::DELETE, "/labels/1");
-        let res = create_app(TodoRepositoryForMemory::new(vec![label]), label_repository)
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        );
+        for color in ["red", "#12345", "#1234567", "#gggggg", "123456"] {
+            for (path, method) in [("/labels", Method::POST), ("/labels/1", Method::PATCH)] {
+                let req = build_req_with_json(
+                    path,
+                    method,
+                    format!(r#"{{ "name": "other", "color": "{}" }}"#, color),
+                );
+                let res = app.clone().oneshot(req).await.unwrap();
+                assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", color);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_find_label() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let expected = label_repository
+            .create("should_find_label".to_string(), None)
+            .await
+            .expect("failed create label");
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let label = res_to_label(res).await;
+        assert_eq!(expected, label);
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels/999");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_all_label_readed() {
+        let expected = Label::new(1, "should_all_label_readed".to_string());
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create("should_all_label_readed".to_string(), None)
+            .await
+            .expect("failed create label");
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![label]),
+            label_repository,
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let labels: Vec<Label> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Label list instance. body: {}", body));
+        assert_eq!(vec![expected], labels);
+    }
+
+    #[tokio::test]
+    async fn should_all_label_with_counts() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let todo_repository = TodoRepositoryForMemory::with_label_repository(&label_repository);
+        let mut labels = vec![];
+        for name in ["should_all_label_with_counts", "orphan"] {
+            let label = label_repository
+                .create(name.to_string(), None)
+                .await
+                .expect("failed create label");
+            labels.push(label);
+        }
+        for text in ["completed", "active"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![labels[0].id]))
+                .await
+                .expect("failed create todo");
+        }
+        todo_repository.toggle(1).await.expect("failed toggle todo");
+        // 論理削除したTodoと他のユーザーのTodoは数えない
+        let todo = todo_repository
+            .create(CreateTodo::new("deleted".to_string(), vec![labels[0].id]))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .delete(todo.id, false)
+            .await
+            .expect("failed delete todo");
+        todo_repository
+            .for_user(DEFAULT_USER_ID + 1)
+            .create(CreateTodo::new(
+                "other user".to_string(),
+                vec![labels[0].id, labels[1].id],
+            ))
+            .await
+            .expect("failed create todo");
+        let app = create_app(todo_repository, label_repository, test_jwt_secret());
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels?with_counts=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "id": 1,
+                    "name": "should_all_label_with_counts",
+                    "color": null,
+                    "todo_count": 2,
+                    "completed_count": 1,
+                },
+                {
+                    "id": 2,
+                    "name": "orphan",
+                    "color": null,
+                    "todo_count": 0,
+                    "completed_count": 0,
+                },
+            ]),
+            body
+        );
+
+        // 指定しなければ件数を含めない
+        let req = build_todo_req_with_empty(Method::GET, "/labels");
+        let res = app.oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let labels = body.as_array().unwrap();
+        assert_eq!(2, labels.len());
+        assert!(labels.iter().all(
+            |label| label.get("todo_count").is_none() && label.get("completed_count").is_none()
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_autocomplete_labels() {
+        let label_repository = LabelRepositoryForMemory::new();
+        for name in ["Work", "World", "Homework"] {
+            label_repository
+                .create(name.to_string(), None)
+                .await
+                .expect("failed create label");
+        }
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        );
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels/autocomplete?prefix=wo");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let labels: Vec<Label> = serde_json::from_slice(&bytes).unwrap();
+        let names: Vec<&str> = labels.iter().map(|label| label.name.as_str()).collect();
+        assert_eq!(vec!["Work", "World"], names);
+    }
+
+    #[tokio::test]
+    async fn should_get_labels_in_use() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let todo_repository = TodoRepositoryForMemory::with_label_repository(&label_repository);
+        let mut labels = vec![];
+        for name in ["attached", "orphan", "deleted_todo"] {
+            let label = label_repository
+                .create(name.to_string(), None)
+                .await
+                .expect("failed create label");
+            labels.push(label);
+        }
+        for label_ids in [vec![labels[0].id], vec![labels[0].id]] {
+            todo_repository
+                .create(CreateTodo::new("in use".to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+        // 論理削除したTodoにだけ付いているラベルは含めない
+        let todo = todo_repository
+            .create(CreateTodo::new("deleted".to_string(), vec![labels[2].id]))
+            .await
+            .expect("failed create todo");
+        todo_repository
+            .delete(todo.id, false)
+            .await
+            .expect("failed delete todo");
+        // 他のユーザーのTodoにだけ付いているラベルは含めず、件数にも数えない
+        todo_repository
+            .for_user(DEFAULT_USER_ID + 1)
+            .create(CreateTodo::new(
+                "other user".to_string(),
+                vec![labels[0].id, labels[1].id],
+            ))
+            .await
+            .expect("failed create todo");
+
+        let req = build_todo_req_with_empty(Method::GET, "/labels/in-use");
+        let res = create_app(todo_repository, label_repository, test_jwt_secret())
             .oneshot(req)
             .await
             .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!([{ "id": 1, "name": "attached", "color": null, "todo_count": 2 }]),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn should_create_todo_with_warnings_when_not_strict() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let long_text = "a".repeat(101);
+        let body = |text: &str| serde_json::json!({ "text": text, "labels": [] }).to_string();
+        let res_to_json = |res: Response| async move {
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        // 既定では長すぎるtextはエラー
+        let req = build_req_with_json("/todos", Method::POST, body(&long_text));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(
+            serde_json::json!({ "text": ["Over text length, max is 100"] }),
+            res_to_json(res).await["fields"]
+        );
+
+        // strict=falseなら作成し、警告を返す
+        let req = build_req_with_json("/todos?strict=false", Method::POST, body(&long_text));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let json = res_to_json(res).await;
+        assert_eq!(long_text, json["text"]);
+        assert_eq!(
+            serde_json::json!([{ "field": "text", "message": "Over text length, max is 100" }]),
+            json["warnings"]
+        );
+
+        // 警告がなければwarningsを含めない
+        let req = build_req_with_json("/todos?strict=false", Method::POST, body("short"));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert!(res_to_json(res).await.get("warnings").is_none());
+
+        // 同じtextのTodoがあれば、strictに関係なく作成した上で警告する
+        let req = build_req_with_json("/todos", Method::POST, body(" short "));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_eq!(
+            serde_json::json!([{ "field": "text", "message": "Same text already exists, id is 2" }]),
+            res_to_json(res).await["warnings"]
+        );
+
+        // 警告にできないエラーはstrict=falseでもエラー
+        let req = build_req_with_json("/todos?strict=false", Method::POST, body(""));
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_return_errors_as_json() {
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            LabelRepositoryForMemory::new(),
+            test_jwt_secret(),
+        );
+        let content_type = |res: &Response| res.headers().get(header::CONTENT_TYPE).cloned();
+        let json = Some(HeaderValue::from_static("application/json"));
+
+        // バリデーションエラー
+        let req = build_req_with_json("/todos", Method::POST, r#"{ "text": "" }"#.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(json, content_type(&res));
+
+        // axum標準の抽出エラーとステータスコードだけを返すハンドラ
+        for path in ["/todos/abc", "/labels/1"] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert!(res.status().is_client_error(), "{}", path);
+            assert_eq!(json, content_type(&res), "{}", path);
+        }
+
+        // Acceptで表現が変わるルートはエラーでもVaryを付ける
+        for path in ["/todos/1", "/todos?created_after=yesterday"] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert!(res.status().is_client_error(), "{}", path);
+            assert_eq!(json, content_type(&res), "{}", path);
+            assert_eq!(
+                "accept",
+                res.headers().get(header::VARY).unwrap(),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_assign_label_to_todos() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create("should_assign_label_to_todos".to_string(), None)
+            .await
+            .expect("failed create label");
+        let todo_repository = TodoRepositoryForMemory::with_label_repository(&label_repository);
+        for label_ids in [vec![label.id], vec![], vec![]] {
+            todo_repository
+                .create(CreateTodo::new("assign".to_string(), label_ids))
+                .await
+                .expect("failed create todo");
+        }
+        let app = create_app(todo_repository.clone(), label_repository, test_jwt_secret());
+
+        let req = build_req_with_json(
+            "/labels/1/assign",
+            Method::POST,
+            r#"{ "todo_ids": [1, 2, 3, 99] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(serde_json::json!({ "added": 2, "not_found": [99] }), body);
+        for id in 1..=3 {
+            let todo = todo_repository.find(id).await.unwrap();
+            assert_eq!(vec![label.clone()], todo.labels);
+        }
+
+        let req = build_req_with_json(
+            "/labels/99/assign",
+            Method::POST,
+            r#"{ "todo_ids": [1] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("label_not_found", body["error"]);
+
+        let req = build_req_with_json(
+            "/labels/1/assign",
+            Method::POST,
+            r#"{ "todo_ids": [] }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_update_label() {
+        let expected = Label::new(1, "should_update_label".to_string());
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create("before_update_label".to_string(), None)
+            .await
+            .expect("failed create label");
+        let req = build_req_with_json(
+            "/labels/1",
+            Method::PATCH,
+            r#"{ "name": "should_update_label" }"#.to_string(),
+        );
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![label]),
+            label_repository,
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let label = res_to_label(res).await;
+        assert_eq!(expected, label);
+    }
+
+    #[tokio::test]
+    async fn should_keep_label_without_name() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create("should_keep_label".to_string(), None)
+            .await
+            .expect("failed create label");
+        let req = build_req_with_json("/labels/1", Method::PATCH, "{}".to_string());
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(label, res_to_label(res).await);
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_label_name_on_update() {
+        let label_repository = LabelRepositoryForMemory::new();
+        label_repository
+            .create("label".to_string(), None)
+            .await
+            .expect("failed create label");
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        );
+        for name in [String::new(), "a".repeat(51)] {
+            let req = build_req_with_json(
+                "/labels/1",
+                Method::PATCH,
+                format!(r#"{{ "name": "{}" }}"#, name),
+            );
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_conflict_on_duplicate_label_name() {
+        let label_repository = LabelRepositoryForMemory::new();
+        label_repository
+            .create("label 1".to_string(), None)
+            .await
+            .expect("failed create label");
+        label_repository
+            .create("label 2".to_string(), None)
+            .await
+            .expect("failed create label");
+        let req = build_req_with_json(
+            "/labels/2",
+            Method::PATCH,
+            r#"{ "name": "label 1" }"#.to_string(),
+        );
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+
+        let req = build_req_with_json(
+            "/labels",
+            Method::POST,
+            r#"{ "name": "label 1" }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_delete_label() {
+        let label_repository = LabelRepositoryForMemory::new();
+        let label = label_repository
+            .create("should_delete_label".to_string(), None)
+            .await
+            .expect("failed create label");
+        let req = build_todo_req_with_empty(Method::DELETE, "/labels/1");
+        let res = create_app(
+            TodoRepositoryForMemory::new(vec![label]),
+            label_repository,
+            test_jwt_secret(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
         assert_eq!(StatusCode::NO_CONTENT, res.status());
     }
+
+    #[tokio::test]
+    async fn should_merge_label() {
+        let label_repository = LabelRepositoryForMemory::new();
+        for name in ["from", "into"] {
+            label_repository
+                .create(name.to_string(), None)
+                .await
+                .expect("failed create label");
+        }
+        let app = create_app(
+            TodoRepositoryForMemory::new(vec![]),
+            label_repository,
+            test_jwt_secret(),
+        );
+        let req = build_req_with_json(
+            "/labels/1/merge",
+            Method::PATCH,
+            r#"{ "into_id": 2 }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(Label::new(2, "into".to_string()), res_to_label(res).await);
+
+        // 統合したラベルは削除されている
+        let req = build_todo_req_with_empty(Method::GET, "/labels/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        // 自分自身には統合できない
+        let req = build_req_with_json(
+            "/labels/2/merge",
+            Method::PATCH,
+            r#"{ "into_id": 2 }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+
+    #[test]
+    fn repository_backend_from_env() {
+        env::remove_var("REPOSITORY");
+        assert_eq!(
+            RepositoryBackend::Postgres,
+            RepositoryBackend::from_env().unwrap()
+        );
+        env::set_var("REPOSITORY", "memory");
+        assert_eq!(
+            RepositoryBackend::Memory,
+            RepositoryBackend::from_env().unwrap()
+        );
+        env::set_var("REPOSITORY", "mysql");
+        assert!(RepositoryBackend::from_env().is_err());
+        env::remove_var("REPOSITORY");
+    }
+
+    #[tokio::test]
+    async fn should_serve_with_memory_repositories() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(
+            server.serve(
+                create_memory_app(
+                    test_jwt_secret(),
+                    DEFAULT_SIMILARITY_THRESHOLD,
+                    OrderBy::default(),
+                )
+                .into_make_service(),
+            ),
+        );
+
+        let client = hyper::Client::new();
+        let send = |method: Method, path: &str, json_body: Option<&str>| {
+            let mut req = Request::builder()
+                .uri(format!("http://{}{}", addr, path))
+                .method(method)
+                .header(header::AUTHORIZATION, bearer_token());
+            let body = match json_body {
+                Some(json_body) => {
+                    req = req.header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref());
+                    Body::from(json_body.to_string())
+                }
+                None => Body::empty(),
+            };
+            client.request(req.body(body).unwrap())
+        };
+
+        let res = send(Method::POST, "/labels", Some(r#"{ "name": "memory" }"#))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let label: Label = serde_json::from_slice(&bytes).unwrap();
+
+        // 作成したラベルをTodoに付けられる
+        let res = send(
+            Method::POST,
+            "/todos",
+            Some(&format!(
+                r#"{{ "text": "in memory", "labels": [{}] }}"#,
+                label.id
+            )),
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+
+        let res = send(Method::GET, "/todos", None).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(1, page.total);
+        assert_eq!("in memory", page.items[0].text);
+        assert_eq!(vec![label], page.items[0].labels);
+    }
 }
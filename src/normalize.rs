@@ -0,0 +1,72 @@
+use std::env;
+
+// 登録・更新時にTodoのtextへ適用する正規化の設定
+// 前後の空白は常に取り除き、連続した空白を1つにまとめるかは設定で切り替える
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextNormalization {
+    pub collapse_whitespace: bool,
+}
+
+impl TextNormalization {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let collapse_whitespace = match env::var("TODO_COLLAPSE_WHITESPACE") {
+            Ok(value) => value.parse()?,
+            Err(_) => false,
+        };
+        Ok(Self {
+            collapse_whitespace,
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        if self.collapse_whitespace {
+            text.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else {
+            text.trim().to_string()
+        }
+    }
+}
+
+// ValidatedJsonがバリデーションの前に呼ぶ、正規化した値に対して長さなどを検証する
+// 正規化する項目がない型は既定の実装のまま何もしない
+pub trait Normalize {
+    fn normalize(&mut self, _normalization: &TextNormalization) {}
+}
+
+impl<T: Normalize> Normalize for Option<T> {
+    fn normalize(&mut self, normalization: &TextNormalization) {
+        if let Some(value) = self {
+            value.normalize(normalization);
+        }
+    }
+}
+
+impl Normalize for String {
+    fn normalize(&mut self, normalization: &TextNormalization) {
+        *self = normalization.apply(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trim_text() {
+        let normalization = TextNormalization::default();
+        assert_eq!("buy  milk", normalization.apply("  buy  milk \n"));
+        assert_eq!("", normalization.apply("   "));
+    }
+
+    #[test]
+    fn collapse_whitespace() {
+        let normalization = TextNormalization {
+            collapse_whitespace: true,
+        };
+        assert_eq!("buy milk now", normalization.apply(" buy \t milk\n\nnow "));
+
+        let mut text = Some(" buy   milk ".to_string());
+        text.normalize(&normalization);
+        assert_eq!(Some("buy milk".to_string()), text);
+    }
+}
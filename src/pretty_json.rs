@@ -0,0 +1,161 @@
+use std::{
+    convert::Infallible,
+    env,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    async_trait,
+    body::{self, BoxBody, Full},
+    extract::{rejection::JsonRejection, FromRequest, RequestParts},
+    http::{header::CONTENT_TYPE, Request, Response, StatusCode},
+    response::{Headers, IntoResponse},
+    BoxError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tower::{Layer, Service};
+
+// ?pretty=true(または?pretty)の場合、レスポンスのJSONを改行とインデントを付けて返す
+// 省略した場合はJSON_PRETTYの設定に従い、未設定時はサイズの小さい1行の形式にする
+tokio::task_local! {
+    static PRETTY: bool;
+}
+
+pub fn pretty_from_env() -> anyhow::Result<bool> {
+    match env::var("JSON_PRETTY") {
+        Ok(value) => Ok(value.trim().parse()?),
+        Err(_) => Ok(false),
+    }
+}
+
+// PrettyJsonLayerの外(ボディを送りながらシリアライズするストリームなど)では常に1行にする
+pub fn to_vec<T: Serialize>(value: &T) -> serde_json::Result<Vec<u8>> {
+    if PRETTY.try_with(|pretty| *pretty).unwrap_or(false) {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    }
+}
+
+// axum::Jsonの代わりに使う、レスポンスの形式だけが異なり抽出はaxum::Jsonと同じ
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for Json<T>
+where
+    T: DeserializeOwned,
+    B: http_body::Body + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = JsonRejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req).await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response<BoxBody> {
+        match to_vec(&self.0) {
+            Ok(bytes) => (Headers([(CONTENT_TYPE, "application/json")]), bytes).into_response(),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::boxed(Full::from(e.to_string())))
+                .unwrap(),
+        }
+    }
+}
+
+// 値のない?prettyはtrueとみなす、true・false以外の値は無視して既定の形式にする
+fn pretty_param(query: &str) -> Option<bool> {
+    query
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .find(|(key, _)| *key == "pretty")
+        .and_then(|(_, value)| match value {
+            "" | "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        })
+}
+
+// ハンドラでのシリアライズを?prettyやJSON_PRETTYで指定された形式で行わせる
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrettyJsonLayer {
+    default: bool,
+}
+
+impl PrettyJsonLayer {
+    pub fn new(default: bool) -> Self {
+        Self { default }
+    }
+}
+
+impl<S> Layer<S> for PrettyJsonLayer {
+    type Service = PrettyJson<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PrettyJson {
+            inner,
+            default: self.default,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrettyJson<S> {
+    inner: S,
+    default: bool,
+}
+
+impl<S, B> Service<Request<B>> for PrettyJson<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let pretty = req
+            .uri()
+            .query()
+            .and_then(pretty_param)
+            .unwrap_or(self.default);
+        Box::pin(PRETTY.scope(pretty, self.inner.call(req)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_pretty_param() {
+        assert_eq!(Some(true), pretty_param("pretty=true"));
+        assert_eq!(Some(true), pretty_param("limit=5&pretty"));
+        assert_eq!(Some(false), pretty_param("pretty=false&limit=5"));
+        assert_eq!(None, pretty_param("pretty=yes"));
+        assert_eq!(None, pretty_param("prettier=true"));
+    }
+
+    #[tokio::test]
+    async fn serialize_compact_or_pretty() {
+        let value = json!({ "id": 1 });
+        assert_eq!(br#"{"id":1}"#.to_vec(), to_vec(&value).unwrap());
+
+        let bytes = PRETTY.scope(true, async { to_vec(&value).unwrap() }).await;
+        assert_eq!("{\n  \"id\": 1\n}", String::from_utf8(bytes).unwrap());
+    }
+}
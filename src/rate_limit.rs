@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    env,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::BoxBody,
+    extract::ConnectInfo,
+    http::{header::RETRY_AFTER, Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use serde_json::json;
+use tower::{Layer, Service};
+
+use crate::pretty_json::Json;
+
+// 未設定時は1つのクライアントにつき1分あたり100リクエストまで許可する
+const DEFAULT_MAX_REQUESTS: u32 = 100;
+const DEFAULT_PERIOD_SECS: u64 = 60;
+
+// 記録しているクライアントがこの数を超えたら、期間の過ぎたものを捨てる
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub max_requests: u32,
+    pub period: Duration,
+    // プロキシの背後に置く場合のみ有効にする、直接公開した状態で信用すると偽装できてしまう
+    pub trust_forwarded_for: bool,
+    // クライアントとの間にある信用しているプロキシの数、末尾からこの数だけ戻った値をクライアントとする
+    pub trusted_proxies: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: DEFAULT_MAX_REQUESTS,
+            period: Duration::from_secs(DEFAULT_PERIOD_SECS),
+            trust_forwarded_for: false,
+            trusted_proxies: 1,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let default = Self::default();
+        let max_requests = match env::var("RATE_LIMIT_MAX_REQUESTS") {
+            Ok(value) => value.parse()?,
+            Err(_) => default.max_requests,
+        };
+        let period = match env::var("RATE_LIMIT_PERIOD_SECS") {
+            Ok(value) => Duration::from_secs(value.parse()?),
+            Err(_) => default.period,
+        };
+        let trust_forwarded_for = match env::var("RATE_LIMIT_TRUST_FORWARDED_FOR") {
+            Ok(value) => value.parse()?,
+            Err(_) => default.trust_forwarded_for,
+        };
+        let trusted_proxies = match env::var("RATE_LIMIT_TRUSTED_PROXIES") {
+            Ok(value) => value.parse()?,
+            Err(_) => default.trusted_proxies,
+        };
+        if trusted_proxies == 0 {
+            anyhow::bail!("RATE_LIMIT_TRUSTED_PROXIES must be at least 1");
+        }
+        Ok(Self {
+            max_requests,
+            period,
+            trust_forwarded_for,
+            trusted_proxies,
+        })
+    }
+}
+
+// クライアントごとの固定ウィンドウ、期間の開始時刻とその間のリクエスト数を持つ
+#[derive(Debug)]
+struct Window {
+    started_at: Instant,
+    count: u32,
+}
+
+// 各ルートのサービスに個別に適用されるため、カウンタはArcで共有する
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    config: RateLimitConfig,
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            windows: Arc::default(),
+        }
+    }
+
+    // 上限を超えていれば、次のウィンドウが始まるまでの待ち時間を返す
+    fn acquire(&self, client: String) -> Result<(), Duration> {
+        let now = Instant::now();
+        let period = self.config.period;
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_TRACKED_CLIENTS {
+            windows.retain(|_, window| now.duration_since(window.started_at) < period);
+        }
+        let window = windows.entry(client).or_insert(Window {
+            started_at: now,
+            count: 0,
+        });
+        let elapsed = now.duration_since(window.started_at);
+        if elapsed >= period {
+            window.started_at = now;
+            window.count = 0;
+        }
+        if window.count >= self.config.max_requests {
+            return Err(period - now.duration_since(window.started_at));
+        }
+        window.count += 1;
+        Ok(())
+    }
+
+    // X-Forwarded-Forは"client, proxy1, proxy2"の形式だが、先頭はクライアントが自由に設定できる
+    // 各プロキシは接続元を末尾に追加するため、信用しているプロキシの数だけ末尾から戻った値を使う
+    // 接続元が分からない場合(テストなど)は1つのクライアントとして扱う
+    fn client_key<B>(&self, req: &Request<B>) -> String {
+        let forwarded = self
+            .config
+            .trust_forwarded_for
+            .then(|| req.headers().get("x-forwarded-for"))
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                value
+                    .rsplit(',')
+                    .nth(self.config.trusted_proxies.saturating_sub(1))
+            })
+            .and_then(|value| value.trim().parse::<IpAddr>().ok());
+        let ip = forwarded.or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        });
+        ip.map_or_else(|| String::from("unknown"), |ip| ip.to_string())
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: RateLimitLayer,
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let client = self.limiter.client_key(&req);
+        match self.limiter.acquire(client) {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(retry_after) => Box::pin(async move { Ok(too_many_requests(retry_after)) }),
+        }
+    }
+}
+
+// Retry-Afterは秒単位のため切り上げる
+fn too_many_requests(retry_after: Duration) -> Response<BoxBody> {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let body = json!({
+        "error": "too_many_requests",
+        "message": format!("retry after {} seconds", secs),
+    });
+    let mut res = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+    res.headers_mut().insert(RETRY_AFTER, secs.into());
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(forwarded_for: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+        if let Some(forwarded_for) = forwarded_for {
+            builder = builder.header("x-forwarded-for", forwarded_for);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn limit_per_client() {
+        let limiter = RateLimitLayer::new(RateLimitConfig {
+            max_requests: 2,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!(Ok(()), limiter.acquire("a".to_string()));
+        assert_eq!(Ok(()), limiter.acquire("a".to_string()));
+        let retry_after = limiter.acquire("a".to_string()).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(DEFAULT_PERIOD_SECS));
+        // 別のクライアントは影響を受けない
+        assert_eq!(Ok(()), limiter.acquire("b".to_string()));
+    }
+
+    #[test]
+    fn reset_after_period() {
+        let limiter = RateLimitLayer::new(RateLimitConfig {
+            max_requests: 1,
+            period: Duration::from_millis(10),
+            ..RateLimitConfig::default()
+        });
+        assert_eq!(Ok(()), limiter.acquire("a".to_string()));
+        assert!(limiter.acquire("a".to_string()).is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(Ok(()), limiter.acquire("a".to_string()));
+    }
+
+    #[test]
+    fn client_key_from_forwarded_for() {
+        let limiter = RateLimitLayer::new(RateLimitConfig::default());
+        let req = request(Some("203.0.113.1, 10.0.0.1"));
+        assert_eq!("unknown", limiter.client_key(&req));
+
+        let limiter = RateLimitLayer::new(RateLimitConfig {
+            trust_forwarded_for: true,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!("10.0.0.1", limiter.client_key(&req));
+        assert_eq!("unknown", limiter.client_key(&request(Some("not an ip"))));
+        assert_eq!("unknown", limiter.client_key(&request(None)));
+    }
+
+    #[test]
+    fn client_key_with_trusted_proxies() {
+        let req = request(Some("198.51.100.7, 203.0.113.1, 10.0.0.1"));
+        let limiter = RateLimitLayer::new(RateLimitConfig {
+            trust_forwarded_for: true,
+            trusted_proxies: 1,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!("10.0.0.1", limiter.client_key(&req));
+
+        let limiter = RateLimitLayer::new(RateLimitConfig {
+            trust_forwarded_for: true,
+            trusted_proxies: 2,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!("203.0.113.1", limiter.client_key(&req));
+        // 先頭の偽装された値は使わない
+        assert_eq!(
+            limiter.client_key(&request(Some("192.0.2.1, 203.0.113.1, 10.0.0.1"))),
+            limiter.client_key(&req)
+        );
+        // プロキシの数より値が少なければ接続元を使う
+        assert_eq!("unknown", limiter.client_key(&request(Some("10.0.0.1"))));
+    }
+}
@@ -1,14 +1,94 @@
 pub mod label;
+pub mod pool;
+pub mod retry;
 pub mod todo;
 
+use std::future::Future;
+
+use anyhow::Context;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
-enum RepositoryError {
+pub enum RepositoryError {
     #[error("Unexpected Error: [{0}]")]
     Unexpected(String),
     #[error("NotFound, id is {0}")]
     NotFound(i32),
     #[error("Duplicate data, id is {0}")]
     Duplicate(i32),
+    #[error("Label NotFound, id is {0}")]
+    LabelNotFound(i32),
+    #[error("Conflict, id is {0}")]
+    Conflict(i32),
+    // 存在しないラベルの指定など、リクエストの形式は正しいが業務上受け付けられない場合
+    #[error("Validation error: {0}")]
+    Validation(String),
+}
+
+// anyhow::Errorに包まれたRepositoryErrorを取り出す、それ以外(sqlx::Errorなど)は想定外のエラーとする
+impl From<anyhow::Error> for RepositoryError {
+    fn from(error: anyhow::Error) -> Self {
+        error
+            .downcast::<RepositoryError>()
+            // {:#}で"failed to update todo id=5: error returned from database: ..."のように原因まで含める
+            .unwrap_or_else(|error| RepositoryError::Unexpected(format!("{:#}", error)))
+    }
+}
+
+// 失敗した操作を"failed to update todo id=5"の形式でエラーの文脈に加える
+// 元のエラー(RepositoryErrorなど)はこれまでどおりdowncastで取り出せる
+pub async fn with_operation<T>(
+    operation: impl FnOnce() -> String,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    future
+        .await
+        .with_context(|| format!("failed to {}", operation()))
+}
+
+// ilikeのワイルドカード(%と_)とエスケープ文字(\)をエスケープして文字どおりに検索させる
+pub fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escape_like_wildcards() {
+        assert_eq!("100\\% \\_a\\\\b", escape_like("100% _a\\b"));
+    }
+
+    #[tokio::test]
+    async fn with_operation_adds_context() {
+        let error = with_operation(|| format!("update todo id={}", 5), async {
+            Err::<(), _>(anyhow::anyhow!("connection lost"))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            "failed to update todo id=5: connection lost",
+            format!("{:#}", error)
+        );
+        assert!(matches!(
+            RepositoryError::from(error),
+            RepositoryError::Unexpected(message)
+                if message == "failed to update todo id=5: connection lost"
+        ));
+
+        // 文脈を加えてもRepositoryErrorとして判別できる
+        let error = with_operation(|| "find todo id=1".to_string(), async {
+            Err::<(), _>(RepositoryError::NotFound(1).into())
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(error),
+            RepositoryError::NotFound(1)
+        ));
+    }
 }
@@ -1,99 +1,425 @@
-use super::RepositoryError;
+use super::{escape_like, retry::RetryPolicy, with_operation, RepositoryError};
 use axum::async_trait;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use utoipa::ToSchema;
 
 #[async_trait]
 pub trait LabelRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, name: String) -> anyhow::Result<Label>;
+    async fn create(&self, name: String, color: Option<String>) -> anyhow::Result<Label>;
+    async fn find(&self, id: i32) -> anyhow::Result<Label>;
     async fn all(&self) -> anyhow::Result<Vec<Label>>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+    // user_idのユーザーが所有する論理削除されていないTodoの件数をラベルごとに数える
+    async fn all_with_counts(&self, user_id: i32) -> anyhow::Result<Vec<LabelWithCounts>>;
+    // user_idのユーザーが所有する論理削除されていないTodoに1件以上付いているラベルと、付いているTodoの件数
+    async fn in_use(&self, user_id: i32) -> anyhow::Result<Vec<(Label, i64)>>;
+    // 名前がprefixで始まるラベルを大文字・小文字を区別せずに名前順で最大AUTOCOMPLETE_LIMIT件返す
+    async fn autocomplete(&self, prefix: String) -> anyhow::Result<Vec<Label>>;
+    // Noneの項目は変更しない
+    async fn update(
+        &self,
+        id: i32,
+        name: Option<String>,
+        color: Option<String>,
+    ) -> anyhow::Result<Label>;
+    async fn delete(&self, id: i32, force: bool) -> anyhow::Result<()>;
+    // from_idのラベルが付いたTodoをinto_idのラベルに付け替えてからfrom_idを削除し、残ったラベルを返す
+    async fn merge(&self, from_id: i32, into_id: i32) -> anyhow::Result<Label>;
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow)]
+// 入力補完で返すラベルの数
+pub const AUTOCOMPLETE_LIMIT: usize = 10;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow, ToSchema)]
 pub struct Label {
+    #[serde(serialize_with = "crate::id_format::serialize_id")]
     pub id: i32,
     pub name: String,
+    pub color: Option<String>, // #RRGGBB形式
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct UpdateLabel {
-    id: i32,
-    name: String,
+// GET /labels?with_counts=trueのレスポンス
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow, ToSchema)]
+pub struct LabelWithCounts {
+    #[serde(serialize_with = "crate::id_format::serialize_id")]
+    pub id: i32,
+    pub name: String,
+    pub color: Option<String>,
+    pub todo_count: i64,
+    pub completed_count: i64,
 }
 
 #[derive(Debug, Clone)]
 pub struct LabelRepositoryForDb {
     pool: PgPool,
+    retry: RetryPolicy, // 読み取りクエリの再試行設定
 }
 
 impl LabelRepositoryForDb {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self::with_retry_policy(pool, RetryPolicy::default())
     }
-}
 
-#[async_trait]
-impl LabelRepository for LabelRepositoryForDb {
-    async fn create(&self, name: String) -> anyhow::Result<Label> {
-        let optional_label = sqlx::query_as::<_, Label>(
+    pub fn with_retry_policy(pool: PgPool, retry: RetryPolicy) -> Self {
+        Self { pool, retry }
+    }
+
+    // 大文字・小文字を区別せずに同じ名前のラベルを探す、except_idのラベルは除く
+    async fn find_duplicate(
+        &self,
+        name: &str,
+        except_id: Option<i32>,
+    ) -> anyhow::Result<Option<i32>> {
+        let id = sqlx::query_scalar::<_, i32>(
             r#"
-select * from labels where name = $1
+select id from labels where lower(name) = lower($1) and ($2::int4 is null or id <> $2)
         "#,
         )
-        .bind(name.clone())
+        .bind(name)
+        .bind(except_id)
         .fetch_optional(&self.pool)
         .await?;
+        Ok(id)
+    }
+}
 
-        if let Some(label) = optional_label {
-            return Err(RepositoryError::Duplicate(label.id).into());
-        }
+// 確認してから書き込むまでの間に同じ名前のラベルが作られると、一意インデックスの違反になる
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| code == "23505")
+}
 
-        let label = sqlx::query_as::<_, Label>(
-            r#"
-insert into labels ( name )
-values ( $1 )
+#[async_trait]
+impl LabelRepository for LabelRepositoryForDb {
+    async fn create(&self, name: String, color: Option<String>) -> anyhow::Result<Label> {
+        with_operation(|| format!("create label name={:?}", name), async {
+            // "Work"と"work"は同じラベルとみなす、一意インデックスもlower(name)に張っている
+            if let Some(id) = self.find_duplicate(&name, None).await? {
+                return Err(RepositoryError::Duplicate(id).into());
+            }
+
+            let res = sqlx::query_as::<_, Label>(
+                r#"
+insert into labels ( name, color )
+values ( $1, $2 )
 returning *
         "#,
-        )
-        .bind(name.clone())
-        .fetch_one(&self.pool)
-        .await?;
+            )
+            .bind(name.clone())
+            .bind(color)
+            .fetch_one(&self.pool)
+            .await;
 
-        Ok(label)
+            match res {
+                Err(e) if is_unique_violation(&e) => {
+                    let id = self.find_duplicate(&name, None).await?.ok_or(e)?;
+                    Err(RepositoryError::Duplicate(id).into())
+                }
+                res => Ok(res?),
+            }
+        })
+        .await
+    }
+
+    async fn find(&self, id: i32) -> anyhow::Result<Label> {
+        with_operation(|| format!("find label id={}", id), async {
+            let label = self
+                .retry
+                .run(|| {
+                    sqlx::query_as::<_, Label>(
+                        r#"
+select * from labels where id = $1
+        "#,
+                    )
+                    .bind(id)
+                    .fetch_one(&self.pool)
+                })
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                    _ => RepositoryError::Unexpected(e.to_string()),
+                })?;
+
+            Ok(label)
+        })
+        .await
     }
 
     async fn all(&self) -> anyhow::Result<Vec<Label>> {
-        let labels = sqlx::query_as::<_, Label>(
-            r#"
+        with_operation(|| "list labels".to_string(), async {
+            let labels = self
+                .retry
+                .run(|| {
+                    sqlx::query_as::<_, Label>(
+                        r#"
 select * from labels
 order by labels.id asc;
         "#,
+                    )
+                    .fetch_all(&self.pool)
+                })
+                .await?;
+
+            Ok(labels)
+        })
+        .await
+    }
+
+    async fn all_with_counts(&self, user_id: i32) -> anyhow::Result<Vec<LabelWithCounts>> {
+        with_operation(
+            || format!("list labels with counts user_id={}", user_id),
+            async {
+                // Todoが付いていないラベルも0件として返すため、labelsを起点に外部結合する
+                let labels = self
+                    .retry
+                    .run(|| {
+                        sqlx::query_as::<_, LabelWithCounts>(
+                            r#"
+select labels.id, labels.name, labels.color,
+    count(todos.id) as todo_count,
+    count(todos.id) filter (where todos.completed) as completed_count
+from labels
+    left outer join todo_labels on todo_labels.label_id = labels.id
+    left outer join todos on todos.id = todo_labels.todo_id
+        and todos.deleted_at is null
+        and todos.user_id = $1
+group by labels.id, labels.name, labels.color
+order by labels.id asc;
+        "#,
+                        )
+                        .bind(user_id)
+                        .fetch_all(&self.pool)
+                    })
+                    .await?;
+
+                Ok(labels)
+            },
         )
-        .fetch_all(&self.pool)
-        .await?;
+        .await
+    }
+
+    async fn in_use(&self, user_id: i32) -> anyhow::Result<Vec<(Label, i64)>> {
+        with_operation(
+            || format!("list labels in use user_id={}", user_id),
+            async {
+                // 内部結合にして、どのTodoにも付いていないラベルと他のユーザーのTodoにだけ付いているラベルを除く
+                let rows = self
+                    .retry
+                    .run(|| {
+                        sqlx::query_as::<_, (i32, String, Option<String>, i64)>(
+                            r#"
+select labels.id, labels.name, labels.color, count(todos.id) as todo_count
+from labels
+    inner join todo_labels on todo_labels.label_id = labels.id
+    inner join todos on todos.id = todo_labels.todo_id
+        and todos.deleted_at is null
+        and todos.user_id = $1
+group by labels.id, labels.name, labels.color
+order by labels.id asc;
+        "#,
+                        )
+                        .bind(user_id)
+                        .fetch_all(&self.pool)
+                    })
+                    .await?;
 
-        Ok(labels)
+                Ok(rows
+                    .into_iter()
+                    .map(|(id, name, color, todo_count)| (Label { id, name, color }, todo_count))
+                    .collect())
+            },
+        )
+        .await
     }
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        sqlx::query(
-            r#"
-delete from labels where id=$1
+    async fn autocomplete(&self, prefix: String) -> anyhow::Result<Vec<Label>> {
+        with_operation(
+            || format!("autocomplete labels prefix={:?}", prefix),
+            async {
+                let pattern = escape_like(&prefix);
+                let labels = self
+                    .retry
+                    .run(|| {
+                        sqlx::query_as::<_, Label>(
+                            r#"
+select * from labels
+where name ilike $1 || '%'
+order by lower(name) asc, id asc
+limit $2;
         "#,
+                        )
+                        .bind(&pattern)
+                        .bind(AUTOCOMPLETE_LIMIT as i64)
+                        .fetch_all(&self.pool)
+                    })
+                    .await?;
+
+                Ok(labels)
+            },
         )
-        .bind(id)
-        .execute(&self.pool)
         .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    }
+
+    async fn update(
+        &self,
+        id: i32,
+        name: Option<String>,
+        color: Option<String>,
+    ) -> anyhow::Result<Label> {
+        with_operation(|| format!("update label id={}", id), async {
+            // 何も指定されなければ何も変更せず、現在のラベルを返す
+            if name.is_none() && color.is_none() {
+                return self.find(id).await;
+            }
+
+            if let Some(name) = &name {
+                if let Some(duplicate_id) = self.find_duplicate(name, Some(id)).await? {
+                    return Err(RepositoryError::Duplicate(duplicate_id).into());
+                }
+            }
+
+            let res = sqlx::query_as::<_, Label>(
+                r#"
+update labels set name = coalesce($1, name), color = coalesce($3, color)
+where id = $2
+returning *
+        "#,
+            )
+            .bind(&name)
+            .bind(id)
+            .bind(color)
+            .fetch_optional(&self.pool)
+            .await;
+
+            match (res, &name) {
+                (Err(e), Some(name)) if is_unique_violation(&e) => {
+                    let duplicate_id = self.find_duplicate(name, Some(id)).await?.ok_or(e)?;
+                    Err(RepositoryError::Duplicate(duplicate_id).into())
+                }
+                (res, _) => Ok(res?.ok_or(RepositoryError::NotFound(id))?),
+            }
+        })
+        .await
+    }
+
+    // Todoに紐付いているラベルはforceが指定されない限り削除しない
+    async fn delete(&self, id: i32, force: bool) -> anyhow::Result<()> {
+        with_operation(|| format!("delete label id={}", id), async {
+            let mut tx = self.pool.begin().await?;
+
+            let (referenced,) = sqlx::query_as::<_, (i64,)>(
+                r#"
+select count(*) from todo_labels where label_id=$1
+        "#,
+            )
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+            if referenced > 0 {
+                if !force {
+                    return Err(RepositoryError::Conflict(id).into());
+                }
+                sqlx::query(
+                    r#"
+delete from todo_labels where label_id=$1
+            "#,
+                )
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            }
+
+            let result = sqlx::query(
+                r#"
+delete from labels where id=$1
+        "#,
+            )
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+
+            tx.commit().await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn merge(&self, from_id: i32, into_id: i32) -> anyhow::Result<Label> {
+        with_operation(
+            || format!("merge label id={} into_id={}", from_id, into_id),
+            async {
+                if from_id == into_id {
+                    return Err(RepositoryError::Validation(format!(
+                        "label {} can not be merged into itself",
+                        from_id
+                    ))
+                    .into());
+                }
+
+                let mut tx = self.pool.begin().await?;
+
+                // 付け替えの途中で削除・変更されないよう両方のラベルをロックする
+                lock_label(&mut tx, from_id).await?;
+                let into = lock_label(&mut tx, into_id).await?;
+
+                // 両方のラベルが付いているTodoは付け替えると重複するため、付け替えずに下の削除で外す
+                sqlx::query(
+                    r#"
+update todo_labels set label_id = $2
+where label_id = $1
+    and todo_id not in (select todo_id from todo_labels where label_id = $2);
+        "#,
+                )
+                .bind(from_id)
+                .bind(into_id)
+                .execute(&mut tx)
+                .await?;
+                sqlx::query(
+                    r#"
+delete from todo_labels where label_id = $1
+        "#,
+                )
+                .bind(from_id)
+                .execute(&mut tx)
+                .await?;
+
+                sqlx::query(
+                    r#"
+delete from labels where id = $1
+        "#,
+                )
+                .bind(from_id)
+                .execute(&mut tx)
+                .await?;
 
-        Ok(())
+                tx.commit().await?;
+
+                Ok(into)
+            },
+        )
+        .await
     }
 }
 
+async fn lock_label(tx: &mut Transaction<'_, Postgres>, id: i32) -> anyhow::Result<Label> {
+    let label = sqlx::query_as::<_, Label>(
+        r#"
+select * from labels where id = $1 for update
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(tx)
+    .await?
+    .ok_or(RepositoryError::NotFound(id))?;
+
+    Ok(label)
+}
+
 #[cfg(test)]
 #[cfg(feature = "database-test")]
 mod test {
@@ -108,96 +434,761 @@ mod test {
         let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
         let pool = PgPool::connect(database_url)
             .await
-            .expect(&format!("fail connect database, url is [{}]", database_url));
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
 
         let repository = LabelRepositoryForDb::new(pool);
         let label_text = "test_label";
 
         // create
         let label = repository
-            .create(label_text.to_string())
+            .create(label_text.to_string(), None)
             .await
             .expect("[create] returned Err");
         assert_eq!(label.name, label_text);
 
+        // find
+        let found = repository
+            .find(label.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(label, found);
+        let res = repository.find(i32::MAX).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(i32::MAX))
+        ));
+
+        // update
+        let renamed_text = "test_label_renamed";
+        let label = repository
+            .update(label.id, Some(renamed_text.to_string()), None)
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(label.name, renamed_text);
+
+        // nameを指定しなければ変更しない
+        let unchanged = repository
+            .update(label.id, None, None)
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(label, unchanged);
+
+        // 色だけを変更する
+        let label = repository
+            .update(label.id, None, Some("#00ff00".to_string()))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(
+            (renamed_text, Some("#00ff00")),
+            (label.name.as_str(), label.color.as_deref())
+        );
+        assert_eq!(label, repository.find(label.id).await.unwrap());
+
         // delete
         repository
-            .delete(label.id)
+            .delete(label.id, false)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn case_insensitive_name_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = LabelRepositoryForDb::new(pool);
+        let label = repository
+            .create("[case_insensitive_name_scenario] Work".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        let other = repository
+            .create("[case_insensitive_name_scenario] other".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+
+        let res = repository
+            .create("[case_insensitive_name_scenario] work".to_string(), None)
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Duplicate(id)) if *id == label.id
+        ));
+        let res = repository
+            .update(
+                other.id,
+                Some("[CASE_INSENSITIVE_NAME_SCENARIO] WORK".to_string()),
+                None,
+            )
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Duplicate(id)) if *id == label.id
+        ));
+
+        for id in [label.id, other.id] {
+            repository
+                .delete(id, false)
+                .await
+                .expect("[delete] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_concurrently_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        // 同時に同じ名前で作成しても、1つだけが作成され残りはDuplicateになる
+        let repository = LabelRepositoryForDb::new(pool);
+        let results =
+            futures_util::future::join_all((0..5).map(|_| {
+                repository.create("[create_concurrently_scenario] label".to_string(), None)
+            }))
+            .await;
+        let (created, duplicated): (Vec<_>, Vec<_>) =
+            results.into_iter().partition(|res| res.is_ok());
+        assert_eq!(1, created.len());
+        let label = created.into_iter().next().unwrap().unwrap();
+        for res in duplicated {
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Duplicate(id)) if *id == label.id
+            ));
+        }
+
+        repository
+            .delete(label.id, false)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn delete_referenced_label_scenario() {
+        use crate::repositories::todo::{CreateTodo, TodoRepository, TodoRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = LabelRepositoryForDb::new(pool.clone());
+        let todo_repository = TodoRepositoryForDb::new(pool);
+        let label = repository
+            .create("[delete_referenced_label_scenario] label".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        let todo = todo_repository
+            .create(CreateTodo::new(
+                "[delete_referenced_label_scenario] text".to_string(),
+                vec![label.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // 紐付いているラベルは削除できない
+        let res = repository.delete(label.id, false).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Conflict(id)) if *id == label.id
+        ));
+        assert_eq!(label, repository.find(label.id).await.unwrap());
+
+        // forceを指定すると紐付けごと削除する
+        repository
+            .delete(label.id, true)
+            .await
+            .expect("[delete] returned Err");
+        let todo = todo_repository.find(todo.id).await.unwrap();
+        assert!(todo.labels.is_empty());
+        let res = repository.delete(label.id, true).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(_))
+        ));
+
+        todo_repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn all_with_counts_scenario() {
+        use crate::repositories::todo::{CreateTodo, TodoRepository, TodoRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = LabelRepositoryForDb::new(pool.clone());
+        // 他のテストと重ならないユーザーを使う
+        let todo_repository = TodoRepositoryForDb::new(pool).for_user(1005);
+        let label = repository
+            .create("[all_with_counts_scenario] label".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let todo = todo_repository
+                .create(CreateTodo::new(
+                    "[all_with_counts_scenario] text".to_string(),
+                    vec![label.id],
+                ))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+        todo_repository
+            .toggle(ids[0])
+            .await
+            .expect("[toggle] returned Err");
+
+        let count_of = |labels: Vec<LabelWithCounts>| {
+            labels
+                .into_iter()
+                .find(|counted| counted.id == label.id)
+                .expect("label is missing")
+        };
+        let counted = count_of(
+            repository
+                .all_with_counts(1005)
+                .await
+                .expect("[all_with_counts] returned Err"),
+        );
+        assert_eq!(
+            LabelWithCounts {
+                id: label.id,
+                name: label.name.clone(),
+                color: None,
+                todo_count: 3,
+                completed_count: 1,
+            },
+            counted
+        );
+        // 他のユーザーのTodoは数えない
+        let counted = count_of(
+            repository
+                .all_with_counts(1006)
+                .await
+                .expect("[all_with_counts] returned Err"),
+        );
+        assert_eq!((0, 0), (counted.todo_count, counted.completed_count));
+
+        for id in ids {
+            todo_repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+        repository
+            .delete(label.id, false)
+            .await
+            .expect("[delete] returned Err");
+    }
+
+    #[tokio::test]
+    async fn in_use_scenario() {
+        use crate::repositories::todo::{CreateTodo, TodoRepository, TodoRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = LabelRepositoryForDb::new(pool.clone());
+        // 他のテストと重ならないユーザーを使う
+        let todo_repository = TodoRepositoryForDb::new(pool).for_user(1009);
+        let attached = repository
+            .create("[in_use_scenario] attached".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        let orphan = repository
+            .create("[in_use_scenario] orphan".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let todo = todo_repository
+                .create(CreateTodo::new(
+                    "[in_use_scenario] text".to_string(),
+                    vec![attached.id],
+                ))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+        // 論理削除したTodoは数えない
+        todo_repository
+            .delete(ids[0], false)
+            .await
+            .expect("[delete] returned Err");
+
+        // 他のユーザーのTodoは数えない
+        let other = todo_repository
+            .for_user(1020)
+            .create(CreateTodo::new(
+                "[in_use_scenario] text".to_string(),
+                vec![attached.id, orphan.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        let labels = repository
+            .in_use(1009)
+            .await
+            .expect("[in_use] returned Err");
+        assert!(labels.contains(&(attached.clone(), 2)));
+        assert!(labels.iter().all(|(label, _)| label.id != orphan.id));
+
+        todo_repository
+            .for_user(1020)
+            .purge(other.id, false)
+            .await
+            .expect("[purge] returned Err");
+
+        for id in ids {
+            todo_repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+        for label in [attached, orphan] {
+            repository
+                .delete(label.id, false)
+                .await
+                .expect("[delete] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn autocomplete_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = LabelRepositoryForDb::new(pool);
+        let mut labels = vec![];
+        for name in ["World", "Homework", "work", "100%"] {
+            let label = repository
+                .create(format!("[autocomplete_scenario] {}", name), None)
+                .await
+                .expect("[create] returned Err");
+            labels.push(label);
+        }
+        let names = |labels: Vec<Label>| -> Vec<String> {
+            labels.into_iter().map(|label| label.name).collect()
+        };
+
+        let found = repository
+            .autocomplete("[AUTOCOMPLETE_scenario] wo".to_string())
+            .await
+            .expect("[autocomplete] returned Err");
+        assert_eq!(
+            vec![
+                "[autocomplete_scenario] work",
+                "[autocomplete_scenario] World"
+            ],
+            names(found)
+        );
+        // %はワイルドカードとして扱わない
+        let found = repository
+            .autocomplete("[autocomplete_scenario] %".to_string())
+            .await
+            .expect("[autocomplete] returned Err");
+        assert!(found.is_empty());
+        let found = repository
+            .autocomplete(String::new())
+            .await
+            .expect("[autocomplete] returned Err");
+        assert!(found.len() <= AUTOCOMPLETE_LIMIT);
+
+        for label in labels {
+            repository
+                .delete(label.id, false)
+                .await
+                .expect("[delete] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_label_scenario() {
+        use crate::repositories::todo::{CreateTodo, TodoRepository, TodoRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = LabelRepositoryForDb::new(pool.clone());
+        let todo_repository = TodoRepositoryForDb::new(pool);
+        let from = repository
+            .create("[merge_label_scenario] from".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        let into = repository
+            .create("[merge_label_scenario] into".to_string(), None)
+            .await
+            .expect("[create] returned Err");
+        // 両方のラベルが付いたTodoと、統合元のラベルだけが付いたTodo
+        let shared = todo_repository
+            .create(CreateTodo::new(
+                "[merge_label_scenario] shared".to_string(),
+                vec![from.id, into.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        let only_from = todo_repository
+            .create(CreateTodo::new(
+                "[merge_label_scenario] only from".to_string(),
+                vec![from.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        let merged = repository
+            .merge(from.id, into.id)
+            .await
+            .expect("[merge] returned Err");
+        assert_eq!(into, merged);
+
+        // 紐付けが重複せず、統合先のラベルだけが付いている
+        for id in [shared.id, only_from.id] {
+            let todo = todo_repository.find(id).await.unwrap();
+            assert_eq!(vec![into.clone()], todo.labels);
+        }
+        let res = repository.find(from.id).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(id)) if *id == from.id
+        ));
+
+        // 存在しないラベルや自分自身には統合できない
+        let res = repository.merge(from.id, into.id).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(id)) if *id == from.id
+        ));
+        let res = repository.merge(into.id, into.id).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+
+        for id in [shared.id, only_from.id] {
+            todo_repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+        repository
+            .delete(into.id, true)
             .await
             .expect("[delete] returned Err");
     }
 }
 
-#[cfg(test)]
-pub mod test_utils {
+// メモリ上に保存するリポジトリ、テストの他にREPOSITORY=memoryで起動した場合にも使う
+pub mod memory {
     use crate::repositories::label::{LabelRepository, RepositoryError};
+    use crate::repositories::todo::memory::{TodoDatas, TodoTombstones};
     use axum::async_trait;
-    use std::collections::HashMap;
-    use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    };
 
-    use super::Label;
+    use super::{Label, LabelWithCounts, AUTOCOMPLETE_LIMIT};
 
     impl Label {
         pub fn new(id: i32, name: String) -> Self {
-            Label { id, name }
+            Label {
+                id,
+                name,
+                color: None,
+            }
         }
     }
 
-    type LabelData = HashMap<i32, Label>;
+    pub type LabelData = HashMap<i32, Label>;
 
     #[derive(Debug, Clone)]
     pub struct LabelRepositoryForMemory {
         store: Arc<RwLock<LabelData>>,
+        last_id: Arc<AtomicI32>, // 削除後もidを再利用しないよう採番済みの最大値を持つ
+        // 使用中のラベルを数えるため、with_label_repositoryで作成したTodoRepositoryForMemoryと共有する
+        todos: Arc<RwLock<TodoDatas>>,
+        tombstones: Arc<RwLock<TodoTombstones>>,
+        // Todoを書き換える場合は、TodoRepositoryForMemoryのwith_transactionが終わるまで待つ
+        todo_transaction: Arc<tokio::sync::Mutex<()>>,
     }
 
     impl LabelRepositoryForMemory {
         pub fn new() -> Self {
             LabelRepositoryForMemory {
                 store: Arc::default(),
+                last_id: Arc::default(),
+                todos: Arc::default(),
+                tombstones: Arc::default(),
+                todo_transaction: Arc::default(),
             }
         }
 
-        fn write_store_ref(&self) -> RwLockWriteGuard<LabelData> {
+        // TodoRepositoryForMemoryから同じラベルを参照するために使う
+        pub fn shared_store(&self) -> Arc<RwLock<LabelData>> {
+            self.store.clone()
+        }
+
+        // TodoRepositoryForMemoryが保存先として使う
+        pub fn shared_todo_store(&self) -> (Arc<RwLock<TodoDatas>>, Arc<RwLock<TodoTombstones>>) {
+            (self.todos.clone(), self.tombstones.clone())
+        }
+
+        // TodoRepositoryForMemoryがwith_transactionの実行中に書き込みを待たせるために使う
+        pub fn shared_todo_transaction(&self) -> Arc<tokio::sync::Mutex<()>> {
+            self.todo_transaction.clone()
+        }
+
+        fn next_id(&self) -> i32 {
+            self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+        }
+
+        fn write_store_ref(&self) -> RwLockWriteGuard<'_, LabelData> {
             self.store.write().unwrap()
         }
 
-        fn read_store_ref(&self) -> RwLockReadGuard<LabelData> {
+        fn read_store_ref(&self) -> RwLockReadGuard<'_, LabelData> {
             self.store.read().unwrap()
         }
     }
 
     #[async_trait]
     impl LabelRepository for LabelRepositoryForMemory {
-        async fn create(&self, name: String) -> anyhow::Result<Label> {
+        async fn create(&self, name: String, color: Option<String>) -> anyhow::Result<Label> {
             let mut store = self.write_store_ref();
-            if let Some((_key, label)) = store.iter().find(|(_key, label)| label.name == name) {
-                return Ok(label.clone());
+            // DBの実装に合わせて、大文字・小文字だけが異なるラベルも重複として弾く
+            if let Some((_key, label)) = store
+                .iter()
+                .find(|(_key, label)| label.name.to_lowercase() == name.to_lowercase())
+            {
+                return Err(RepositoryError::Duplicate(label.id).into());
             };
 
-            let id = (store.len() + 1) as i32;
-            let label = Label::new(id, name.clone());
+            let id = self.next_id();
+            let label = Label {
+                color,
+                ..Label::new(id, name.clone())
+            };
             store.insert(id, label.clone());
             Ok(label)
         }
 
+        async fn find(&self, id: i32) -> anyhow::Result<Label> {
+            let store = self.read_store_ref();
+            let label = store
+                .get(&id)
+                .cloned()
+                .ok_or(RepositoryError::NotFound(id))?;
+            Ok(label)
+        }
+
         async fn all(&self) -> anyhow::Result<Vec<Label>> {
             let store = self.read_store_ref();
-            let labels = Vec::from_iter(store.values().map(|label| label.clone()));
+            let labels = Vec::from_iter(store.values().cloned());
+            Ok(labels)
+        }
+
+        // in_useと同じく、user_idのユーザーが所有する論理削除されていないTodoだけを数える
+        async fn all_with_counts(&self, user_id: i32) -> anyhow::Result<Vec<LabelWithCounts>> {
+            let tombstones = self.tombstones.read().unwrap();
+            let mut counts: HashMap<i32, (i64, i64)> = HashMap::new();
+            for todo in self.todos.read().unwrap().values() {
+                if todo.user_id != user_id || tombstones.contains(&todo.id) {
+                    continue;
+                }
+                for label in todo.labels.iter() {
+                    let (todo_count, completed_count) = counts.entry(label.id).or_default();
+                    *todo_count += 1;
+                    if todo.completed {
+                        *completed_count += 1;
+                    }
+                }
+            }
+            // Todoが付いていないラベルも0件として返す
+            let mut labels: Vec<LabelWithCounts> = self
+                .read_store_ref()
+                .values()
+                .map(|label| {
+                    let (todo_count, completed_count) =
+                        counts.get(&label.id).copied().unwrap_or_default();
+                    LabelWithCounts {
+                        id: label.id,
+                        name: label.name.clone(),
+                        color: label.color.clone(),
+                        todo_count,
+                        completed_count,
+                    }
+                })
+                .collect();
+            labels.sort_by_key(|label| label.id);
+            Ok(labels)
+        }
+
+        async fn autocomplete(&self, prefix: String) -> anyhow::Result<Vec<Label>> {
+            let prefix = prefix.to_lowercase();
+            let mut labels: Vec<Label> = self
+                .read_store_ref()
+                .values()
+                .filter(|label| label.name.to_lowercase().starts_with(&prefix))
+                .cloned()
+                .collect();
+            labels.sort_by_key(|label| (label.name.to_lowercase(), label.id));
+            labels.truncate(AUTOCOMPLETE_LIMIT);
+            Ok(labels)
+        }
+
+        async fn in_use(&self, user_id: i32) -> anyhow::Result<Vec<(Label, i64)>> {
+            let tombstones = self.tombstones.read().unwrap();
+            let mut counts: BTreeMap<i32, i64> = BTreeMap::new();
+            for todo in self.todos.read().unwrap().values() {
+                if todo.user_id != user_id || tombstones.contains(&todo.id) {
+                    continue;
+                }
+                for label in todo.labels.iter() {
+                    *counts.entry(label.id).or_default() += 1;
+                }
+            }
+            // Todoに残っていても削除済みのラベルは返さない
+            let store = self.read_store_ref();
+            let labels = counts
+                .into_iter()
+                .filter_map(|(id, count)| store.get(&id).map(|label| (label.clone(), count)))
+                .collect();
             Ok(labels)
         }
 
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
+        async fn update(
+            &self,
+            id: i32,
+            name: Option<String>,
+            color: Option<String>,
+        ) -> anyhow::Result<Label> {
+            let _todo_transaction = self.todo_transaction.lock().await;
+            let mut store = self.write_store_ref();
+            if let Some((_key, label)) = store.iter().find(|(_key, label)| {
+                name.as_ref()
+                    .is_some_and(|name| label.name.to_lowercase() == name.to_lowercase())
+                    && label.id != id
+            }) {
+                return Err(RepositoryError::Duplicate(label.id).into());
+            };
+
+            let label = store.get_mut(&id).ok_or(RepositoryError::NotFound(id))?;
+            if let Some(name) = name {
+                label.name = name;
+            }
+            if let Some(color) = color {
+                label.color = Some(color);
+            }
+            let label = label.clone();
+
+            // Todoはラベルを複製して持っているため、DBの実装と同じく変更後の名前・色が見えるよう書き換える
+            for todo_label in self
+                .todos
+                .write()
+                .unwrap()
+                .values_mut()
+                .flat_map(|todo| todo.labels.iter_mut())
+                .filter(|todo_label| todo_label.id == id)
+            {
+                *todo_label = label.clone();
+            }
+            Ok(label)
+        }
+
+        // DBの実装と同じく、論理削除されたTodoや他のユーザーのTodoに付いている場合も参照として扱う
+        async fn delete(&self, id: i32, force: bool) -> anyhow::Result<()> {
+            let _todo_transaction = self.todo_transaction.lock().await;
             let mut store = self.write_store_ref();
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?;
+            if !store.contains_key(&id) {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            let mut todos = self.todos.write().unwrap();
+            let referenced = todos
+                .values()
+                .any(|todo| todo.labels.iter().any(|label| label.id == id));
+            if referenced {
+                if !force {
+                    return Err(RepositoryError::Conflict(id).into());
+                }
+                for todo in todos.values_mut() {
+                    todo.labels.retain(|label| label.id != id);
+                }
+            }
+            store.remove(&id);
             Ok(())
         }
+
+        async fn merge(&self, from_id: i32, into_id: i32) -> anyhow::Result<Label> {
+            if from_id == into_id {
+                return Err(RepositoryError::Validation(format!(
+                    "label {} can not be merged into itself",
+                    from_id
+                ))
+                .into());
+            }
+            let _todo_transaction = self.todo_transaction.lock().await;
+            let mut store = self.write_store_ref();
+            let into = store
+                .get(&into_id)
+                .cloned()
+                .ok_or(RepositoryError::NotFound(into_id))?;
+            store
+                .remove(&from_id)
+                .ok_or(RepositoryError::NotFound(from_id))?;
+
+            // 両方のラベルが付いているTodoは付け替えると重複するため、from_idを外すだけにする
+            for todo in self.todos.write().unwrap().values_mut() {
+                if !todo.labels.iter().any(|label| label.id == from_id) {
+                    continue;
+                }
+                let has_into = todo.labels.iter().any(|label| label.id == into_id);
+                if has_into {
+                    todo.labels.retain(|label| label.id != from_id);
+                } else {
+                    for label in todo.labels.iter_mut().filter(|label| label.id == from_id) {
+                        *label = into.clone();
+                    }
+                }
+            }
+            Ok(into)
+        }
     }
 
+    #[cfg(test)]
     mod test {
         use std::vec;
 
-        use super::{LabelRepository, LabelRepositoryForMemory};
+        use super::{
+            LabelRepository, LabelRepositoryForMemory, RepositoryError, AUTOCOMPLETE_LIMIT,
+        };
         use crate::repositories::label::Label;
+        use crate::repositories::todo::{
+            memory::TodoRepositoryForMemory, CreateTodo, TodoRepository,
+        };
 
         #[tokio::test]
         async fn label_crud_scenario() {
@@ -208,18 +1199,255 @@ pub mod test_utils {
             // create
             let repository = LabelRepositoryForMemory::new();
             let label = repository
-                .create(text.clone())
+                .create(text.clone(), None)
                 .await
                 .expect("failed label create");
             assert_eq!(expected, label);
 
+            // find
+            let label = repository.find(id).await.expect("failed label find");
+            assert_eq!(expected, label);
+            assert!(repository.find(999).await.is_err());
+
             // all
             let label = repository.all().await.unwrap();
             assert_eq!(vec![expected], label);
 
             // delete
-            let res = repository.delete(id).await;
+            let res = repository.delete(id, false).await;
             assert!(res.is_ok())
         }
+
+        #[tokio::test]
+        async fn create_does_not_reuse_deleted_id() {
+            let repository = LabelRepositoryForMemory::new();
+            for name in ["label 1", "label 2", "label 3"] {
+                repository
+                    .create(name.to_string(), None)
+                    .await
+                    .expect("failed label create");
+            }
+            repository
+                .delete(2, false)
+                .await
+                .expect("failed label delete");
+            let label = repository
+                .create("label 4".to_string(), None)
+                .await
+                .expect("failed label create");
+            assert_eq!(Label::new(4, "label 4".to_string()), label);
+
+            let mut labels = repository.all().await.unwrap();
+            labels.sort_by_key(|label| label.id);
+            assert_eq!(
+                vec![
+                    Label::new(1, "label 1".to_string()),
+                    Label::new(3, "label 3".to_string()),
+                    Label::new(4, "label 4".to_string()),
+                ],
+                labels
+            );
+        }
+
+        #[tokio::test]
+        async fn create_rejects_duplicate_name() {
+            let repository = LabelRepositoryForMemory::new();
+            let label = repository
+                .create("duplicate".to_string(), None)
+                .await
+                .expect("failed label create");
+            let res = repository.create("duplicate".to_string(), None).await;
+            let err = res.expect_err("duplicate label was created");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Duplicate(id)) if *id == label.id
+            ));
+        }
+
+        #[tokio::test]
+        async fn create_rejects_duplicate_name_ignoring_case() {
+            let repository = LabelRepositoryForMemory::new();
+            let label = repository
+                .create("Work".to_string(), None)
+                .await
+                .expect("failed label create");
+            let res = repository.create("work".to_string(), None).await;
+            let err = res.expect_err("duplicate label was created");
+            assert!(matches!(
+                err.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Duplicate(id)) if *id == label.id
+            ));
+
+            // 自身の大文字・小文字を変えるだけの変更は重複ではない
+            let renamed = repository
+                .update(label.id, Some("WORK".to_string()), None)
+                .await
+                .expect("failed label update");
+            assert_eq!("WORK", renamed.name);
+        }
+
+        #[tokio::test]
+        async fn autocomplete_by_prefix() {
+            let repository = LabelRepositoryForMemory::new();
+            for name in ["World", "Homework", "work", "Private"] {
+                repository
+                    .create(name.to_string(), None)
+                    .await
+                    .expect("failed label create");
+            }
+            let names = |labels: Vec<Label>| -> Vec<String> {
+                labels.into_iter().map(|label| label.name).collect()
+            };
+
+            let labels = repository.autocomplete("wo".to_string()).await.unwrap();
+            assert_eq!(vec!["work", "World"], names(labels));
+
+            // 空の場合は全てのラベルが候補になる
+            let labels = repository.autocomplete(String::new()).await.unwrap();
+            assert_eq!(vec!["Homework", "Private", "work", "World"], names(labels));
+
+            for i in 0..AUTOCOMPLETE_LIMIT {
+                repository
+                    .create(format!("word {}", i), None)
+                    .await
+                    .expect("failed label create");
+            }
+            let labels = repository.autocomplete("wo".to_string()).await.unwrap();
+            assert_eq!(AUTOCOMPLETE_LIMIT, labels.len());
+        }
+
+        #[tokio::test]
+        async fn label_rename() {
+            let repository = LabelRepositoryForMemory::new();
+            let label = repository
+                .create("before rename".to_string(), None)
+                .await
+                .expect("failed label create");
+            let other = repository
+                .create("other".to_string(), None)
+                .await
+                .expect("failed label create");
+
+            let renamed = repository
+                .update(label.id, Some("after rename".to_string()), None)
+                .await
+                .expect("failed label update");
+            assert_eq!(Label::new(label.id, "after rename".to_string()), renamed);
+
+            let res = repository
+                .update(label.id, Some(other.name.clone()), None)
+                .await;
+            assert!(res.is_err());
+            let res = repository
+                .update(999, Some("missing".to_string()), None)
+                .await;
+            assert!(res.is_err());
+
+            let unchanged = repository
+                .update(label.id, None, None)
+                .await
+                .expect("failed label update");
+            assert_eq!(renamed, unchanged);
+            let res = repository.update(999, None, None).await;
+            assert!(res.is_err());
+        }
+
+        #[tokio::test]
+        async fn merge_rewrites_todo_labels() {
+            let repository = LabelRepositoryForMemory::new();
+            let todo_repository = TodoRepositoryForMemory::with_label_repository(&repository);
+            let from = repository
+                .create("from".to_string(), None)
+                .await
+                .expect("failed label create");
+            let into = repository
+                .create("into".to_string(), None)
+                .await
+                .expect("failed label create");
+            // 両方のラベルが付いたTodoと、統合元のラベルだけが付いたTodo
+            let shared = todo_repository
+                .create(CreateTodo::new(
+                    "shared".to_string(),
+                    vec![from.id, into.id],
+                ))
+                .await
+                .expect("failed todo create");
+            let only_from = todo_repository
+                .create(CreateTodo::new("only from".to_string(), vec![from.id]))
+                .await
+                .expect("failed todo create");
+
+            let merged = repository
+                .merge(from.id, into.id)
+                .await
+                .expect("failed label merge");
+            assert_eq!(into, merged);
+
+            // 紐付けが重複せず、統合先のラベルだけが付いている
+            for id in [shared.id, only_from.id] {
+                let todo = todo_repository.find(id).await.unwrap();
+                assert_eq!(vec![into.clone()], todo.labels);
+            }
+            assert!(repository.find(from.id).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn delete_referenced_label() {
+            let repository = LabelRepositoryForMemory::new();
+            let todo_repository = TodoRepositoryForMemory::with_label_repository(&repository);
+            let label = repository
+                .create("referenced".to_string(), None)
+                .await
+                .expect("failed label create");
+            let todo = todo_repository
+                .create(CreateTodo::new("todo".to_string(), vec![label.id]))
+                .await
+                .expect("failed todo create");
+
+            let res = repository.delete(label.id, false).await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Conflict(id)) if *id == label.id
+            ));
+            assert_eq!(label, repository.find(label.id).await.unwrap());
+
+            // forceを指定するとTodoからも外して削除する
+            repository
+                .delete(label.id, true)
+                .await
+                .expect("failed label delete");
+            assert!(repository.find(label.id).await.is_err());
+            let todo = todo_repository.find(todo.id).await.unwrap();
+            assert!(todo.labels.is_empty());
+        }
+
+        #[tokio::test]
+        async fn update_rewrites_todo_labels() {
+            let repository = LabelRepositoryForMemory::new();
+            let todo_repository = TodoRepositoryForMemory::with_label_repository(&repository);
+            let label = repository
+                .create("before rename".to_string(), None)
+                .await
+                .expect("failed label create");
+            let todo = todo_repository
+                .create(CreateTodo::new("todo".to_string(), vec![label.id]))
+                .await
+                .expect("failed todo create");
+
+            let renamed = repository
+                .update(label.id, Some("after rename".to_string()), None)
+                .await
+                .expect("failed label update");
+            let todo = todo_repository.find(todo.id).await.unwrap();
+            assert_eq!(vec![renamed], todo.labels);
+
+            let recolored = repository
+                .update(label.id, None, Some("#ff0000".to_string()))
+                .await
+                .expect("failed label update");
+            let todo = todo_repository.find(todo.id).await.unwrap();
+            assert_eq!(Some("#ff0000".to_string()), todo.labels[0].color);
+            assert_eq!(vec![recolored], todo.labels);
+        }
     }
 }
@@ -0,0 +1,281 @@
+use std::{env, future::Future, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+// コネクションプールの設定、未指定の項目はsqlxの既定値と同じ値を使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,       // 空きコネクションを待つ上限
+    pub idle_timeout: Duration,          // 使われていないコネクションを閉じるまでの時間
+    pub connect_retry_timeout: Duration, // 起動時に接続できない場合に再試行を続ける時間、0なら再試行しない
+}
+
+// 起動時の接続の再試行の間隔、1秒から倍にしていき最大5秒
+const CONNECT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(10 * 60),
+            connect_retry_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl PoolConfig {
+    // DATABASE_MAX_CONNECTIONS / DATABASE_MIN_CONNECTIONS / DATABASE_ACQUIRE_TIMEOUT_SECS / DATABASE_IDLE_TIMEOUT_SECS
+    // DATABASE_CONNECT_RETRY_SECS
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let default = Self::default();
+        let config = Self {
+            max_connections: parse(&lookup, "DATABASE_MAX_CONNECTIONS")?
+                .unwrap_or(default.max_connections),
+            min_connections: parse(&lookup, "DATABASE_MIN_CONNECTIONS")?
+                .unwrap_or(default.min_connections),
+            acquire_timeout: parse(&lookup, "DATABASE_ACQUIRE_TIMEOUT_SECS")?
+                .map_or(default.acquire_timeout, Duration::from_secs),
+            idle_timeout: parse(&lookup, "DATABASE_IDLE_TIMEOUT_SECS")?
+                .map_or(default.idle_timeout, Duration::from_secs),
+            connect_retry_timeout: parse(&lookup, "DATABASE_CONNECT_RETRY_SECS")?
+                .map_or(default.connect_retry_timeout, Duration::from_secs),
+        };
+        if config.max_connections == 0 || config.min_connections > config.max_connections {
+            bail!(
+                "invalid pool size, min is {} and max is {}",
+                config.min_connections,
+                config.max_connections
+            );
+        }
+        Ok(config)
+    }
+}
+
+fn parse<T: FromStr>(
+    lookup: &impl Fn(&str) -> Option<String>,
+    key: &str,
+) -> anyhow::Result<Option<T>> {
+    lookup(key)
+        .map(|value| {
+            value
+                .trim()
+                .parse::<T>()
+                .ok()
+                .with_context(|| format!("invalid value for [{}]: {}", key, value))
+        })
+        .transpose()
+}
+
+pub async fn create_pool(database_url: &str, opts: PoolConfig) -> anyhow::Result<PgPool> {
+    tracing::info!(
+        max_connections = opts.max_connections,
+        min_connections = opts.min_connections,
+        acquire_timeout_secs = opts.acquire_timeout.as_secs(),
+        idle_timeout_secs = opts.idle_timeout.as_secs(),
+        connect_retry_secs = opts.connect_retry_timeout.as_secs(),
+        "database pool settings"
+    );
+    // コンテナでの起動時など、DBがまだ接続を受け付けていない場合は待ってから接続し直す
+    let delays = retry_delays(
+        opts.connect_retry_timeout,
+        CONNECT_RETRY_BASE_DELAY,
+        CONNECT_RETRY_MAX_DELAY,
+    );
+    connect_with_retry(&delays, || {
+        PgPoolOptions::new()
+            .max_connections(opts.max_connections)
+            .min_connections(opts.min_connections)
+            .connect_timeout(opts.acquire_timeout)
+            .idle_timeout(opts.idle_timeout)
+            .connect(database_url)
+    })
+    .await
+}
+
+// 再試行の前に待つ時間を順に並べる、待ち時間の合計はtotalを超えない
+fn retry_delays(total: Duration, base: Duration, max: Duration) -> Vec<Duration> {
+    let mut delays = vec![];
+    let mut elapsed = Duration::ZERO;
+    let mut delay = base.min(max);
+    while !delay.is_zero() && elapsed + delay <= total {
+        delays.push(delay);
+        elapsed += delay;
+        delay = (delay * 2).min(max);
+    }
+    delays
+}
+
+// 失敗するたびにdelaysの順に待って試し直し、使い切っても接続できなければ最後のエラーを返す
+// URLの誤りなど設定の問題は待っても直らないため再試行しない
+async fn connect_with_retry<T, F, Fut>(delays: &[Duration], mut connect: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match connect().await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => e,
+        };
+        match delays.get(attempt - 1) {
+            Some(delay) if !matches!(error, sqlx::Error::Configuration(_)) => {
+                tracing::warn!(
+                    attempt,
+                    retry_in_ms = delay.as_millis() as u64,
+                    "failed to connect database, retrying: {}",
+                    error
+                );
+                tokio::time::sleep(*delay).await;
+                attempt += 1;
+            }
+            _ => {
+                tracing::error!(attempts = attempt, "failed to connect database: {}", error);
+                return Err(error).with_context(|| {
+                    format!("failed to connect database after {} attempts", attempt)
+                });
+            }
+        }
+    }
+}
+
+// migrations/配下のSQLのうち未適用のものを順に適用する
+// 適用済みかどうかは_sqlx_migrationsテーブルで管理され、sqlx-cliで適用した場合と互換がある
+pub async fn run_migrations(pool: &PgPool) -> anyhow::Result<()> {
+    tracing::info!("running database migrations...");
+    sqlx::migrate!()
+        .run(pool)
+        .await
+        .context("failed to run database migrations")?;
+    tracing::info!("database migrations completed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn default_when_unset() {
+        let config = PoolConfig::from_lookup(lookup(&[])).unwrap();
+        assert_eq!(PoolConfig::default(), config);
+    }
+
+    #[test]
+    fn read_from_vars() {
+        let config = PoolConfig::from_lookup(lookup(&[
+            ("DATABASE_MAX_CONNECTIONS", "20"),
+            ("DATABASE_MIN_CONNECTIONS", "2"),
+            ("DATABASE_ACQUIRE_TIMEOUT_SECS", "5"),
+            ("DATABASE_IDLE_TIMEOUT_SECS", "60"),
+            ("DATABASE_CONNECT_RETRY_SECS", "10"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            PoolConfig {
+                max_connections: 20,
+                min_connections: 2,
+                acquire_timeout: Duration::from_secs(5),
+                idle_timeout: Duration::from_secs(60),
+                connect_retry_timeout: Duration::from_secs(10),
+            },
+            config
+        );
+    }
+
+    #[test]
+    fn retry_delays_within_total() {
+        let secs = |secs: &[u64]| {
+            secs.iter()
+                .map(|s| Duration::from_secs(*s))
+                .collect::<Vec<_>>()
+        };
+        let delays = |total| {
+            retry_delays(
+                Duration::from_secs(total),
+                CONNECT_RETRY_BASE_DELAY,
+                CONNECT_RETRY_MAX_DELAY,
+            )
+        };
+        // 1, 2, 4秒の後は5秒ずつ
+        assert_eq!(secs(&[1, 2, 4, 5, 5, 5, 5]), delays(30));
+        assert_eq!(secs(&[1, 2]), delays(6));
+        assert!(delays(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn stop_retrying_after_configured_attempts() {
+        let calls = AtomicU32::new(0);
+        let delays = [Duration::from_millis(1); 3];
+        let res: anyhow::Result<()> = connect_with_retry(&delays, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::PoolTimedOut)
+        })
+        .await;
+        let error = res.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::PoolTimedOut)
+        ));
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn connect_after_retries() {
+        let calls = AtomicU32::new(0);
+        let delays = [Duration::from_millis(1); 3];
+        let res = connect_with_retry(&delays, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(sqlx::Error::PoolTimedOut),
+                _ => Ok("connected"),
+            }
+        })
+        .await;
+        assert_eq!("connected", res.unwrap());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn no_retry_on_configuration_error() {
+        let calls = AtomicU32::new(0);
+        let delays = [Duration::from_millis(1); 3];
+        let res: anyhow::Result<()> = connect_with_retry(&delays, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::Configuration("invalid url".into()))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reject_invalid_vars() {
+        let res = PoolConfig::from_lookup(lookup(&[("DATABASE_MAX_CONNECTIONS", "many")]));
+        assert!(res.is_err());
+        let res = PoolConfig::from_lookup(lookup(&[
+            ("DATABASE_MAX_CONNECTIONS", "2"),
+            ("DATABASE_MIN_CONNECTIONS", "5"),
+        ]));
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use std::{future::Future, time::Duration};
+
+// 一時的なDBエラー(接続断やプールの枯渇)に対する再試行の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration, // n回目の再試行の前にbase_delay * 2^(n-1)だけ待つ
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // 書き込みを含む処理は二重に実行される恐れがあるため、読み取りのみに使う
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> Result<T, sqlx::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        let mut retries = 0;
+        loop {
+            match operation().await {
+                Err(e) if retries < self.max_retries && is_transient(&e) => {
+                    tokio::time::sleep(self.base_delay * 2u32.pow(retries)).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// RowNotFoundや制約違反(Database)は何度実行しても結果が変わらないので再試行しない
+fn is_transient(error: &sqlx::Error) -> bool {
+    matches!(
+        error,
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let calls = AtomicU32::new(0);
+        let res = policy()
+            .run(|| async {
+                // 2回失敗した後に成功する
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(sqlx::Error::PoolTimedOut),
+                    _ => Ok("ok"),
+                }
+            })
+            .await;
+        assert_eq!("ok", res.unwrap());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn give_up_after_max_retries() {
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = policy()
+            .run(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(sqlx::Error::PoolTimedOut)
+            })
+            .await;
+        assert!(matches!(res, Err(sqlx::Error::PoolTimedOut)));
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn no_retry_on_row_not_found() {
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = policy()
+            .run(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(sqlx::Error::RowNotFound)
+            })
+            .await;
+        assert!(matches!(res, Err(sqlx::Error::RowNotFound)));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
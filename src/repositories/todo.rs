@@ -1,617 +1,7363 @@
+use anyhow::Context as _;
 use axum::async_trait;
-use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
-use validator::Validate;
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use super::{label::Label, RepositoryError};
+use chrono::{Days, Local, Months, NaiveDate, NaiveDateTime};
+use futures_util::{stream::Stream, TryStreamExt};
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
+use sqlx::{
+    pool::PoolConnection,
+    postgres::{PgHasArrayType, PgTypeInfo},
+    Executor, FromRow, PgConnection, PgPool, Postgres, Transaction,
+};
+use std::env;
+use tokio::sync::{broadcast, mpsc, Mutex, MutexGuard};
+use unicode_segmentation::UnicodeSegmentation;
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
+
+use super::{escape_like, label::Label, retry::RetryPolicy, with_operation, RepositoryError};
+use crate::normalize::{Normalize, TextNormalization};
+
+// あいまい検索で一致とみなす類似度の既定値、pg_trgmの既定値と同じ
+pub const DEFAULT_SIMILARITY_THRESHOLD: f32 = 0.3;
+
+// for_userで絞り込む前のリポジトリが扱うユーザー、所有者のいなかった既存のTodoもこのユーザーに割り当てている
+pub const DEFAULT_USER_ID: i32 = 1;
+
+// 変更通知を溜めておける件数、これを超えて遅れた購読者はLaggedを受け取る
+const TODO_EVENT_CAPACITY: usize = 256;
+
+// stream_allで取得するTodo
+pub type TodoStream = Pin<Box<dyn Stream<Item = anyhow::Result<TodoEntity>> + Send>>;
+
+// stream_allで先読みしておくTodoの件数、受け取る側が遅い場合はDBからの読み込みも止まる
+const TODO_STREAM_BUFFER: usize = 64;
+
+// with_transactionに渡す処理が返すFuture
+pub type TransactionFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+// with_transactionの中で作ったリポジトリが共有するトランザクション
+#[derive(Debug)]
+struct SharedTransaction {
+    transaction: Mutex<Option<Transaction<'static, Postgres>>>, // 終了後はNoneになる
+    rollback_pending: AtomicBool, // 失敗したメソッドのセーブポイントをまだ取り消していない
+}
+
+impl SharedTransaction {
+    fn new(transaction: Transaction<'static, Postgres>) -> Self {
+        Self {
+            transaction: Mutex::new(Some(transaction)),
+            rollback_pending: AtomicBool::new(false),
+        }
+    }
+
+    // 失敗したメソッドの変更を取り消す、Postgresがトランザクションを中断した状態からも戻す
+    async fn rollback_pending(
+        &self,
+        tx: &mut Transaction<'static, Postgres>,
+    ) -> Result<(), sqlx::Error> {
+        if self.rollback_pending.swap(false, Ordering::SeqCst) {
+            tx.execute(
+                "rollback to savepoint repository_method; release savepoint repository_method",
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+// 共有のトランザクションを使うメソッドごとにセーブポイントを作り、
+// 失敗したメソッドの変更だけを取り消して、fがエラーを握りつぶしても途中までの変更をコミットしない
+struct SharedGuard<'a> {
+    guard: MutexGuard<'a, Option<Transaction<'static, Postgres>>>,
+    shared: &'a SharedTransaction,
+    released: bool,
+}
+
+impl SharedGuard<'_> {
+    async fn release(mut self) -> Result<(), sqlx::Error> {
+        self.execute("release savepoint repository_method").await?;
+        self.released = true;
+        Ok(())
+    }
+}
+
+// Dropの中では問い合わせられないため、次に共有のトランザクションを使う時に取り消す
+impl Drop for SharedGuard<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.shared.rollback_pending.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Deref for SharedGuard<'_> {
+    type Target = Transaction<'static, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        // lock_transactionでSomeであることを確かめている
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for SharedGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+// 各メソッドが書き込みに使うトランザクション
+enum DbTransaction<'a> {
+    Owned(Box<Transaction<'static, Postgres>>),
+    Shared(SharedGuard<'a>),
+}
+
+impl DbTransaction<'_> {
+    // 共有のトランザクションはセーブポイントだけを解放し、with_transactionの終了時にまとめてコミットする
+    async fn commit(self) -> Result<(), sqlx::Error> {
+        match self {
+            DbTransaction::Owned(tx) => (*tx).commit().await,
+            DbTransaction::Shared(guard) => guard.release().await,
+        }
+    }
+}
+
+impl Deref for DbTransaction<'_> {
+    type Target = Transaction<'static, Postgres>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DbTransaction::Owned(tx) => tx,
+            DbTransaction::Shared(guard) => guard,
+        }
+    }
+}
+
+impl DerefMut for DbTransaction<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            DbTransaction::Owned(tx) => tx,
+            DbTransaction::Shared(guard) => guard,
+        }
+    }
+}
+
+// 各メソッドが読み取りに使う接続
+// 共有のトランザクションでは、失敗した問い合わせで中断されたままにならないよう使い終わると必ず取り消す
+enum DbConnection<'a> {
+    Pool(Box<PoolConnection<Postgres>>),
+    Shared(SharedGuard<'a>),
+}
+
+impl Deref for DbConnection<'_> {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DbConnection::Pool(conn) => conn,
+            DbConnection::Shared(guard) => guard,
+        }
+    }
+}
+
+impl DerefMut for DbConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            DbConnection::Pool(conn) => conn,
+            DbConnection::Shared(guard) => guard,
+        }
+    }
+}
+
+// with_transactionの終了後にリポジトリが使われた場合は、閉じたプールと同じく扱う
+async fn lock_transaction(shared: &SharedTransaction) -> Result<SharedGuard<'_>, sqlx::Error> {
+    let mut guard = shared.transaction.lock().await;
+    let Some(tx) = guard.as_mut() else {
+        return Err(sqlx::Error::PoolClosed);
+    };
+    shared.rollback_pending(tx).await?;
+    tx.execute("savepoint repository_method").await?;
+    Ok(SharedGuard {
+        guard,
+        shared,
+        released: false,
+    })
+}
 
 #[derive(Debug, Clone)]
 pub struct TodoRepositoryForDb {
     pool: PgPool,
+    retry: RetryPolicy,        // 読み取りクエリの再試行設定
+    similarity_threshold: f32, // あいまい検索で一致とみなす類似度(0.0〜1.0)
+    user_id: i32,              // 読み書きの対象にするTodoの所有者
+    default_order: OrderBy,    // 一覧取得で並べ替えのキーが指定されなかった場合の並び順
+    events: TodoEvents,
+    transaction: Option<Arc<SharedTransaction>>, // with_transactionの中でのみSome
 }
 
 impl TodoRepositoryForDb {
     pub fn new(pool: PgPool) -> Self {
-        TodoRepositoryForDb { pool }
+        Self::with_retry_policy(pool, RetryPolicy::default())
+    }
+
+    pub fn with_retry_policy(pool: PgPool, retry: RetryPolicy) -> Self {
+        TodoRepositoryForDb {
+            pool,
+            retry,
+            similarity_threshold: DEFAULT_SIMILARITY_THRESHOLD,
+            user_id: DEFAULT_USER_ID,
+            default_order: OrderBy::default(),
+            events: TodoEvents::new(),
+            transaction: None,
+        }
+    }
+
+    pub fn with_default_order(self, default_order: OrderBy) -> Self {
+        Self {
+            default_order,
+            ..self
+        }
+    }
+
+    pub fn with_similarity_threshold(self, similarity_threshold: f32) -> Self {
+        Self {
+            similarity_threshold,
+            ..self
+        }
+    }
+
+    // コミットした後に呼ぶ
+    fn publish(&self, kind: TodoEventKind, ids: &[i32]) {
+        publish(&self.events, self.user_id, kind, ids);
+    }
+
+    // with_transactionの中では共有のトランザクションにセーブポイントを作って使い、コミットはwith_transactionに任せる
+    async fn begin(&self) -> Result<DbTransaction<'_>, sqlx::Error> {
+        match &self.transaction {
+            Some(transaction) => Ok(DbTransaction::Shared(lock_transaction(transaction).await?)),
+            None => Ok(DbTransaction::Owned(Box::new(self.pool.begin().await?))),
+        }
+    }
+
+    // 読み取りもwith_transactionの中ではコミット前の変更が見えるよう共有のトランザクションで行う
+    async fn connection(&self) -> Result<DbConnection<'_>, sqlx::Error> {
+        match &self.transaction {
+            Some(transaction) => Ok(DbConnection::Shared(lock_transaction(transaction).await?)),
+            None => Ok(DbConnection::Pool(Box::new(self.pool.acquire().await?))),
+        }
+    }
+
+    // include_deletedがfalseの場合、論理削除済みのTodoはNotFoundとして扱う
+    async fn fetch(&self, id: i32, include_deleted: bool) -> anyhow::Result<TodoEntity> {
+        with_operation(|| format!("find todo id={}", id), async {
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(
+                        r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.id=$1 and ($2 or todos.deleted_at is null) and todos.user_id=$3;
+        "#,
+                    )
+                    .bind(id)
+                    .bind(include_deleted)
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                    _ => RepositoryError::Unexpected(e.to_string()),
+                })?;
+
+            if items.is_empty() {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            fold_entity(items)
+        })
+        .await
     }
 }
 
 #[async_trait]
 impl TodoRepository for TodoRepositoryForDb {
+    fn for_user(&self, user_id: i32) -> Self {
+        Self {
+            user_id,
+            ..self.clone()
+        }
+    }
+
     async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
-        let tx = self.pool.begin().await?;
-        // todosテーブルへレコードの追加
-        let row = sqlx::query_as::<_, TodoFromRow>(
-            r#"
-insert into todos (text, completed)
-values ($1, false)
+        with_operation(|| "create todo".to_string(), async {
+            ensure_label_limit(&payload.labels)?;
+            let mut tx = self.begin().await?;
+            // 外部キー制約の違反を待たず、存在しないラベルは422として作成ごと取りやめる
+            ensure_labels_exist(&mut tx, &payload.labels).await?;
+            // todosテーブルへレコードの追加
+            if let Some(parent_id) = payload.parent_id {
+                ensure_valid_parent(&mut tx, self.user_id, None, parent_id).await?;
+            }
+            let row = sqlx::query_as::<_, TodoFromRow>(
+                r#"
+insert into todos (text, completed, due_date, priority, parent_id, recurrence, user_id)
+values ($1, false, $2, $3, $4, $5, $6)
 returning *;
         "#,
-        )
-        .bind(payload.text.clone())
-        .fetch_one(&self.pool)
-        .await?;
+            )
+            .bind(payload.text.clone())
+            .bind(payload.due_date)
+            .bind(payload.priority)
+            .bind(payload.parent_id)
+            .bind(payload.recurrence)
+            .bind(self.user_id)
+            .fetch_one(&mut *tx)
+            .await?;
 
-        // todo_labelsテーブルへレコードの追加
-        sqlx::query(
-            r#"
+            // todo_labelsテーブルへレコードの追加
+            sqlx::query(
+                r#"
 insert into todo_labels (todo_id, label_id)
-select $1, id
+select distinct $1, id
 from unnest($2) as t(id);
         "#,
-        )
-        .bind(row.id)
-        .bind(payload.labels)
-        .execute(&self.pool)
-        .await?;
+            )
+            .bind(row.id)
+            .bind(payload.labels)
+            .execute(&mut *tx)
+            .await?;
+
+            record_audit(&mut tx, &[row.id], AuditAction::Create).await?;
+
+            tx.commit().await?;
+            self.publish(TodoEventKind::Created, &[row.id]);
+
+            let todo = self.find(row.id).await?; // todo(label付き)を取得
+            Ok(todo)
+        })
+        .await
+    }
+
+    async fn create_many(&self, payloads: Vec<CreateTodo>) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| "create todos".to_string(), async {
+            for payload in payloads.iter() {
+                ensure_label_limit(&payload.labels)?;
+            }
+            let mut tx = self.begin().await?;
+            let label_ids: Vec<i32> = payloads
+                .iter()
+                .flat_map(|payload| payload.labels.clone())
+                .collect();
+            ensure_labels_exist(&mut tx, &label_ids).await?;
+            for parent_id in payloads.iter().filter_map(|payload| payload.parent_id) {
+                ensure_valid_parent(&mut tx, self.user_id, None, parent_id).await?;
+            }
+
+            // returningの順序は保証されないため、先に採番したidを明示して挿入する
+            let ids: Vec<i32> = sqlx::query_scalar(
+                r#"
+select nextval(pg_get_serial_sequence('todos', 'id'))::int4
+from generate_series(1, $1);
+        "#,
+            )
+            .bind(payloads.len() as i32)
+            .fetch_all(&mut *tx)
+            .await?;
+
+            sqlx::query(
+                r#"
+insert into todos (id, text, completed, due_date, priority, parent_id, recurrence, user_id)
+select t.id, t.text, false, t.due_date, t.priority, t.parent_id, t.recurrence, $7
+from unnest($1::int4[], $2::text[], $3::date[], $4::priority[], $5::int4[], $6::recurrence[])
+    as t(id, text, due_date, priority, parent_id, recurrence);
+        "#,
+            )
+            .bind(&ids)
+            .bind(payloads.iter().map(|p| p.text.clone()).collect::<Vec<_>>())
+            .bind(payloads.iter().map(|p| p.due_date).collect::<Vec<_>>())
+            .bind(payloads.iter().map(|p| p.priority).collect::<Vec<_>>())
+            .bind(payloads.iter().map(|p| p.parent_id).collect::<Vec<_>>())
+            .bind(payloads.iter().map(|p| p.recurrence).collect::<Vec<_>>())
+            .bind(self.user_id)
+            .execute(&mut *tx)
+            .await?;
+
+            // todo_labelsテーブルへは(todo_id, label_id)の組を平坦にし、重複を除いて追加
+            let (todo_ids, label_ids): (Vec<i32>, Vec<i32>) = ids
+                .iter()
+                .zip(payloads.iter())
+                .flat_map(|(id, payload)| {
+                    payload.labels.iter().map(move |label_id| (*id, *label_id))
+                })
+                .unzip();
+            sqlx::query(
+                r#"
+insert into todo_labels (todo_id, label_id)
+select distinct * from unnest($1::int4[], $2::int4[]);
+        "#,
+            )
+            .bind(todo_ids)
+            .bind(label_ids)
+            .execute(&mut *tx)
+            .await?;
+
+            record_audit(&mut tx, &ids, AuditAction::Create).await?;
 
-        tx.commit().await?;
+            tx.commit().await?;
+            self.publish(TodoEventKind::Created, &ids);
+
+            let items = sqlx::query_as::<_, TodoWithLabelFromRow>(
+                r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.id = any($1)
+order by todos.id asc;
+        "#,
+            )
+            .bind(ids)
+            .fetch_all(&mut *self.connection().await?)
+            .await?;
 
-        let todo = self.find(row.id).await?; // todo(label付き)を取得
-        Ok(todo)
+            fold_entities(items)
+        })
+        .await
     }
 
     async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
-        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(
-            r#"
-select todos.*, labels.id as label_id, labels.name as label_name
+        self.fetch(id, false).await
+    }
+
+    async fn find_including_deleted(&self, id: i32) -> anyhow::Result<TodoEntity> {
+        self.fetch(id, true).await
+    }
+
+    async fn find_many(&self, ids: Vec<i32>) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| format!("find todos ids={:?}", ids), async {
+            // 存在しないidはエラーにせず結果から除くだけ
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(
+                        r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
 from todos
             left outer join todo_labels tl on todos.id = tl.todo_id
             left outer join labels on labels.id = tl.label_id
-where todos.id=$1;
+where todos.id = any($1) and todos.deleted_at is null and todos.user_id = $2
+order by todos.id asc;
         "#,
-        )
-        .bind(id)
-        .fetch_all(&self.pool)
+                    )
+                    .bind(&ids)
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
+
+            fold_entities(items)
+        })
         .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    }
+
+    async fn all(
+        &self,
+        filter: TodoFilter,
+        sort: TodoSort,
+        pagination: Pagination,
+    ) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| "list todos".to_string(), async {
+            // JOIN後の行に対してlimitをかけるとラベルの数だけTodoが欠けるため、先にtodosをサブクエリで絞り込む
+            // order byはバインドできないため、OrderByが返す固定の文字列のみを埋め込む
+            let sql = format!(
+                r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from (
+    select * from todos
+    where deleted_at is null
+        and user_id = $6
+        and ($3::boolean is null or completed = $3)
+        and ($5::int4 is null or parent_id = $5)
+        and ($7::timestamp is null or created_at >= $7)
+        and ($8::timestamp is null or created_at <= $8)
+        and (cardinality($4::int4[]) = 0 or id in (
+            select todo_id from todo_labels
+            where label_id = any($4)
+            group by todo_id
+            having count(distinct label_id) = cardinality($4)
+        ))
+    order by {order_by}
+    limit $1 offset $2
+) todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+order by {order_by};
+        "#,
+                order_by = sort.resolve(self.default_order).order_by_clause()
+            );
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(&sql)
+                        .bind(pagination.limit)
+                        .bind(pagination.offset)
+                        .bind(filter.completed)
+                        .bind(&filter.label_ids)
+                        .bind(filter.parent_id)
+                        .bind(self.user_id)
+                        .bind(filter.created_after)
+                        .bind(filter.created_before)
+                        .fetch_all(&mut *self.connection().await?)
+                        .await
+                })
+                .await?;
 
-        let todos = fold_entities(items);
-        let todo = todos.first().ok_or(RepositoryError::NotFound(id))?;
-        Ok(todo.clone())
+            fold_entities(items)
+        })
+        .await
     }
 
-    async fn all(&self) -> anyhow::Result<Vec<TodoEntity>> {
-        // SQLのfindとの違いはwhere句を使わず、order句を使っている点のみ
-        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(
+    // クエリの結果はプールの接続を借りている間しか読めないため、別のタスクで読み込んでチャネルで受け渡す
+    // 受け取る側が破棄された時点で読み込みをやめる
+    // with_transactionの中でもプールの接続を使うため、コミットしていない変更は含まれない
+    fn stream_all(&self, filter: TodoFilter, sort: TodoSort) -> TodoStream {
+        let sql = format!(
             r#"
-select todos.*, labels.id as label_id, labels.name as label_name
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.deleted_at is null
+    and todos.user_id = $1
+    and ($2::boolean is null or todos.completed = $2)
+    and ($4::int4 is null or todos.parent_id = $4)
+    and ($5::timestamp is null or todos.created_at >= $5)
+    and ($6::timestamp is null or todos.created_at <= $6)
+    and (cardinality($3::int4[]) = 0 or todos.id in (
+        select todo_id from todo_labels
+        where label_id = any($3)
+        group by todo_id
+        having count(distinct label_id) = cardinality($3)
+    ))
+order by {order_by};
+        "#,
+            order_by = sort.resolve(self.default_order).order_by_clause()
+        );
+        let pool = self.pool.clone();
+        let user_id = self.user_id;
+        let (sender, mut receiver) = mpsc::channel(TODO_STREAM_BUFFER);
+        tokio::spawn(async move {
+            let mut rows = sqlx::query_as::<_, TodoWithLabelFromRow>(&sql)
+                .bind(user_id)
+                .bind(filter.completed)
+                .bind(&filter.label_ids)
+                .bind(filter.parent_id)
+                .bind(filter.created_after)
+                .bind(filter.created_before)
+                .fetch(&pool);
+            let mut fold = TodoFold::default();
+            loop {
+                let todo = match rows.try_next().await {
+                    Ok(Some(row)) => match fold.push(&row) {
+                        Some(todo) => Ok(todo),
+                        None => continue,
+                    },
+                    Ok(None) => break,
+                    Err(e) => Err(anyhow::Error::new(e).context("failed to stream todos")),
+                };
+                let failed = todo.is_err();
+                if sender.send(todo).await.is_err() || failed {
+                    return;
+                }
+            }
+            if let Some(todo) = fold.finish() {
+                let _ = sender.send(Ok(todo)).await;
+            }
+        });
+        Box::pin(futures_util::stream::poll_fn(move |cx| {
+            receiver.poll_recv(cx)
+        }))
+    }
+
+    async fn count(&self, filter: TodoFilter) -> anyhow::Result<i64> {
+        with_operation(|| "count todos".to_string(), async {
+            // allと同じ絞り込み条件で数える
+            let (count,) = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, (i64,)>(
+                        r#"
+select count(*) from todos
+where deleted_at is null
+    and user_id = $4
+    and ($1::boolean is null or completed = $1)
+    and ($3::int4 is null or parent_id = $3)
+    and ($5::timestamp is null or created_at >= $5)
+    and ($6::timestamp is null or created_at <= $6)
+    and (cardinality($2::int4[]) = 0 or id in (
+        select todo_id from todo_labels
+        where label_id = any($2)
+        group by todo_id
+        having count(distinct label_id) = cardinality($2)
+    ));
+            "#,
+                    )
+                    .bind(filter.completed)
+                    .bind(&filter.label_ids)
+                    .bind(filter.parent_id)
+                    .bind(self.user_id)
+                    .bind(filter.created_after)
+                    .bind(filter.created_before)
+                    .fetch_one(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
+
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn stats(&self) -> anyhow::Result<TodoStats> {
+        with_operation(|| "aggregate todo stats".to_string(), async {
+            let (total, completed) = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, (i64, i64)>(
+                        r#"
+select count(*), count(*) filter (where completed) from todos
+where deleted_at is null and user_id = $1;
+            "#,
+                    )
+                    .bind(self.user_id)
+                    .fetch_one(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
+
+            // Todoが1件もないラベルも0件として返すため、labelsを起点に外部結合する
+            // 他のユーザーや論理削除済みのTodoは結合条件で除くので数に含まれない
+            let labels = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, LabelStats>(
+                        r#"
+select labels.id, labels.name,
+    count(todos.id) as total,
+    count(todos.id) filter (where todos.completed) as completed
+from labels
+    left outer join todo_labels on todo_labels.label_id = labels.id
+    left outer join todos on todos.id = todo_labels.todo_id
+        and todos.deleted_at is null
+        and todos.user_id = $1
+group by labels.id, labels.name
+order by labels.id;
+            "#,
+                    )
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
+
+            Ok(TodoStats::new(total, completed, labels))
+        })
+        .await
+    }
+
+    async fn group_by_label(&self) -> anyhow::Result<Vec<TodoGroup>> {
+        with_operation(|| "group todos by label".to_string(), async {
+            let sql = format!(
+                r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.deleted_at is null and todos.user_id = $1
+order by {order_by};
+        "#,
+                order_by = self.default_order.order_by_clause()
+            );
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(&sql)
+                        .bind(self.user_id)
+                        .fetch_all(&mut *self.connection().await?)
+                        .await
+                })
+                .await?;
+
+            Ok(group_todos_by_label(fold_entities(items)?))
+        })
+        .await
+    }
+
+    async fn search(&self, query: String) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| format!("search todos query={:?}", query), async {
+            let pattern = escape_like(query.trim());
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(
+                        r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
 from todos
             left outer join todo_labels tl on todos.id = tl.todo_id
             left outer join labels on labels.id = tl.label_id
+where todos.text ilike '%' || $1 || '%' and todos.deleted_at is null and todos.user_id = $2
 order by todos.id desc;
         "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
+                    )
+                    .bind(&pattern)
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
 
-        Ok(fold_entities(items))
+            fold_entities(items)
+        })
+        .await
     }
 
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
-        let tx = self.pool.begin().await?;
+    async fn find_by_text(&self, text: &str) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| format!("find todos by text text={:?}", text), async {
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(
+                        r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.text = $1 and todos.deleted_at is null and todos.user_id = $2
+order by todos.id desc;
+        "#,
+                    )
+                    .bind(text.trim())
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
 
-        // todo update
-        let old_todo = self.find(id).await?;
-        sqlx::query(
-            r#"
-update todos set text=$1, completed=$2
-where id=$3
-returning *
+            fold_entities(items)
+        })
+        .await
+    }
+
+    async fn fuzzy_search(&self, query: String) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| format!("fuzzy search todos query={:?}", query), async {
+            // 似ているものから順に返す、類似度が同じ場合は新しいものから
+            let items = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoWithLabelFromRow>(
+                        r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where similarity(todos.text, $1) > $2 and todos.deleted_at is null and todos.user_id = $3
+order by similarity(todos.text, $1) desc, todos.id desc;
         "#,
-        )
-        .bind(payload.text.unwrap_or(old_todo.text))
-        .bind(payload.completed.unwrap_or(old_todo.completed))
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await?;
-
-        if let Some(labels) = payload.labels {
-            // 一度関連するレコードを削除
-            sqlx::query(
+                    )
+                    .bind(query.trim())
+                    .bind(self.similarity_threshold)
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
+
+            fold_entities(items)
+        })
+        .await
+    }
+
+    async fn find_by_label(&self, label_id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+        with_operation(|| format!("find todos by label id={}", label_id), async {
+            let mut tx = self.begin().await?;
+            // 絞り込みに使うラベル自体がなければNotFound
+            sqlx::query("select id from labels where id = $1")
+                .bind(label_id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or(RepositoryError::LabelNotFound(label_id))?;
+
+            // 絞り込みはサブクエリで行い、Todoに付いている全てのラベルをJOINする
+            let items = sqlx::query_as::<_, TodoWithLabelFromRow>(
                 r#"
-    delete from todo_labels where todo_id=$1
-            "#,
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.id in (select todo_id from todo_labels where label_id = $1)
+    and todos.deleted_at is null
+    and todos.user_id = $2
+order by todos.id desc;
+        "#,
             )
-            .bind(id)
-            .execute(&self.pool)
+            .bind(label_id)
+            .bind(self.user_id)
+            .fetch_all(&mut *tx)
             .await?;
 
-            sqlx::query(
+            tx.commit().await?;
+
+            fold_entities(items)
+        })
+        .await
+    }
+
+    async fn find_labels(&self, id: i32) -> anyhow::Result<Vec<Label>> {
+        with_operation(|| format!("find labels of todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            ensure_todo_exists(&mut tx, self.user_id, id).await?;
+
+            let labels = sqlx::query_as::<_, Label>(
                 r#"
-    insert into todo_labels (todo_id, label_id)
-    select $1, id
-    from unnest($2) as t(id);
-            "#,
+select labels.* from labels
+    inner join todo_labels on todo_labels.label_id = labels.id
+where todo_labels.todo_id = $1
+order by labels.id asc;
+        "#,
             )
             .bind(id)
-            .bind(labels)
-            .execute(&self.pool)
+            .fetch_all(&mut *tx)
             .await?;
-        };
 
-        tx.commit().await?;
-        let todo = self.find(id).await?;
+            tx.commit().await?;
 
-        Ok(todo)
+            Ok(labels)
+        })
+        .await
     }
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        let tx = self.pool.begin().await?;
-        // todo's label delete
-        sqlx::query(
-            r#"
-delete from todo_labels where todo_id=$1
-        "#,
-        )
-        .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
-        // todo delete
-        sqlx::query(
-            r#"
-delete from todos where id=$1
+    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
+        with_operation(|| format!("update todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            if let Some(parent_id) = payload.parent_id {
+                // メモリ上の実装と同じく、Todoが存在しない場合は親の検証より先にNotFoundとする
+                ensure_todo_exists(&mut tx, self.user_id, id).await?;
+                ensure_valid_parent(&mut tx, self.user_id, Some(id), parent_id).await?;
+            }
+
+            // todo update
+            // Noneの項目はcoalesceで現在の値を残すため、事前に読み取る必要がなく他の更新とも競合しない
+            // 繰り返しの判定に使うため、beforeから更新前の完了状態を返す
+            let updated = sqlx::query_as::<_, (Option<Recurrence>, bool)>(
+                r#"
+update todos set
+    text = coalesce($1, text),
+    completed = coalesce($2, completed),
+    due_date = coalesce($3, due_date),
+    priority = coalesce($4, priority),
+    parent_id = coalesce($7, parent_id),
+    recurrence = coalesce($8, recurrence),
+    -- 右辺のcompletedは更新前の値を指す、未完了から完了になった時だけ日時を記録する
+    completed_at = case
+        when $2 is null or $2 = completed then completed_at
+        when $2 then now()
+        else null
+    end,
+    version = version + 1,
+    updated_at = now()
+from (select completed as was_completed from todos where id = $5 for update) as before
+where id=$5 and deleted_at is null and user_id=$9 and ($6::int4 is null or version = $6)
+returning recurrence, completed and not before.was_completed
         "#,
-        )
-        .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+            )
+            .bind(payload.text)
+            .bind(payload.completed)
+            .bind(payload.due_date)
+            .bind(payload.priority)
+            .bind(id)
+            .bind(payload.expected_version)
+            .bind(payload.parent_id)
+            .bind(payload.recurrence)
+            .bind(self.user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some((recurrence, completed_now)) = updated else {
+                // Todoが存在するのに更新されなかった場合は、他の更新でversionが進んでいる
+                ensure_todo_exists(&mut tx, self.user_id, id).await?;
+                return Err(RepositoryError::Conflict(id).into());
+            };
 
-        tx.commit().await?;
+            // labelsがNoneの場合は既存の紐付けをそのまま残す
+            if let Some(labels) = payload.labels {
+                replace_labels(&mut tx, id, &labels).await?;
+            };
 
-        Ok(())
-    }
-}
+            record_audit(&mut tx, &[id], AuditAction::Update).await?;
 
-#[async_trait]
-pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity>;
-    async fn find(&self, id: i32) -> anyhow::Result<TodoEntity>;
-    async fn all(&self) -> anyhow::Result<Vec<TodoEntity>>;
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
-}
-
-// todosテーブルのみ
-#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
-struct TodoFromRow {
-    id: i32,
-    text: String,
-    completed: bool,
-}
+            // 繰り返しのTodoを完了にした場合、完了したものは残して次の回を作る
+            let next_id = match (recurrence, completed_now) {
+                (Some(recurrence), true) => {
+                    Some(create_next_occurrence(&mut tx, id, recurrence).await?)
+                }
+                _ => None,
+            };
 
-// OUTER JOIN
-#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
-struct TodoWithLabelFromRow {
-    id: i32,
-    text: String,
-    completed: bool,
-    label_id: Option<i32>,
-    label_name: Option<String>,
-}
+            tx.commit().await?;
+            self.publish(TodoEventKind::Updated, &[id]);
+            self.publish(TodoEventKind::Created, next_id.as_slice());
+            let todo = self.find(id).await?;
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct TodoEntity {
-    pub id: i32,
-    pub text: String,
-    pub completed: bool,
-    pub labels: Vec<Label>,
-}
+            Ok(todo)
+        })
+        .await
+    }
 
-fn fold_entities(rows: Vec<TodoWithLabelFromRow>) -> Vec<TodoEntity> {
-    let mut rows = rows.iter();
-    let mut accum: Vec<TodoEntity> = vec![];
-    'outer: while let Some(row) = rows.next() {
-        let mut todos = accum.iter_mut();
-        while let Some(todo) = todos.next() {
-            // idが一致＝Todoに紐づくラベルが複数存在している
-            if todo.id == row.id {
-                todo.labels.push(Label {
-                    id: row.label_id.unwrap(),
-                    name: row.label_name.clone().unwrap(),
-                });
-                continue 'outer;
+    async fn replace(&self, id: i32, payload: ReplaceTodo) -> anyhow::Result<TodoEntity> {
+        with_operation(|| format!("replace todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            if let Some(parent_id) = payload.parent_id {
+                ensure_valid_parent(&mut tx, self.user_id, Some(id), parent_id).await?;
             }
-        }
 
-        // Todoのidに一致がなかった時のみ到達、TodoEntityを作成
-        let labels = if row.label_id.is_some() {
-            vec![Label {
-                id: row.label_id.unwrap(),
-                name: row.label_name.clone().unwrap(),
-            }]
-        } else {
-            vec![]
-        };
+            // updateと違い、全ての項目をリクエストの値で置き換える
+            sqlx::query(
+                r#"
+update todos set
+    text = $1,
+    completed = $2,
+    due_date = $3,
+    priority = $4,
+    parent_id = $6,
+    recurrence = $7,
+    completed_at = case
+        when $2 = completed then completed_at
+        when $2 then now()
+        else null
+    end,
+    version = version + 1,
+    updated_at = now()
+where id=$5 and deleted_at is null and user_id=$8
+returning id
+        "#,
+            )
+            .bind(payload.text)
+            .bind(payload.completed)
+            .bind(payload.due_date)
+            .bind(payload.priority)
+            .bind(id)
+            .bind(payload.parent_id)
+            .bind(payload.recurrence)
+            .bind(self.user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(RepositoryError::NotFound(id))?;
 
-        accum.push(TodoEntity {
-            id: row.id,
-            text: row.text.clone(),
-            completed: row.completed,
-            labels,
-        });
+            replace_labels(&mut tx, id, &payload.labels).await?;
+            record_audit(&mut tx, &[id], AuditAction::Update).await?;
+
+            tx.commit().await?;
+            self.publish(TodoEventKind::Updated, &[id]);
+            let todo = self.find(id).await?;
+
+            Ok(todo)
+        })
+        .await
     }
-    accum
-}
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
-pub struct CreateTodo {
-    #[validate(length(min = 1, message = "Can not be empty"))]
-    #[validate(length(max = 100, message = "Over text length"))]
-    text: String,
-    labels: Vec<i32>,
-}
+    async fn toggle(&self, id: i32) -> anyhow::Result<TodoEntity> {
+        with_operation(|| format!("toggle todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            // 現在の値を読んでから書き込むと同時に反転された時に打ち消し合うため、1つのupdateで反転する
+            let (recurrence, completed) = sqlx::query_as::<_, (Option<Recurrence>, bool)>(
+                r#"
+update todos set
+    completed = not completed,
+    completed_at = case when completed then null else now() end,
+    version = version + 1,
+    updated_at = now()
+where id=$1 and deleted_at is null and user_id=$2
+returning recurrence, completed
+        "#,
+            )
+            .bind(id)
+            .bind(self.user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(RepositoryError::NotFound(id))?;
+            record_audit(&mut tx, &[id], AuditAction::Update).await?;
+            let next_id = match (recurrence, completed) {
+                (Some(recurrence), true) => {
+                    Some(create_next_occurrence(&mut tx, id, recurrence).await?)
+                }
+                _ => None,
+            };
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
-pub struct UpdateTodo {
-    #[validate(length(min = 1, message = "Can not be empty"))]
-    #[validate(length(max = 100, message = "Over text length"))]
-    text: Option<String>,
-    completed: Option<bool>,
-    labels: Option<Vec<i32>>,
-}
+            tx.commit().await?;
+            self.publish(TodoEventKind::Updated, &[id]);
+            self.publish(TodoEventKind::Created, next_id.as_slice());
+            let todo = self.find(id).await?;
+            Ok(todo)
+        })
+        .await
+    }
 
-#[cfg(test)]
-#[cfg(feature = "database-test")]
-mod test {
-    use super::*;
-    use dotenv::dotenv;
-    use sqlx::PgPool;
-    use std::env;
+    async fn complete_all(&self, dry_run: bool) -> anyhow::Result<Vec<i32>> {
+        with_operation(
+            || format!("complete all todos dry_run={}", dry_run),
+            async {
+                if dry_run {
+                    let ids = self
+                        .retry
+                        .run(|| async {
+                            sqlx::query_scalar::<_, i32>(
+                                r#"
+select id from todos
+where completed = false and deleted_at is null and user_id = $1
+order by id asc;
+            "#,
+                            )
+                            .bind(self.user_id)
+                            .fetch_all(&mut *self.connection().await?)
+                            .await
+                        })
+                        .await?;
+                    return Ok(ids);
+                }
 
-    #[test]
-    fn fold_entities_test() {
-        let label_1 = Label {
-            id: 1,
-            name: String::from("label 1"),
-        };
-        let label_2 = Label {
-            id: 2,
-            name: String::from("label 2"),
-        };
-        let rows = vec![
-            TodoWithLabelFromRow {
-                id: 1,
-                text: String::from("todo 1"),
-                completed: false,
-                label_id: Some(label_1.id),
-                label_name: Some(label_1.name.clone()),
+                // 更新した行をそのまま履歴に追加し、繰り返しの設定と合わせてidを返す
+                let mut tx = self.begin().await?;
+                let mut rows = sqlx::query_as::<_, (i32, Option<Recurrence>)>(
+                    r#"
+with updated as (
+    update todos set
+        completed = true,
+        completed_at = now(),
+        version = version + 1,
+        updated_at = now()
+    where completed = false and deleted_at is null and user_id = $1
+    returning *
+), audited as (
+    insert into todo_audit (todo_id, action, snapshot)
+    select id, 'update', to_jsonb(updated) from updated
+)
+select id, recurrence from updated
+        "#,
+                )
+                .bind(self.user_id)
+                .fetch_all(&mut *tx)
+                .await?;
+                rows.sort_unstable_by_key(|(id, _)| *id);
+
+                // update・toggleと同様に、繰り返しのTodoは次の回を作る
+                let mut next_ids = vec![];
+                for (id, recurrence) in &rows {
+                    if let Some(recurrence) = recurrence {
+                        next_ids.push(create_next_occurrence(&mut tx, *id, *recurrence).await?);
+                    }
+                }
+
+                tx.commit().await?;
+                let ids: Vec<i32> = rows.into_iter().map(|(id, _)| id).collect();
+                self.publish(TodoEventKind::Updated, &ids);
+                self.publish(TodoEventKind::Created, &next_ids);
+
+                Ok(ids)
             },
-            TodoWithLabelFromRow {
-                id: 1,
-                text: String::from("todo 1"),
-                completed: false,
-                label_id: Some(label_2.id),
-                label_name: Some(label_2.name.clone()),
+        )
+        .await
+    }
+
+    async fn add_label(&self, todo_id: i32, label_id: i32) -> anyhow::Result<TodoEntity> {
+        with_operation(
+            || format!("add label to todo id={} label_id={}", todo_id, label_id),
+            async {
+                let mut tx = self.begin().await?;
+                ensure_todo_exists(&mut tx, self.user_id, todo_id).await?;
+                ensure_labels_exist(&mut tx, &[label_id]).await?;
+                let mut attached: Vec<i32> = sqlx::query_scalar(
+                    r#"
+select label_id from todo_labels where todo_id = $1
+        "#,
+                )
+                .bind(todo_id)
+                .fetch_all(&mut *tx)
+                .await?;
+                attached.push(label_id);
+                ensure_label_limit(&attached)?;
+
+                // 既に紐付いている場合は何もしない
+                sqlx::query(
+                    r#"
+insert into todo_labels (todo_id, label_id)
+select $1, $2
+where not exists (
+    select 1 from todo_labels where todo_id = $1 and label_id = $2
+);
+        "#,
+                )
+                .bind(todo_id)
+                .bind(label_id)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+                self.publish(TodoEventKind::Updated, &[todo_id]);
+                let todo = self.find(todo_id).await?;
+
+                Ok(todo)
             },
-            TodoWithLabelFromRow {
-                id: 2,
-                text: String::from("todo 2"),
-                completed: false,
-                label_id: Some(label_1.id),
-                label_name: Some(label_1.name.clone()),
+        )
+        .await
+    }
+
+    async fn assign_label(
+        &self,
+        label_id: i32,
+        todo_ids: Vec<i32>,
+    ) -> anyhow::Result<AssignResult> {
+        with_operation(
+            || format!("assign label id={} to todos ids={:?}", label_id, todo_ids),
+            async {
+                let mut tx = self.begin().await?;
+                sqlx::query("select id from labels where id = $1")
+                    .bind(label_id)
+                    .fetch_optional(&mut *tx)
+                    .await?
+                    .ok_or(RepositoryError::LabelNotFound(label_id))?;
+
+                let existing: Vec<(i32,)> = sqlx::query_as(
+                    r#"
+select id from todos where id = any($1) and deleted_at is null and user_id = $2
+        "#,
+                )
+                .bind(&todo_ids)
+                .bind(self.user_id)
+                .fetch_all(&mut *tx)
+                .await?;
+                let (found, not_found) = partition_ids(todo_ids.clone(), |id| {
+                    existing.iter().any(|(existing_id,)| *existing_id == id)
+                });
+
+                // 上限に達しているTodoが1件でもあれば、どのTodoにも付けない
+                let full: Option<i32> = sqlx::query_scalar(
+                    r#"
+select todo_id from todo_labels
+where todo_id = any($1)
+group by todo_id
+having count(*) >= $2 and not bool_or(label_id = $3)
+order by todo_id
+limit 1
+        "#,
+                )
+                .bind(&found)
+                .bind(MAX_LABELS_PER_TODO as i64)
+                .bind(label_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+                if let Some(todo_id) = full {
+                    return Err(label_limit_reached(todo_id));
+                }
+
+                // 既に紐付いている組み合わせは飛ばす
+                let added: Vec<(i32,)> = sqlx::query_as(
+                    r#"
+insert into todo_labels (todo_id, label_id)
+select todo_id, $2 from unnest($1::int[]) as ids(todo_id)
+where not exists (
+    select 1 from todo_labels where todo_labels.todo_id = ids.todo_id and label_id = $2
+)
+returning todo_id;
+        "#,
+                )
+                .bind(&found)
+                .bind(label_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+                let added: Vec<i32> = added.into_iter().map(|(id,)| id).collect();
+                self.publish(TodoEventKind::Updated, &added);
+
+                Ok(AssignResult {
+                    added: added.len() as u64,
+                    not_found,
+                })
             },
-        ];
-        let res = fold_entities(rows);
-        assert_eq!(
-            res,
-            vec![
-                TodoEntity {
-                    id: 1,
-                    text: String::from("todo 1"),
-                    completed: false,
-                    labels: vec![label_1.clone(), label_2.clone()],
-                },
-                TodoEntity {
-                    id: 2,
-                    text: String::from("todo 2"),
-                    completed: false,
-                    labels: vec![label_1.clone()],
-                },
-            ]
-        );
+        )
+        .await
     }
 
-    #[tokio::test]
-    async fn crud_scenario() {
-        dotenv().ok();
-        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
-        let pool = PgPool::connect(database_url)
-            .await
-            .expect(&format!("fail connect database, url is [{}]", database_url));
+    async fn update_labels(
+        &self,
+        todo_id: i32,
+        add: Vec<i32>,
+        remove: Vec<i32>,
+    ) -> anyhow::Result<TodoEntity> {
+        with_operation(
+            || {
+                format!(
+                    "update labels of todo id={} add={:?} remove={:?}",
+                    todo_id, add, remove
+                )
+            },
+            async {
+                let mut tx = self.begin().await?;
+                ensure_todo_exists(&mut tx, self.user_id, todo_id).await?;
+                ensure_labels_exist(&mut tx, &add).await?;
+                let attached: Vec<i32> = sqlx::query_scalar(
+                    r#"
+select label_id from todo_labels where todo_id = $1
+        "#,
+                )
+                .bind(todo_id)
+                .fetch_all(&mut *tx)
+                .await?;
+                let labels: Vec<i32> = attached
+                    .into_iter()
+                    .chain(add.iter().copied())
+                    .filter(|id| !remove.contains(id))
+                    .collect();
+                ensure_label_limit(&labels)?;
 
-        // label data prepare
-        let label_name = String::from("test label");
-        let optional_label = sqlx::query_as::<_, Label>(
-            r#"
-select * from labels where name = $1
+                // 付いているラベルの追加と、付いていないラベルの削除は何もしない
+                sqlx::query(
+                    r#"
+insert into todo_labels (todo_id, label_id)
+select distinct $1, label_id from unnest($2::int[]) as ids(label_id)
+where not exists (
+    select 1 from todo_labels where todo_labels.todo_id = $1 and todo_labels.label_id = ids.label_id
+);
+        "#,
+                )
+                .bind(todo_id)
+                .bind(&add)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query(
+                    r#"
+delete from todo_labels where todo_id = $1 and label_id = any($2)
         "#,
+                )
+                .bind(todo_id)
+                .bind(&remove)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+                self.publish(TodoEventKind::Updated, &[todo_id]);
+                let todo = self.find(todo_id).await?;
+
+                Ok(todo)
+            },
         )
-        .bind(label_name.clone())
-        .fetch_optional(&pool)
         .await
-        .expect("Failed to prepare label data.");
-        let label_1 = if let Some(label) = optional_label {
-            label
-        } else {
-            let label = sqlx::query_as::<_, Label>(
+    }
+
+    async fn remove_label(&self, todo_id: i32, label_id: i32) -> anyhow::Result<()> {
+        with_operation(
+            || {
+                format!(
+                    "remove label from todo id={} label_id={}",
+                    todo_id, label_id
+                )
+            },
+            async {
+                let mut tx = self.begin().await?;
+                ensure_todo_exists(&mut tx, self.user_id, todo_id).await?;
+
+                sqlx::query(
+                    r#"
+delete from todo_labels where todo_id = $1 and label_id = $2
+        "#,
+                )
+                .bind(todo_id)
+                .bind(label_id)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+                self.publish(TodoEventKind::Updated, &[todo_id]);
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    async fn delete(&self, id: i32, cascade: bool) -> anyhow::Result<()> {
+        with_operation(|| format!("delete todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            ensure_todo_exists(&mut tx, self.user_id, id).await?;
+            // 論理削除済みの子孫は対象外
+            let descendants: Vec<i32> = descendant_ids(&mut tx, id)
+                .await?
+                .into_iter()
+                .filter(|(_, deleted)| !deleted)
+                .map(|(id, _)| id)
+                .collect();
+            if !descendants.is_empty() && !cascade {
+                return Err(RepositoryError::Conflict(id).into());
+            }
+
+            // 論理削除、ラベルの紐付けは復元に備えて残しておく
+            let mut ids = descendants;
+            ids.push(id);
+            sqlx::query(
                 r#"
-insert into labels ( name )
-values ( $1 )
-returning *
-            "#,
+update todos set deleted_at = now()
+where id = any($1) and deleted_at is null
+        "#,
             )
-            .bind(label_name)
-            .fetch_one(&pool)
-            .await
-            .expect("Failed to insert label data.");
-            label
-        };
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+            record_audit(&mut tx, &ids, AuditAction::Delete).await?;
+
+            tx.commit().await?;
+            self.publish(TodoEventKind::Deleted, &ids);
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn restore(&self, id: i32) -> anyhow::Result<TodoEntity> {
+        with_operation(|| format!("restore todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            let result = sqlx::query(
+                r#"
+update todos set deleted_at = null
+where id=$1 and deleted_at is not null and user_id=$2
+        "#,
+            )
+            .bind(id)
+            .bind(self.user_id)
+            .execute(&mut *tx)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            record_audit(&mut tx, &[id], AuditAction::Restore).await?;
+
+            tx.commit().await?;
+            self.publish(TodoEventKind::Created, &[id]);
+            let todo = self.find(id).await?;
+            Ok(todo)
+        })
+        .await
+    }
+
+    async fn purge(&self, id: i32, cascade: bool) -> anyhow::Result<()> {
+        with_operation(|| format!("purge todo id={}", id), async {
+            let mut tx = self.begin().await?;
+            // 論理削除済みのものも対象にするため、ensure_todo_existsは使えない
+            sqlx::query("select id from todos where id = $1 and user_id = $2")
+                .bind(id)
+                .bind(self.user_id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or(RepositoryError::NotFound(id))?;
+            // 論理削除済みの子孫も外部キーで参照しているため対象にする
+            let mut ids: Vec<i32> = descendant_ids(&mut tx, id)
+                .await?
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            if !ids.is_empty() && !cascade {
+                return Err(RepositoryError::Conflict(id).into());
+            }
+            ids.push(id);
+
+            // 削除すると行を参照できなくなるため、先に履歴を残す
+            record_audit(&mut tx, &ids, AuditAction::Purge).await?;
+            // todo's label delete
+            sqlx::query(
+                r#"
+delete from todo_labels where todo_id = any($1)
+        "#,
+            )
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+            // todo delete(論理削除済みのものも対象)
+            let result = sqlx::query(
+                r#"
+delete from todos where id = any($1)
+        "#,
+            )
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+
+            tx.commit().await?;
+            self.publish(TodoEventKind::Deleted, &ids);
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn reorder(&self, ordered_ids: Vec<i32>) -> anyhow::Result<()> {
+        with_operation(|| "reorder todos".to_string(), async {
+            let mut tx = self.begin().await?;
+            // 検証から更新までの間に追加・削除されないよう行をロックする
+            let existing: Vec<i32> = sqlx::query_scalar(
+                r#"
+select id from todos where deleted_at is null and user_id = $1 for update
+        "#,
+            )
+            .bind(self.user_id)
+            .fetch_all(&mut *tx)
+            .await?;
+            ensure_complete_order(&ordered_ids, &existing.into_iter().collect())?;
+
+            // 並び順は内容の変更ではないため、versionと履歴は更新しない
+            sqlx::query(
+                r#"
+update todos set position = t.position
+from unnest($1::int4[]) with ordinality as t(id, position)
+where todos.id = t.id
+        "#,
+            )
+            .bind(&ordered_ids)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+            self.publish(TodoEventKind::Updated, &ordered_ids);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_completed(&self, dry_run: bool) -> anyhow::Result<Vec<i32>> {
+        with_operation(
+            || format!("delete completed todos dry_run={}", dry_run),
+            async {
+                // 完了済みのTodoを物理削除する、論理削除済みのものは復元に備えて残す
+                // 子を持つTodoは子が宙に浮かないよう残す
+                if dry_run {
+                    let ids = self
+                        .retry
+                        .run(|| async {
+                            sqlx::query_scalar::<_, i32>(
+                                r#"
+select id from todos
+where completed and deleted_at is null and user_id = $1
+    and not exists (select 1 from todos children where children.parent_id = todos.id)
+order by id asc;
+            "#,
+                            )
+                            .bind(self.user_id)
+                            .fetch_all(&mut *self.connection().await?)
+                            .await
+                        })
+                        .await?;
+                    return Ok(ids);
+                }
+
+                let mut tx = self.begin().await?;
+                // 削除した行をそのまま履歴に追加する
+                let mut ids: Vec<i32> = sqlx::query_scalar(
+                    r#"
+with deleted as (
+    delete from todos
+    where completed and deleted_at is null and user_id = $1
+        and not exists (select 1 from todos children where children.parent_id = todos.id)
+    returning *
+)
+insert into todo_audit (todo_id, action, snapshot)
+select id, 'purge', to_jsonb(deleted) from deleted
+returning todo_id
+        "#,
+                )
+                .bind(self.user_id)
+                .fetch_all(&mut *tx)
+                .await?;
+                sqlx::query(
+                    r#"
+delete from todo_labels where todo_id = any($1)
+        "#,
+                )
+                .bind(&ids)
+                .execute(&mut *tx)
+                .await?;
+
+                tx.commit().await?;
+                ids.sort_unstable();
+                self.publish(TodoEventKind::Deleted, &ids);
+
+                Ok(ids)
+            },
+        )
+        .await
+    }
+
+    async fn delete_many(&self, ids: Vec<i32>) -> anyhow::Result<u64> {
+        with_operation(|| format!("delete todos ids={:?}", ids), async {
+            let mut tx = self.begin().await?;
+            // 論理削除済みのTodoは復元に備えて残す
+            // 対象外の子を持つTodoは子が宙に浮かないよう残し、残したTodoの親も同じく残す
+            let mut deleted: Vec<i32> = sqlx::query_scalar(
+                r#"
+with recursive targets as (
+    select id from todos
+    where id = any($1) and deleted_at is null and user_id = $2
+),
+blocked as (
+    select todos.id, todos.parent_id from todos
+    where todos.id in (select id from targets)
+        and exists (
+            select 1 from todos children
+            where children.parent_id = todos.id and children.id not in (select id from targets)
+        )
+    union
+    select parents.id, parents.parent_id from todos parents
+        join blocked on parents.id = blocked.parent_id
+    where parents.id in (select id from targets)
+),
+deleted as (
+    delete from todos
+    where id in (select id from targets) and id not in (select id from blocked)
+    returning *
+)
+insert into todo_audit (todo_id, action, snapshot)
+select id, 'purge', to_jsonb(deleted) from deleted
+returning todo_id
+        "#,
+            )
+            .bind(&ids)
+            .bind(self.user_id)
+            .fetch_all(&mut *tx)
+            .await?;
+            sqlx::query(
+                r#"
+delete from todo_labels where todo_id = any($1)
+        "#,
+            )
+            .bind(&deleted)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+            deleted.sort_unstable();
+            self.publish(TodoEventKind::Deleted, &deleted);
+
+            Ok(deleted.len() as u64)
+        })
+        .await
+    }
+
+    async fn history(&self, id: i32) -> anyhow::Result<Vec<TodoAudit>> {
+        with_operation(|| format!("get history of todo id={}", id), async {
+            let audits = self
+                .retry
+                .run(|| async {
+                    sqlx::query_as::<_, TodoAudit>(
+                        r#"
+select * from todo_audit where todo_id = $1 and (snapshot->>'user_id')::int4 = $2
+order by id asc;
+        "#,
+                    )
+                    .bind(id)
+                    .bind(self.user_id)
+                    .fetch_all(&mut *self.connection().await?)
+                    .await
+                })
+                .await?;
+            // 一度も作成されていないTodoには履歴がない
+            if audits.is_empty() {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+
+            Ok(audits)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> anyhow::Result<()> {
+        with_operation(|| "ping database".to_string(), async {
+            sqlx::query("select 1")
+                .execute(&mut *self.connection().await?)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+        self.events.subscribe()
+    }
+
+    async fn with_transaction<R, F>(&self, f: F) -> anyhow::Result<R>
+    where
+        R: Send,
+        F: for<'a> FnOnce(&'a Self) -> TransactionFuture<'a, R> + Send,
+    {
+        // 入れ子になった場合は外側のトランザクションに含める
+        if self.transaction.is_some() {
+            return f(self).await;
+        }
+        with_operation(|| "run transaction".to_string(), async {
+            let repository = Self {
+                events: self.events.buffered(),
+                transaction: Some(Arc::new(SharedTransaction::new(self.pool.begin().await?))),
+                ..self.clone()
+            };
+            let result = f(&repository).await;
+            // 処理の中でクローンされたリポジトリが残っていても、以降はトランザクションを使わせない
+            let shared = repository.transaction.as_ref().unwrap();
+            let mut tx = shared
+                .transaction
+                .try_lock()
+                .ok()
+                .and_then(|mut guard| guard.take())
+                .context("transaction is still in use")?;
+            match result {
+                Ok(value) => {
+                    shared.rollback_pending(&mut tx).await?;
+                    tx.commit().await?;
+                    repository.events.flush();
+                    Ok(value)
+                }
+                Err(e) => {
+                    tx.rollback().await?;
+                    Err(e)
+                }
+            }
+        })
+        .await
+    }
+}
+
+// 他のユーザーのTodoは存在を知られないよう、存在しない場合と同じくNotFoundとする
+async fn ensure_todo_exists(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    id: i32,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+select id from todos where id = $1 and deleted_at is null and user_id = $2
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(tx)
+    .await?
+    .ok_or(RepositoryError::NotFound(id))?;
+
+    Ok(())
+}
+
+// 親に指定できるのは同じユーザーの論理削除されていない既存のTodoのみ
+// 自身や自身の子孫を親にすると循環するため弾く
+async fn ensure_valid_parent(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    id: Option<i32>,
+    parent_id: i32,
+) -> anyhow::Result<()> {
+    sqlx::query("select id from todos where id = $1 and deleted_at is null and user_id = $2")
+        .bind(parent_id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| parent_not_found(parent_id))?;
+    let Some(id) = id else {
+        return Ok(());
+    };
+
+    let (cyclic,) = sqlx::query_as::<_, (bool,)>(
+        r#"
+with recursive ancestors as (
+    select id, parent_id from todos where id = $1
+    union
+    select todos.id, todos.parent_id from todos
+        join ancestors on todos.id = ancestors.parent_id
+)
+select exists(select 1 from ancestors where id = $2)
+        "#,
+    )
+    .bind(parent_id)
+    .bind(id)
+    .fetch_one(tx)
+    .await?;
+    if cyclic {
+        return Err(cyclic_parent(parent_id).into());
+    }
+
+    Ok(())
+}
+
+// 完了したTodoを複製し、次の期限を設定した未完了のTodoを作る
+// 期限が未設定の場合は今日を起点にする、作ったTodoのidを返す
+async fn create_next_occurrence(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    recurrence: Recurrence,
+) -> anyhow::Result<i32> {
+    let (due_date,) =
+        sqlx::query_as::<_, (Option<NaiveDate>,)>("select due_date from todos where id = $1")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+    let due_date = recurrence.next(due_date.unwrap_or_else(|| Local::now().date_naive()));
+
+    let (next_id,) = sqlx::query_as::<_, (i32,)>(
+        r#"
+insert into todos (text, completed, due_date, priority, parent_id, recurrence, user_id)
+select text, false, $2, priority, parent_id, recurrence, user_id from todos where id = $1
+returning id
+        "#,
+    )
+    .bind(id)
+    .bind(due_date)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+insert into todo_labels (todo_id, label_id)
+select $2, label_id from todo_labels where todo_id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(next_id)
+    .execute(&mut *tx)
+    .await?;
+
+    record_audit(tx, &[next_id], AuditAction::Create).await?;
+
+    Ok(next_id)
+}
+
+fn parent_not_found(parent_id: i32) -> RepositoryError {
+    RepositoryError::Validation(format!("parent todo {} does not exist", parent_id))
+}
+
+fn cyclic_parent(parent_id: i32) -> RepositoryError {
+    RepositoryError::Validation(format!(
+        "todo {} can not be a parent of itself or its ancestors",
+        parent_id
+    ))
+}
+
+// 存在しないid・重複・指定漏れはいずれもValidationエラーとする
+fn ensure_complete_order(ordered_ids: &[i32], existing: &HashSet<i32>) -> anyhow::Result<()> {
+    let mut seen = HashSet::new();
+    for id in ordered_ids {
+        if !existing.contains(id) {
+            return Err(RepositoryError::Validation(format!("todo {} does not exist", id)).into());
+        }
+        if !seen.insert(*id) {
+            return Err(RepositoryError::Validation(format!("todo {} is duplicated", id)).into());
+        }
+    }
+    if let Some(missing) = existing.difference(&seen).min() {
+        return Err(RepositoryError::Validation(format!("todo {} is missing", missing)).into());
+    }
+    Ok(())
+}
+
+// 子孫のidと論理削除済みかどうかを返す
+async fn descendant_ids(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+) -> anyhow::Result<Vec<(i32, bool)>> {
+    let rows = sqlx::query_as::<_, (i32, bool)>(
+        r#"
+with recursive descendants as (
+    select id, deleted_at from todos where parent_id = $1
+    union
+    select todos.id, todos.deleted_at from todos
+        join descendants on todos.parent_id = descendants.id
+)
+select id, deleted_at is not null from descendants
+        "#,
+    )
+    .bind(id)
+    .fetch_all(tx)
+    .await?;
+
+    Ok(rows)
+}
+
+// 変更後のtodosの行をスナップショットとして履歴に追加する
+async fn record_audit(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+    action: AuditAction,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+insert into todo_audit (todo_id, action, snapshot)
+select id, $2, to_jsonb(todos) from todos where id = any($1)
+        "#,
+    )
+    .bind(ids)
+    .bind(action)
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+// Todoに紐付くラベルをlabelsで置き換える
+async fn replace_labels(
+    tx: &mut Transaction<'_, Postgres>,
+    todo_id: i32,
+    labels: &[i32],
+) -> anyhow::Result<()> {
+    ensure_label_limit(labels)?;
+    ensure_labels_exist(tx, labels).await?;
+
+    // 一度関連するレコードを削除
+    sqlx::query(
+        r#"
+delete from todo_labels where todo_id=$1
+        "#,
+    )
+    .bind(todo_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+insert into todo_labels (todo_id, label_id)
+select distinct $1, id
+from unnest($2) as t(id);
+        "#,
+    )
+    .bind(todo_id)
+    .bind(labels)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+// 重複を除いた上で、条件を満たすidと満たさないidに分ける
+fn partition_ids(mut ids: Vec<i32>, pred: impl Fn(i32) -> bool) -> (Vec<i32>, Vec<i32>) {
+    ids.sort_unstable();
+    ids.dedup();
+    ids.into_iter().partition(|id| pred(*id))
+}
+
+// 1つのTodoに付けるラベルの数(重複は1つと数える)が上限を超えていればValidationエラーを返す
+fn ensure_label_limit(label_ids: &[i32]) -> anyhow::Result<()> {
+    let count = label_ids.iter().collect::<HashSet<_>>().len();
+    if count > MAX_LABELS_PER_TODO {
+        return Err(RepositoryError::Validation(format!(
+            "a todo can have at most {} labels, but {} were given",
+            MAX_LABELS_PER_TODO, count
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn label_limit_reached(todo_id: i32) -> anyhow::Error {
+    RepositoryError::Validation(format!(
+        "todo {} already has {} labels",
+        todo_id, MAX_LABELS_PER_TODO
+    ))
+    .into()
+}
+
+// 指定されたラベルが全て存在するか確認し、存在しないものがあればValidationエラーを返す
+async fn ensure_labels_exist(
+    tx: &mut Transaction<'_, Postgres>,
+    label_ids: &[i32],
+) -> anyhow::Result<()> {
+    let existing: Vec<(i32,)> = sqlx::query_as(
+        r#"
+select id from labels where id = any($1)
+        "#,
+    )
+    .bind(label_ids)
+    .fetch_all(tx)
+    .await?;
+
+    match label_ids
+        .iter()
+        .find(|id| !existing.iter().any(|(existing_id,)| existing_id == *id))
+    {
+        Some(id) => Err(RepositoryError::Validation(format!("label {} does not exist", id)).into()),
+        None => Ok(()),
+    }
+}
+
+#[async_trait]
+pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    // user_idのユーザーが所有するTodoのみを読み書きするリポジトリを返す
+    // 他のユーザーのTodoは存在しないものとして扱う
+    fn for_user(&self, user_id: i32) -> Self;
+    async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity>;
+    async fn create_many(&self, payloads: Vec<CreateTodo>) -> anyhow::Result<Vec<TodoEntity>>;
+    async fn find(&self, id: i32) -> anyhow::Result<TodoEntity>;
+    async fn find_including_deleted(&self, id: i32) -> anyhow::Result<TodoEntity>;
+    async fn find_many(&self, ids: Vec<i32>) -> anyhow::Result<Vec<TodoEntity>>;
+    async fn all(
+        &self,
+        filter: TodoFilter,
+        sort: TodoSort,
+        pagination: Pagination,
+    ) -> anyhow::Result<Vec<TodoEntity>>;
+    // allと違いページングせずに全件を取得した順に返す、途中でエラーになった場合はErrを最後に返す
+    fn stream_all(&self, filter: TodoFilter, sort: TodoSort) -> TodoStream;
+    async fn count(&self, filter: TodoFilter) -> anyhow::Result<i64>;
+    // 論理削除されていないTodoの件数をラベルごとの内訳とともに集計する
+    async fn stats(&self) -> anyhow::Result<TodoStats>;
+    // 複数のラベルが付いたTodoはそれぞれのグループに含める
+    async fn group_by_label(&self) -> anyhow::Result<Vec<TodoGroup>>;
+    async fn search(&self, query: String) -> anyhow::Result<Vec<TodoEntity>>;
+    // 前後の空白を除いたtextと完全に一致するTodoを返す、作成時の重複の確認に使う
+    async fn find_by_text(&self, text: &str) -> anyhow::Result<Vec<TodoEntity>>;
+    // 部分一致ではなく類似度で検索するため、多少の誤字があっても一致する
+    async fn fuzzy_search(&self, query: String) -> anyhow::Result<Vec<TodoEntity>>;
+    async fn find_by_label(&self, label_id: i32) -> anyhow::Result<Vec<TodoEntity>>;
+    // Todoに付いているラベルだけを返す、TodoがなければNotFound
+    async fn find_labels(&self, id: i32) -> anyhow::Result<Vec<Label>>;
+    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity>;
+    async fn replace(&self, id: i32, payload: ReplaceTodo) -> anyhow::Result<TodoEntity>;
+    async fn toggle(&self, id: i32) -> anyhow::Result<TodoEntity>;
+    // 完了にしたTodoのidを返す、dry_runの場合は変更せずに対象のidだけを返す
+    async fn complete_all(&self, dry_run: bool) -> anyhow::Result<Vec<i32>>;
+    async fn add_label(&self, todo_id: i32, label_id: i32) -> anyhow::Result<TodoEntity>;
+    async fn remove_label(&self, todo_id: i32, label_id: i32) -> anyhow::Result<()>;
+    // addのラベルを付けてからremoveのラベルを外す、両方に含まれるラベルは外れる
+    // addに存在しないラベルがあればValidationエラーを返し、何も変更しない
+    async fn update_labels(
+        &self,
+        todo_id: i32,
+        add: Vec<i32>,
+        remove: Vec<i32>,
+    ) -> anyhow::Result<TodoEntity>;
+    // 複数のTodoに同じラベルを付ける、付いていたTodoは飛ばし、存在しないTodoのidは結果で返す
+    async fn assign_label(&self, label_id: i32, todo_ids: Vec<i32>)
+        -> anyhow::Result<AssignResult>;
+    // 子孫があればcascadeの場合は子孫ごと削除し、それ以外はConflictを返す
+    async fn delete(&self, id: i32, cascade: bool) -> anyhow::Result<()>;
+    async fn restore(&self, id: i32) -> anyhow::Result<TodoEntity>;
+    async fn purge(&self, id: i32, cascade: bool) -> anyhow::Result<()>;
+    // 論理削除されていない全てのTodoのidを、並べたい順に過不足なく指定する
+    async fn reorder(&self, ordered_ids: Vec<i32>) -> anyhow::Result<()>;
+    // 削除したTodoのidを返す、dry_runの場合は変更せずに対象のidだけを返す
+    async fn delete_completed(&self, dry_run: bool) -> anyhow::Result<Vec<i32>>;
+    // 物理削除した件数を返す、存在しないidは無視する
+    async fn delete_many(&self, ids: Vec<i32>) -> anyhow::Result<u64>;
+    async fn history(&self, id: i32) -> anyhow::Result<Vec<TodoAudit>>;
+    async fn ping(&self) -> anyhow::Result<()>;
+    // 書き込みが成功するたびに通知されるTodoEventを購読する、他のユーザーの変更も含まれる
+    fn subscribe(&self) -> broadcast::Receiver<TodoEvent>;
+    // fの中で行った変更を1つのトランザクションにまとめ、Errを返した場合は全て取り消す
+    // 失敗したメソッドの変更は、fがそのエラーを握りつぶしてOkを返しても残さない
+    // 変更の通知もコミットした後にまとめて行う
+    async fn with_transaction<R, F>(&self, f: F) -> anyhow::Result<R>
+    where
+        R: Send,
+        F: for<'a> FnOnce(&'a Self) -> TransactionFuture<'a, R> + Send;
+}
+
+// WebSocketなどで購読者に送る変更通知、クライアントはidのTodoを取得し直して反映する
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TodoEvent {
+    #[serde(rename = "type")]
+    pub kind: TodoEventKind,
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32, // 送信先を所有者に絞るために使う
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TodoEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl TodoEventKind {
+    // SSEのイベント名に使う、シリアライズした値と同じ
+    pub fn name(&self) -> &'static str {
+        match self {
+            TodoEventKind::Created => "created",
+            TodoEventKind::Updated => "updated",
+            TodoEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+// 論理削除から復元したTodoは一覧に再び現れるため、作成として通知する
+impl From<AuditAction> for TodoEventKind {
+    fn from(action: AuditAction) -> Self {
+        match action {
+            AuditAction::Create | AuditAction::Restore => TodoEventKind::Created,
+            AuditAction::Update => TodoEventKind::Updated,
+            AuditAction::Delete | AuditAction::Purge => TodoEventKind::Deleted,
+        }
+    }
+}
+
+// 変更通知の送り先、for_userで作ったリポジトリ間でも共有する
+// トランザクションの中ではpendingに溜めておき、コミットするまで購読者には届けない
+#[derive(Debug, Clone)]
+struct TodoEvents {
+    sender: broadcast::Sender<TodoEvent>,
+    pending: Option<Arc<std::sync::Mutex<Vec<TodoEvent>>>>,
+}
+
+impl TodoEvents {
+    fn new() -> Self {
+        Self {
+            sender: broadcast::channel(TODO_EVENT_CAPACITY).0,
+            pending: None,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+        self.sender.subscribe()
+    }
+
+    // 送り先は同じで、送るのはflushを呼ぶまで待つ
+    fn buffered(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            pending: Some(Arc::default()),
+        }
+    }
+
+    // 溜めた件数に上限はなく、チャネルの容量を超える変更があっても全て送る
+    fn flush(&self) {
+        if let Some(pending) = &self.pending {
+            for event in pending.lock().unwrap().drain(..) {
+                let _ = self.sender.send(event);
+            }
+        }
+    }
+
+    // 購読者がいない場合のエラーは無視する
+    fn send(&self, event: TodoEvent) {
+        match &self.pending {
+            Some(pending) => pending.lock().unwrap().push(event),
+            None => {
+                let _ = self.sender.send(event);
+            }
+        }
+    }
+}
+
+fn publish(events: &TodoEvents, user_id: i32, kind: TodoEventKind, ids: &[i32]) {
+    for &id in ids {
+        events.send(TodoEvent { kind, id, user_id });
+    }
+}
+
+// todosテーブルのみ
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+struct TodoFromRow {
+    id: i32,
+    text: String,
+    completed: bool,
+    due_date: Option<NaiveDate>,
+    priority: Priority,
+    completed_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    version: i32,
+    parent_id: Option<i32>,
+    recurrence: Option<Recurrence>,
+    position: i32,
+    user_id: i32,
+}
+
+// OUTER JOIN
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+struct TodoWithLabelFromRow {
+    id: i32,
+    text: String,
+    completed: bool,
+    due_date: Option<NaiveDate>,
+    priority: Priority,
+    completed_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    version: i32,
+    parent_id: Option<i32>,
+    recurrence: Option<Recurrence>,
+    position: i32,
+    user_id: i32,
+    label_id: Option<i32>,
+    label_name: Option<String>,
+    label_color: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct TodoEntity {
+    #[serde(serialize_with = "crate::id_format::serialize_id")]
+    pub id: i32,
+    pub text: String,
+    pub completed: bool,
+    pub due_date: Option<NaiveDate>, // "2023-02-05"形式でシリアライズされる
+    pub priority: Priority,
+    pub completed_at: Option<NaiveDateTime>, // 完了した日時、未完了に戻すとNoneになる
+    pub created_at: NaiveDateTime,           // "2023-02-17T09:05:41.123456"形式でシリアライズされる
+    pub updated_at: NaiveDateTime,           // 内容を変更した日時、versionと同時に更新される
+    pub version: i32,                        // 更新のたびに1ずつ増える
+    pub parent_id: Option<i32>,              // サブタスクの場合は親のTodoのid
+    pub recurrence: Option<Recurrence>,      // 完了にすると次の期限で新しいTodoが作られる
+    pub position: i32,                       // 並べ替えた順序、作成時は末尾になる
+    pub user_id: i32,                        // 所有するユーザーのid
+    pub labels: Vec<Label>,
+}
+
+// DB上はpriority型(enum)、JSON上は"low" | "medium" | "high"で表現する
+// 宣言順がそのまま大小関係になる(Low < Medium < High)
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    sqlx::Type,
+    ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "priority", rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+// DB上はrecurrence型(enum)、JSON上は"daily" | "weekly" | "monthly"で表現する
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "recurrence", rename_all = "lowercase")]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+    Monthly, // 翌月に同じ日がない場合は月末になる(1/31 -> 2/28)
+}
+
+impl Recurrence {
+    pub fn next(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Recurrence::Daily => date + Days::new(1),
+            Recurrence::Weekly => date + Days::new(7),
+            Recurrence::Monthly => date + Months::new(1),
+        }
+    }
+}
+
+// 作成・更新・削除のたびにtodo_auditへ追加される変更履歴
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow, ToSchema)]
+pub struct TodoAudit {
+    pub id: i32,
+    pub todo_id: i32,
+    pub action: AuditAction,
+    #[schema(value_type = Object)]
+    pub snapshot: serde_json::Value, // 変更後(削除の場合は削除前)のTodo
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "audit_action", rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,  // 論理削除
+    Restore, // 論理削除からの復元
+    Purge,   // 物理削除
+}
+
+// 一括登録でpriority[]としてバインドするために必要
+impl PgHasArrayType for Priority {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_priority")
+    }
+}
+
+impl PgHasArrayType for Recurrence {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_recurrence")
+    }
+}
+
+// 1件のTodoに対応する行をまとめる、行が空の場合はpanicせずにエラーを返す
+fn fold_entity(rows: Vec<TodoWithLabelFromRow>) -> anyhow::Result<TodoEntity> {
+    fold_entities(rows)?.into_iter().next().ok_or_else(|| {
+        RepositoryError::Unexpected("expected 1 todo, but no rows".to_string()).into()
+    })
+}
+
+// グループはラベルのidの順に並べ、ラベルのないTodoのグループは空でも最後に置く
+// グループの中ではtodosの並び順を保つ
+fn group_todos_by_label(todos: Vec<TodoEntity>) -> Vec<TodoGroup> {
+    let mut groups: BTreeMap<i32, TodoGroup> = BTreeMap::new();
+    let mut unlabeled = vec![];
+    for todo in todos {
+        if todo.labels.is_empty() {
+            unlabeled.push(todo);
+            continue;
+        }
+        for label in todo.labels.iter() {
+            groups
+                .entry(label.id)
+                .or_insert_with(|| TodoGroup {
+                    label: Some(label.clone()),
+                    todos: vec![],
+                })
+                .todos
+                .push(todo.clone());
+        }
+    }
+    groups
+        .into_values()
+        .chain(std::iter::once(TodoGroup {
+            label: None,
+            todos: unlabeled,
+        }))
+        .collect()
+}
+
+fn fold_entities(rows: Vec<TodoWithLabelFromRow>) -> anyhow::Result<Vec<TodoEntity>> {
+    let mut accum: Vec<TodoEntity> = vec![];
+    'outer: for row in rows.iter() {
+        for todo in accum.iter_mut() {
+            // idが一致＝Todoに紐づくラベルが複数存在している
+            if todo.id == row.id {
+                todo.labels.extend(label_from_row(row));
+                continue 'outer;
+            }
+        }
+
+        // Todoのidに一致がなかった時のみ到達、TodoEntityを作成
+        accum.push(entity_from_row(row));
+    }
+    Ok(accum)
+}
+
+// 取得した順に1件ずつTodoにまとめる、同じTodoの行が連続して届くことを前提にする
+// 並べ替えの最後のキーは常にidのため、stream_allの結果はこれを満たす
+#[derive(Debug, Default)]
+struct TodoFold {
+    current: Option<TodoEntity>,
+}
+
+impl TodoFold {
+    // 別のTodoの行が届いた時点で、まとめ終わったTodoを返す
+    fn push(&mut self, row: &TodoWithLabelFromRow) -> Option<TodoEntity> {
+        if let Some(todo) = self.current.as_mut().filter(|todo| todo.id == row.id) {
+            todo.labels.extend(label_from_row(row));
+            return None;
+        }
+        self.current.replace(entity_from_row(row))
+    }
+
+    fn finish(self) -> Option<TodoEntity> {
+        self.current
+    }
+}
+
+fn entity_from_row(row: &TodoWithLabelFromRow) -> TodoEntity {
+    TodoEntity {
+        id: row.id,
+        text: row.text.clone(),
+        completed: row.completed,
+        due_date: row.due_date,
+        priority: row.priority,
+        completed_at: row.completed_at,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+        version: row.version,
+        parent_id: row.parent_id,
+        recurrence: row.recurrence,
+        position: row.position,
+        user_id: row.user_id,
+        labels: label_from_row(row).into_iter().collect(),
+    }
+}
+
+// LEFT OUTER JOINでラベルがない行はNone
+// idだけあってnameがない行は想定外のデータだが、Todo自体は返せるようラベルを読み飛ばして警告だけ残す
+fn label_from_row(row: &TodoWithLabelFromRow) -> Option<Label> {
+    match (row.label_id, &row.label_name) {
+        (Some(id), Some(name)) => Some(Label {
+            id,
+            name: name.clone(),
+            color: row.label_color.clone(),
+        }),
+        (None, _) => None,
+        (Some(id), None) => {
+            tracing::warn!("skip label {} of todo {} without name", id, row.id);
+            None
+        }
+    }
+}
+
+// クエリパラメータ(?completed=true)のパース先、Noneの条件は絞り込みに使わない
+// 不正な日時などはValidatedQueryで400として弾く
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq, Validate)]
+pub struct TodoFilter {
+    pub completed: Option<bool>,
+    // 指定された全てのラベルが付いたTodoに絞り込む、空なら絞り込まない
+    // 同じキーを繰り返す形式(?label_id=5&label_id=7)はQueryでパースできないためハンドラで詰める
+    #[serde(skip)]
+    pub label_ids: Vec<i32>,
+    pub parent_id: Option<i32>, // 指定したTodoの直下の子に絞り込む
+    // 作成日時の範囲で絞り込む、どちらも境界の日時を含む
+    pub created_after: Option<NaiveDateTime>,
+    pub created_before: Option<NaiveDateTime>,
+}
+
+// クエリパラメータ(?sort=text&order=asc)のパース先、省略時はリポジトリに設定した既定の並び順
+// 許可されていない値はデシリアライズの段階で弾かれる
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Validate)]
+pub struct TodoSort {
+    pub sort: Option<TodoSortKey>,
+    pub order: Option<SortOrder>, // 省略時はキーごとの既定の向き
+}
+
+// 実際に使う並べ替えのキーと向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderBy {
+    pub key: TodoSortKey,
+    pub order: SortOrder,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoSortKey {
+    #[default]
+    Position,
+    Id,
+    Text,
+    Completed,
+    Priority,
+    CreatedAt,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl TodoSortKey {
+    fn column(&self) -> &'static str {
+        match self {
+            TodoSortKey::Position => "todos.position",
+            TodoSortKey::Id => "todos.id",
+            TodoSortKey::Text => "todos.text",
+            TodoSortKey::Completed => "todos.completed",
+            TodoSortKey::Priority => "todos.priority",
+            TodoSortKey::CreatedAt => "todos.created_at",
+        }
+    }
+
+    // 並べ替えた順序は先頭から、それ以外は新しいもの・大きいものから並べる
+    fn default_order(&self) -> SortOrder {
+        match self {
+            TodoSortKey::Position => SortOrder::Asc,
+            _ => SortOrder::Desc,
+        }
+    }
+}
+
+impl SortOrder {
+    fn keyword(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+impl TodoSort {
+    // sortが省略された場合は既定の並び順を使い、orderだけが指定されていればその向きにする
+    fn resolve(&self, default: OrderBy) -> OrderBy {
+        match self.sort {
+            Some(key) => OrderBy::new(key, self.order),
+            None => OrderBy {
+                order: self.order.unwrap_or(default.order),
+                ..default
+            },
+        }
+    }
+}
+
+// 未設定時は並べ替えた順序(position)の昇順
+impl Default for OrderBy {
+    fn default() -> Self {
+        Self::new(TodoSortKey::default(), None)
+    }
+}
+
+impl OrderBy {
+    // orderを省略した場合はキーごとの既定の向き
+    pub fn new(key: TodoSortKey, order: Option<SortOrder>) -> Self {
+        Self {
+            key,
+            order: order.unwrap_or_else(|| key.default_order()),
+        }
+    }
+
+    // TODO_DEFAULT_SORT / TODO_DEFAULT_ORDER、値はクエリパラメータのsort・orderと同じ
+    pub fn from_env() -> anyhow::Result<Self> {
+        let key = match env::var("TODO_DEFAULT_SORT") {
+            Ok(value) => parse_env_value("TODO_DEFAULT_SORT", &value)?,
+            Err(_) => TodoSortKey::default(),
+        };
+        let order = env::var("TODO_DEFAULT_ORDER")
+            .ok()
+            .map(|value| parse_env_value("TODO_DEFAULT_ORDER", &value))
+            .transpose()?;
+        Ok(Self::new(key, order))
+    }
+
+    // enumから固定の文字列だけを組み立てるので、リクエストの値がそのままSQLに入ることはない
+    fn order_by_clause(&self) -> String {
+        let order = self.order.keyword();
+        match self.key {
+            TodoSortKey::Id => format!("todos.id {}", order),
+            // 同じ値同士の並びを安定させ、ページングで重複や抜けが出ないようidを第二キーにする
+            key => format!("{} {}, todos.id {}", key.column(), order, order),
+        }
+    }
+}
+
+// serdeのrenameに合わせて"created_at"や"desc"のような文字列をenumに変換する
+fn parse_env_value<'de, T: Deserialize<'de>>(key: &str, value: &'de str) -> anyhow::Result<T> {
+    T::deserialize(value.trim().into_deserializer())
+        .map_err(|e: serde::de::value::Error| anyhow::anyhow!("invalid [{}]: {}", key, e))
+}
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 20;
+pub const MAX_PAGE_LIMIT: i64 = 100;
+
+// クエリパラメータ(?limit=20&offset=0)のパース先、省略時はデフォルト値を使う
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    #[serde(default = "default_page_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+fn default_page_limit() -> i64 {
+    DEFAULT_PAGE_LIMIT
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_PAGE_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+impl Pagination {
+    // limitを1..=MAX_PAGE_LIMITに、offsetを0以上に丸める
+    pub fn clamp(self) -> Self {
+        Self {
+            limit: self.limit.clamp(1, MAX_PAGE_LIMIT),
+            offset: self.offset.max(0),
+        }
+    }
+}
+
+// 1つのTodoに付けられるラベルの数、画面に表示しきれなくなるのを防ぐ
+pub const MAX_LABELS_PER_TODO: usize = 10;
+
+// POST /labels/:id/assignのレスポンス
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct AssignResult {
+    pub added: u64,          // 新たにラベルを付けたTodoの件数
+    pub not_found: Vec<i32>, // 存在しないか論理削除されたTodoのid
+}
+
+// 一覧取得のレスポンス、totalはページングに関係なく絞り込み条件に一致する件数
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct TodoPage {
+    pub items: Vec<TodoEntity>,
+    pub total: i64,
+}
+
+// GET /todos/by-labelのレスポンスの要素、labelがNoneのグループにはラベルのないTodoをまとめる
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct TodoGroup {
+    pub label: Option<Label>,
+    pub todos: Vec<TodoEntity>,
+}
+
+// GET /todos/statsのレスポンス
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct TodoStats {
+    pub total: i64,
+    pub completed: i64,
+    pub incomplete: i64,
+    pub labels: Vec<LabelStats>, // Todoが付いていないラベルも0件として含む
+}
+
+impl TodoStats {
+    fn new(total: i64, completed: i64, labels: Vec<LabelStats>) -> Self {
+        Self {
+            total,
+            completed,
+            incomplete: total - completed,
+            labels,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, FromRow, ToSchema)]
+pub struct LabelStats {
+    pub id: i32,
+    pub name: String,
+    pub total: i64,
+    pub completed: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate, ToSchema)]
+pub struct CreateTodo {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    // POST /todos?strict=falseの場合は警告にとどめて作成する
+    #[validate(custom = "validate_text_length_warning")]
+    text: String,
+    // 省略した場合はラベルを付けずに作成する
+    #[serde(default)]
+    labels: Vec<i32>,
+    #[validate(custom(function = "validate_not_past", message = "Can not be in the past"))]
+    due_date: Option<NaiveDate>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    parent_id: Option<i32>,
+    #[serde(default)]
+    recurrence: Option<Recurrence>,
+}
+
+// 一括登録用、JSON上はCreateTodoの配列をそのまま受け付ける
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+#[serde(transparent)]
+pub struct CreateTodos {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate]
+    todos: Vec<CreateTodo>,
+}
+
+impl CreateTodo {
+    pub fn new(text: String, labels: Vec<i32>) -> Self {
+        Self {
+            text,
+            labels,
+            due_date: None,
+            priority: Priority::default(),
+            parent_id: None,
+            recurrence: None,
+        }
+    }
+}
+
+impl Normalize for CreateTodo {
+    fn normalize(&mut self, normalization: &TextNormalization) {
+        self.text.normalize(normalization);
+    }
+}
+
+impl Normalize for CreateTodos {
+    fn normalize(&mut self, normalization: &TextNormalization) {
+        for todo in &mut self.todos {
+            todo.normalize(normalization);
+        }
+    }
+}
+
+impl Normalize for UpdateTodo {
+    fn normalize(&mut self, normalization: &TextNormalization) {
+        self.text.normalize(normalization);
+    }
+}
+
+impl Normalize for ReplaceTodo {
+    fn normalize(&mut self, normalization: &TextNormalization) {
+        self.text.normalize(normalization);
+    }
+}
+
+impl CreateTodos {
+    pub fn into_inner(self) -> Vec<CreateTodo> {
+        self.todos
+    }
+}
+
+// 期限は今日以降のみ受け付ける
+fn validate_not_past(due_date: &NaiveDate) -> Result<(), ValidationError> {
+    if *due_date < Local::now().date_naive() {
+        return Err(ValidationError::new("past_date"));
+    }
+    Ok(())
+}
+
+// textの最大文字数、MAX_TODO_TEXT_LENで環境ごとに変えられる
+// 検証はValidatedJsonなどがExtensionで渡された値をscopeで指定した上で行う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxTextLength(pub usize);
+
+impl Default for MaxTextLength {
+    fn default() -> Self {
+        Self(100)
+    }
+}
+
+tokio::task_local! {
+    static MAX_TEXT_LENGTH: MaxTextLength;
+}
+
+impl MaxTextLength {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var("MAX_TODO_TEXT_LEN") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim().parse()? {
+            0 => Err(anyhow::anyhow!("must be greater than 0")),
+            max => Ok(Self(max)),
+        }
+    }
+
+    // fの中で行う検証にこの最大文字数を使わせる
+    pub fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        MAX_TEXT_LENGTH.sync_scope(self, f)
+    }
+
+    // scopeの外(リポジトリのテストなど)では既定値
+    fn current() -> Self {
+        MAX_TEXT_LENGTH.try_with(|max| *max).unwrap_or_default()
+    }
+}
+
+// 家族の絵文字や結合文字を含む"é"のように、見た目の1文字(書記素クラスタ)を1文字として数える
+// エラーメッセージに設定された最大文字数を含める
+fn check_text_length(text: &str, code: &'static str) -> Result<(), ValidationError> {
+    let MaxTextLength(max) = MaxTextLength::current();
+    if text.graphemes(true).count() <= max {
+        return Ok(());
+    }
+    let mut error = ValidationError::new(code);
+    error.message = Some(format!("Over text length, max is {}", max).into());
+    error.add_param("max".into(), &max);
+    Err(error)
+}
+
+fn validate_text_length(text: &str) -> Result<(), ValidationError> {
+    check_text_length(text, "length")
+}
+
+fn validate_text_length_warning(text: &str) -> Result<(), ValidationError> {
+    check_text_length(text, "warning")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate, ToSchema)]
+pub struct UpdateTodo {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(custom = "validate_text_length")]
+    text: Option<String>,
+    completed: Option<bool>,
+    labels: Option<Vec<i32>>,
+    due_date: Option<NaiveDate>,
+    priority: Option<Priority>,
+    parent_id: Option<i32>,
+    recurrence: Option<Recurrence>,
+    expected_version: Option<i32>, // 指定した場合、現在のversionと一致しなければ更新しない
+}
+
+// PUT用、text・completed・labels以外は省略でき、省略した場合は未設定(既定値)に戻す
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate, ToSchema)]
+pub struct ReplaceTodo {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(custom = "validate_text_length")]
+    text: String,
+    completed: bool,
+    labels: Vec<i32>,
+    #[validate(custom(function = "validate_not_past", message = "Can not be in the past"))]
+    #[serde(default)]
+    due_date: Option<NaiveDate>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    parent_id: Option<i32>,
+    #[serde(default)]
+    recurrence: Option<Recurrence>,
+}
+
+impl UpdateTodo {
+    pub fn new(text: Option<String>, completed: Option<bool>, labels: Option<Vec<i32>>) -> Self {
+        Self {
+            text,
+            completed,
+            labels,
+            due_date: None,
+            priority: None,
+            parent_id: None,
+            recurrence: None,
+            expected_version: None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+    use std::env;
+
+    fn created_at() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 2, 17)
+            .unwrap()
+            .and_hms_opt(9, 5, 41)
+            .unwrap()
+    }
+
+    fn todo_with_label_row(id: i32, text: &str, label: &Label) -> TodoWithLabelFromRow {
+        TodoWithLabelFromRow {
+            id,
+            text: String::from(text),
+            completed: false,
+            due_date: None,
+            priority: Priority::default(),
+            completed_at: None,
+            created_at: created_at(),
+            updated_at: created_at(),
+            version: 1,
+            parent_id: None,
+            recurrence: None,
+            position: id,
+            user_id: DEFAULT_USER_ID,
+            label_id: Some(label.id),
+            label_name: Some(label.name.clone()),
+            label_color: label.color.clone(),
+        }
+    }
+
+    #[test]
+    fn order_by_clause_test() {
+        assert_eq!(
+            "todos.position asc, todos.id asc",
+            OrderBy::default().order_by_clause()
+        );
+        assert_eq!(
+            "todos.id desc",
+            OrderBy::new(TodoSortKey::Id, None).order_by_clause()
+        );
+        assert_eq!(
+            "todos.text asc, todos.id asc",
+            OrderBy::new(TodoSortKey::Text, Some(SortOrder::Asc)).order_by_clause()
+        );
+        assert_eq!(
+            "todos.completed desc, todos.id desc",
+            OrderBy::new(TodoSortKey::Completed, Some(SortOrder::Desc)).order_by_clause()
+        );
+        assert_eq!(
+            "todos.created_at asc, todos.id asc",
+            OrderBy::new(TodoSortKey::CreatedAt, Some(SortOrder::Asc)).order_by_clause()
+        );
+    }
+
+    #[test]
+    fn resolve_sort_test() {
+        let default = OrderBy::new(TodoSortKey::CreatedAt, None);
+        assert_eq!(default, TodoSort::default().resolve(default));
+        // orderだけの指定は既定のキーに適用する
+        assert_eq!(
+            OrderBy::new(TodoSortKey::CreatedAt, Some(SortOrder::Asc)),
+            TodoSort {
+                sort: None,
+                order: Some(SortOrder::Asc),
+            }
+            .resolve(default)
+        );
+        // キーを指定した場合、向きはそのキーの既定に従う
+        assert_eq!(
+            OrderBy::new(TodoSortKey::Position, Some(SortOrder::Asc)),
+            TodoSort {
+                sort: Some(TodoSortKey::Position),
+                order: None,
+            }
+            .resolve(default)
+        );
+    }
+
+    #[test]
+    fn fold_entities_test() {
+        let label_1 = Label {
+            id: 1,
+            name: String::from("label 1"),
+            color: None,
+        };
+        let label_2 = Label {
+            id: 2,
+            name: String::from("label 2"),
+            color: None,
+        };
+        let rows = vec![
+            todo_with_label_row(1, "todo 1", &label_1),
+            todo_with_label_row(1, "todo 1", &label_2),
+            todo_with_label_row(2, "todo 2", &label_1),
+        ];
+        let res = fold_entities(rows).unwrap();
+        assert_eq!(
+            res,
+            vec![
+                TodoEntity {
+                    created_at: created_at(),
+                    updated_at: created_at(),
+                    ..TodoEntity::new(
+                        1,
+                        String::from("todo 1"),
+                        vec![label_1.clone(), label_2.clone()]
+                    )
+                },
+                TodoEntity {
+                    created_at: created_at(),
+                    updated_at: created_at(),
+                    ..TodoEntity::new(2, String::from("todo 2"), vec![label_1.clone()])
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_max_text_length() {
+        assert_eq!(MaxTextLength(5), MaxTextLength::parse(" 5 ").unwrap());
+        assert!(MaxTextLength::parse("0").is_err());
+        assert!(MaxTextLength::parse("many").is_err());
+    }
+
+    #[test]
+    fn validate_text_with_max_text_length() {
+        let todo = |text: &str| CreateTodo::new(text.to_string(), vec![]);
+        let text_errors = |todo: CreateTodo| {
+            todo.validate().unwrap_err().field_errors()["text"]
+                .iter()
+                .map(|error| (error.code.to_string(), error.message.clone().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        // 設定した最大文字数がエラーメッセージに含まれる
+        MaxTextLength(5).scope(|| {
+            assert!(todo("12345").validate().is_ok());
+            assert_eq!(
+                vec![("warning".to_string(), "Over text length, max is 5".into())],
+                text_errors(todo("123456"))
+            );
+            let update = UpdateTodo::new(Some("123456".to_string()), None, None);
+            assert_eq!(
+                "length",
+                update.validate().unwrap_err().field_errors()["text"][0].code
+            );
+        });
+
+        // 設定していなければ100文字まで
+        assert!(todo(&"a".repeat(100)).validate().is_ok());
+        assert_eq!(
+            vec![("warning".to_string(), "Over text length, max is 100".into())],
+            text_errors(todo(&"a".repeat(101)))
+        );
+    }
+
+    #[test]
+    fn validate_text_length_by_graphemes() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}";
+        let combined = "e\u{0301}";
+        assert_eq!(7, family.chars().count());
+        assert_eq!(2, combined.chars().count());
+
+        // コードポイントの数ではなく見た目の文字数で数える
+        MaxTextLength(5).scope(|| {
+            for text in [
+                family.repeat(5),
+                combined.repeat(5),
+                format!("{}a{}", family, combined),
+            ] {
+                assert!(validate_text_length(&text).is_ok(), "{}", text);
+            }
+            for text in [
+                family.repeat(6),
+                combined.repeat(6),
+                format!("{}abcde", family),
+            ] {
+                assert!(validate_text_length(&text).is_err(), "{}", text);
+            }
+        });
+        assert!(validate_text_length(&family.repeat(100)).is_ok());
+        assert!(validate_text_length(&family.repeat(101)).is_err());
+    }
+
+    #[test]
+    fn fold_todos_incrementally() {
+        let label_1 = Label {
+            id: 1,
+            name: String::from("label 1"),
+            color: None,
+        };
+        let label_2 = Label {
+            id: 2,
+            name: String::from("label 2"),
+            color: None,
+        };
+        let rows = [
+            todo_with_label_row(1, "todo 1", &label_1),
+            todo_with_label_row(1, "todo 1", &label_2),
+            todo_with_label_row(2, "todo 2", &label_1),
+        ];
+        let mut fold = TodoFold::default();
+        // 次のTodoの行が届くまではまとめ終わらない
+        assert_eq!(None, fold.push(&rows[0]));
+        assert_eq!(None, fold.push(&rows[1]));
+        let first = fold.push(&rows[2]).unwrap();
+        assert_eq!(vec![label_1.clone(), label_2], first.labels);
+        let second = fold.finish().unwrap();
+        assert_eq!((2, vec![label_1]), (second.id, second.labels));
+
+        assert_eq!(None, TodoFold::default().finish());
+    }
+
+    #[test]
+    fn fold_entity_with_empty_rows() {
+        let res = fold_entity(vec![]);
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Unexpected(_))
+        ));
+    }
+
+    #[test]
+    fn fold_entities_with_missing_label_name() {
+        let label = Label {
+            id: 1,
+            name: String::from("label 1"),
+            color: None,
+        };
+        let label_2 = Label {
+            id: 2,
+            name: String::from("label 2"),
+            color: None,
+        };
+        let rows = vec![
+            TodoWithLabelFromRow {
+                label_name: None,
+                ..todo_with_label_row(1, "todo 1", &label)
+            },
+            todo_with_label_row(1, "todo 1", &label_2),
+        ];
+        // nameのないラベルだけを除いてTodoを返す
+        let res = fold_entities(rows).unwrap();
+        assert_eq!(1, res.len());
+        assert_eq!(vec![label_2], res[0].labels);
+    }
+
+    // 同名のラベルがあればそれを使い、なければ作成する
+    async fn prepare_label(pool: &PgPool, name: &str) -> Label {
+        let optional_label = sqlx::query_as::<_, Label>(
+            r#"
+select * from labels where name = $1
+        "#,
+        )
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+        .expect("Failed to prepare label data.");
+        if let Some(label) = optional_label {
+            return label;
+        }
+        sqlx::query_as::<_, Label>(
+            r#"
+insert into labels ( name )
+values ( $1 )
+returning *
+        "#,
+        )
+        .bind(name)
+        .fetch_one(pool)
+        .await
+        .expect("Failed to insert label data.")
+    }
+
+    async fn connect() -> PgPool {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        // テストごとに作るプールの接続が残り続けて接続数の上限に達しないよう、使い終わった接続は閉じる
+        sqlx::postgres::PgPoolOptions::new()
+            .after_release(|_| false)
+            .connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url))
+    }
+
+    #[tokio::test]
+    async fn find_with_multiple_labels() {
+        let pool = connect().await;
+        let label_1 = prepare_label(&pool, "[find_with_multiple_labels] label 1").await;
+        let label_2 = prepare_label(&pool, "[find_with_multiple_labels] label 2").await;
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[find_with_multiple_labels] text".to_string(),
+                vec![label_1.id, label_2.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(created.labels.len(), 2);
+
+        let todo = repository
+            .find(created.id)
+            .await
+            .expect("[find] returned Err");
+        assert!(todo.labels.contains(&label_1));
+        assert!(todo.labels.contains(&label_2));
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn update_replaces_labels() {
+        let pool = connect().await;
+        let label_1 = prepare_label(&pool, "[update_replaces_labels] label 1").await;
+        let label_2 = prepare_label(&pool, "[update_replaces_labels] label 2").await;
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[update_replaces_labels] text".to_string(),
+                vec![label_1.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // labelsがNoneなら紐付けは変わらない
+        let todo = repository
+            .update(
+                created.id,
+                UpdateTodo::new(
+                    Some("[update_replaces_labels] updated".to_string()),
+                    None,
+                    None,
+                ),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(vec![label_1.clone()], todo.labels);
+
+        // 存在しないラベルが含まれる場合は何も変更されない
+        let res = repository
+            .update(
+                created.id,
+                UpdateTodo::new(None, None, Some(vec![label_2.id, i32::MAX])),
+            )
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+        let todo = repository.find(created.id).await.unwrap();
+        assert_eq!(vec![label_1.clone()], todo.labels);
+
+        let todo = repository
+            .update(
+                created.id,
+                UpdateTodo::new(None, None, Some(vec![label_2.id])),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(vec![label_2], todo.labels);
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn label_filter_scenario() {
+        let pool = connect().await;
+        let label_1 = prepare_label(&pool, "[label_filter_scenario] label 1").await;
+        let label_2 = prepare_label(&pool, "[label_filter_scenario] label 2").await;
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let both = repository
+            .create(CreateTodo::new(
+                "[label_filter_scenario] both".to_string(),
+                vec![label_1.id, label_2.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        let only_1 = repository
+            .create(CreateTodo::new(
+                "[label_filter_scenario] only 1".to_string(),
+                vec![label_1.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        let filter = |label_ids: Vec<i32>| TodoFilter {
+            label_ids,
+            ..TodoFilter::default()
+        };
+        let pagination = Pagination {
+            limit: MAX_PAGE_LIMIT,
+            offset: 0,
+        };
+
+        let todos = repository
+            .all(filter(vec![label_1.id]), TodoSort::default(), pagination)
+            .await
+            .expect("[all] returned Err");
+        let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![both.id, only_1.id], ids);
+        // 絞り込みに使っていないラベルも含めて返す
+        assert_eq!(2, todos[0].labels.len());
+        let count = repository
+            .count(filter(vec![label_1.id]))
+            .await
+            .expect("[count] returned Err");
+        assert_eq!(2, count);
+
+        // 複数指定は全てのラベルが付いたものだけに一致する
+        let todos = repository
+            .all(
+                filter(vec![label_1.id, label_2.id]),
+                TodoSort::default(),
+                pagination,
+            )
+            .await
+            .expect("[all] returned Err");
+        let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+        assert_eq!(vec![both.id], ids);
+        let count = repository
+            .count(filter(vec![label_1.id, label_2.id]))
+            .await
+            .expect("[count] returned Err");
+        assert_eq!(1, count);
+
+        for id in [both.id, only_1.id] {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn find_by_label_scenario() {
+        let pool = connect().await;
+        let label_1 = prepare_label(&pool, "[find_by_label_scenario] label 1").await;
+        let label_2 = prepare_label(&pool, "[find_by_label_scenario] label 2").await;
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[find_by_label_scenario] text".to_string(),
+                vec![label_1.id, label_2.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        let todos = repository
+            .find_by_label(label_1.id)
+            .await
+            .expect("[find_by_label] returned Err");
+        assert_eq!(vec![created.clone()], todos);
+
+        let res = repository.find_by_label(i32::MAX).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::LabelNotFound(i32::MAX))
+        ));
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+        let todos = repository
+            .find_by_label(label_1.id)
+            .await
+            .expect("[find_by_label] returned Err");
+        assert!(todos.is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_many_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[find_many_scenario] label").await;
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let first = repository
+            .create(CreateTodo::new(
+                "[find_many_scenario] first".to_string(),
+                vec![label.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        let second = repository
+            .create(CreateTodo::new(
+                "[find_many_scenario] second".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // 存在しないidは結果から除かれる
+        let todos = repository
+            .find_many(vec![second.id, i32::MAX, first.id])
+            .await
+            .expect("[find_many] returned Err");
+        assert_eq!(vec![first.clone(), second.clone()], todos);
+
+        let todos = repository
+            .find_many(vec![])
+            .await
+            .expect("[find_many] returned Err");
+        assert!(todos.is_empty());
+
+        for id in [first.id, second.id] {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_update_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[concurrent_update_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // 別々のフィールドを同時に更新しても、どちらの変更も失われない
+        for i in 0..10 {
+            let text = format!("[concurrent_update_scenario] updated {}", i);
+            let completed = i % 2 == 0;
+            let (text_res, completed_res) = tokio::join!(
+                repository.update(created.id, UpdateTodo::new(Some(text.clone()), None, None)),
+                repository.update(created.id, UpdateTodo::new(None, Some(completed), None)),
+            );
+            text_res.expect("[update] returned Err");
+            completed_res.expect("[update] returned Err");
+
+            let todo = repository.find(created.id).await.unwrap();
+            assert_eq!(text, todo.text);
+            assert_eq!(completed, todo.completed);
+        }
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn parent_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let parent = repository
+            .create(CreateTodo::new(
+                "[parent_scenario] parent".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+        let child = repository
+            .create(CreateTodo {
+                parent_id: Some(parent.id),
+                ..CreateTodo::new("[parent_scenario] child".to_string(), vec![])
+            })
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(Some(parent.id), child.parent_id);
+
+        let filter = TodoFilter {
+            parent_id: Some(parent.id),
+            ..TodoFilter::default()
+        };
+        let todos = repository
+            .all(filter.clone(), TodoSort::default(), Pagination::default())
+            .await
+            .expect("[all] returned Err");
+        assert_eq!(vec![child.clone()], todos);
+        assert_eq!(
+            1,
+            repository
+                .count(filter)
+                .await
+                .expect("[count] returned Err")
+        );
+
+        // 存在しない親はValidationエラー
+        let res = repository
+            .create(CreateTodo {
+                parent_id: Some(i32::MAX),
+                ..CreateTodo::new("[parent_scenario] orphan".to_string(), vec![])
+            })
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+
+        // 自身や子孫を親にすると循環する
+        for parent_id in [parent.id, child.id] {
+            let res = repository
+                .update(
+                    parent.id,
+                    UpdateTodo {
+                        parent_id: Some(parent_id),
+                        ..UpdateTodo::new(None, None, None)
+                    },
+                )
+                .await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Validation(_))
+            ));
+        }
+
+        // 存在しないTodoは親が正しくてもNotFound
+        let res = repository
+            .update(
+                i32::MAX,
+                UpdateTodo {
+                    parent_id: Some(parent.id),
+                    ..UpdateTodo::new(None, None, None)
+                },
+            )
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(id)) if *id == i32::MAX
+        ));
+
+        // 子が残っている間はcascadeを指定しないと削除できない
+        let res = repository.delete(parent.id, false).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Conflict(id)) if *id == parent.id
+        ));
+        repository
+            .delete(parent.id, true)
+            .await
+            .expect("[delete] returned Err");
+        let res = repository.find(child.id).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(id)) if *id == child.id
+        ));
+
+        // 論理削除済みの子も物理削除を妨げる
+        let res = repository.purge(parent.id, false).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Conflict(id)) if *id == parent.id
+        ));
+        repository
+            .purge(parent.id, true)
+            .await
+            .expect("[purge] returned Err");
+        let res = repository.find_including_deleted(child.id).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(id)) if *id == child.id
+        ));
+    }
+
+    #[tokio::test]
+    async fn version_conflict_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[version_conflict_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(1, created.version);
+
+        // 同じversionを前提にした更新が競合した場合、片方だけが成功する
+        let stale = |text: &str| UpdateTodo {
+            expected_version: Some(created.version),
+            ..UpdateTodo::new(Some(text.to_string()), None, None)
+        };
+        let (first, second) = tokio::join!(
+            repository.update(created.id, stale("[version_conflict_scenario] first")),
+            repository.update(created.id, stale("[version_conflict_scenario] second")),
+        );
+        let (updated, conflicted) = match (first, second) {
+            (Ok(todo), Err(e)) | (Err(e), Ok(todo)) => (todo, e),
+            (first, second) => panic!(
+                "expected exactly one update to fail: {:?}, {:?}",
+                first, second
+            ),
+        };
+        assert_eq!(2, updated.version);
+        assert!(matches!(
+            conflicted.downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Conflict(id)) if *id == created.id
+        ));
+
+        // 最新のversionを指定すれば更新できる
+        let todo = repository
+            .update(
+                created.id,
+                UpdateTodo {
+                    expected_version: Some(updated.version),
+                    ..UpdateTodo::new(None, Some(true), None)
+                },
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(3, todo.version);
+
+        // 存在しないTodoはversionの指定に関わらずNotFound
+        let res = repository.update(i32::MAX, stale("not found")).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(i32::MAX))
+        ));
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn partial_update_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[partial_update_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // completedだけを更新してもtextはそのまま
+        let todo = repository
+            .update(created.id, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(created.text, todo.text);
+        assert!(todo.completed);
+
+        let res = repository
+            .update(i32::MAX, UpdateTodo::new(None, Some(true), None))
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(i32::MAX))
+        ));
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn replace_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[replace_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo {
+                priority: Priority::High,
+                ..CreateTodo::new("[replace_scenario] text".to_string(), vec![label.id])
+            })
+            .await
+            .expect("[create] returned Err");
+
+        // 指定しなかったpriorityは既定値に戻り、ラベルも外れる
+        let todo = repository
+            .replace(
+                created.id,
+                ReplaceTodo {
+                    text: "[replace_scenario] replaced".to_string(),
+                    completed: true,
+                    labels: vec![],
+                    due_date: None,
+                    priority: Priority::default(),
+                    parent_id: None,
+                    recurrence: None,
+                },
+            )
+            .await
+            .expect("[replace] returned Err");
+        assert_eq!("[replace_scenario] replaced", todo.text);
+        assert!(todo.completed);
+        assert!(todo.completed_at.is_some());
+        assert!(todo.labels.is_empty());
+        assert_eq!(Priority::Medium, todo.priority);
+
+        let res = repository
+            .replace(
+                created.id,
+                ReplaceTodo {
+                    text: "[replace_scenario] replaced".to_string(),
+                    completed: true,
+                    labels: vec![i32::MAX],
+                    due_date: None,
+                    priority: Priority::default(),
+                    parent_id: None,
+                    recurrence: None,
+                },
+            )
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn audit_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new("[audit_scenario] text".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+        repository
+            .update(
+                created.id,
+                UpdateTodo::new(Some("[audit_scenario] updated".to_string()), None, None),
+            )
+            .await
+            .expect("[update] returned Err");
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+
+        // 物理削除した後も履歴は残る
+        let audits = repository
+            .history(created.id)
+            .await
+            .expect("[history] returned Err");
+        let actions: Vec<AuditAction> = audits.iter().map(|audit| audit.action).collect();
+        assert_eq!(
+            vec![AuditAction::Create, AuditAction::Update, AuditAction::Purge],
+            actions
+        );
+        assert_eq!("[audit_scenario] text", audits[0].snapshot["text"]);
+        assert_eq!("[audit_scenario] updated", audits[1].snapshot["text"]);
+        assert_eq!(2, audits[1].snapshot["version"]);
+
+        let res = repository.history(i32::MAX).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(i32::MAX))
+        ));
+    }
+
+    #[tokio::test]
+    async fn search_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[search_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[search_scenario] Buy 100% Milk".to_string(),
+                vec![label.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        let todos = repository
+            .search("search_scenario] buy 100%".to_string())
+            .await
+            .expect("[search] returned Err");
+        let todo = todos.iter().find(|todo| todo.id == created.id).unwrap();
+        assert_eq!(vec![label], todo.labels);
+
+        // ワイルドカードとして解釈されないこと
+        let todos = repository
+            .search("search_scenario] buy 1_0".to_string())
+            .await
+            .expect("[search] returned Err");
+        assert!(todos.iter().all(|todo| todo.id != created.id));
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn find_by_text_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[find_by_text_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1015);
+        let text = "[find_by_text_scenario] Buy milk";
+        let mut ids = vec![];
+        for (text, label_ids) in [
+            (text.to_string(), vec![label.id]),
+            (text.to_string(), vec![]),
+            (text.to_lowercase(), vec![]),
+            (format!("{} now", text), vec![]),
+        ] {
+            let todo = repository
+                .create(CreateTodo::new(text, label_ids))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+
+        // 前後の空白は無視し、大文字小文字や部分一致は区別する
+        let todos = repository
+            .find_by_text(&format!("  {}\n", text))
+            .await
+            .expect("[find_by_text] returned Err");
+        let found: Vec<(i32, Vec<Label>)> = todos
+            .into_iter()
+            .map(|todo| (todo.id, todo.labels))
+            .collect();
+        assert_eq!(vec![(ids[1], vec![]), (ids[0], vec![label])], found);
+
+        // 削除済みや他のユーザーのTodoは含まない
+        repository
+            .delete(ids[1], false)
+            .await
+            .expect("[delete] returned Err");
+        let todos = repository
+            .find_by_text(text)
+            .await
+            .expect("[find_by_text] returned Err");
+        assert_eq!(
+            vec![ids[0]],
+            todos.iter().map(|todo| todo.id).collect::<Vec<_>>()
+        );
+        let todos = repository
+            .for_user(1016)
+            .find_by_text(text)
+            .await
+            .expect("[find_by_text] returned Err");
+        assert!(todos.is_empty());
+
+        for id in ids {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn fuzzy_search_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        // 類似度は文字列全体で比べるため、他のシナリオと区別する接頭辞は付けない
+        let created = repository
+            .create(CreateTodo::new("buy milk".to_string(), vec![]))
+            .await
+            .expect("[create] returned Err");
+
+        let todos = repository
+            .fuzzy_search("by milk".to_string())
+            .await
+            .expect("[fuzzy_search] returned Err");
+        assert!(todos.iter().any(|todo| todo.id == created.id));
+
+        // 閾値を上げると一致しなくなる
+        let strict = repository.clone().with_similarity_threshold(0.9);
+        let todos = strict
+            .fuzzy_search("by milk".to_string())
+            .await
+            .expect("[fuzzy_search] returned Err");
+        assert!(todos.iter().all(|todo| todo.id != created.id));
+
+        repository
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn due_date_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let due_date = NaiveDate::from_ymd_opt(2999, 12, 31).unwrap();
+        let created = repository
+            .create(CreateTodo {
+                due_date: Some(due_date),
+                ..CreateTodo::new("[due_date_scenario] text".to_string(), vec![])
+            })
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(Some(due_date), created.due_date);
+
+        // 期限を指定しない更新では期限が残る
+        let todo = repository
+            .update(created.id, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(Some(due_date), todo.due_date);
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn priority_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo {
+                priority: Priority::High,
+                ..CreateTodo::new("[priority_scenario] text".to_string(), vec![])
+            })
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(Priority::High, created.priority);
+
+        let todos = repository
+            .all(
+                TodoFilter::default(),
+                TodoSort {
+                    sort: Some(TodoSortKey::Priority),
+                    ..TodoSort::default()
+                },
+                Pagination {
+                    limit: MAX_PAGE_LIMIT,
+                    offset: 0,
+                },
+            )
+            .await
+            .expect("[all] returned Err");
+        assert!(todos
+            .windows(2)
+            .all(|pair| pair[0].priority >= pair[1].priority));
+
+        let todo = repository
+            .update(
+                created.id,
+                UpdateTodo {
+                    priority: Some(Priority::Low),
+                    ..UpdateTodo::new(None, None, None)
+                },
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(Priority::Low, todo.priority);
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_label_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[add_and_remove_label_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[add_and_remove_label_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        repository
+            .add_label(created.id, label.id)
+            .await
+            .expect("[add_label] returned Err");
+        let todo = repository
+            .add_label(created.id, label.id)
+            .await
+            .expect("[add_label] returned Err");
+        assert_eq!(vec![label.clone()], todo.labels);
+
+        let res = repository.add_label(created.id, i32::MAX).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+
+        repository
+            .remove_label(created.id, label.id)
+            .await
+            .expect("[remove_label] returned Err");
+        let todo = repository.find(created.id).await.unwrap();
+        assert!(todo.labels.is_empty());
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn label_limit_scenario() {
+        let pool = connect().await;
+        let mut label_ids = vec![];
+        for i in 0..=MAX_LABELS_PER_TODO {
+            let label = prepare_label(&pool, &format!("[label_limit_scenario] label {}", i)).await;
+            label_ids.push(label.id);
+        }
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let (last, limit) = label_ids.split_last().unwrap();
+        let todo = repository
+            .create(CreateTodo::new(
+                "[label_limit_scenario] text".to_string(),
+                limit.to_vec(),
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        let is_validation_error = |res: anyhow::Result<TodoEntity>| {
+            matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Validation(_))
+            )
+        };
+        assert!(is_validation_error(
+            repository.add_label(todo.id, *last).await
+        ));
+        assert!(is_validation_error(
+            repository
+                .update(
+                    todo.id,
+                    UpdateTodo::new(None, None, Some(label_ids.clone()))
+                )
+                .await
+        ));
+        let res = repository.assign_label(*last, vec![todo.id]).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+
+        // 書き込む前に弾くため、付いていたラベルはそのまま残る
+        let found_labels = repository
+            .find_labels(todo.id)
+            .await
+            .expect("[find_labels] returned Err");
+        assert_eq!(
+            limit.to_vec(),
+            found_labels
+                .iter()
+                .map(|label| label.id)
+                .collect::<Vec<_>>()
+        );
+        let found = repository.find(todo.id).await.unwrap();
+        assert_eq!(MAX_LABELS_PER_TODO, found.labels.len());
+        assert!(found.labels.iter().all(|label| label.id != *last));
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn stream_all_scenario() {
+        let pool = connect().await;
+        let first = prepare_label(&pool, "[stream_all_scenario] first").await;
+        let second = prepare_label(&pool, "[stream_all_scenario] second").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1014);
+        let mut todos = vec![];
+        for (text, label_ids) in [
+            ("both", vec![first.id, second.id]),
+            ("none", vec![]),
+            ("first", vec![first.id]),
+        ] {
+            let todo = repository
+                .create(CreateTodo::new(
+                    format!("[stream_all_scenario] {}", text),
+                    label_ids,
+                ))
+                .await
+                .expect("[create] returned Err");
+            todos.push(todo);
+        }
+
+        // ラベルが複数あっても1件にまとまり、指定した順に返る
+        let sort = TodoSort {
+            sort: Some(TodoSortKey::Id),
+            order: Some(SortOrder::Desc),
+        };
+        let streamed: Vec<TodoEntity> = repository
+            .stream_all(TodoFilter::default(), sort)
+            .try_collect()
+            .await
+            .expect("[stream_all] returned Err");
+        assert_eq!(todos.iter().rev().cloned().collect::<Vec<_>>(), streamed);
+
+        let filter = TodoFilter {
+            label_ids: vec![first.id],
+            ..TodoFilter::default()
+        };
+        let streamed: Vec<TodoEntity> = repository
+            .stream_all(filter, TodoSort::default())
+            .try_collect()
+            .await
+            .expect("[stream_all] returned Err");
+        assert_eq!(vec![todos[0].clone(), todos[2].clone()], streamed);
+
+        for todo in todos {
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_with_labels_scenario() {
+        let pool = connect().await;
+        let first = prepare_label(&pool, "[create_with_labels_scenario] first").await;
+        let second = prepare_label(&pool, "[create_with_labels_scenario] second").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1011);
+
+        let todo = repository
+            .create(CreateTodo::new(
+                "[create_with_labels_scenario] text".to_string(),
+                vec![first.id, second.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(vec![first.clone(), second.clone()], todo.labels);
+
+        // 1つでも存在しないラベルがあればTodoも作成しない
+        let res = repository
+            .create(CreateTodo::new(
+                "[create_with_labels_scenario] missing".to_string(),
+                vec![first.id, i32::MAX],
+            ))
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+        assert_eq!(1, repository.count(TodoFilter::default()).await.unwrap());
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn update_labels_scenario() {
+        let pool = connect().await;
+        let first = prepare_label(&pool, "[update_labels_scenario] first").await;
+        let second = prepare_label(&pool, "[update_labels_scenario] second").await;
+        let third = prepare_label(&pool, "[update_labels_scenario] third").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1017);
+        let todo = repository
+            .create(CreateTodo::new(
+                "[update_labels_scenario] text".to_string(),
+                vec![first.id, third.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // 付いているラベルの追加や付いていないラベルの削除は無視する
+        let updated = repository
+            .update_labels(todo.id, vec![first.id, second.id], vec![third.id, i32::MAX])
+            .await
+            .expect("[update_labels] returned Err");
+        assert_eq!(vec![first.clone(), second.clone()], updated.labels);
+
+        // 存在しないラベルがあれば削除も含めて何も変更しない
+        let res = repository
+            .update_labels(todo.id, vec![third.id, i32::MAX], vec![first.id])
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+        let found = repository.find(todo.id).await.expect("[find] returned Err");
+        assert_eq!(vec![first, second], found.labels);
+
+        let res = repository
+            .for_user(1018)
+            .update_labels(todo.id, vec![third.id], vec![])
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(_))
+        ));
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn group_by_label_scenario() {
+        let pool = connect().await;
+        let first = prepare_label(&pool, "[group_by_label_scenario] first").await;
+        let second = prepare_label(&pool, "[group_by_label_scenario] second").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1012);
+        let mut todos = vec![];
+        for (text, label_ids) in [
+            ("both", vec![first.id, second.id]),
+            ("first", vec![first.id]),
+            ("none", vec![]),
+        ] {
+            let todo = repository
+                .create(CreateTodo::new(
+                    format!("[group_by_label_scenario] {}", text),
+                    label_ids,
+                ))
+                .await
+                .expect("[create] returned Err");
+            todos.push(todo);
+        }
+
+        let groups = repository
+            .group_by_label()
+            .await
+            .expect("[group_by_label] returned Err");
+        let summary: Vec<(Option<i32>, Vec<i32>)> = groups
+            .iter()
+            .map(|group| {
+                let ids = group.todos.iter().map(|todo| todo.id).collect();
+                (group.label.as_ref().map(|label| label.id), ids)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (Some(first.id), vec![todos[0].id, todos[1].id]),
+                (Some(second.id), vec![todos[0].id]),
+                (None, vec![todos[2].id]),
+            ],
+            summary
+        );
+        // グループの中のTodoにも全てのラベルが付いている
+        assert_eq!(todos[0], groups[1].todos[0]);
+
+        for todo in todos {
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_many_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[delete_many_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1013);
+        let create = |text: &str, labels: Vec<i32>, parent_id: Option<i32>| CreateTodo {
+            parent_id,
+            ..CreateTodo::new(format!("[delete_many_scenario] {}", text), labels)
+        };
+        let first = repository
+            .create(create("first", vec![label.id], None))
+            .await
+            .expect("[create] returned Err");
+        let parent = repository
+            .create(create("parent", vec![], None))
+            .await
+            .expect("[create] returned Err");
+        let child = repository
+            .create(create("child", vec![], Some(parent.id)))
+            .await
+            .expect("[create] returned Err");
+        let grandchild = repository
+            .create(create("grandchild", vec![], Some(child.id)))
+            .await
+            .expect("[create] returned Err");
+
+        // 子を持つchildと、その親のparentは残る
+        let deleted = repository
+            .delete_many(vec![first.id, parent.id, child.id, i32::MAX])
+            .await
+            .expect("[delete_many] returned Err");
+        assert_eq!(1, deleted);
+        assert!(repository.find(first.id).await.is_err());
+        let label_count: i64 =
+            sqlx::query_scalar("select count(*) from todo_labels where todo_id = $1")
+                .bind(first.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(0, label_count);
+        let audits = repository.history(first.id).await.unwrap();
+        assert_eq!(AuditAction::Purge, audits.last().unwrap().action);
+
+        // 他のユーザーのTodoは削除しない
+        let deleted = repository
+            .for_user(1001)
+            .delete_many(vec![parent.id])
+            .await
+            .expect("[delete_many] returned Err");
+        assert_eq!(0, deleted);
+
+        let deleted = repository
+            .delete_many(vec![parent.id, child.id, grandchild.id])
+            .await
+            .expect("[delete_many] returned Err");
+        assert_eq!(3, deleted);
+        assert_eq!(0, repository.count(TodoFilter::default()).await.unwrap());
+    }
+
+    // テストのテーブルは小さく順次走査が選ばれるため、順次走査を無効にしてインデックスを使える計画であることを確かめる
+    #[tokio::test]
+    async fn query_plans_use_indexes() {
+        let pool = connect().await;
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("set local enable_seqscan = off")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        for (index, sql) in [
+            (
+                "todo_labels_todo_id_label_id_key",
+                r#"
+select todos.*, labels.id as label_id, labels.name as label_name, labels.color as label_color
+from todos
+            left outer join todo_labels tl on todos.id = tl.todo_id
+            left outer join labels on labels.id = tl.label_id
+where todos.id = 1
+        "#,
+            ),
+            (
+                "todo_labels_label_id_idx",
+                "select todo_id from todo_labels where label_id = 1",
+            ),
+            (
+                "todos_completed_idx",
+                "select id from todos where completed and deleted_at is null",
+            ),
+            (
+                "todos_created_at_idx",
+                "select id from todos where created_at >= '2023-01-01' order by created_at",
+            ),
+        ] {
+            let plan: Vec<String> = sqlx::query_scalar(&format!("explain {}", sql))
+                .fetch_all(&mut tx)
+                .await
+                .unwrap();
+            let plan = plan.join("\n");
+            assert!(plan.contains(index), "{} is not used:\n{}", index, plan);
+        }
+    }
+
+    #[tokio::test]
+    async fn transaction_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[transaction_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1010);
+        let mut events = repository.subscribe();
+
+        // ラベルを付けられなければ作成したTodoも残らない
+        let res = repository
+            .with_transaction(|repository| {
+                Box::pin(async move {
+                    let todo = repository
+                        .create(CreateTodo::new(
+                            "[transaction_scenario] rollback".to_string(),
+                            vec![],
+                        ))
+                        .await?;
+                    repository.add_label(todo.id, i32::MAX).await
+                })
+            })
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+        assert_eq!(0, repository.count(TodoFilter::default()).await.unwrap());
+        assert!(events.try_recv().is_err());
+
+        // トランザクションの中では作成したTodoが見え、コミットした後に通知される
+        let todo = repository
+            .with_transaction(|repository| {
+                Box::pin(async move {
+                    let todo = repository
+                        .create(CreateTodo::new(
+                            "[transaction_scenario] commit".to_string(),
+                            vec![],
+                        ))
+                        .await?;
+                    repository.add_label(todo.id, label.id).await
+                })
+            })
+            .await
+            .expect("[with_transaction] returned Err");
+        assert_eq!(vec![label.clone()], todo.labels);
+        let found = repository.find(todo.id).await.unwrap();
+        assert_eq!(todo, found);
+        assert_eq!(TodoEventKind::Created, events.try_recv().unwrap().kind);
+        assert_eq!(TodoEventKind::Updated, events.try_recv().unwrap().kind);
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+
+        // fがエラーを握りつぶした場合、失敗したメソッドの途中までの変更だけを取り消してコミットする
+        let todo = repository
+            .with_transaction(|repository| {
+                Box::pin(async move {
+                    let todo = repository
+                        .create(CreateTodo::new(
+                            "[transaction_scenario] swallow".to_string(),
+                            vec![],
+                        ))
+                        .await?;
+                    // textを更新した後にラベルが存在しないことが分かり失敗する
+                    let res = repository
+                        .update(
+                            todo.id,
+                            UpdateTodo::new(
+                                Some("[transaction_scenario] changed".to_string()),
+                                None,
+                                Some(vec![i32::MAX]),
+                            ),
+                        )
+                        .await;
+                    assert!(res.is_err());
+                    // SQLのエラーでPostgresがトランザクションを中断しても、続けて使える
+                    let res = repository
+                        .create(CreateTodo::new(
+                            "[transaction_scenario] \0".to_string(),
+                            vec![],
+                        ))
+                        .await;
+                    assert!(res.is_err());
+                    repository.find(todo.id).await
+                })
+            })
+            .await
+            .expect("[with_transaction] returned Err");
+        assert_eq!("[transaction_scenario] swallow", todo.text);
+        assert_eq!(todo, repository.find(todo.id).await.unwrap());
+        assert_eq!(1, repository.count(TodoFilter::default()).await.unwrap());
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn assign_label_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[assign_label_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let mut ids = vec![];
+        for labels in [vec![label.id], vec![], vec![]] {
+            let todo = repository
+                .create(CreateTodo::new(
+                    "[assign_label_scenario] text".to_string(),
+                    labels,
+                ))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+
+        // 既に付いているTodoは数えず、存在しないTodoのidは返す
+        let res = repository
+            .assign_label(label.id, vec![ids[0], ids[1], ids[2], i32::MAX])
+            .await
+            .expect("[assign_label] returned Err");
+        assert_eq!(
+            AssignResult {
+                added: 2,
+                not_found: vec![i32::MAX],
+            },
+            res
+        );
+        for id in ids.iter() {
+            let todo = repository.find(*id).await.unwrap();
+            assert_eq!(vec![label.clone()], todo.labels);
+        }
+
+        let res = repository.find_labels(i32::MAX).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(i32::MAX))
+        ));
+
+        let res = repository.assign_label(i32::MAX, ids.clone()).await;
+        let err = res.unwrap_err();
+        let err = err.downcast_ref::<RepositoryError>();
+        assert!(matches!(
+            err,
+            Some(RepositoryError::LabelNotFound(i32::MAX))
+        ));
+        assert_eq!(
+            format!("Label NotFound, id is {}", i32::MAX),
+            err.unwrap().to_string()
+        );
+
+        for id in ids {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn completed_at_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[completed_at_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(None, created.completed_at);
+
+        let todo = repository
+            .update(created.id, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("[update] returned Err");
+        let completed_at = todo.completed_at;
+        assert!(completed_at.is_some());
+
+        let todo = repository
+            .update(created.id, UpdateTodo::new(None, None, None))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(completed_at, todo.completed_at);
+
+        let todo = repository
+            .update(created.id, UpdateTodo::new(None, Some(false), None))
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(None, todo.completed_at);
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn recurrence_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let due_date = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+        let created = repository
+            .create(CreateTodo {
+                due_date: Some(due_date),
+                recurrence: Some(Recurrence::Daily),
+                ..CreateTodo::new("[recurrence_scenario] text".to_string(), vec![])
+            })
+            .await
+            .expect("[create] returned Err");
+
+        // 完了にすると翌日が期限の未完了のTodoが作られ、完了したものは残る
+        let todo = repository
+            .update(created.id, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("[update] returned Err");
+        assert!(todo.completed);
+        assert_eq!(Some(due_date), todo.due_date);
+        let todos = repository
+            .search("[recurrence_scenario]".to_string())
+            .await
+            .expect("[search] returned Err");
+        assert_eq!(2, todos.len());
+        let next = &todos[0];
+        assert_ne!(created.id, next.id);
+        assert!(!next.completed);
+        assert_eq!(Some(due_date + Days::new(1)), next.due_date);
+        assert_eq!(Some(Recurrence::Daily), next.recurrence);
+
+        // 既に完了済みのTodoを完了にしても増えない
+        repository
+            .update(created.id, UpdateTodo::new(None, Some(true), None))
+            .await
+            .expect("[update] returned Err");
+        let todos = repository
+            .search("[recurrence_scenario]".to_string())
+            .await
+            .expect("[search] returned Err");
+        assert_eq!(2, todos.len());
+
+        for todo in todos {
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicated_labels_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[duplicated_labels_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+
+        // 同じラベルを重複して指定しても1つだけ付く
+        let todo = repository
+            .create(CreateTodo::new(
+                "[duplicated_labels_scenario] text".to_string(),
+                vec![label.id, label.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(vec![label.clone()], todo.labels);
+        let todo = repository
+            .update(
+                todo.id,
+                UpdateTodo::new(None, None, Some(vec![label.id, label.id])),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(vec![label.clone()], todo.labels);
+        let todos = repository
+            .create_many(vec![CreateTodo::new(
+                "[duplicated_labels_scenario] text".to_string(),
+                vec![label.id, label.id],
+            )])
+            .await
+            .expect("[create_many] returned Err");
+        assert_eq!(vec![label.clone()], todos[0].labels);
+
+        for id in [todo.id, todos[0].id] {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn complete_all_recurrence_scenario() {
+        let pool = connect().await;
+        // 他のテストと重ならないユーザーを使う
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1019);
+        let due_date = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+        let created = repository
+            .create(CreateTodo {
+                due_date: Some(due_date),
+                recurrence: Some(Recurrence::Weekly),
+                ..CreateTodo::new(
+                    "[complete_all_recurrence_scenario] text".to_string(),
+                    vec![],
+                )
+            })
+            .await
+            .expect("[create] returned Err");
+
+        // まとめて完了にした場合も、繰り返しのTodoは次の回が作られる
+        let ids = repository
+            .complete_all(false)
+            .await
+            .expect("[complete_all] returned Err");
+        assert_eq!(vec![created.id], ids);
+        let todos = repository
+            .search("[complete_all_recurrence_scenario]".to_string())
+            .await
+            .expect("[search] returned Err");
+        assert_eq!(2, todos.len());
+        let next = &todos[0];
+        assert_ne!(created.id, next.id);
+        assert!(!next.completed);
+        assert_eq!(Some(due_date + Days::new(7)), next.due_date);
+        assert_eq!(Some(Recurrence::Weekly), next.recurrence);
+
+        for todo in todos {
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn toggle_scenario() {
+        let pool = connect().await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let created = repository
+            .create(CreateTodo::new(
+                "[toggle_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+
+        // 未完了 -> 完了
+        let todo = repository
+            .toggle(created.id)
+            .await
+            .expect("[toggle] returned Err");
+        assert!(todo.completed);
+        assert!(todo.completed_at.is_some());
+
+        // 完了 -> 未完了
+        let todo = repository
+            .toggle(created.id)
+            .await
+            .expect("[toggle] returned Err");
+        assert!(!todo.completed);
+        assert_eq!(None, todo.completed_at);
+        assert_eq!(created.version + 2, todo.version);
+
+        let res = repository.toggle(i32::MAX).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::NotFound(i32::MAX))
+        ));
+
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn user_scope_scenario() {
+        let pool = connect().await;
+        // 他のテストと重ならないユーザーを使う
+        let owner = TodoRepositoryForDb::new(pool.clone()).for_user(1001);
+        let other = owner.for_user(1002);
+        let created = owner
+            .create(CreateTodo::new(
+                "[user_scope_scenario] text".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(1001, created.user_id);
+
+        // 他のユーザーからは存在しないものとして扱う
+        let not_found = |error: anyhow::Error| {
+            matches!(
+                error.downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::NotFound(id)) if *id == created.id
+            )
+        };
+        assert!(not_found(other.find(created.id).await.unwrap_err()));
+        assert!(not_found(
+            other
+                .update(created.id, UpdateTodo::new(None, Some(true), None))
+                .await
+                .unwrap_err()
+        ));
+        assert!(not_found(other.toggle(created.id).await.unwrap_err()));
+        assert!(not_found(
+            other.delete(created.id, false).await.unwrap_err()
+        ));
+        assert!(not_found(other.history(created.id).await.unwrap_err()));
+        let todos = other
+            .all(
+                TodoFilter::default(),
+                TodoSort::default(),
+                Pagination::default(),
+            )
+            .await
+            .expect("[all] returned Err");
+        assert!(todos.is_empty());
+        assert_eq!(
+            0,
+            other
+                .count(TodoFilter::default())
+                .await
+                .expect("[count] returned Err")
+        );
+
+        // 他のユーザーのTodoは親にもできない
+        let res = other
+            .create(CreateTodo {
+                parent_id: Some(created.id),
+                ..CreateTodo::new("[user_scope_scenario] child".to_string(), vec![])
+            })
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<RepositoryError>(),
+            Some(RepositoryError::Validation(_))
+        ));
+
+        let todo = owner.find(created.id).await.expect("[find] returned Err");
+        assert_eq!(created, todo);
+
+        owner
+            .purge(created.id, false)
+            .await
+            .expect("[purge] returned Err");
+    }
+
+    #[tokio::test]
+    async fn created_range_scenario() {
+        let pool = connect().await;
+        // 他のテストと重ならないユーザーを使う
+        let repository = TodoRepositoryForDb::new(pool).for_user(1008);
+        let mut todos = vec![];
+        for _ in 0..3 {
+            let todo = repository
+                .create(CreateTodo::new(
+                    "[created_range_scenario] text".to_string(),
+                    vec![],
+                ))
+                .await
+                .expect("[create] returned Err");
+            todos.push(todo);
+        }
+        let ids_between = |created_after, created_before| {
+            let repository = repository.clone();
+            async move {
+                let filter = TodoFilter {
+                    created_after,
+                    created_before,
+                    ..TodoFilter::default()
+                };
+                let count = repository
+                    .count(filter.clone())
+                    .await
+                    .expect("[count] returned Err");
+                let ids: Vec<i32> = repository
+                    .all(filter, TodoSort::default(), Pagination::default())
+                    .await
+                    .expect("[all] returned Err")
+                    .into_iter()
+                    .map(|todo| todo.id)
+                    .collect();
+                assert_eq!(ids.len() as i64, count);
+                ids
+            }
+        };
+
+        // 境界の日時ちょうどに作成されたTodoも含む
+        let middle = todos[1].created_at;
+        assert_eq!(
+            vec![todos[1].id],
+            ids_between(Some(middle), Some(middle)).await
+        );
+        assert_eq!(
+            vec![todos[1].id, todos[2].id],
+            ids_between(Some(middle), None).await
+        );
+        assert_eq!(
+            vec![todos[0].id, todos[1].id],
+            ids_between(None, Some(middle)).await
+        );
+
+        for todo in todos {
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_scenario() {
+        let pool = connect().await;
+        let used = prepare_label(&pool, "[stats_scenario] used").await;
+        let unused = prepare_label(&pool, "[stats_scenario] unused").await;
+        // 他のテストと重ならないユーザーを使う
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1004);
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let todo = repository
+                .create(CreateTodo::new(
+                    "[stats_scenario] text".to_string(),
+                    vec![used.id],
+                ))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+        repository
+            .toggle(ids[0])
+            .await
+            .expect("[toggle] returned Err");
+        repository
+            .delete(ids[2], false)
+            .await
+            .expect("[delete] returned Err");
+
+        let stats = repository.stats().await.expect("[stats] returned Err");
+        assert_eq!((2, 1, 1), (stats.total, stats.completed, stats.incomplete));
+        let label_stats = |id: i32| {
+            stats
+                .labels
+                .iter()
+                .find(|label| label.id == id)
+                .cloned()
+                .expect("label is missing in stats")
+        };
+        assert_eq!(
+            LabelStats {
+                id: used.id,
+                name: used.name.clone(),
+                total: 2,
+                completed: 1,
+            },
+            label_stats(used.id)
+        );
+        assert_eq!(
+            LabelStats {
+                id: unused.id,
+                name: unused.name.clone(),
+                total: 0,
+                completed: 0,
+            },
+            label_stats(unused.id)
+        );
+
+        for id in ids {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_scenario() {
+        let pool = connect().await;
+        // 他のテストと重ならないユーザーを使う
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1007);
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let todo = repository
+                .create(CreateTodo::new(
+                    "[dry_run_scenario] text".to_string(),
+                    vec![],
+                ))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+        repository
+            .toggle(ids[0])
+            .await
+            .expect("[toggle] returned Err");
+
+        // dry_runでは対象のidを返すだけで変更しない
+        let dry_run = repository
+            .delete_completed(true)
+            .await
+            .expect("[delete_completed] returned Err");
+        assert_eq!(vec![ids[0]], dry_run);
+        assert!(repository.find(ids[0]).await.is_ok());
+        let deleted = repository
+            .delete_completed(false)
+            .await
+            .expect("[delete_completed] returned Err");
+        assert_eq!(dry_run, deleted);
+
+        let dry_run = repository
+            .complete_all(true)
+            .await
+            .expect("[complete_all] returned Err");
+        assert_eq!(ids[1..].to_vec(), dry_run);
+        assert!(!repository.find(ids[1]).await.unwrap().completed);
+        let completed = repository
+            .complete_all(false)
+            .await
+            .expect("[complete_all] returned Err");
+        assert_eq!(dry_run, completed);
+
+        for id in &ids[1..] {
+            repository
+                .purge(*id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn error_context_scenario() {
+        // 閉じたプールを使って必ず失敗させる
+        let pool = connect().await;
+        pool.close().await;
+        let repository = TodoRepositoryForDb::new(pool);
+        let error = repository
+            .update(5, UpdateTodo::new(None, Some(true), None))
+            .await
+            .unwrap_err();
+        let chain: Vec<String> = error.chain().map(ToString::to_string).collect();
+        assert_eq!("failed to update todo id=5", chain[0]);
+        match RepositoryError::from(error) {
+            RepositoryError::Unexpected(message) => {
+                assert!(message.starts_with("failed to update todo id=5: "))
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn stable_pagination_scenario() {
+        let pool = connect().await;
+        // 他のテストと重ならないユーザーを使う
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_user(1003);
+        let mut ids = vec![];
+        for _ in 0..5 {
+            let todo = repository
+                .create(CreateTodo::new(
+                    "[stable_pagination_scenario] same text".to_string(),
+                    vec![],
+                ))
+                .await
+                .expect("[create] returned Err");
+            ids.push(todo.id);
+        }
+
+        // 並べ替えのキーが全て同じでも、idを第二キーにしているのでページ間で重複や抜けが出ない
+        let sort = TodoSort {
+            sort: Some(TodoSortKey::Text),
+            order: Some(SortOrder::Asc),
+        };
+        let mut paged = vec![];
+        for offset in [0, 2, 4] {
+            let todos = repository
+                .all(TodoFilter::default(), sort, Pagination { limit: 2, offset })
+                .await
+                .expect("[all] returned Err");
+            paged.extend(todos.into_iter().map(|todo| todo.id));
+        }
+        assert_eq!(ids, paged);
+
+        // sortを省略した場合はリポジトリに設定した既定の並び順を使う
+        let repository =
+            repository.with_default_order(OrderBy::new(TodoSortKey::Text, Some(SortOrder::Desc)));
+        let todos = repository
+            .all(
+                TodoFilter::default(),
+                TodoSort::default(),
+                Pagination::default(),
+            )
+            .await
+            .expect("[all] returned Err");
+        let mut desc = ids.clone();
+        desc.reverse();
+        assert_eq!(desc, todos.iter().map(|todo| todo.id).collect::<Vec<_>>());
+
+        for id in ids {
+            repository
+                .purge(id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_many_scenario() {
+        let pool = connect().await;
+        let label = prepare_label(&pool, "[create_many_scenario] label").await;
+        let repository = TodoRepositoryForDb::new(pool.clone());
+
+        // 存在しないラベルを含むと1件も登録されない
+        let res = repository
+            .create_many(vec![
+                CreateTodo::new("[create_many_scenario] rollback".to_string(), vec![]),
+                CreateTodo::new(
+                    "[create_many_scenario] rollback".to_string(),
+                    vec![i32::MAX],
+                ),
+            ])
+            .await;
+        assert!(res.is_err());
+        let count: i64 = sqlx::query_scalar("select count(*) from todos where text = $1")
+            .bind("[create_many_scenario] rollback")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(0, count);
+
+        let todos = repository
+            .create_many(vec![
+                CreateTodo::new("[create_many_scenario] 1".to_string(), vec![label.id]),
+                CreateTodo::new("[create_many_scenario] 2".to_string(), vec![]),
+            ])
+            .await
+            .expect("[create_many] returned Err");
+        assert_eq!(2, todos.len());
+        assert_eq!("[create_many_scenario] 1", todos[0].text);
+        assert_eq!(vec![label], todos[0].labels);
+        assert_eq!("[create_many_scenario] 2", todos[1].text);
+        assert!(todos[1].labels.is_empty());
+
+        for todo in todos {
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("[purge] returned Err");
+        }
+    }
+
+    #[tokio::test]
+    async fn crud_scenario() {
+        let pool = connect().await;
+
+        // label data prepare
+        let label_1 = prepare_label(&pool, "test label").await;
+
+        let repository = TodoRepositoryForDb::new(pool.clone());
+        let todo_text = "[crud_scenario] text";
+
+        // create
+        let created = repository
+            .create(CreateTodo::new(todo_text.to_string(), vec![label_1.id]))
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(created.text, todo_text);
+        assert!(!created.completed);
+        assert_eq!(*created.labels.first().unwrap(), label_1);
+
+        // find
+        let todo = repository
+            .find(created.id)
+            .await
+            .expect("[find] returned Err");
+        assert_eq!(created, todo);
+
+        // all
+        let todos = repository
+            .all(
+                TodoFilter::default(),
+                TodoSort::default(),
+                Pagination::default(),
+            )
+            .await
+            .expect("[all] returned Err");
+        // 他のテストと並行して実行されるため、先頭ではなくidで探す
+        let todo = todos.iter().find(|todo| todo.id == created.id).unwrap();
+        assert_eq!(created, *todo);
+
+        // update
+        let updated_text = "[crud_scenario] updated text";
+        let todo = repository
+            .update(
+                todo.id,
+                UpdateTodo::new(Some(updated_text.to_string()), Some(true), Some(vec![])),
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(created.id, todo.id);
+        assert_eq!(todo.text, updated_text);
+        assert!(todo.labels.is_empty());
+        // 作成日時はそのままで、更新日時だけが進む
+        assert_eq!(created.created_at, todo.created_at);
+        assert!(todo.updated_at > created.updated_at);
+
+        // delete(論理削除)
+        repository
+            .delete(todo.id, false)
+            .await
+            .expect("[delete] returned Err");
+        let res = repository.find(created.id).await; // expect not found err
+        assert!(res.is_err());
+        let deleted = repository
+            .find_including_deleted(created.id)
+            .await
+            .expect("[find_including_deleted] returned Err");
+        assert_eq!(todo, deleted);
+
+        // restore
+        let restored = repository
+            .restore(created.id)
+            .await
+            .expect("[restore] returned Err");
+        assert_eq!(todo, restored);
+
+        // purge(物理削除)
+        repository
+            .purge(todo.id, false)
+            .await
+            .expect("[purge] returned Err");
+        let res = repository.find_including_deleted(created.id).await;
+        assert!(res.is_err());
+
+        let todo_rows = sqlx::query(
+            r#"
+select * from todos where id=$1
+        "#,
+        )
+        .bind(todo.id)
+        .fetch_all(&pool)
+        .await
+        .expect("[delete] todo_labels fetch error");
+        assert!(todo_rows.is_empty());
+
+        let rows = sqlx::query(
+            r#"
+select * from todo_labels where todo_id=$1
+        "#,
+        )
+        .bind(todo.id)
+        .fetch_all(&pool)
+        .await
+        .expect("[delete] todo_labels fetch error");
+        assert!(rows.is_empty());
+    }
+}
+
+// メモリ上に保存するリポジトリ、テストの他にREPOSITORY=memoryで起動した場合にも使う
+pub mod memory {
+    use anyhow::Context;
+    use axum::async_trait;
+    use std::{
+        cmp::Reverse,
+        collections::{HashMap, HashSet},
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        },
+    };
+
+    use super::*;
+    use crate::id_format::with_numeric_ids;
+    use crate::repositories::label::memory::LabelRepositoryForMemory;
+
+    impl TodoEntity {
+        pub fn new(id: i32, text: String, labels: Vec<Label>) -> Self {
+            let now = Local::now().naive_local();
+            Self {
+                id,
+                text,
+                completed: false,
+                due_date: None,
+                priority: Priority::default(),
+                completed_at: None,
+                // メモリ上のリポジトリでは作成した時点の日時を作成日時とする
+                created_at: now,
+                updated_at: now,
+                version: 1,
+                parent_id: None,
+                recurrence: None,
+                // DBの実装に合わせて作成順に末尾へ追加する
+                position: id,
+                user_id: DEFAULT_USER_ID,
+                labels,
+            }
+        }
+    }
+
+    impl OrderBy {
+        fn sort(&self, todos: &mut [TodoEntity]) {
+            todos.sort_by(|a, b| {
+                let ordering = match self.key {
+                    TodoSortKey::Position => a.position.cmp(&b.position).then(a.id.cmp(&b.id)),
+                    TodoSortKey::Id => a.id.cmp(&b.id),
+                    TodoSortKey::Text => a.text.cmp(&b.text).then(a.id.cmp(&b.id)),
+                    TodoSortKey::Completed => a.completed.cmp(&b.completed).then(a.id.cmp(&b.id)),
+                    TodoSortKey::Priority => a.priority.cmp(&b.priority).then(a.id.cmp(&b.id)),
+                    TodoSortKey::CreatedAt => a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)),
+                };
+                match self.order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                }
+            });
+        }
+    }
+
+    impl TodoFilter {
+        fn matches(&self, todo: &TodoEntity) -> bool {
+            self.completed
+                .is_none_or(|completed| todo.completed == completed)
+                && self
+                    .label_ids
+                    .iter()
+                    .all(|id| todo.labels.iter().any(|label| label.id == *id))
+                && self
+                    .parent_id
+                    .is_none_or(|parent_id| todo.parent_id == Some(parent_id))
+                && self
+                    .created_after
+                    .is_none_or(|created_after| todo.created_at >= created_after)
+                && self
+                    .created_before
+                    .is_none_or(|created_before| todo.created_at <= created_before)
+        }
+    }
+
+    pub type TodoDatas = HashMap<i32, TodoEntity>;
+    pub type TodoTombstones = HashSet<i32>;
+
+    // 1.0で完全に一致し、編集が必要な文字が多いほど0.0に近づく
+    fn similarity(a: &str, b: &str) -> f32 {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        let len = a.len().max(b.len());
+        if len == 0 {
+            return 1.0;
+        }
+        1.0 - levenshtein(&a, &b) as f32 / len as f32
+    }
+
+    fn levenshtein(a: &[char], b: &[char]) -> usize {
+        let mut prev: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.iter().enumerate() {
+            let mut current = vec![i + 1];
+            for (j, cb) in b.iter().enumerate() {
+                let cost = usize::from(ca != cb);
+                current.push((prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1));
+            }
+            prev = current;
+        }
+        prev[b.len()]
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct TodoRepositoryForMemory {
+        store: Arc<RwLock<TodoDatas>>,
+        tombstones: Arc<RwLock<TodoTombstones>>, // 論理削除されたTodoのid
+        last_id: Arc<AtomicI32>, // 削除後もidを再利用しないよう採番済みの最大値を持つ
+        audits: Arc<RwLock<Vec<TodoAudit>>>,
+        labels: Arc<RwLock<HashMap<i32, Label>>>, // 付けられるラベル
+        user_id: i32,
+        similarity_threshold: f32, // fuzzy_searchで一致とみなす類似度(0.0〜1.0)
+        default_order: OrderBy,    // 一覧取得で並べ替えのキーが指定されなかった場合の並び順
+        events: TodoEvents,
+        transaction: Arc<Mutex<()>>, // with_transactionの実行中は他の書き込みを待たせる
+        in_transaction: bool,
+    }
+
+    impl TodoRepositoryForMemory {
+        // テストで固定のラベルを付けられるようにする
+        #[cfg(test)]
+        pub fn new(labels: Vec<Label>) -> Self {
+            let labels = labels.into_iter().map(|label| (label.id, label)).collect();
+            Self::with_labels(Arc::new(RwLock::new(labels)))
+        }
+
+        // LabelRepositoryForMemoryで作成・削除したラベルがそのまま反映される
+        // 保存先のTodoも共有し、使用中のラベルを数えられるようにする
+        // ラベルの変更・統合・削除によるTodoの書き換えも、with_transactionの実行中は待たせる
+        pub fn with_label_repository(label_repository: &LabelRepositoryForMemory) -> Self {
+            let (store, tombstones) = label_repository.shared_todo_store();
+            TodoRepositoryForMemory {
+                store,
+                tombstones,
+                transaction: label_repository.shared_todo_transaction(),
+                ..Self::with_labels(label_repository.shared_store())
+            }
+        }
+
+        fn with_labels(labels: Arc<RwLock<HashMap<i32, Label>>>) -> Self {
+            TodoRepositoryForMemory {
+                store: Arc::default(),
+                tombstones: Arc::default(),
+                last_id: Arc::default(),
+                audits: Arc::default(),
+                labels,
+                user_id: DEFAULT_USER_ID,
+                similarity_threshold: DEFAULT_SIMILARITY_THRESHOLD,
+                default_order: OrderBy::default(),
+                events: TodoEvents::new(),
+                transaction: Arc::default(),
+                in_transaction: false,
+            }
+        }
+
+        pub fn with_default_order(self, default_order: OrderBy) -> Self {
+            Self {
+                default_order,
+                ..self
+            }
+        }
+
+        pub fn with_similarity_threshold(self, similarity_threshold: f32) -> Self {
+            Self {
+                similarity_threshold,
+                ..self
+            }
+        }
+
+        // 他のユーザーのTodoは存在しないものとして扱う
+        fn owns(&self, todo: &TodoEntity) -> bool {
+            todo.user_id == self.user_id
+        }
+
+        // 履歴を残す変更は全て通知の対象になるため、ここで合わせて通知する
+        fn record_audit(&self, todo: &TodoEntity, action: AuditAction) {
+            let mut audits = self.audits.write().unwrap();
+            let audit = TodoAudit {
+                id: audits.len() as i32 + 1,
+                todo_id: todo.id,
+                action,
+                snapshot: with_numeric_ids(|| serde_json::to_value(todo).unwrap()),
+                created_at: Local::now().naive_local(),
+            };
+            audits.push(audit);
+            publish(&self.events, todo.user_id, action.into(), &[todo.id]);
+        }
+
+        fn next_id(&self) -> i32 {
+            self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+        }
+
+        // with_transactionの実行中は、外からの書き込みをコミットか取り消しが終わるまで待たせる
+        async fn lock_writes(&self) -> Option<MutexGuard<'_, ()>> {
+            if self.in_transaction {
+                None
+            } else {
+                Some(self.transaction.lock().await)
+            }
+        }
+
+        fn is_deleted(&self, id: i32) -> bool {
+            self.tombstones.read().unwrap().contains(&id)
+        }
+
+        fn ensure_not_deleted(&self, id: i32) -> anyhow::Result<()> {
+            if self.is_deleted(id) {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            Ok(())
+        }
+
+        // DBの実装に合わせて、存在しない親や循環する親はValidationエラーとする
+        fn ensure_valid_parent(
+            &self,
+            store: &TodoDatas,
+            id: Option<i32>,
+            parent_id: i32,
+        ) -> anyhow::Result<()> {
+            if !store
+                .get(&parent_id)
+                .is_some_and(|parent| self.owns(parent))
+                || self.is_deleted(parent_id)
+            {
+                return Err(parent_not_found(parent_id).into());
+            }
+            let mut visited = HashSet::new();
+            let mut current = Some(parent_id);
+            while let Some(ancestor) = current {
+                if Some(ancestor) == id {
+                    return Err(cyclic_parent(parent_id).into());
+                }
+                if !visited.insert(ancestor) {
+                    break;
+                }
+                current = store.get(&ancestor).and_then(|todo| todo.parent_id);
+            }
+            Ok(())
+        }
+
+        // 論理削除済みのものも含めた子孫のid
+        fn descendant_ids(&self, store: &TodoDatas, id: i32) -> Vec<i32> {
+            let mut descendants = vec![];
+            let mut parents = vec![id];
+            while let Some(parent_id) = parents.pop() {
+                for todo in store.values() {
+                    if todo.parent_id == Some(parent_id) && !descendants.contains(&todo.id) {
+                        descendants.push(todo.id);
+                        parents.push(todo.id);
+                    }
+                }
+            }
+            descendants
+        }
+
+        // DBの実装に合わせて、ラベルを含めて複製し次の期限を設定する
+        fn create_next_occurrence(
+            &self,
+            store: &mut TodoDatas,
+            todo: &TodoEntity,
+            recurrence: Recurrence,
+        ) {
+            let due_date = todo.due_date.unwrap_or_else(|| Local::now().date_naive());
+            let id = self.next_id();
+            let next = TodoEntity {
+                due_date: Some(recurrence.next(due_date)),
+                priority: todo.priority,
+                parent_id: todo.parent_id,
+                recurrence: Some(recurrence),
+                user_id: todo.user_id,
+                ..TodoEntity::new(id, todo.text.clone(), todo.labels.clone())
+            };
+            store.insert(id, next.clone());
+            self.record_audit(&next, AuditAction::Create);
+        }
+
+        fn write_store_ref(&self) -> RwLockWriteGuard<'_, TodoDatas> {
+            self.store.write().unwrap()
+        }
+
+        fn read_store_ref(&self) -> RwLockReadGuard<'_, TodoDatas> {
+            self.store.read().unwrap()
+        }
+
+        // DBの実装に合わせて、同じラベルが重複して指定されても1つだけ付ける
+        fn resolve_labels(&self, labels: Vec<i32>) -> anyhow::Result<Vec<Label>> {
+            ensure_label_limit(&labels)?;
+            let existing = self.labels.read().unwrap();
+            let mut seen = HashSet::new();
+            let labels = labels
+                .iter()
+                .filter(|id| seen.insert(**id))
+                .map(|id| {
+                    existing.get(id).cloned().ok_or_else(|| {
+                        RepositoryError::Validation(format!("label {} does not exist", id))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(labels)
+        }
+    }
+
+    #[async_trait]
+    impl TodoRepository for TodoRepositoryForMemory {
+        fn for_user(&self, user_id: i32) -> Self {
+            Self {
+                user_id,
+                ..self.clone()
+            }
+        }
+
+        async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            if let Some(parent_id) = payload.parent_id {
+                self.ensure_valid_parent(&store, None, parent_id)?;
+            }
+            let id = self.next_id();
+            let labels = self.resolve_labels(payload.labels)?;
+            let todo = TodoEntity {
+                due_date: payload.due_date,
+                priority: payload.priority,
+                parent_id: payload.parent_id,
+                recurrence: payload.recurrence,
+                user_id: self.user_id,
+                ..TodoEntity::new(id, payload.text.clone(), labels)
+            };
+            store.insert(id, todo.clone());
+            self.record_audit(&todo, AuditAction::Create);
+            Ok(todo)
+        }
+
+        async fn create_many(&self, payloads: Vec<CreateTodo>) -> anyhow::Result<Vec<TodoEntity>> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            // 途中で失敗しても1件も登録されないよう、先に全てのラベルを解決しておく
+            let labels = payloads
+                .iter()
+                .map(|payload| self.resolve_labels(payload.labels.clone()))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            for parent_id in payloads.iter().filter_map(|payload| payload.parent_id) {
+                self.ensure_valid_parent(&store, None, parent_id)?;
+            }
+            let todos: Vec<TodoEntity> = payloads
+                .into_iter()
+                .zip(labels)
+                .map(|(payload, labels)| {
+                    let id = self.next_id();
+                    let todo = TodoEntity {
+                        due_date: payload.due_date,
+                        priority: payload.priority,
+                        parent_id: payload.parent_id,
+                        recurrence: payload.recurrence,
+                        user_id: self.user_id,
+                        ..TodoEntity::new(id, payload.text, labels)
+                    };
+                    store.insert(id, todo.clone());
+                    self.record_audit(&todo, AuditAction::Create);
+                    todo
+                })
+                .collect();
+            Ok(todos)
+        }
+
+        async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
+            self.ensure_not_deleted(id)?;
+            self.find_including_deleted(id).await
+        }
+
+        async fn find_including_deleted(&self, id: i32) -> anyhow::Result<TodoEntity> {
+            let store = self.read_store_ref();
+            let todo = store
+                .get(&id)
+                .filter(|todo| self.owns(todo))
+                .cloned()
+                .ok_or(RepositoryError::NotFound(id))?;
+            Ok(todo)
+        }
+
+        async fn find_many(&self, ids: Vec<i32>) -> anyhow::Result<Vec<TodoEntity>> {
+            let store = self.read_store_ref();
+            // DBの実装に合わせてid昇順で、重複は1件にまとめる
+            let mut ids = ids;
+            ids.sort_unstable();
+            ids.dedup();
+            let todos = ids
+                .into_iter()
+                .filter(|id| !self.is_deleted(*id))
+                .filter_map(|id| store.get(&id).filter(|todo| self.owns(todo)).cloned())
+                .collect();
+            Ok(todos)
+        }
+
+        async fn all(
+            &self,
+            filter: TodoFilter,
+            sort: TodoSort,
+            pagination: Pagination,
+        ) -> anyhow::Result<Vec<TodoEntity>> {
+            let store = self.read_store_ref();
+            // DBの実装に合わせて並べ替えてから切り出す
+            let mut todos = Vec::from_iter(
+                store
+                    .values()
+                    .filter(|todo| {
+                        self.owns(todo) && !self.is_deleted(todo.id) && filter.matches(todo)
+                    })
+                    .cloned(),
+            );
+            sort.resolve(self.default_order).sort(&mut todos);
+            let todos = todos
+                .into_iter()
+                .skip(pagination.offset as usize)
+                .take(pagination.limit as usize)
+                .collect();
+            Ok(todos)
+        }
+
+        // メモリ上では全件を複製してから1件ずつ返す
+        fn stream_all(&self, filter: TodoFilter, sort: TodoSort) -> TodoStream {
+            let store = self.read_store_ref();
+            let mut todos = Vec::from_iter(
+                store
+                    .values()
+                    .filter(|todo| {
+                        self.owns(todo) && !self.is_deleted(todo.id) && filter.matches(todo)
+                    })
+                    .cloned(),
+            );
+            sort.resolve(self.default_order).sort(&mut todos);
+            Box::pin(futures_util::stream::iter(todos.into_iter().map(Ok)))
+        }
+
+        async fn group_by_label(&self) -> anyhow::Result<Vec<TodoGroup>> {
+            let store = self.read_store_ref();
+            let mut todos = Vec::from_iter(
+                store
+                    .values()
+                    .filter(|todo| self.owns(todo) && !self.is_deleted(todo.id))
+                    .cloned(),
+            );
+            self.default_order.sort(&mut todos);
+            Ok(group_todos_by_label(todos))
+        }
+
+        async fn count(&self, filter: TodoFilter) -> anyhow::Result<i64> {
+            let store = self.read_store_ref();
+            let count = store
+                .values()
+                .filter(|todo| self.owns(todo) && !self.is_deleted(todo.id) && filter.matches(todo))
+                .count();
+            Ok(count as i64)
+        }
+
+        async fn stats(&self) -> anyhow::Result<TodoStats> {
+            let store = self.read_store_ref();
+            let todos: Vec<&TodoEntity> = store
+                .values()
+                .filter(|todo| self.owns(todo) && !self.is_deleted(todo.id))
+                .collect();
+            let count = |todos: &[&TodoEntity]| {
+                let completed = todos.iter().filter(|todo| todo.completed).count();
+                (todos.len() as i64, completed as i64)
+            };
+            let (total, completed) = count(&todos);
+            let mut labels: Vec<LabelStats> = self
+                .labels
+                .read()
+                .unwrap()
+                .values()
+                .map(|label| {
+                    let labeled: Vec<&TodoEntity> = todos
+                        .iter()
+                        .copied()
+                        .filter(|todo| todo.labels.iter().any(|l| l.id == label.id))
+                        .collect();
+                    let (total, completed) = count(&labeled);
+                    LabelStats {
+                        id: label.id,
+                        name: label.name.clone(),
+                        total,
+                        completed,
+                    }
+                })
+                .collect();
+            labels.sort_by_key(|label| label.id);
+            Ok(TodoStats::new(total, completed, labels))
+        }
+
+        async fn search(&self, query: String) -> anyhow::Result<Vec<TodoEntity>> {
+            let store = self.read_store_ref();
+            let query = query.trim().to_lowercase();
+            let mut todos: Vec<TodoEntity> = store
+                .values()
+                .filter(|todo| {
+                    self.owns(todo)
+                        && !self.is_deleted(todo.id)
+                        && todo.text.to_lowercase().contains(&query)
+                })
+                .cloned()
+                .collect();
+            todos.sort_by_key(|todo| Reverse(todo.id));
+            Ok(todos)
+        }
+
+        async fn find_by_text(&self, text: &str) -> anyhow::Result<Vec<TodoEntity>> {
+            let store = self.read_store_ref();
+            let text = text.trim();
+            let mut todos: Vec<TodoEntity> = store
+                .values()
+                .filter(|todo| self.owns(todo) && !self.is_deleted(todo.id) && todo.text == text)
+                .cloned()
+                .collect();
+            todos.sort_by_key(|todo| Reverse(todo.id));
+            Ok(todos)
+        }
+
+        // pg_trgmの代わりにレーベンシュタイン距離から求めた類似度で近似する
+        async fn fuzzy_search(&self, query: String) -> anyhow::Result<Vec<TodoEntity>> {
+            let store = self.read_store_ref();
+            let query = query.trim().to_lowercase();
+            let mut todos: Vec<(f32, TodoEntity)> = store
+                .values()
+                .filter(|todo| self.owns(todo) && !self.is_deleted(todo.id))
+                .map(|todo| (similarity(&todo.text.to_lowercase(), &query), todo.clone()))
+                .filter(|(similarity, _)| *similarity > self.similarity_threshold)
+                .collect();
+            todos
+                .sort_by(|(a, a_todo), (b, b_todo)| b.total_cmp(a).then(b_todo.id.cmp(&a_todo.id)));
+            Ok(todos.into_iter().map(|(_, todo)| todo).collect())
+        }
+
+        async fn find_by_label(&self, label_id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+            if !self.labels.read().unwrap().contains_key(&label_id) {
+                return Err(RepositoryError::LabelNotFound(label_id).into());
+            }
+            let store = self.read_store_ref();
+            let mut todos: Vec<TodoEntity> = store
+                .values()
+                .filter(|todo| {
+                    self.owns(todo)
+                        && !self.is_deleted(todo.id)
+                        && todo.labels.iter().any(|label| label.id == label_id)
+                })
+                .cloned()
+                .collect();
+            todos.sort_by_key(|todo| Reverse(todo.id));
+            Ok(todos)
+        }
+
+        async fn find_labels(&self, id: i32) -> anyhow::Result<Vec<Label>> {
+            self.ensure_not_deleted(id)?;
+            let store = self.read_store_ref();
+            let mut labels = store
+                .get(&id)
+                .filter(|todo| self.owns(todo))
+                .ok_or(RepositoryError::NotFound(id))?
+                .labels
+                .clone();
+            labels.sort_by_key(|label| label.id);
+            Ok(labels)
+        }
+
+        async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            self.ensure_not_deleted(id)?;
+            let mut store = self.write_store_ref();
+            let mut todo = store
+                .get(&id)
+                .filter(|todo| self.owns(todo))
+                .context(RepositoryError::NotFound(id))?
+                .clone();
+            if payload.expected_version.is_some_and(|v| v != todo.version) {
+                return Err(RepositoryError::Conflict(id).into());
+            }
+            if let Some(parent_id) = payload.parent_id {
+                self.ensure_valid_parent(&store, Some(id), parent_id)?;
+                todo.parent_id = Some(parent_id);
+            }
+            todo.version += 1;
+            todo.updated_at = Local::now().naive_local();
+            // 指定されなかった項目は既存の値をそのまま残す
+            if let Some(label_ids) = payload.labels {
+                todo.labels = self.resolve_labels(label_ids)?;
+            }
+            if let Some(text) = payload.text {
+                todo.text = text;
+            }
+            let completed_now = payload.completed == Some(true) && !todo.completed;
+            if let Some(completed) = payload.completed {
+                // 完了状態が変わった時だけcompleted_atを更新する
+                if completed != todo.completed {
+                    todo.completed_at = completed.then(|| Local::now().naive_local());
+                }
+                todo.completed = completed;
+            }
+            if let Some(due_date) = payload.due_date {
+                todo.due_date = Some(due_date);
+            }
+            if let Some(priority) = payload.priority {
+                todo.priority = priority;
+            }
+            if let Some(recurrence) = payload.recurrence {
+                todo.recurrence = Some(recurrence);
+            }
+            store.insert(id, todo.clone());
+            self.record_audit(&todo, AuditAction::Update);
+            if let (Some(recurrence), true) = (todo.recurrence, completed_now) {
+                self.create_next_occurrence(&mut store, &todo, recurrence);
+            }
+            Ok(todo)
+        }
+
+        async fn replace(&self, id: i32, payload: ReplaceTodo) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            self.ensure_not_deleted(id)?;
+            let labels = self.resolve_labels(payload.labels)?;
+            let mut store = self.write_store_ref();
+            if let Some(parent_id) = payload.parent_id {
+                self.ensure_valid_parent(&store, Some(id), parent_id)?;
+            }
+            let todo = store
+                .get_mut(&id)
+                .filter(|todo| self.owns(todo))
+                .ok_or(RepositoryError::NotFound(id))?;
+            if payload.completed != todo.completed {
+                todo.completed_at = payload.completed.then(|| Local::now().naive_local());
+            }
+            todo.text = payload.text;
+            todo.completed = payload.completed;
+            todo.labels = labels;
+            todo.due_date = payload.due_date;
+            todo.priority = payload.priority;
+            todo.parent_id = payload.parent_id;
+            todo.recurrence = payload.recurrence;
+            todo.version += 1;
+            todo.updated_at = Local::now().naive_local();
+            self.record_audit(todo, AuditAction::Update);
+            Ok(todo.clone())
+        }
+
+        async fn toggle(&self, id: i32) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            self.ensure_not_deleted(id)?;
+            let mut store = self.write_store_ref();
+            let todo = store
+                .get_mut(&id)
+                .filter(|todo| self.owns(todo))
+                .ok_or(RepositoryError::NotFound(id))?;
+            todo.completed = !todo.completed;
+            todo.completed_at = todo.completed.then(|| Local::now().naive_local());
+            todo.version += 1;
+            todo.updated_at = Local::now().naive_local();
+            self.record_audit(todo, AuditAction::Update);
+            let todo = todo.clone();
+            if let (Some(recurrence), true) = (todo.recurrence, todo.completed) {
+                self.create_next_occurrence(&mut store, &todo, recurrence);
+            }
+            Ok(todo)
+        }
+
+        async fn complete_all(&self, dry_run: bool) -> anyhow::Result<Vec<i32>> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            let mut ids: Vec<i32> = store
+                .values()
+                .filter(|todo| self.owns(todo) && !todo.completed && !self.is_deleted(todo.id))
+                .map(|todo| todo.id)
+                .collect();
+            ids.sort_unstable();
+            if dry_run {
+                return Ok(ids);
+            }
+            for id in &ids {
+                let todo = store.get_mut(id).unwrap();
+                todo.completed = true;
+                todo.completed_at = Some(Local::now().naive_local());
+                todo.version += 1;
+                todo.updated_at = Local::now().naive_local();
+                self.record_audit(todo, AuditAction::Update);
+                let todo = todo.clone();
+                if let Some(recurrence) = todo.recurrence {
+                    self.create_next_occurrence(&mut store, &todo, recurrence);
+                }
+            }
+            Ok(ids)
+        }
+
+        async fn add_label(&self, todo_id: i32, label_id: i32) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            self.ensure_not_deleted(todo_id)?;
+            let mut store = self.write_store_ref();
+            let todo = store
+                .get_mut(&todo_id)
+                .filter(|todo| self.owns(todo))
+                .ok_or(RepositoryError::NotFound(todo_id))?;
+            let label = self.resolve_labels(vec![label_id])?.remove(0);
+            let mut attached: Vec<i32> = todo.labels.iter().map(|label| label.id).collect();
+            attached.push(label_id);
+            ensure_label_limit(&attached)?;
+            if !todo.labels.contains(&label) {
+                todo.labels.push(label);
+            }
+            publish(
+                &self.events,
+                self.user_id,
+                TodoEventKind::Updated,
+                &[todo_id],
+            );
+            Ok(todo.clone())
+        }
+
+        async fn assign_label(
+            &self,
+            label_id: i32,
+            todo_ids: Vec<i32>,
+        ) -> anyhow::Result<AssignResult> {
+            let _writes = self.lock_writes().await;
+            let label = self
+                .labels
+                .read()
+                .unwrap()
+                .get(&label_id)
+                .cloned()
+                .ok_or(RepositoryError::LabelNotFound(label_id))?;
+            let mut store = self.write_store_ref();
+            let (found, not_found) = partition_ids(todo_ids, |id| {
+                !self.is_deleted(id) && store.get(&id).is_some_and(|todo| self.owns(todo))
+            });
+            // 上限に達しているTodoが1件でもあれば、どのTodoにも付けない
+            if let Some(id) = found.iter().find(|id| {
+                let labels = &store[id].labels;
+                labels.len() >= MAX_LABELS_PER_TODO
+                    && labels.iter().all(|attached| attached.id != label_id)
+            }) {
+                return Err(label_limit_reached(*id));
+            }
+            let mut added = vec![];
+            for id in found {
+                let todo = store.get_mut(&id).unwrap();
+                if todo.labels.iter().all(|attached| attached.id != label_id) {
+                    todo.labels.push(label.clone());
+                    added.push(id);
+                }
+            }
+            publish(&self.events, self.user_id, TodoEventKind::Updated, &added);
+            Ok(AssignResult {
+                added: added.len() as u64,
+                not_found,
+            })
+        }
+
+        async fn update_labels(
+            &self,
+            todo_id: i32,
+            add: Vec<i32>,
+            remove: Vec<i32>,
+        ) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            self.ensure_not_deleted(todo_id)?;
+            let mut store = self.write_store_ref();
+            let todo = store
+                .get_mut(&todo_id)
+                .filter(|todo| self.owns(todo))
+                .ok_or(RepositoryError::NotFound(todo_id))?;
+            let mut labels = todo.labels.clone();
+            for label in self.resolve_labels(add)? {
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+            }
+            labels.retain(|label| !remove.contains(&label.id));
+            let label_ids: Vec<i32> = labels.iter().map(|label| label.id).collect();
+            ensure_label_limit(&label_ids)?;
+            todo.labels = labels;
+            publish(
+                &self.events,
+                self.user_id,
+                TodoEventKind::Updated,
+                &[todo_id],
+            );
+            Ok(todo.clone())
+        }
+
+        async fn remove_label(&self, todo_id: i32, label_id: i32) -> anyhow::Result<()> {
+            let _writes = self.lock_writes().await;
+            self.ensure_not_deleted(todo_id)?;
+            let mut store = self.write_store_ref();
+            let todo = store
+                .get_mut(&todo_id)
+                .filter(|todo| self.owns(todo))
+                .ok_or(RepositoryError::NotFound(todo_id))?;
+            todo.labels.retain(|label| label.id != label_id);
+            publish(
+                &self.events,
+                self.user_id,
+                TodoEventKind::Updated,
+                &[todo_id],
+            );
+            Ok(())
+        }
+
+        async fn delete(&self, id: i32, cascade: bool) -> anyhow::Result<()> {
+            let _writes = self.lock_writes().await;
+            let store = self.read_store_ref();
+            // 存在しない、または既に論理削除済みの場合はNotFound
+            if !store.get(&id).is_some_and(|todo| self.owns(todo)) || self.is_deleted(id) {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            let mut ids: Vec<i32> = self
+                .descendant_ids(&store, id)
+                .into_iter()
+                .filter(|id| !self.is_deleted(*id))
+                .collect();
+            if !ids.is_empty() && !cascade {
+                return Err(RepositoryError::Conflict(id).into());
+            }
+            ids.push(id);
+            let mut tombstones = self.tombstones.write().unwrap();
+            for id in ids {
+                tombstones.insert(id);
+                self.record_audit(&store[&id], AuditAction::Delete);
+            }
+            Ok(())
+        }
+
+        async fn restore(&self, id: i32) -> anyhow::Result<TodoEntity> {
+            let _writes = self.lock_writes().await;
+            self.find_including_deleted(id).await?;
+            if !self.tombstones.write().unwrap().remove(&id) {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            let todo = self.find(id).await?;
+            self.record_audit(&todo, AuditAction::Restore);
+            Ok(todo)
+        }
+
+        async fn purge(&self, id: i32, cascade: bool) -> anyhow::Result<()> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            if !store.get(&id).is_some_and(|todo| self.owns(todo)) {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            let mut ids = self.descendant_ids(&store, id);
+            if !ids.is_empty() && !cascade {
+                return Err(RepositoryError::Conflict(id).into());
+            }
+            ids.push(id);
+            for id in ids {
+                let todo = store.remove(&id).unwrap();
+                self.tombstones.write().unwrap().remove(&id);
+                self.record_audit(&todo, AuditAction::Purge);
+            }
+            Ok(())
+        }
+
+        async fn reorder(&self, ordered_ids: Vec<i32>) -> anyhow::Result<()> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            let existing = store
+                .values()
+                .filter(|todo| self.owns(todo) && !self.is_deleted(todo.id))
+                .map(|todo| todo.id)
+                .collect();
+            ensure_complete_order(&ordered_ids, &existing)?;
+            for (position, id) in ordered_ids.iter().enumerate() {
+                store.get_mut(id).unwrap().position = position as i32 + 1;
+            }
+            publish(
+                &self.events,
+                self.user_id,
+                TodoEventKind::Updated,
+                &ordered_ids,
+            );
+            Ok(())
+        }
+
+        async fn delete_completed(&self, dry_run: bool) -> anyhow::Result<Vec<i32>> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            // 子を持つTodoは子が宙に浮かないよう残す
+            let parent_ids: HashSet<i32> =
+                store.values().filter_map(|todo| todo.parent_id).collect();
+            let mut ids: Vec<i32> = store
+                .values()
+                .filter(|todo| {
+                    self.owns(todo)
+                        && todo.completed
+                        && !self.is_deleted(todo.id)
+                        && !parent_ids.contains(&todo.id)
+                })
+                .map(|todo| todo.id)
+                .collect();
+            ids.sort_unstable();
+            if dry_run {
+                return Ok(ids);
+            }
+            for id in &ids {
+                let todo = store.remove(id).unwrap();
+                self.record_audit(&todo, AuditAction::Purge);
+            }
+            Ok(ids)
+        }
+
+        async fn delete_many(&self, ids: Vec<i32>) -> anyhow::Result<u64> {
+            let _writes = self.lock_writes().await;
+            let mut store = self.write_store_ref();
+            let mut targets: HashSet<i32> = ids
+                .into_iter()
+                .filter(|id| {
+                    store.get(id).is_some_and(|todo| self.owns(todo)) && !self.is_deleted(*id)
+                })
+                .collect();
+            // 対象外の子を持つTodoは子が宙に浮かないよう残し、残したTodoの親も同じく残す
+            loop {
+                let blocked: HashSet<i32> = store
+                    .values()
+                    .filter(|todo| !targets.contains(&todo.id))
+                    .filter_map(|todo| todo.parent_id)
+                    .filter(|parent_id| targets.contains(parent_id))
+                    .collect();
+                if blocked.is_empty() {
+                    break;
+                }
+                targets.retain(|id| !blocked.contains(id));
+            }
+            let mut ids: Vec<i32> = targets.into_iter().collect();
+            ids.sort_unstable();
+            for id in &ids {
+                let todo = store.remove(id).unwrap();
+                self.record_audit(&todo, AuditAction::Purge);
+            }
+            Ok(ids.len() as u64)
+        }
+
+        async fn history(&self, id: i32) -> anyhow::Result<Vec<TodoAudit>> {
+            let audits: Vec<TodoAudit> = self
+                .audits
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|audit| audit.todo_id == id && audit.snapshot["user_id"] == self.user_id)
+                .cloned()
+                .collect();
+            if audits.is_empty() {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+            Ok(audits)
+        }
+
+        async fn ping(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+            self.events.subscribe()
+        }
+
+        // 開始時の状態を複製しておき、Errであれば書き戻す
+        // 外からの書き込みはlock_writesで待たせているため、書き戻すのはfの中の変更だけになる
+        async fn with_transaction<R, F>(&self, f: F) -> anyhow::Result<R>
+        where
+            R: Send,
+            F: for<'a> FnOnce(&'a Self) -> TransactionFuture<'a, R> + Send,
+        {
+            if self.in_transaction {
+                return f(self).await;
+            }
+            let _guard = self.transaction.lock().await;
+            // DBのシーケンスと同じく、採番したidは取り消さない
+            let store = self.store.read().unwrap().clone();
+            let tombstones = self.tombstones.read().unwrap().clone();
+            let audits = self.audits.read().unwrap().clone();
+            let repository = Self {
+                events: self.events.buffered(),
+                in_transaction: true,
+                ..self.clone()
+            };
+            let result = f(&repository).await;
+            match result {
+                Ok(_) => repository.events.flush(),
+                Err(_) => {
+                    *self.store.write().unwrap() = store;
+                    *self.tombstones.write().unwrap() = tombstones;
+                    *self.audits.write().unwrap() = audits;
+                }
+            }
+            result
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[tokio::test]
+        async fn todo_crud_scenario() {
+            let text = "todo text".to_string();
+            let id = 1;
+            let label_data = Label {
+                id: 1,
+                name: String::from("test label"),
+                color: None,
+            };
+            let labels = vec![label_data.clone()];
+            let expected = TodoEntity::new(id, text.clone(), labels.clone());
+
+            // create
+            let label_data = Label {
+                id: 1,
+                name: String::from("test label"),
+                color: None,
+            };
+            let labels = vec![label_data.clone()];
+            let repository = TodoRepositoryForMemory::new(labels.clone());
+            let todo = repository
+                .create(CreateTodo::new(text, vec![label_data.id]))
+                .await
+                .expect("failed create todo");
+            let expected = TodoEntity {
+                created_at: todo.created_at,
+                updated_at: todo.updated_at,
+                ..expected
+            };
+            assert_eq!(expected, todo);
+
+            // find
+            let todo = repository.find(todo.id).await.unwrap();
+            assert_eq!(expected, todo);
+
+            // all
+            let todo = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![expected.clone()], todo);
+
+            // update
+            let text = "update todo text".to_string();
+            let todo = repository
+                .update(
+                    1,
+                    UpdateTodo::new(Some(text.clone()), Some(true), Some(vec![])),
+                )
+                .await
+                .expect("failed update todo.");
+            assert_eq!(
+                TodoEntity {
+                    completed: true,
+                    completed_at: todo.completed_at,
+                    created_at: expected.created_at,
+                    updated_at: todo.updated_at,
+                    version: 2,
+                    ..TodoEntity::new(id, text, vec![])
+                },
+                todo
+            );
+            assert!(todo.completed_at.is_some());
+            assert!(todo.updated_at > expected.updated_at);
+
+            // delete
+            let res = repository.delete(id, false).await;
+            assert!(res.is_ok())
+        }
+
+        #[tokio::test]
+        async fn create_does_not_reuse_purged_id() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=3 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository.purge(2, false).await.expect("failed purge todo");
+
+            let todo = repository
+                .create(CreateTodo::new("todo 4".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            assert_eq!(4, todo.id);
+            let todo = repository.find(3).await.expect("failed find todo");
+            assert_eq!("todo 3", todo.text);
+        }
+
+        #[tokio::test]
+        async fn update_keeps_labels() {
+            let label = Label::new(1, String::from("test label"));
+            let repository = TodoRepositoryForMemory::new(vec![label.clone()]);
+            let todo = repository
+                .create(CreateTodo::new("todo text".to_string(), vec![label.id]))
+                .await
+                .expect("failed create todo");
+
+            let todo = repository
+                .update(
+                    todo.id,
+                    UpdateTodo::new(Some("update todo text".to_string()), None, None),
+                )
+                .await
+                .expect("failed update todo.");
+            assert_eq!("update todo text", todo.text);
+            assert_eq!(vec![label], todo.labels);
+        }
+
+        #[tokio::test]
+        async fn ignore_duplicated_labels() {
+            let label = Label::new(1, String::from("test label"));
+            let repository = TodoRepositoryForMemory::new(vec![label.clone()]);
+            let todo = repository
+                .create(CreateTodo::new(
+                    "todo text".to_string(),
+                    vec![label.id, label.id],
+                ))
+                .await
+                .expect("failed create todo");
+            assert_eq!(vec![label.clone()], todo.labels);
+
+            let todo = repository
+                .update(
+                    todo.id,
+                    UpdateTodo::new(None, None, Some(vec![label.id, label.id])),
+                )
+                .await
+                .expect("failed update todo.");
+            assert_eq!(vec![label], todo.labels);
+        }
+
+        #[tokio::test]
+        async fn update_with_unknown_label() {
+            let label = Label::new(1, String::from("test label"));
+            let repository = TodoRepositoryForMemory::new(vec![label.clone()]);
+            let todo = repository
+                .create(CreateTodo::new("todo text".to_string(), vec![label.id]))
+                .await
+                .expect("failed create todo");
+
+            let res = repository
+                .update(
+                    todo.id,
+                    UpdateTodo::new(None, None, Some(vec![label.id, 999])),
+                )
+                .await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Validation(_))
+            ));
+
+            let todo = repository.find(todo.id).await.unwrap();
+            assert_eq!(vec![label], todo.labels);
+        }
+
+        #[tokio::test]
+        async fn add_and_remove_label() {
+            let label = Label::new(1, String::from("test label"));
+            let repository = TodoRepositoryForMemory::new(vec![label.clone()]);
+            let todo = repository
+                .create(CreateTodo::new("todo text".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+
+            // 2回追加しても紐付けは1つだけ
+            repository
+                .add_label(todo.id, label.id)
+                .await
+                .expect("failed add label");
+            let todo = repository
+                .add_label(todo.id, label.id)
+                .await
+                .expect("failed add label");
+            assert_eq!(vec![label.clone()], todo.labels);
+
+            assert!(repository.add_label(todo.id, 999).await.is_err());
+            assert!(repository.add_label(999, label.id).await.is_err());
+
+            repository
+                .remove_label(todo.id, label.id)
+                .await
+                .expect("failed remove label");
+            let todo = repository.find(todo.id).await.unwrap();
+            assert!(todo.labels.is_empty());
+        }
+
+        #[tokio::test]
+        async fn reject_labels_over_limit() {
+            let labels: Vec<Label> = (1..=MAX_LABELS_PER_TODO as i32 + 1)
+                .map(|id| Label::new(id, format!("label {}", id)))
+                .collect();
+            let label_ids: Vec<i32> = labels.iter().map(|label| label.id).collect();
+            let repository = TodoRepositoryForMemory::new(labels.clone());
+            let (last, limit) = label_ids.split_last().unwrap();
+            let todo = repository
+                .create(CreateTodo::new("todo text".to_string(), limit.to_vec()))
+                .await
+                .expect("failed create todo");
+
+            let is_validation_error = |res: anyhow::Result<TodoEntity>| {
+                matches!(
+                    res.unwrap_err().downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::Validation(_))
+                )
+            };
+            // 上限に達したTodoには付けられない、付いているラベルは何度付けても変わらない
+            assert!(is_validation_error(
+                repository.add_label(todo.id, *last).await
+            ));
+            repository
+                .add_label(todo.id, limit[0])
+                .await
+                .expect("failed add label");
+            assert!(is_validation_error(
+                repository
+                    .update(
+                        todo.id,
+                        UpdateTodo::new(None, None, Some(label_ids.clone()))
+                    )
+                    .await
+            ));
+            assert!(is_validation_error(
+                repository
+                    .create(CreateTodo::new("todo text".to_string(), label_ids.clone()))
+                    .await
+            ));
+            let res = repository.assign_label(*last, vec![todo.id]).await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Validation(_))
+            ));
+
+            let todo = repository.find(todo.id).await.unwrap();
+            assert_eq!(labels[..MAX_LABELS_PER_TODO].to_vec(), todo.labels);
+        }
+
+        #[tokio::test]
+        async fn find_todos_by_text() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for text in ["buy milk", "buy milk", "Buy milk", "buy milk now"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let todos = repository
+                .find_by_text(" buy milk ")
+                .await
+                .expect("failed find todos");
+            assert_eq!(
+                vec![2, 1],
+                todos.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            );
+            let todos = repository
+                .for_user(2)
+                .find_by_text("buy milk")
+                .await
+                .expect("failed find todos");
+            assert!(todos.is_empty());
+        }
+
+        #[tokio::test]
+        async fn rollback_transaction_on_error() {
+            let label = Label::new(1, "label".to_string());
+            let repository = TodoRepositoryForMemory::new(vec![label.clone()]);
+            let mut events = repository.subscribe();
+
+            let res = repository
+                .with_transaction(|repository| {
+                    Box::pin(async move {
+                        let todo = repository
+                            .create(CreateTodo::new("rollback".to_string(), vec![]))
+                            .await?;
+                        repository.add_label(todo.id, 999).await
+                    })
+                })
+                .await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<RepositoryError>(),
+                Some(RepositoryError::Validation(_))
+            ));
+            assert_eq!(0, repository.count(TodoFilter::default()).await.unwrap());
+            assert!(events.try_recv().is_err());
+
+            let todo = repository
+                .with_transaction(|repository| {
+                    Box::pin(async move {
+                        let todo = repository
+                            .create(CreateTodo::new("commit".to_string(), vec![]))
+                            .await?;
+                        repository.add_label(todo.id, label.id).await
+                    })
+                })
+                .await
+                .expect("failed transaction");
+            assert_eq!(todo, repository.find(todo.id).await.unwrap());
+            assert_eq!(TodoEventKind::Created, events.try_recv().unwrap().kind);
+            assert_eq!(TodoEventKind::Updated, events.try_recv().unwrap().kind);
+
+            // fが握りつぶしたエラーのメソッドの変更は残さず、それ以外をコミットする
+            let todo = repository
+                .with_transaction(|repository| {
+                    Box::pin(async move {
+                        let todo = repository
+                            .create(CreateTodo::new("swallow".to_string(), vec![]))
+                            .await?;
+                        let res = repository
+                            .update(
+                                todo.id,
+                                UpdateTodo::new(Some("changed".to_string()), None, Some(vec![999])),
+                            )
+                            .await;
+                        assert!(res.is_err());
+                        repository.find(todo.id).await
+                    })
+                })
+                .await
+                .expect("failed transaction");
+            assert_eq!("swallow", todo.text);
+            assert_eq!(todo, repository.find(todo.id).await.unwrap());
+        }
+
+        #[tokio::test]
+        async fn keep_writes_outside_failed_transaction() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+            let (finish_tx, finish_rx) = tokio::sync::oneshot::channel::<()>();
+
+            let transaction = tokio::spawn({
+                let repository = repository.clone();
+                async move {
+                    repository
+                        .with_transaction(|repository| {
+                            Box::pin(async move {
+                                repository
+                                    .create(CreateTodo::new("rollback".to_string(), vec![]))
+                                    .await?;
+                                started_tx.send(()).unwrap();
+                                finish_rx.await?;
+                                Err::<(), _>(anyhow::anyhow!("failed"))
+                            })
+                        })
+                        .await
+                }
+            });
+            started_rx.await.unwrap();
+            // トランザクションの実行中に外から書き込む
+            let outside = tokio::spawn({
+                let repository = repository.clone();
+                async move {
+                    repository
+                        .create(CreateTodo::new("outside".to_string(), vec![]))
+                        .await
+                }
+            });
+            tokio::task::yield_now().await;
+            finish_tx.send(()).unwrap();
+
+            assert!(transaction.await.unwrap().is_err());
+            let outside = outside.await.unwrap().expect("failed create todo");
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(vec![outside], todos);
+        }
+
+        #[tokio::test]
+        async fn publish_all_events_on_commit() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            let mut events = repository.subscribe();
+            let count = TODO_EVENT_CAPACITY + 44;
+
+            let last_id = repository
+                .with_transaction(|repository| {
+                    Box::pin(async move {
+                        let mut last_id = 0;
+                        for i in 0..count {
+                            let todo = repository
+                                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                                .await?;
+                            last_id = todo.id;
+                        }
+                        Ok(last_id)
+                    })
+                })
+                .await
+                .expect("failed transaction");
+
+            // 購読者の容量を超えた分はLaggedとして知らされ、黙って捨てられることはない
+            let mut received = 0;
+            let mut last_event = None;
+            loop {
+                match events.try_recv() {
+                    Ok(event) => {
+                        received += 1;
+                        last_event = Some(event);
+                    }
+                    Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                        received += skipped as usize
+                    }
+                    Err(_) => break,
+                }
+            }
+            assert_eq!(count, received);
+            assert_eq!(
+                Some(TodoEvent {
+                    kind: TodoEventKind::Created,
+                    id: last_id,
+                    user_id: DEFAULT_USER_ID,
+                }),
+                last_event
+            );
+        }
+
+        #[tokio::test]
+        async fn completed_at_follows_completed() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            let todo = repository
+                .create(CreateTodo::new("todo text".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            assert_eq!(None, todo.completed_at);
+
+            let todo = repository
+                .update(todo.id, UpdateTodo::new(None, Some(true), None))
+                .await
+                .expect("failed update todo.");
+            let completed_at = todo.completed_at;
+            assert!(completed_at.is_some());
+
+            // completedが変わらない更新では日時を動かさない
+            let todo = repository
+                .update(
+                    todo.id,
+                    UpdateTodo::new(Some("update todo text".to_string()), Some(true), None),
+                )
+                .await
+                .expect("failed update todo.");
+            assert_eq!(completed_at, todo.completed_at);
+
+            let todo = repository
+                .update(todo.id, UpdateTodo::new(None, Some(false), None))
+                .await
+                .expect("failed update todo.");
+            assert_eq!(None, todo.completed_at);
+        }
+
+        #[tokio::test]
+        async fn soft_delete_and_restore() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            let todo = repository
+                .create(CreateTodo::new("todo text".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+
+            repository
+                .delete(todo.id, false)
+                .await
+                .expect("failed delete todo");
+            assert!(repository.find(todo.id).await.is_err());
+            assert!(repository.delete(todo.id, false).await.is_err());
+            assert_eq!(
+                todo,
+                repository.find_including_deleted(todo.id).await.unwrap()
+            );
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .unwrap();
+            assert!(todos.is_empty());
+
+            let restored = repository
+                .restore(todo.id)
+                .await
+                .expect("failed restore todo");
+            assert_eq!(todo, restored);
+            assert!(repository.restore(todo.id).await.is_err());
+
+            repository
+                .purge(todo.id, false)
+                .await
+                .expect("failed purge todo");
+            assert!(repository.find_including_deleted(todo.id).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn todo_pagination() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=5 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination {
+                        limit: 2,
+                        offset: 1,
+                    },
+                )
+                .await
+                .expect("failed get all todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![2, 3], ids);
+
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination {
+                        limit: 2,
+                        offset: 4,
+                    },
+                )
+                .await
+                .expect("failed get all todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![5], ids);
+        }
+
+        #[tokio::test]
+        async fn todo_sort_by_priority() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for priority in [
+                Priority::High,
+                Priority::Low,
+                Priority::High,
+                Priority::Medium,
+            ] {
+                repository
+                    .create(CreateTodo {
+                        priority,
+                        ..CreateTodo::new("todo".to_string(), vec![])
+                    })
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort {
+                        sort: Some(TodoSortKey::Priority),
+                        ..TodoSort::default()
+                    },
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![3, 1, 4, 2], ids);
+        }
+
+        #[tokio::test]
+        async fn todo_sort_by_text_asc() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for text in ["banana", "apple", "cherry", "apple"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort {
+                        sort: Some(TodoSortKey::Text),
+                        order: Some(SortOrder::Asc),
+                    },
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![2, 4, 1, 3], ids);
+        }
+
+        #[tokio::test]
+        async fn todo_default_order() {
+            let repository = TodoRepositoryForMemory::new(vec![])
+                .with_default_order(OrderBy::new(TodoSortKey::Text, Some(SortOrder::Desc)));
+            for text in ["banana", "apple", "cherry"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            // sortを省略した場合はリポジトリに設定した既定の並び順を使う
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![3, 1, 2], ids);
+
+            let todos: Vec<TodoEntity> = repository
+                .stream_all(TodoFilter::default(), TodoSort::default())
+                .try_collect()
+                .await
+                .expect("failed stream todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![3, 1, 2], ids);
+        }
+
+        #[tokio::test]
+        async fn todo_completed_filter() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=3 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository
+                .update(2, UpdateTodo::new(None, Some(true), None))
+                .await
+                .expect("failed update todo.");
+
+            let ids = |todos: Vec<TodoEntity>| todos.iter().map(|todo| todo.id).collect::<Vec<_>>();
+            let completed = repository
+                .all(
+                    TodoFilter {
+                        completed: Some(true),
+                        ..TodoFilter::default()
+                    },
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![2], ids(completed));
+
+            let incomplete = repository
+                .all(
+                    TodoFilter {
+                        completed: Some(false),
+                        ..TodoFilter::default()
+                    },
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![1, 3], ids(incomplete));
+
+            let all = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![1, 2, 3], ids(all));
+        }
+
+        #[tokio::test]
+        async fn todo_label_filter() {
+            let labels = vec![
+                Label::new(1, "label 1".to_string()),
+                Label::new(2, "label 2".to_string()),
+            ];
+            let repository = TodoRepositoryForMemory::new(labels);
+            for label_ids in [vec![1, 2], vec![1], vec![]] {
+                repository
+                    .create(CreateTodo::new("todo".to_string(), label_ids))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let ids = |todos: Vec<TodoEntity>| todos.iter().map(|todo| todo.id).collect::<Vec<_>>();
+            let single = repository
+                .all(
+                    TodoFilter {
+                        label_ids: vec![1],
+                        ..TodoFilter::default()
+                    },
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![1, 2], ids(single));
+
+            let multi = repository
+                .all(
+                    TodoFilter {
+                        label_ids: vec![1, 2],
+                        ..TodoFilter::default()
+                    },
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![1], ids(multi));
+        }
+
+        #[tokio::test]
+        async fn todo_fuzzy_search() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for text in ["buy milk", "walk the dog", "Buy Milk and eggs"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            // 似ているものから順に返す
+            let todos = repository
+                .fuzzy_search("by milk".to_string())
+                .await
+                .expect("failed fuzzy search todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![1, 3], ids);
+        }
+
+        #[test]
+        fn similarity_test() {
+            assert_eq!(1.0, similarity("milk", "milk"));
+            assert_eq!(0.75, similarity("milk", "mil"));
+            assert_eq!(0.0, similarity("abc", "xyz"));
+        }
+
+        #[tokio::test]
+        async fn todo_find_many() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=3 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository
+                .delete(2, false)
+                .await
+                .expect("failed delete todo.");
+
+            let todos = repository
+                .find_many(vec![3, 99, 1, 2, 1])
+                .await
+                .expect("failed find many todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![1, 3], ids);
+        }
+
+        #[test]
+        fn recurrence_next() {
+            let date = |m, d| NaiveDate::from_ymd_opt(2023, m, d).unwrap();
+            assert_eq!(date(3, 1), Recurrence::Daily.next(date(2, 28)));
+            assert_eq!(date(3, 7), Recurrence::Weekly.next(date(2, 28)));
+            // 翌月に同じ日がなければ月末
+            assert_eq!(date(2, 28), Recurrence::Monthly.next(date(1, 31)));
+        }
+
+        #[tokio::test]
+        async fn todo_recurrence() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            let due_date = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+            repository
+                .create(CreateTodo {
+                    due_date: Some(due_date),
+                    recurrence: Some(Recurrence::Daily),
+                    ..CreateTodo::new("daily".to_string(), vec![])
+                })
+                .await
+                .expect("failed create todo");
+
+            let todo = repository
+                .update(1, UpdateTodo::new(None, Some(true), None))
+                .await
+                .expect("failed update todo.");
+            assert!(todo.completed);
+            let next = repository.find(2).await.expect("failed find todo");
+            assert!(!next.completed);
+            assert_eq!(Some(due_date + Days::new(1)), next.due_date);
+            assert_eq!(Some(Recurrence::Daily), next.recurrence);
+
+            // トグルで完了にした場合も次の回が作られる
+            let todo = repository.toggle(2).await.expect("failed toggle todo");
+            assert!(todo.completed);
+            let next = repository.find(3).await.expect("failed find todo");
+            assert_eq!(Some(due_date + Days::new(2)), next.due_date);
+
+            // まとめて完了にした場合も次の回が作られる
+            let ids = repository
+                .complete_all(false)
+                .await
+                .expect("failed complete all todo");
+            assert_eq!(vec![3], ids);
+            let next = repository.find(4).await.expect("failed find todo");
+            assert!(!next.completed);
+            assert_eq!(Some(due_date + Days::new(3)), next.due_date);
+            assert_eq!(Some(Recurrence::Daily), next.recurrence);
+        }
+
+        #[tokio::test]
+        async fn todo_complete_all() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=3 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository
+                .update(1, UpdateTodo::new(None, Some(true), None))
+                .await
+                .expect("failed update todo.");
+            repository
+                .delete(3, false)
+                .await
+                .expect("failed delete todo.");
+
+            // dry_runでは対象のidを返すだけで変更しない
+            let ids = repository
+                .complete_all(true)
+                .await
+                .expect("failed complete all todo");
+            assert_eq!(vec![2], ids);
+            assert!(!repository.find(2).await.unwrap().completed);
+
+            // 論理削除済みのTodoは対象外
+            let ids = repository
+                .complete_all(false)
+                .await
+                .expect("failed complete all todo");
+            assert_eq!(vec![2], ids);
+            let todo = repository.find(2).await.unwrap();
+            assert!(todo.completed);
+            assert!(todo.completed_at.is_some());
+
+            // 全て完了済みなら何もしない
+            let ids = repository
+                .complete_all(false)
+                .await
+                .expect("failed complete all todo");
+            assert!(ids.is_empty());
+        }
+
+        #[tokio::test]
+        async fn todo_reorder() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=4 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository
+                .delete(4, false)
+                .await
+                .expect("failed delete todo.");
+            let ids = |todos: Vec<TodoEntity>| todos.iter().map(|todo| todo.id).collect::<Vec<_>>();
+
+            // 論理削除済みのTodoは指定しない
+            repository
+                .reorder(vec![3, 1, 2])
+                .await
+                .expect("failed reorder todo");
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![3, 1, 2], ids(todos));
+
+            // 作成したTodoは末尾に追加される
+            repository
+                .create(CreateTodo::new("todo 5".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert_eq!(vec![3, 1, 2, 5], ids(todos));
 
-        let repository = TodoRepositoryForDb::new(pool.clone());
-        let todo_text = "[crud_scenario] text";
+            for (ordered_ids, expected) in [
+                (vec![3, 1, 2, 5, 999], "todo 999 does not exist"),
+                (vec![3, 1, 2, 5, 4], "todo 4 does not exist"),
+                (vec![3, 1, 1, 2, 5], "todo 1 is duplicated"),
+                (vec![3, 1, 5], "todo 2 is missing"),
+            ] {
+                let res = repository.reorder(ordered_ids).await;
+                assert!(matches!(
+                    res.unwrap_err().downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::Validation(message)) if message == expected
+                ));
+            }
+        }
 
-        // create
-        let created = repository
-            .create(CreateTodo::new(todo_text.to_string(), vec![label_1.id]))
-            .await
-            .expect("[create] returned Err");
-        assert_eq!(created.text, todo_text);
-        assert!(!created.completed);
-        assert_eq!(*created.labels.first().unwrap(), label_1);
+        #[tokio::test]
+        async fn todo_delete_completed() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=4 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            for id in [1, 3] {
+                repository
+                    .update(id, UpdateTodo::new(None, Some(true), None))
+                    .await
+                    .expect("failed update todo.");
+            }
 
-        // find
-        let todo = repository
-            .find(created.id)
-            .await
-            .expect("[find] returned Err");
-        assert_eq!(created, todo);
+            let dry_run = repository
+                .delete_completed(true)
+                .await
+                .expect("failed delete completed todo");
+            assert_eq!(vec![1, 3], dry_run);
+            assert!(repository.find(1).await.is_ok());
 
-        // all
-        let todos = repository.all().await.expect("[all] returned Err");
-        let todo = todos.first().unwrap();
-        assert_eq!(created, *todo);
+            let ids = repository
+                .delete_completed(false)
+                .await
+                .expect("failed delete completed todo");
+            assert_eq!(dry_run, ids);
+            let todos = repository
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            let ids: Vec<i32> = todos.iter().map(|todo| todo.id).collect();
+            assert_eq!(vec![2, 4], ids);
+        }
 
-        // update
-        let updated_text = "[crud_scenario] updated text";
-        let todo = repository
-            .update(
-                todo.id,
-                UpdateTodo {
-                    text: Some(updated_text.to_string()),
+        #[tokio::test]
+        async fn todo_count() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            for i in 1..=3 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository
+                .update(1, UpdateTodo::new(None, Some(true), None))
+                .await
+                .expect("failed update todo.");
+            repository
+                .delete(3, false)
+                .await
+                .expect("failed delete todo.");
+
+            let total = repository
+                .count(TodoFilter::default())
+                .await
+                .expect("failed count todo");
+            assert_eq!(2, total);
+
+            let completed = repository
+                .count(TodoFilter {
                     completed: Some(true),
-                    labels: Some(vec![]),
+                    ..TodoFilter::default()
+                })
+                .await
+                .expect("failed count todo");
+            assert_eq!(1, completed);
+        }
+
+        #[tokio::test]
+        async fn todo_user_scope() {
+            let owner = TodoRepositoryForMemory::new(vec![]).for_user(1);
+            let other = owner.for_user(2);
+            let todo = owner
+                .create(CreateTodo::new("todo".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            assert_eq!(1, todo.user_id);
+
+            let not_found = |error: anyhow::Error| {
+                matches!(
+                    error.downcast_ref::<RepositoryError>(),
+                    Some(RepositoryError::NotFound(1))
+                )
+            };
+            assert!(not_found(other.find(1).await.unwrap_err()));
+            assert!(not_found(
+                other
+                    .update(1, UpdateTodo::new(None, Some(true), None))
+                    .await
+                    .unwrap_err()
+            ));
+            assert!(not_found(other.delete(1, false).await.unwrap_err()));
+            assert!(not_found(other.history(1).await.unwrap_err()));
+            let todos = other
+                .all(
+                    TodoFilter::default(),
+                    TodoSort::default(),
+                    Pagination::default(),
+                )
+                .await
+                .expect("failed get all todo");
+            assert!(todos.is_empty());
+            assert!(other
+                .complete_all(false)
+                .await
+                .expect("failed complete all")
+                .is_empty());
+
+            // 同じストアを共有していても、所有者からは変更されずに見える
+            assert_eq!(todo, owner.find(1).await.expect("failed find todo"));
+        }
+
+        #[tokio::test]
+        async fn todo_stats() {
+            let labels = vec![
+                Label {
+                    id: 1,
+                    name: "used".to_string(),
+                    color: None,
                 },
-            )
-            .await
-            .expect("[update] returned Err");
-        assert_eq!(created.id, todo.id);
-        assert_eq!(todo.text, updated_text);
-        assert!(todo.labels.len() == 0);
+                Label {
+                    id: 2,
+                    name: "unused".to_string(),
+                    color: None,
+                },
+            ];
+            let repository = TodoRepositoryForMemory::new(labels);
+            for text in ["todo 1", "todo 2", "todo 3"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![1]))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository.toggle(1).await.expect("failed toggle todo");
+            // 論理削除したものや他のユーザーのTodoは数えない
+            repository
+                .delete(3, false)
+                .await
+                .expect("failed delete todo");
+            repository
+                .for_user(2)
+                .create(CreateTodo::new("other".to_string(), vec![1]))
+                .await
+                .expect("failed create todo");
 
-        // delete
-        let _ = repository
-            .delete(todo.id)
-            .await
-            .expect("[delete] returned Err");
-        let res = repository.find(created.id).await; // expect not found err
-        assert!(res.is_err());
+            let stats = repository.stats().await.expect("failed get stats");
+            let expected = TodoStats {
+                total: 2,
+                completed: 1,
+                incomplete: 1,
+                labels: vec![
+                    LabelStats {
+                        id: 1,
+                        name: "used".to_string(),
+                        total: 2,
+                        completed: 1,
+                    },
+                    LabelStats {
+                        id: 2,
+                        name: "unused".to_string(),
+                        total: 0,
+                        completed: 0,
+                    },
+                ],
+            };
+            assert_eq!(expected, stats);
+        }
 
-        let todo_rows = sqlx::query(
-            r#"
-select * from todos where id=$1
-        "#,
-        )
-        .bind(todo.id)
-        .fetch_all(&pool)
-        .await
-        .expect("[delete] todo_labels fetch error");
-        assert!(todo_rows.len() == 0);
+        #[tokio::test]
+        async fn publish_events() {
+            let repository = TodoRepositoryForMemory::new(vec![]);
+            let mut events = repository.subscribe();
+            repository
+                .create(CreateTodo::new("todo".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
+            repository.toggle(1).await.expect("failed toggle todo");
+            repository
+                .delete(1, false)
+                .await
+                .expect("failed delete todo");
+            // for_userで作ったリポジトリの変更も同じチャネルに流れる
+            repository
+                .for_user(2)
+                .create(CreateTodo::new("other".to_string(), vec![]))
+                .await
+                .expect("failed create todo");
 
-        let rows = sqlx::query(
-            r#"
-select * from todo_labels where todo_id=$1
-        "#,
-        )
-        .bind(todo.id)
-        .fetch_all(&pool)
-        .await
-        .expect("[delete] todo_labels fetch error");
-        assert!(rows.len() == 0);
+            let expected = [
+                (TodoEventKind::Created, 1, DEFAULT_USER_ID),
+                (TodoEventKind::Updated, 1, DEFAULT_USER_ID),
+                (TodoEventKind::Deleted, 1, DEFAULT_USER_ID),
+                (TodoEventKind::Created, 2, 2),
+            ];
+            for (kind, id, user_id) in expected {
+                assert_eq!(
+                    TodoEvent { kind, id, user_id },
+                    events.try_recv().expect("event is not published")
+                );
+            }
+            assert!(events.try_recv().is_err());
+        }
     }
 }
 
 #[cfg(test)]
 pub mod test_utils {
-    use anyhow::Context;
     use axum::async_trait;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    };
+    use tokio::sync::broadcast;
 
     use super::*;
 
-    impl TodoEntity {
-        pub fn new(id: i32, text: String, labels: Vec<Label>) -> Self {
-            Self {
-                id,
-                text,
-                completed: false,
-                labels,
-            }
-        }
+    // 常にエラーを返すリポジトリ、DB障害時のハンドラの振る舞いを確認するために使う
+    #[derive(Debug, Clone)]
+    pub struct TodoRepositoryForFailure;
+
+    fn unexpected<T>() -> anyhow::Result<T> {
+        Err(RepositoryError::Unexpected(String::from("connection lost")).into())
     }
 
-    impl CreateTodo {
-        pub fn new(text: String, labels: Vec<i32>) -> Self {
-            Self { text, labels }
+    #[async_trait]
+    impl TodoRepository for TodoRepositoryForFailure {
+        fn for_user(&self, _user_id: i32) -> Self {
+            Self
         }
-    }
 
-    type TodoDatas = HashMap<i32, TodoEntity>;
+        async fn create(&self, _payload: CreateTodo) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
 
-    #[derive(Debug, Clone)]
-    pub struct TodoRepositoryForMemory {
-        store: Arc<RwLock<TodoDatas>>,
-        labels: Vec<Label>,
-    }
+        async fn create_many(&self, _payloads: Vec<CreateTodo>) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
+        }
 
-    impl TodoRepositoryForMemory {
-        pub fn new(labels: Vec<Label>) -> Self {
-            TodoRepositoryForMemory {
-                store: Arc::default(),
-                labels,
-            }
+        async fn find(&self, _id: i32) -> anyhow::Result<TodoEntity> {
+            unexpected()
         }
 
-        fn write_store_ref(&self) -> RwLockWriteGuard<TodoDatas> {
-            self.store.write().unwrap()
+        async fn find_including_deleted(&self, _id: i32) -> anyhow::Result<TodoEntity> {
+            unexpected()
         }
 
-        fn read_store_ref(&self) -> RwLockReadGuard<TodoDatas> {
-            self.store.read().unwrap()
+        async fn find_many(&self, _ids: Vec<i32>) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
         }
 
-        fn resolve_labels(&self, labels: Vec<i32>) -> Vec<Label> {
-            let mut label_list = self.labels.iter().cloned();
-            let labels = labels
-                .iter()
-                .map(|id| label_list.find(|label| label.id == *id).unwrap())
-                .collect();
-            labels
+        async fn all(
+            &self,
+            _filter: TodoFilter,
+            _sort: TodoSort,
+            _pagination: Pagination,
+        ) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
         }
-    }
 
-    #[async_trait]
-    impl TodoRepository for TodoRepositoryForMemory {
-        async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
-            let mut store = self.write_store_ref();
-            let id = (store.len() + 1) as i32;
-            let labels = self.resolve_labels(payload.labels);
-            let todo = TodoEntity::new(id, payload.text.clone(), labels);
-            store.insert(id, todo.clone());
-            Ok(todo)
+        fn stream_all(&self, _filter: TodoFilter, _sort: TodoSort) -> TodoStream {
+            Box::pin(futures_util::stream::iter([unexpected()]))
         }
 
-        async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
-            let store = self.read_store_ref();
-            let todo = store
-                .get(&id)
-                .map(|todo| todo.clone())
-                .ok_or(RepositoryError::NotFound(id))?;
-            Ok(todo)
+        async fn count(&self, _filter: TodoFilter) -> anyhow::Result<i64> {
+            unexpected()
         }
 
-        async fn all(&self) -> anyhow::Result<Vec<TodoEntity>> {
-            let store = self.read_store_ref();
-            Ok(Vec::from_iter(store.values().map(|todo| todo.clone())))
+        async fn stats(&self) -> anyhow::Result<TodoStats> {
+            unexpected()
         }
 
-        async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
-            let mut store = self.write_store_ref();
-            let todo = store.get(&id).context(RepositoryError::NotFound(id))?;
-            let text = payload.text.unwrap_or(todo.text.clone());
-            let completed = payload.completed.unwrap_or(todo.completed);
-            let labels = match payload.labels {
-                Some(label_ids) => self.resolve_labels(label_ids),
-                None => todo.labels.clone(),
-            };
-            let todo = TodoEntity {
-                id,
-                text,
-                completed,
-                labels,
-            };
-            store.insert(id, todo.clone());
-            Ok(todo)
+        async fn group_by_label(&self) -> anyhow::Result<Vec<TodoGroup>> {
+            unexpected()
         }
 
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
-            let mut store = self.write_store_ref();
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?;
-            Ok(())
+        async fn search(&self, _query: String) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
         }
-    }
 
-    #[cfg(test)]
-    mod test {
-        use super::*;
+        async fn find_by_text(&self, _text: &str) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
+        }
 
-        #[tokio::test]
-        async fn todo_crud_scenario() {
-            let text = "todo text".to_string();
-            let id = 1;
-            let label_data = Label {
-                id: 1,
-                name: String::from("test label"),
-            };
-            let labels = vec![label_data.clone()];
-            let expected = TodoEntity {
-                id,
-                text: text.clone(),
-                completed: false,
-                labels: labels.clone(),
-            };
+        async fn fuzzy_search(&self, _query: String) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
+        }
 
-            // create
-            let label_data = Label {
-                id: 1,
-                name: String::from("test label"),
-            };
-            let labels = vec![label_data.clone()];
-            let repository = TodoRepositoryForMemory::new(labels.clone());
-            let todo = repository
-                .create(CreateTodo::new(text, vec![label_data.id]))
-                .await
-                .expect("failed create todo");
-            assert_eq!(expected, todo);
+        async fn find_by_label(&self, _label_id: i32) -> anyhow::Result<Vec<TodoEntity>> {
+            unexpected()
+        }
 
-            // find
-            let todo = repository.find(todo.id).await.unwrap();
-            assert_eq!(expected, todo);
+        async fn find_labels(&self, _id: i32) -> anyhow::Result<Vec<Label>> {
+            unexpected()
+        }
 
-            // all
-            let todo = repository.all().await.expect("failed get all todo");
-            assert_eq!(vec![expected], todo);
+        async fn update(&self, _id: i32, _payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
 
-            // update
-            let text = "update todo text".to_string();
-            let todo = repository
-                .update(
-                    1,
-                    UpdateTodo {
-                        text: Some(text.clone()),
-                        completed: Some(true),
-                        labels: Some(vec![]),
-                    },
-                )
-                .await
-                .expect("failed update todo.");
-            assert_eq!(
-                TodoEntity {
-                    id,
-                    text,
-                    completed: true,
-                    labels: vec![],
-                },
-                todo
-            );
+        async fn replace(&self, _id: i32, _payload: ReplaceTodo) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
 
-            // delete
-            let res = repository.delete(id).await;
-            assert!(res.is_ok())
+        async fn toggle(&self, _id: i32) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
+
+        async fn complete_all(&self, _dry_run: bool) -> anyhow::Result<Vec<i32>> {
+            unexpected()
+        }
+
+        async fn add_label(&self, _todo_id: i32, _label_id: i32) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
+
+        async fn remove_label(&self, _todo_id: i32, _label_id: i32) -> anyhow::Result<()> {
+            unexpected()
+        }
+
+        async fn update_labels(
+            &self,
+            _todo_id: i32,
+            _add: Vec<i32>,
+            _remove: Vec<i32>,
+        ) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
+
+        async fn assign_label(
+            &self,
+            _label_id: i32,
+            _todo_ids: Vec<i32>,
+        ) -> anyhow::Result<AssignResult> {
+            unexpected()
+        }
+
+        async fn delete(&self, _id: i32, _cascade: bool) -> anyhow::Result<()> {
+            unexpected()
+        }
+
+        async fn restore(&self, _id: i32) -> anyhow::Result<TodoEntity> {
+            unexpected()
+        }
+
+        async fn purge(&self, _id: i32, _cascade: bool) -> anyhow::Result<()> {
+            unexpected()
+        }
+
+        async fn reorder(&self, _ordered_ids: Vec<i32>) -> anyhow::Result<()> {
+            unexpected()
+        }
+
+        async fn delete_completed(&self, _dry_run: bool) -> anyhow::Result<Vec<i32>> {
+            unexpected()
+        }
+
+        async fn delete_many(&self, _ids: Vec<i32>) -> anyhow::Result<u64> {
+            unexpected()
+        }
+
+        async fn history(&self, _id: i32) -> anyhow::Result<Vec<TodoAudit>> {
+            unexpected()
+        }
+
+        async fn ping(&self) -> anyhow::Result<()> {
+            unexpected()
+        }
+
+        // 送信側をすぐに破棄するため、購読してもClosedになる
+        fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+            broadcast::channel(1).1
+        }
+
+        async fn with_transaction<R, F>(&self, _f: F) -> anyhow::Result<R>
+        where
+            R: Send,
+            F: for<'a> FnOnce(&'a Self) -> TransactionFuture<'a, R> + Send,
+        {
+            unexpected()
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,128 @@
+use std::{
+    convert::Infallible,
+    env,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::BoxBody,
+    http::{Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use serde_json::json;
+use tower::{Layer, Service};
+
+use crate::pretty_json::Json;
+
+// 未設定時は30秒でレスポンスを返せなければ打ち切る
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+pub fn timeout_from_env() -> anyhow::Result<Duration> {
+    match env::var("REQUEST_TIMEOUT_SECS") {
+        Ok(value) => Ok(Duration::from_secs(value.parse()?)),
+        Err(_) => Ok(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+    }
+}
+
+// レスポンスのヘッダーを返すまでの時間を制限する、ボディの送信中は対象外
+// ハンドラのFutureを破棄するため、実行中のクエリもそこで中断される
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Timeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    timeout: Duration,
+}
+
+impl<S, B> Service<Request<B>> for Timeout<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let timeout = self.timeout;
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, future).await {
+                Ok(res) => res,
+                Err(_) => Ok(service_unavailable(timeout)),
+            }
+        })
+    }
+}
+
+fn service_unavailable(timeout: Duration) -> Response<BoxBody> {
+    tracing::warn!("request timed out after {:?}", timeout);
+    let body = json!({
+        "error": "timeout",
+        "message": format!("request did not complete within {:?}", timeout),
+    });
+    (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/fast", get(|| async { "fast" }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    "slow"
+                }),
+            )
+            .layer(TimeoutLayer::new(Duration::from_millis(50)))
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder().uri(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn abort_slow_request() {
+        let res = app().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("timeout", body["error"]);
+
+        let res = app().oneshot(request("/fast")).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+}